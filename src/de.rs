@@ -63,51 +63,458 @@
 //! #   foo().unwrap();
 //! # }
 //! ```
+//!
+//! ## Streaming
+//!
+//! By default, the whole message is decoded into a [`value::Message`](../value/struct.Message.html)
+//! before the first field is handed to the visitor.  For very large messages, the deserializer can
+//! instead be put in streaming mode, where fields are decoded as they are read from the input
+//! stream:
+//!
+//! ```
+//! # extern crate protobuf;
+//! # extern crate serde;
+//! # extern crate serde_protobuf;
+//! # extern crate serde_value;
+//! # use std::fs;
+//! # use serde::de::Deserialize;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! # use serde_protobuf::de::{Deserializer, Options};
+//! # use serde_value::Value;
+//! # fn main() {
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! # let descriptors = Descriptors::from_proto(&proto);
+//! # let data = &[8, 42];
+//! # let input = protobuf::CodedInputStream::from_bytes(data);
+//! # let name = ".protobuf_unittest.TestAllTypes";
+//! let mut deserializer = Deserializer::for_named_message(&descriptors, name, input).unwrap();
+//! deserializer.set_options(Options::new().with_streaming(true));
+//! let value = Value::deserialize(&mut deserializer).unwrap();
+//! # }
+//! ```
+//!
+//! The streaming output is the same as the default output as long as the fields in the input
+//! appear in field number order, which is what all official protobuf implementations produce.
+//! Fields that appear out of order are emitted where they are found, which might lead to the
+//! same key being visited more than once.
+//!
+//! ## Skipping fields
+//!
+//! When deserializing into a struct, only the fields named by the struct are decoded; all other
+//! fields are skipped at the wire level.  Values that the target ignores using
+//! `deserialize_ignored_any` are skipped in the same way.  By default, this only applies to the
+//! fields of the outermost message, because nested messages are decoded along with it.  In
+//! streaming mode, it applies to nested messages as well.
+//!
+//! ## Field keys
+//!
+//! Fields are visited with their names from the `.proto` file as keys by default.  Using
+//! `Options::with_field_keys`, they can instead be keyed by their JSON names (which are
+//! lowerCamelCase unless overridden by the schema, and thus match
+//! `#[serde(rename_all = "camelCase")]`) or by their numbers.  The names of struct fields are
+//! matched against the same kind of key when skipping fields.
+//!
+//! ## Extensions
+//!
+//! Extensions that are registered for a message (see `Descriptors::add_extension`) are decoded
+//! with their declared types, and visited like any other field when they are present in the input.
+//! Unless fields are keyed by number, their key is the fully qualified name of the extension in
+//! brackets, like in the text format:
+//!
+//! ```text
+//! { "name": "Alice", "[mypackage.nickname]": "Al" }
+//! ```
+//!
+//! ## Omitting fields
+//!
+//! Every field declared by a message is visited by default, with missing fields visited as `None`
+//! (or their default value), empty sequences or `()`.  With `Options::with_present_fields_only`,
+//! only the fields that are present in the input are visited.  With `Options::with_omit_defaults`,
+//! fields are only visited if their value differs from the default value of the field, which is
+//! either the declared default or the zero value of its type (the first value for enums, and never
+//! for messages).  Empty repeated fields count as default.
+//!
+//! ## Maps
+//!
+//! Map fields are visited as maps from the keys to the values of their entries, so that they can
+//! be deserialized into a `HashMap` or `BTreeMap`.  Like the official implementations, the last
+//! entry for a key wins, and a missing key or value is the zero value of its type.  In streaming
+//! mode, the entries of a map field are decoded up front as long as they follow each other in the
+//! input.  Empty maps count as default.
+//!
+//! ## Oneofs
+//!
+//! The member fields of a oneof are normally visited like any other fields.  With
+//! `Options::with_oneofs`, each oneof is instead visited as a single key, the name of the oneof.
+//! Its value is `None` if no member is set, or otherwise an externally tagged enum whose variant
+//! is the key of the member field that is set, so that a oneof can be deserialized into an
+//! `Option` of a Rust `enum`.  When deserializing into a self-describing type, the enum is
+//! visited as a map with a single entry.  Synthetic oneofs of `proto3` `optional` fields are not
+//! affected.
+//!
+//! ## Well-known types
+//!
+//! Messages of the well-known types from `google/protobuf` are given an idiomatic representation
+//! by default, which can be turned off with `Options::with_well_known_types`:
+//!
+//! * `Timestamp` and `Duration` are visited as strings like `1972-01-01T10:00:20.021Z` (RFC 3339)
+//!   and `1.500s`, or with `Options::with_time_format` as a map with `secs` and `nanos` keys.
+//! * The wrapper types like `Int32Value` are visited as their wrapped value, so that optional
+//!   fields of these types can be deserialized into an `Option` of the wrapped type.
+//! * `Empty` is visited as `()`.
+//! * `FieldMask` is visited as a sequence of its paths.
+//! * `Struct`, `Value` and `ListValue` are visited as dynamically typed maps, values and
+//!   sequences, with `null` visited as `()`.
+//!
+//! The descriptors of these types have to be loaded like any others, by including the
+//! `google/protobuf/*.proto` files in the descriptor set.
+//!
+//! ### Any
+//!
+//! The payload of a `google.protobuf.Any` is decoded using the message type named by its type URL,
+//! which is looked up with `Descriptors::message_by_type_url`.  Like in the canonical JSON mapping,
+//! the type URL is visited under the key [`TYPE_URL_KEY`](constant.TYPE_URL_KEY.html) (`@type`),
+//! followed by the fields of the payload, or by the key `value` for payloads of well-known types
+//! with a special representation:
+//!
+//! ```text
+//! { "@type": "type.googleapis.com/mypackage.Person", "name": "Alice", "age": 42 }
+//! { "@type": "type.googleapis.com/google.protobuf.Duration", "value": "1.500s" }
+//! ```
+//!
+//! Payloads of unknown types are visited as ordinary messages with `type_url` and `value` fields.
+//!
+//! ## Unknown fields
+//!
+//! Fields that aren't declared by the message descriptor or registered as extensions are normally
+//! dropped.  They can instead be visited under the reserved key
+//! [`UNKNOWN_FIELDS_KEY`](constant.UNKNOWN_FIELDS_KEY.html) using `Options::with_unknown_fields`.
+//! The value is a map from field numbers to the raw values found for them, grouped by wire type:
+//!
+//! ```text
+//! { "__unknown_fields": { 1000: { "varint": [1, 2], "length_delimited": [b"abc"] } } }
+//! ```
+//!
+//! Only the wire types that have values are included, and unknown groups are skipped.
+//!
+//! ## Borrowing
+//!
+//! When the input is an in-memory byte slice, the deserializer can be constructed with
+//! `Deserializer::from_bytes`.  String and bytes values are then handed to the visitor with
+//! `visit_borrowed_str` and `visit_borrowed_bytes`, so types like `&'de str` and `&'de [u8]` can
+//! be deserialized without copying.  Outside of streaming mode, this doesn't extend to map
+//! fields and well-known types, which are always copied.  Default values of missing fields are
+//! borrowed from the descriptors, as are field names in all modes (except for the keys of
+//! extensions).
 
 use crate::descriptor;
 use crate::error;
 
 use crate::value;
+use crate::well_known::{self, WellKnownType};
 use protobuf;
+use protobuf::stream::wire_format;
 use serde;
 use std::collections;
 use std::fmt;
+use std::mem;
+use std::str;
 use std::vec;
 
+/// The key under which unknown fields are visited, when enabled with
+/// `Options::with_unknown_fields`.
+pub const UNKNOWN_FIELDS_KEY: &str = "__unknown_fields";
+
+/// The key under which the type URL of a `google.protobuf.Any` is visited, when its payload is
+/// expanded.
+pub const TYPE_URL_KEY: &str = "@type";
+
 /// A deserializer that can deserialize a single message type.
 pub struct Deserializer<'de> {
     descriptors: &'de descriptor::Descriptors,
     descriptor: &'de descriptor::MessageDescriptor,
     input: protobuf::CodedInputStream<'de>,
+    // The slice that `input` reads from, if any, for handing out borrowed values
+    source: Option<&'de [u8]>,
+    options: Options,
+}
+
+/// Options that control how a `Deserializer` decodes and presents messages.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    streaming: bool,
+    unknown_enum_values: UnknownEnumValues,
+    unknown_fields: bool,
+    field_keys: FieldKeys,
+    present_fields_only: bool,
+    omit_defaults: bool,
+    oneofs: bool,
+    // Inverted, so that well-known types are special-cased by default
+    raw_well_known_types: bool,
+    time_format: TimeFormat,
+}
+
+/// How to present enum values that aren't declared by the enum type of their field.
+///
+/// Such values are common when a message was produced using a newer version of the schema.  Note
+/// that when not in streaming mode, undeclared values of closed (`proto2`) enums are moved into the
+/// unknown fields of their message while decoding, and are never visited.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum UnknownEnumValues {
+    /// Fail with an `Error::UnknownEnumValue` error.
+    #[default]
+    Error,
+    /// Visit the number of the value as an `i32`.
+    Number,
+    /// Visit a synthetic name of the form `UNKNOWN_ENUM_VALUE_<number>` as a string.
+    Name,
+}
+
+/// How to key the fields of messages.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FieldKeys {
+    /// Visit the name of the field as declared in the `.proto` file, e.g. `optional_int32`.
+    #[default]
+    Name,
+    /// Visit the JSON name of the field, e.g. `optionalInt32`.
+    JsonName,
+    /// Visit the number of the field as a `u32`.
+    ///
+    /// Note that derived `Deserialize` implementations treat integer keys as the index of the
+    /// struct field, not its number.
+    Number,
+}
+
+/// How to present `google.protobuf.Timestamp` and `google.protobuf.Duration` values.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TimeFormat {
+    /// Visit timestamps as RFC 3339 strings in UTC, e.g. `1972-01-01T10:00:20.021Z`, and durations
+    /// as a number of seconds with an `s` suffix, e.g. `1.500s`.
+    #[default]
+    String,
+    /// Visit a map with the keys `secs` (an `i64`) and `nanos` (an `i32`), the same layout that
+    /// `std::time::Duration` is deserialized from.
+    SecsNanos,
+}
+
+struct WellKnownDeserializer<'de> {
+    descriptors: &'de descriptor::Descriptors,
+    descriptor: &'de descriptor::MessageDescriptor,
+    well_known_type: WellKnownType,
+    options: Options,
+    message: value::Message,
+}
+
+struct AnyVisitor<'de> {
+    type_url: String,
+    // How many of the keys that precede the payload have been visited
+    visited: usize,
+    payload: AnyPayload<'de>,
+}
+
+enum AnyPayload<'de> {
+    Fields(Box<MessageVisitor<'de>>),
+    // `None` once the value has been visited
+    WellKnown(Option<WellKnownDeserializer<'de>>),
+}
+
+struct SecsNanosVisitor {
+    secs: i64,
+    nanos: i32,
+    visited: usize,
 }
 
 struct MessageVisitor<'de> {
     descriptors: &'de descriptor::Descriptors,
     descriptor: &'de descriptor::MessageDescriptor,
+    options: Options,
     fields: collections::btree_map::IntoIter<i32, value::Field>,
     field: Option<(&'de descriptor::FieldDescriptor, value::Field)>,
+    // Oneofs that are yet to be visited, with their member that is set
+    oneofs: vec::IntoIter<(&'de descriptor::OneofDescriptor, Option<OneofMember<'de>>)>,
+    oneof: Option<Option<OneofMember<'de>>>,
+    // Unknown fields that are yet to be visited
+    unknown: Option<protobuf::UnknownFields>,
+    // Where the length-delimited fields of the message are in the source slice, if any
+    payloads: Payloads<'de>,
 }
 
+type OneofMember<'de> = (&'de descriptor::FieldDescriptor, value::Value);
+
+// The payloads of the length-delimited fields of an encoded message by field number, in the
+// order that they appear in the input
+type Payloads<'de> = collections::BTreeMap<i32, Vec<&'de [u8]>>;
+
 struct MessageKeyDeserializer<'de> {
     descriptor: &'de descriptor::FieldDescriptor,
+    keys: FieldKeys,
 }
 
 struct MessageFieldDeserializer<'de> {
     descriptors: &'de descriptor::Descriptors,
     descriptor: &'de descriptor::FieldDescriptor,
+    options: Options,
     field: Option<value::Field>,
+    payloads: Vec<&'de [u8]>,
 }
 
 struct RepeatedValueVisitor<'de> {
     descriptors: &'de descriptor::Descriptors,
     descriptor: &'de descriptor::FieldDescriptor,
+    options: Options,
     values: vec::IntoIter<value::Value>,
+    // The payloads of the values, if there is one for each of them
+    payloads: vec::IntoIter<&'de [u8]>,
+}
+
+struct MapValueVisitor<'de> {
+    descriptors: &'de descriptor::Descriptors,
+    // The value field of the map entry message
+    descriptor: &'de descriptor::FieldDescriptor,
+    options: Options,
+    entries: collections::btree_map::IntoIter<value::MapKey, value::Value>,
+    // The value of the entry whose key was visited last
+    value: Option<value::Value>,
+}
+
+struct MapKeyDeserializer {
+    key: value::MapKey,
 }
 
 struct ValueDeserializer<'de> {
     descriptors: &'de descriptor::Descriptors,
     descriptor: &'de descriptor::FieldDescriptor,
+    options: Options,
     value: Option<value::Value>,
+    // The bytes in the source slice that the value was decoded from, if known
+    payload: Option<&'de [u8]>,
+}
+
+struct StreamingMessageVisitor<'de, 'b> {
+    descriptors: &'de descriptor::Descriptors,
+    descriptor: &'de descriptor::MessageDescriptor,
+    options: Options,
+    input: &'b mut protobuf::CodedInputStream<'de>,
+    source: Option<&'de [u8]>,
+    // Declared fields ordered by field number, and how many of them have been visited
+    fields: Vec<&'de descriptor::FieldDescriptor>,
+    visited: usize,
+    // The names of the fields to visit, or `None` to visit all fields
+    selected: Option<&'static [&'static str]>,
+    // A tag that has been read but not acted upon yet
+    tag: Option<(u32, wire_format::WireType)>,
+    field: Option<StreamingField<'de>>,
+    // Which oneofs have been visited, by oneof index
+    visited_oneofs: Vec<bool>,
+    unknown: protobuf::UnknownFields,
+}
+
+enum StreamingField<'de> {
+    Missing(&'de descriptor::FieldDescriptor),
+    Present(&'de descriptor::FieldDescriptor, wire_format::WireType),
+    // A value that had to be read up front to tell whether it is the default
+    Read(&'de descriptor::FieldDescriptor, ReadValue<'de>),
+    // A field of a closed enum type, which had to be decoded up front to filter out undeclared
+    // values
+    Decoded(&'de descriptor::FieldDescriptor, value::Field),
+    MissingOneof(&'de descriptor::OneofDescriptor),
+    PresentOneof(
+        &'de descriptor::OneofDescriptor,
+        &'de descriptor::FieldDescriptor,
+        wire_format::WireType,
+    ),
+    DecodedOneof(
+        &'de descriptor::OneofDescriptor,
+        &'de descriptor::FieldDescriptor,
+        value::Field,
+    ),
+    Unknown,
+}
+
+enum ReadValue<'de> {
+    Owned(value::Value),
+    Borrowed(BorrowedValue<'de>),
+}
+
+struct UnknownFieldsDeserializer {
+    fields: protobuf::UnknownFields,
+}
+
+struct UnknownValuesDeserializer {
+    values: protobuf::UnknownValues,
+}
+
+enum UnknownValueListDeserializer {
+    Varint(Vec<u64>),
+    Fixed32(Vec<u32>),
+    Fixed64(Vec<u64>),
+    LengthDelimited(Vec<Vec<u8>>),
+}
+
+struct OneofDeserializer<'de, D> {
+    // The member field that is set along with a deserializer for its value, if any
+    member: Option<(&'de descriptor::FieldDescriptor, D)>,
+    keys: FieldKeys,
+}
+
+struct OneofVariantDeserializer<'de, D> {
+    descriptor: &'de descriptor::FieldDescriptor,
+    keys: FieldKeys,
+    // `None` once the value has been visited
+    value: Option<D>,
+    visited_key: bool,
+}
+
+struct OneofValueDeserializer<D> {
+    value: D,
+}
+
+struct ByteBufDeserializer {
+    value: Vec<u8>,
+}
+
+struct BorrowedFieldDeserializer<'de> {
+    descriptor: &'de descriptor::FieldDescriptor,
+    value: BorrowedValue<'de>,
+}
+
+enum BorrowedValue<'de> {
+    String(&'de str),
+    Bytes(&'de [u8]),
+}
+
+struct StreamingFieldDeserializer<'de, 'b> {
+    descriptors: &'de descriptor::Descriptors,
+    descriptor: &'de descriptor::FieldDescriptor,
+    options: Options,
+    input: &'b mut protobuf::CodedInputStream<'de>,
+    source: Option<&'de [u8]>,
+    wire_type: wire_format::WireType,
+    tag: &'b mut Option<(u32, wire_format::WireType)>,
+    // Whether to visit a singular value as an option
+    optional: bool,
+}
+
+struct StreamingRepeatedValueVisitor<'de, 'b> {
+    descriptors: &'de descriptor::Descriptors,
+    descriptor: &'de descriptor::FieldDescriptor,
+    options: Options,
+    input: &'b mut protobuf::CodedInputStream<'de>,
+    source: Option<&'de [u8]>,
+    // The wire type of the next value, or `None` if there are no more values
+    wire_type: Option<wire_format::WireType>,
+    // The limit to restore once the current packed run of values is exhausted
+    packed_limit: Option<u64>,
+    tag: &'b mut Option<(u32, wire_format::WireType)>,
+}
+
+struct StreamingMessageDeserializer<'de, 'b> {
+    descriptors: &'de descriptor::Descriptors,
+    descriptor: &'de descriptor::MessageDescriptor,
+    options: Options,
+    input: &'b mut protobuf::CodedInputStream<'de>,
+    source: Option<&'de [u8]>,
 }
 
 impl<'de> Deserializer<'de> {
@@ -124,6 +531,25 @@ impl<'de> Deserializer<'de> {
             descriptors,
             descriptor,
             input,
+            source: None,
+            options: Options::default(),
+        }
+    }
+
+    /// Constructs a new protocol buffer deserializer for the specified message type that reads
+    /// from an in-memory byte slice.
+    ///
+    /// String and bytes values are borrowed from the slice instead of being copied, whether or not
+    /// streaming mode is enabled; see the [module documentation](index.html#borrowing).
+    pub fn from_bytes(
+        descriptors: &'de descriptor::Descriptors,
+        descriptor: &'de descriptor::MessageDescriptor,
+        bytes: &'de [u8],
+    ) -> Deserializer<'de> {
+        let input = protobuf::CodedInputStream::from_bytes(bytes);
+        Deserializer {
+            source: Some(bytes),
+            ..Deserializer::new(descriptors, descriptor, input)
         }
     }
 
@@ -146,19 +572,298 @@ impl<'de> Deserializer<'de> {
     }
 }
 
+impl<'de> Deserializer<'de> {
+    /// The options currently used by this deserializer.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Changes the options used by this deserializer.
+    pub fn set_options(&mut self, options: Options) {
+        self.options = options;
+    }
+}
+
+impl Options {
+    /// Creates the default set of options.
+    pub fn new() -> Options {
+        Options::default()
+    }
+
+    /// Whether to visit fields as they are read from the input instead of decoding the whole
+    /// message up front; see the [module documentation](index.html#streaming).
+    pub fn with_streaming(mut self, streaming: bool) -> Options {
+        self.streaming = streaming;
+        self
+    }
+
+    /// Whether fields are visited as they are read from the input.
+    pub fn streaming(&self) -> bool {
+        self.streaming
+    }
+
+    /// How to present enum values that aren't declared by their enum type.
+    pub fn with_unknown_enum_values(mut self, unknown_enum_values: UnknownEnumValues) -> Options {
+        self.unknown_enum_values = unknown_enum_values;
+        self
+    }
+
+    /// How enum values that aren't declared by their enum type are presented.
+    pub fn unknown_enum_values(&self) -> UnknownEnumValues {
+        self.unknown_enum_values
+    }
+
+    /// Whether to visit fields that aren't declared by the message descriptor under
+    /// `UNKNOWN_FIELDS_KEY`; see the [module documentation](index.html#unknown-fields).
+    pub fn with_unknown_fields(mut self, unknown_fields: bool) -> Options {
+        self.unknown_fields = unknown_fields;
+        self
+    }
+
+    /// Whether fields that aren't declared by the message descriptor are visited.
+    pub fn unknown_fields(&self) -> bool {
+        self.unknown_fields
+    }
+
+    /// How to key the fields of messages; see the [module documentation](index.html#field-keys).
+    pub fn with_field_keys(mut self, field_keys: FieldKeys) -> Options {
+        self.field_keys = field_keys;
+        self
+    }
+
+    /// How the fields of messages are keyed.
+    pub fn field_keys(&self) -> FieldKeys {
+        self.field_keys
+    }
+
+    /// Whether to only visit fields that are present in the input; see the
+    /// [module documentation](index.html#omitting-fields).
+    pub fn with_present_fields_only(mut self, present_fields_only: bool) -> Options {
+        self.present_fields_only = present_fields_only;
+        self
+    }
+
+    /// Whether only fields that are present in the input are visited.
+    pub fn present_fields_only(&self) -> bool {
+        self.present_fields_only
+    }
+
+    /// Whether to omit fields whose value is the default value of the field; see the
+    /// [module documentation](index.html#omitting-fields).
+    pub fn with_omit_defaults(mut self, omit_defaults: bool) -> Options {
+        self.omit_defaults = omit_defaults;
+        self
+    }
+
+    /// Whether fields whose value is the default value of the field are omitted.
+    pub fn omit_defaults(&self) -> bool {
+        self.omit_defaults
+    }
+
+    /// Whether to visit each oneof as a single key with an enum value; see the
+    /// [module documentation](index.html#oneofs).
+    pub fn with_oneofs(mut self, oneofs: bool) -> Options {
+        self.oneofs = oneofs;
+        self
+    }
+
+    /// Whether oneofs are visited as a single key with an enum value.
+    pub fn oneofs(&self) -> bool {
+        self.oneofs
+    }
+
+    /// Whether to give well-known types like `google.protobuf.Timestamp` an idiomatic
+    /// representation instead of visiting them as messages; see the
+    /// [module documentation](index.html#well-known-types).  This is enabled by default.
+    pub fn with_well_known_types(mut self, well_known_types: bool) -> Options {
+        self.raw_well_known_types = !well_known_types;
+        self
+    }
+
+    /// Whether well-known types are given an idiomatic representation.
+    pub fn well_known_types(&self) -> bool {
+        !self.raw_well_known_types
+    }
+
+    /// How to present timestamps and durations when well-known types are enabled.
+    pub fn with_time_format(mut self, time_format: TimeFormat) -> Options {
+        self.time_format = time_format;
+        self
+    }
+
+    /// How timestamps and durations are presented.
+    pub fn time_format(&self) -> TimeFormat {
+        self.time_format
+    }
+
+    /// The well-known type that the specified message should be visited as, if any.
+    #[inline]
+    fn well_known_type(self, message: &descriptor::MessageDescriptor) -> Option<WellKnownType> {
+        if self.raw_well_known_types {
+            None
+        } else {
+            WellKnownType::of(message)
+        }
+    }
+
+    /// The oneof that the specified field should be visited as part of, if any.
+    #[inline]
+    fn oneof_of<'a>(
+        self,
+        message: &'a descriptor::MessageDescriptor,
+        field: &descriptor::FieldDescriptor,
+    ) -> Option<&'a descriptor::OneofDescriptor> {
+        if !self.oneofs {
+            return None;
+        }
+        match message.oneof_of_field(field) {
+            Some(oneof) if !oneof.is_synthetic() => Some(oneof),
+            _ => None,
+        }
+    }
+
+    /// Whether any of `keys` selects the specified field, or the oneof that it is visited as part
+    /// of.
+    #[inline]
+    fn selects(
+        self,
+        message: &descriptor::MessageDescriptor,
+        field: &descriptor::FieldDescriptor,
+        keys: &[&str],
+    ) -> bool {
+        match self.oneof_of(message, field) {
+            Some(oneof) => keys.contains(&self.field_keys.oneof_key(oneof)),
+            None => self.field_keys.any_matches(field, keys),
+        }
+    }
+
+    /// Whether fields that are missing from the input are visited.
+    #[inline]
+    fn visits_missing_fields(self) -> bool {
+        !self.present_fields_only && !self.omit_defaults
+    }
+
+    /// Whether the specified decoded field should be left out.
+    #[inline]
+    fn omits(
+        self,
+        descriptors: &descriptor::Descriptors,
+        descriptor: &descriptor::FieldDescriptor,
+        field: &value::Field,
+    ) -> bool {
+        match *field {
+            value::Field::Singular(None) => !self.visits_missing_fields(),
+            value::Field::Singular(Some(ref v)) => {
+                self.omit_defaults && is_default_value(descriptors, descriptor, v)
+            }
+            value::Field::Repeated(ref vs) => vs.is_empty() && !self.visits_missing_fields(),
+            value::Field::Map(ref entries) => entries.is_empty() && !self.visits_missing_fields(),
+        }
+    }
+}
+
+impl FieldKeys {
+    /// Whether `key` is the key of the specified field.
+    #[inline]
+    fn matches(self, field: &descriptor::FieldDescriptor, key: &str) -> bool {
+        match self {
+            FieldKeys::Name | FieldKeys::JsonName if field.is_extension() => {
+                extension_key(field) == key
+            }
+            FieldKeys::Name => field.name() == key,
+            FieldKeys::JsonName => field.json_name() == key,
+            FieldKeys::Number => key.parse() == Ok(field.number()),
+        }
+    }
+
+    /// The key of the specified oneof.  Oneofs don't have numbers, so their names are used
+    /// instead.
+    #[inline]
+    fn oneof_key(self, oneof: &descriptor::OneofDescriptor) -> &str {
+        match self {
+            FieldKeys::Name | FieldKeys::Number => oneof.name(),
+            FieldKeys::JsonName => oneof.json_name(),
+        }
+    }
+
+    /// Whether any of `keys` is the key of the specified field.
+    #[inline]
+    fn any_matches(self, field: &descriptor::FieldDescriptor, keys: &[&str]) -> bool {
+        keys.iter().any(|key| self.matches(field, key))
+    }
+}
+
 impl<'de> fmt::Debug for Deserializer<'de> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Deserializer").finish()
     }
 }
 
+impl<'de> Deserializer<'de> {
+    #[inline]
+    fn deserialize_message<V>(
+        &mut self,
+        selected: Option<&'static [&'static str]>,
+        visitor: V,
+    ) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        let ds = self.descriptors;
+        let d = self.descriptor;
+        if let Some(well_known_type) = self.options.well_known_type(d) {
+            let mut message = value::Message::new(d);
+            message.merge_from(ds, d, &mut self.input)?;
+            return visit_well_known(ds, d, well_known_type, self.options, message, visitor);
+        }
+
+        if self.options.streaming {
+            return visitor.visit_map(StreamingMessageVisitor::new(
+                self.descriptors,
+                self.descriptor,
+                self.options,
+                &mut self.input,
+                self.source,
+                selected,
+            ));
+        }
+
+        let message = match selected {
+            Some(selected) => read_selected_message(
+                self.descriptors,
+                self.descriptor,
+                &mut self.input,
+                selected,
+                self.options,
+            )?,
+            None if self.options.present_fields_only => {
+                let mut message = value::Message::empty();
+                message.merge_present_from(self.descriptors, self.descriptor, &mut self.input)?;
+                message
+            }
+            None => {
+                let mut message = value::Message::new(self.descriptor);
+                message.merge_from(self.descriptors, self.descriptor, &mut self.input)?;
+                message
+            }
+        };
+        visitor.visit_map(MessageVisitor::borrowing(
+            self.descriptors,
+            self.descriptor,
+            self.options,
+            message,
+            self.source,
+        ))
+    }
+}
+
 impl<'de, 'b> serde::Deserializer<'de> for &'b mut Deserializer<'de> {
     type Error = error::CompatError;
 
     forward_to_deserialize_any! {
         bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
         byte_buf option unit unit_struct newtype_struct seq tuple
-        tuple_struct map struct enum identifier ignored_any
+        tuple_struct map enum identifier
     }
 
     #[inline]
@@ -166,13 +871,32 @@ impl<'de, 'b> serde::Deserializer<'de> for &'b mut Deserializer<'de> {
     where
         V: serde::de::Visitor<'de>,
     {
-        let mut message = value::Message::new(self.descriptor);
-        message.merge_from(self.descriptors, self.descriptor, &mut self.input)?;
-        visitor.visit_map(MessageVisitor::new(
-            self.descriptors,
-            self.descriptor,
-            message,
-        ))
+        self.deserialize_message(None, visitor)
+    }
+
+    #[inline]
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        self.deserialize_message(Some(fields), visitor)
+    }
+
+    #[inline]
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        while !self.input.eof()? {
+            let (_, wire_type) = self.input.read_tag_unpack()?;
+            skip_field(&mut self.input, wire_type)?;
+        }
+        visitor.visit_unit()
     }
 }
 
@@ -181,15 +905,57 @@ impl<'de> MessageVisitor<'de> {
     fn new(
         descriptors: &'de descriptor::Descriptors,
         descriptor: &'de descriptor::MessageDescriptor,
+        options: Options,
+        value: value::Message,
+    ) -> MessageVisitor<'de> {
+        MessageVisitor::borrowing(descriptors, descriptor, options, value, None)
+    }
+
+    /// Visits a decoded message, handing out string and bytes values borrowed from the encoded
+    /// message if it is specified.
+    #[inline]
+    fn borrowing(
+        descriptors: &'de descriptor::Descriptors,
+        descriptor: &'de descriptor::MessageDescriptor,
+        options: Options,
         value: value::Message,
+        source: Option<&'de [u8]>,
     ) -> MessageVisitor<'de> {
-        let fields = value.fields.into_iter();
-        let field = None;
+        let mut fields = value.fields;
+        let mut oneofs = Vec::new();
+        if options.oneofs {
+            for oneof in descriptor.oneofs().iter().filter(|o| !o.is_synthetic()) {
+                let mut member = None;
+                for &number in oneof.field_numbers() {
+                    if let Some(value::Field::Singular(Some(v))) = fields.remove(&number) {
+                        let field = descriptor
+                            .field_by_number(number)
+                            .expect("Lost track of field");
+                        member = Some((field, v));
+                    }
+                }
+                // Oneofs without a member that was decoded weren't present in the input
+                if member.is_some() || options.visits_missing_fields() {
+                    oneofs.push((oneof, member));
+                }
+            }
+        }
+
+        let unknown = if options.unknown_fields && value.unknown.fields.is_some() {
+            Some(value.unknown)
+        } else {
+            None
+        };
         MessageVisitor {
             descriptors,
             descriptor,
-            fields,
-            field,
+            options,
+            fields: fields.into_iter(),
+            field: None,
+            oneofs: oneofs.into_iter(),
+            oneof: None,
+            unknown,
+            payloads: source.map(scan_payloads).unwrap_or_default(),
         }
     }
 }
@@ -202,14 +968,30 @@ impl<'de> serde::de::MapAccess<'de> for MessageVisitor<'de> {
     where
         K: serde::de::DeserializeSeed<'de>,
     {
-        if let Some((k, v)) = self.fields.next() {
-            let descriptor = self
-                .descriptor
-                .field_by_number(k)
-                .expect("Lost track of field");
-            let key = seed.deserialize(MessageKeyDeserializer::new(descriptor))?;
+        let ds = self.descriptors;
+        let d = self.descriptor;
+        let options = self.options;
+        let next = self.fields.by_ref().find_map(|(k, v)| {
+            let descriptor = ds.field_or_extension(d, k).expect("Lost track of field");
+            if options.omits(ds, descriptor, &v) {
+                None
+            } else {
+                Some((descriptor, v))
+            }
+        });
+        if let Some((descriptor, v)) = next {
+            let key = seed.deserialize(MessageKeyDeserializer::new(
+                descriptor,
+                self.options.field_keys,
+            ))?;
             self.field = Some((descriptor, v));
             Ok(Some(key))
+        } else if let Some((oneof, member)) = self.oneofs.next() {
+            let key = seed.deserialize(oneof_key(self.options.field_keys, oneof))?;
+            self.oneof = Some(member);
+            Ok(Some(key))
+        } else if self.unknown.is_some() {
+            Ok(Some(seed.deserialize(unknown_fields_key())?))
         } else {
             Ok(None)
         }
@@ -220,23 +1002,52 @@ impl<'de> serde::de::MapAccess<'de> for MessageVisitor<'de> {
     where
         V: serde::de::DeserializeSeed<'de>,
     {
-        let (descriptor, field) = self
-            .field
-            .take()
-            .expect("visit_value was called before visit_key");
+        let (descriptor, field) = match self.field.take() {
+            Some(field) => field,
+            None => {
+                if let Some(member) = self.oneof.take() {
+                    let ds = self.descriptors;
+                    let options = self.options;
+                    let payloads = &mut self.payloads;
+                    let member = member.map(|(d, v)| {
+                        let payloads = payloads.remove(&d.number()).unwrap_or_default();
+                        let payload = singular_payload(payloads, &v);
+                        (
+                            d,
+                            ValueDeserializer::new(ds, d, options, v).borrowing(payload),
+                        )
+                    });
+                    return seed.deserialize(OneofDeserializer {
+                        member,
+                        keys: self.options.field_keys,
+                    });
+                }
+                let fields = self
+                    .unknown
+                    .take()
+                    .expect("visit_value was called before visit_key");
+                return seed.deserialize(UnknownFieldsDeserializer { fields });
+            }
+        };
 
-        seed.deserialize(MessageFieldDeserializer::new(
-            self.descriptors,
-            descriptor,
-            field,
-        ))
+        let payloads = self
+            .payloads
+            .remove(&descriptor.number())
+            .unwrap_or_default();
+        seed.deserialize(
+            MessageFieldDeserializer::new(self.descriptors, descriptor, self.options, field)
+                .borrowing(payloads),
+        )
     }
 }
 
 impl<'de> MessageKeyDeserializer<'de> {
     #[inline]
-    fn new(descriptor: &'de descriptor::FieldDescriptor) -> MessageKeyDeserializer<'de> {
-        MessageKeyDeserializer { descriptor }
+    fn new(
+        descriptor: &'de descriptor::FieldDescriptor,
+        keys: FieldKeys,
+    ) -> MessageKeyDeserializer<'de> {
+        MessageKeyDeserializer { descriptor, keys }
     }
 }
 
@@ -254,7 +1065,14 @@ impl<'de> serde::Deserializer<'de> for MessageKeyDeserializer<'de> {
     where
         V: serde::de::Visitor<'de>,
     {
-        visitor.visit_str(self.descriptor.name())
+        match self.keys {
+            FieldKeys::Name | FieldKeys::JsonName if self.descriptor.is_extension() => {
+                visitor.visit_string(extension_key(self.descriptor))
+            }
+            FieldKeys::Name => visitor.visit_borrowed_str(self.descriptor.name()),
+            FieldKeys::JsonName => visitor.visit_borrowed_str(self.descriptor.json_name()),
+            FieldKeys::Number => visitor.visit_u32(self.descriptor.number() as u32),
+        }
     }
 }
 
@@ -263,15 +1081,25 @@ impl<'de> MessageFieldDeserializer<'de> {
     fn new(
         descriptors: &'de descriptor::Descriptors,
         descriptor: &'de descriptor::FieldDescriptor,
+        options: Options,
         field: value::Field,
     ) -> MessageFieldDeserializer<'de> {
         let field = Some(field);
         MessageFieldDeserializer {
             descriptors,
             descriptor,
+            options,
             field,
+            payloads: Vec::new(),
         }
     }
+
+    /// Sets the payloads that the field was decoded from, in input order.
+    #[inline]
+    fn borrowing(mut self, payloads: Vec<&'de [u8]>) -> MessageFieldDeserializer<'de> {
+        self.payloads = payloads;
+        self
+    }
 }
 
 impl<'de> serde::Deserializer<'de> for MessageFieldDeserializer<'de> {
@@ -292,21 +1120,36 @@ impl<'de> serde::Deserializer<'de> for MessageFieldDeserializer<'de> {
         let d = self.descriptor;
         match self.field.take() {
             Some(value::Field::Singular(None)) => {
-                if d.field_label() == descriptor::FieldLabel::Optional {
+                if is_optional(d) {
                     visitor.visit_none()
                 } else {
                     visitor.visit_unit()
                 }
             }
             Some(value::Field::Singular(Some(v))) => {
-                if d.field_label() == descriptor::FieldLabel::Optional {
-                    visitor.visit_some(ValueDeserializer::new(ds, d, v))
+                let payload = singular_payload(mem::take(&mut self.payloads), &v);
+                if is_optional(d) {
+                    visitor.visit_some(
+                        ValueDeserializer::new(ds, d, self.options, v).borrowing(payload),
+                    )
                 } else {
-                    visit_value(ds, d, v, visitor)
+                    visit_value_from(ds, d, self.options, v, payload, visitor)
                 }
             }
             Some(value::Field::Repeated(vs)) => {
-                visitor.visit_seq(&mut RepeatedValueVisitor::new(ds, d, vs.into_iter()))
+                // Packed values share their payloads, so they can't be told apart
+                let payloads = if self.payloads.len() == vs.len() {
+                    mem::take(&mut self.payloads)
+                } else {
+                    Vec::new()
+                };
+                visitor.visit_seq(
+                    &mut RepeatedValueVisitor::new(ds, d, self.options, vs.into_iter())
+                        .borrowing(payloads),
+                )
+            }
+            Some(value::Field::Map(entries)) => {
+                visitor.visit_map(MapValueVisitor::new(ds, d, self.options, entries)?)
             }
             None => Err(error::Error::EndOfStream.into()),
         }
@@ -318,14 +1161,24 @@ impl<'de> RepeatedValueVisitor<'de> {
     fn new(
         descriptors: &'de descriptor::Descriptors,
         descriptor: &'de descriptor::FieldDescriptor,
+        options: Options,
         values: vec::IntoIter<value::Value>,
     ) -> RepeatedValueVisitor<'de> {
         RepeatedValueVisitor {
             descriptors,
             descriptor,
+            options,
             values,
+            payloads: Vec::new().into_iter(),
         }
     }
+
+    /// Sets the payloads that the values were decoded from, one for each value.
+    #[inline]
+    fn borrowing(mut self, payloads: Vec<&'de [u8]>) -> RepeatedValueVisitor<'de> {
+        self.payloads = payloads.into_iter();
+        self
+    }
 }
 
 impl<'de> serde::de::SeqAccess<'de> for RepeatedValueVisitor<'de> {
@@ -339,7 +1192,9 @@ impl<'de> serde::de::SeqAccess<'de> for RepeatedValueVisitor<'de> {
         let ds = self.descriptors;
         let d = self.descriptor;
         match self.values.next() {
-            Some(v) => Ok(Some(seed.deserialize(ValueDeserializer::new(ds, d, v))?)),
+            Some(v) => Ok(Some(seed.deserialize(
+                ValueDeserializer::new(ds, d, self.options, v).borrowing(self.payloads.next()),
+            )?)),
             None => Ok(None),
         }
     }
@@ -350,20 +1205,122 @@ impl<'de> serde::de::SeqAccess<'de> for RepeatedValueVisitor<'de> {
     }
 }
 
+impl<'de> MapValueVisitor<'de> {
+    #[inline]
+    fn new(
+        descriptors: &'de descriptor::Descriptors,
+        descriptor: &'de descriptor::FieldDescriptor,
+        options: Options,
+        entries: collections::BTreeMap<value::MapKey, value::Value>,
+    ) -> error::Result<MapValueVisitor<'de>> {
+        let value_field = match descriptor.field_type(descriptors) {
+            descriptor::FieldType::Message(m) => m.field_by_number(2),
+            _ => None,
+        };
+        match value_field {
+            Some(value_field) => Ok(MapValueVisitor {
+                descriptors,
+                descriptor: value_field,
+                options,
+                entries: entries.into_iter(),
+                value: None,
+            }),
+            None => Err(error::Error::BadFieldValue {
+                field: descriptor.name().to_owned(),
+            }),
+        }
+    }
+}
+
+impl<'de> serde::de::MapAccess<'de> for MapValueVisitor<'de> {
+    type Error = error::CompatError;
+
+    #[inline]
+    fn next_key_seed<K>(&mut self, seed: K) -> error::CompatResult<Option<K::Value>>
+    where
+        K: serde::de::DeserializeSeed<'de>,
+    {
+        match self.entries.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                Ok(Some(seed.deserialize(MapKeyDeserializer { key })?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    #[inline]
+    fn next_value_seed<V>(&mut self, seed: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::DeserializeSeed<'de>,
+    {
+        let value = self
+            .value
+            .take()
+            .expect("visit_value was called before visit_key");
+        seed.deserialize(ValueDeserializer::new(
+            self.descriptors,
+            self.descriptor,
+            self.options,
+            value,
+        ))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> Option<usize> {
+        self.entries.size_hint().1
+    }
+}
+
+impl<'de> serde::Deserializer<'de> for MapKeyDeserializer {
+    type Error = error::CompatError;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+
+    #[inline]
+    fn deserialize_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        match self.key {
+            value::MapKey::Bool(v) => visitor.visit_bool(v),
+            value::MapKey::I32(v) => visitor.visit_i32(v),
+            value::MapKey::I64(v) => visitor.visit_i64(v),
+            value::MapKey::U32(v) => visitor.visit_u32(v),
+            value::MapKey::U64(v) => visitor.visit_u64(v),
+            value::MapKey::String(v) => visitor.visit_string(v),
+        }
+    }
+}
+
 impl<'de> ValueDeserializer<'de> {
     #[inline]
     fn new(
         descriptors: &'de descriptor::Descriptors,
         descriptor: &'de descriptor::FieldDescriptor,
+        options: Options,
         value: value::Value,
     ) -> ValueDeserializer<'de> {
         let value = Some(value);
         ValueDeserializer {
             descriptors,
             descriptor,
+            options,
             value,
+            payload: None,
         }
     }
+
+    /// Sets the bytes in the source slice that the value was decoded from.
+    #[inline]
+    fn borrowing(mut self, payload: Option<&'de [u8]>) -> ValueDeserializer<'de> {
+        self.payload = payload;
+        self
+    }
 }
 
 impl<'de> serde::Deserializer<'de> for ValueDeserializer<'de> {
@@ -381,45 +1338,1798 @@ impl<'de> serde::Deserializer<'de> for ValueDeserializer<'de> {
         V: serde::de::Visitor<'de>,
     {
         match self.value.take() {
-            Some(value) => visit_value(self.descriptors, self.descriptor, value, visitor),
+            Some(value) => visit_value_from(
+                self.descriptors,
+                self.descriptor,
+                self.options,
+                value,
+                self.payload,
+                visitor,
+            ),
             None => Err(error::Error::EndOfStream.into()),
         }
     }
 }
 
-#[inline]
-fn visit_value<'de, V>(
-    descriptors: &'de descriptor::Descriptors,
-    descriptor: &'de descriptor::FieldDescriptor,
-    value: value::Value,
-    visitor: V,
-) -> error::CompatResult<V::Value>
-where
-    V: serde::de::Visitor<'de>,
-{
-    match value {
-        value::Value::Bool(v) => visitor.visit_bool(v),
-        value::Value::I32(v) => visitor.visit_i32(v),
-        value::Value::I64(v) => visitor.visit_i64(v),
-        value::Value::U32(v) => visitor.visit_u32(v),
-        value::Value::U64(v) => visitor.visit_u64(v),
-        value::Value::F32(v) => visitor.visit_f32(v),
-        value::Value::F64(v) => visitor.visit_f64(v),
-        value::Value::Bytes(v) => visitor.visit_byte_buf(v),
-        value::Value::String(v) => visitor.visit_string(v),
-        value::Value::Message(m) => {
-            if let descriptor::FieldType::Message(d) = descriptor.field_type(descriptors) {
-                visitor.visit_map(MessageVisitor::new(descriptors, d, m))
+impl<'de, 'b> StreamingMessageVisitor<'de, 'b> {
+    #[inline]
+    fn new(
+        descriptors: &'de descriptor::Descriptors,
+        descriptor: &'de descriptor::MessageDescriptor,
+        options: Options,
+        input: &'b mut protobuf::CodedInputStream<'de>,
+        source: Option<&'de [u8]>,
+        selected: Option<&'static [&'static str]>,
+    ) -> StreamingMessageVisitor<'de, 'b> {
+        let mut fields = descriptor.fields().iter().collect::<Vec<_>>();
+        fields.sort_by_key(|f| f.number());
+        StreamingMessageVisitor {
+            descriptors,
+            descriptor,
+            options,
+            input,
+            source,
+            fields,
+            visited: 0,
+            selected,
+            tag: None,
+            field: None,
+            visited_oneofs: vec![false; descriptor.oneofs().len()],
+            unknown: protobuf::UnknownFields::new(),
+        }
+    }
+
+    /// Whether the specified field should be visited when it is missing from the input.
+    #[inline]
+    fn visits_missing(&self, field: &descriptor::FieldDescriptor) -> bool {
+        self.options.visits_missing_fields()
+            && self.is_selected(field)
+            && self.options.oneof_of(self.descriptor, field).is_none()
+    }
+
+    /// Finds the next oneof that hasn't been visited, and marks it as visited.
+    #[inline]
+    fn next_missing_oneof(&mut self) -> Option<&'de descriptor::OneofDescriptor> {
+        if !self.options.oneofs || !self.options.visits_missing_fields() {
+            return None;
+        }
+        let oneofs = self.descriptor.oneofs();
+        for (oneof, visited) in oneofs.iter().zip(self.visited_oneofs.iter_mut()) {
+            if !*visited && !oneof.is_synthetic() {
+                *visited = true;
+                let selected = match self.selected {
+                    Some(selected) => selected.contains(&self.options.field_keys.oneof_key(oneof)),
+                    None => true,
+                };
+                if selected {
+                    return Some(oneof);
+                }
+            }
+        }
+        None
+    }
+
+    /// Reads a singular value of the specified field, returning it unless it is the default value
+    /// of the field.
+    #[inline]
+    fn read_non_default_value(
+        &mut self,
+        field: &'de descriptor::FieldDescriptor,
+        wire_type: wire_format::WireType,
+    ) -> error::Result<Option<ReadValue<'de>>> {
+        let ds = self.descriptors;
+        if let Some(v) = read_borrowed_value(ds, field, self.input, self.source, wire_type)? {
+            let is_default = match (field.default_value(), &v) {
+                (Some(value::Value::String(d)), BorrowedValue::String(v)) => d == v,
+                (Some(value::Value::Bytes(d)), BorrowedValue::Bytes(v)) => d == v,
+                (_, BorrowedValue::String(v)) => v.is_empty(),
+                (_, BorrowedValue::Bytes(v)) => v.is_empty(),
+            };
+            return Ok(if is_default {
+                None
+            } else {
+                Some(ReadValue::Borrowed(v))
+            });
+        }
+
+        let v = read_value(ds, field, self.options, self.input, wire_type)?;
+        Ok(if is_default_value(ds, field, &v) {
+            None
+        } else {
+            Some(ReadValue::Owned(v))
+        })
+    }
+
+    /// Decodes all consecutive values of a field of a closed enum type, moving undeclared values
+    /// to the unknown fields like `Message::merge_from` does.  Returns `None` if there were only
+    /// undeclared values.
+    #[inline]
+    fn read_closed_enum(
+        &mut self,
+        field: &'de descriptor::FieldDescriptor,
+        number: u32,
+        mut wire_type: wire_format::WireType,
+    ) -> error::Result<Option<value::Field>> {
+        let mut value = value::Field::new(field);
+        let mut dropped = protobuf::UnknownFields::new();
+        let keeps_unknown_fields = self.keeps_unknown_fields();
+        loop {
+            let unknown = if keeps_unknown_fields {
+                &mut self.unknown
+            } else {
+                &mut dropped
+            };
+            value.merge_value_from(
+                self.descriptors,
+                field,
+                self.input,
+                wire_type,
+                Some(unknown),
+                !self.options.present_fields_only,
+            )?;
+            if !field.is_repeated() || self.input.eof()? {
+                break;
+            }
+            let (next_number, next_wire_type) = self.input.read_tag_unpack()?;
+            if next_number == number {
+                wire_type = next_wire_type;
             } else {
-                panic!("A field with a message value doesn't have a message type!")
+                self.tag = Some((next_number, next_wire_type));
+                break;
             }
         }
-        value::Value::Enum(e) => {
-            if let descriptor::FieldType::Enum(d) = descriptor.field_type(descriptors) {
-                visitor.visit_str(d.value_by_number(e).unwrap().name())
+        Ok(match value {
+            value::Field::Singular(None) => None,
+            value::Field::Repeated(ref values) if values.is_empty() => None,
+            value => Some(value),
+        })
+    }
+
+    /// Whether unknown fields should be collected so that they can be visited.
+    #[inline]
+    fn keeps_unknown_fields(&self) -> bool {
+        self.options.unknown_fields
+            && match self.selected {
+                Some(selected) => selected.contains(&UNKNOWN_FIELDS_KEY),
+                None => true,
+            }
+    }
+
+    /// Whether the specified field should be visited at all.
+    #[inline]
+    fn is_selected(&self, field: &descriptor::FieldDescriptor) -> bool {
+        match self.selected {
+            Some(selected) => self.options.selects(self.descriptor, field, selected),
+            None => true,
+        }
+    }
+
+    /// Finds the next field to visit, skipping over unknown fields.
+    #[inline]
+    fn next_field(&mut self) -> error::Result<Option<StreamingField<'de>>> {
+        loop {
+            let tag = match self.tag.take() {
+                Some(tag) => Some(tag),
+                None if self.input.eof()? => None,
+                None => Some(self.input.read_tag_unpack()?),
+            };
+
+            let next_missing = self.fields.get(self.visited).cloned();
+
+            match tag {
+                Some((number, wire_type)) => {
+                    // Whether this is the first time that the field is seen in the input
+                    let mut first = false;
+                    match next_missing {
+                        // Fields that were skipped over in the input are visited first
+                        Some(f) if f.number() < number as i32 => {
+                            self.tag = Some((number, wire_type));
+                            self.visited += 1;
+                            if self.visits_missing(f) {
+                                return Ok(Some(StreamingField::Missing(f)));
+                            }
+                            continue;
+                        }
+                        Some(f) if f.number() == number as i32 => {
+                            self.visited += 1;
+                            first = true;
+                        }
+                        _ => (),
+                    }
+
+                    match self
+                        .descriptors
+                        .field_or_extension(self.descriptor, number as i32)
+                    {
+                        Some(f) if self.is_selected(f) && is_closed_enum(self.descriptors, f) => {
+                            let oneof = self.options.oneof_of(self.descriptor, f);
+                            match self.read_closed_enum(f, number, wire_type)? {
+                                Some(field) => match oneof {
+                                    Some(oneof) => {
+                                        let index = f.oneof_index().expect("Lost track of oneof");
+                                        self.visited_oneofs[index] = true;
+                                        return Ok(Some(StreamingField::DecodedOneof(
+                                            oneof, f, field,
+                                        )));
+                                    }
+                                    None => {
+                                        let is_default = match field {
+                                            value::Field::Singular(Some(ref v)) => {
+                                                is_default_value(self.descriptors, f, v)
+                                            }
+                                            _ => false,
+                                        };
+                                        if !self.options.omit_defaults || !is_default {
+                                            return Ok(Some(StreamingField::Decoded(f, field)));
+                                        }
+                                    }
+                                },
+                                // Only undeclared values were found, so the field is missing unless
+                                // it was already visited
+                                None if first && oneof.is_none() && self.visits_missing(f) => {
+                                    return Ok(Some(StreamingField::Missing(f)));
+                                }
+                                None => (),
+                            }
+                        }
+                        Some(f) if self.is_selected(f) => {
+                            if let Some(oneof) = self.options.oneof_of(self.descriptor, f) {
+                                let index = f.oneof_index().expect("Lost track of oneof");
+                                self.visited_oneofs[index] = true;
+                                return Ok(Some(StreamingField::PresentOneof(oneof, f, wire_type)));
+                            }
+                            if !self.options.omit_defaults || !can_be_default(self.descriptors, f) {
+                                return Ok(Some(StreamingField::Present(f, wire_type)));
+                            }
+                            if let Some(v) = self.read_non_default_value(f, wire_type)? {
+                                return Ok(Some(StreamingField::Read(f, v)));
+                            }
+                        }
+                        None if self.keeps_unknown_fields() => {
+                            use protobuf::rt::read_unknown_or_skip_group as u;
+                            u(number, wire_type, self.input, &mut self.unknown)?;
+                        }
+                        _ => skip_field(self.input, wire_type)?,
+                    }
+                }
+                None => match next_missing {
+                    Some(f) => {
+                        self.visited += 1;
+                        if self.visits_missing(f) {
+                            return Ok(Some(StreamingField::Missing(f)));
+                        }
+                    }
+                    None => {
+                        if let Some(oneof) = self.next_missing_oneof() {
+                            return Ok(Some(StreamingField::MissingOneof(oneof)));
+                        }
+                        // Unknown fields are visited last, since they are only known at the end
+                        if self.unknown.fields.is_some() {
+                            return Ok(Some(StreamingField::Unknown));
+                        }
+                        return Ok(None);
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl<'de, 'b> serde::de::MapAccess<'de> for StreamingMessageVisitor<'de, 'b> {
+    type Error = error::CompatError;
+
+    #[inline]
+    fn next_key_seed<K>(&mut self, seed: K) -> error::CompatResult<Option<K::Value>>
+    where
+        K: serde::de::DeserializeSeed<'de>,
+    {
+        match self.next_field()? {
+            Some(field) => {
+                let key = match field {
+                    StreamingField::Missing(d)
+                    | StreamingField::Present(d, _)
+                    | StreamingField::Read(d, _)
+                    | StreamingField::Decoded(d, _) => {
+                        seed.deserialize(MessageKeyDeserializer::new(d, self.options.field_keys))?
+                    }
+                    StreamingField::MissingOneof(o)
+                    | StreamingField::PresentOneof(o, _, _)
+                    | StreamingField::DecodedOneof(o, _, _) => {
+                        seed.deserialize(oneof_key(self.options.field_keys, o))?
+                    }
+                    StreamingField::Unknown => seed.deserialize(unknown_fields_key())?,
+                };
+                self.field = Some(field);
+                Ok(Some(key))
+            }
+            None => Ok(None),
+        }
+    }
+
+    #[inline]
+    fn next_value_seed<V>(&mut self, seed: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::DeserializeSeed<'de>,
+    {
+        let ds = self.descriptors;
+        match self
+            .field
+            .take()
+            .expect("visit_value was called before visit_key")
+        {
+            StreamingField::Missing(d) => {
+                if let Some(v) = borrow_default_value(d) {
+                    return seed.deserialize(BorrowedFieldDeserializer::new(d, v));
+                }
+                let field = missing_field(d);
+                seed.deserialize(MessageFieldDeserializer::new(ds, d, self.options, field))
+            }
+            StreamingField::Present(d, wire_type) => seed.deserialize(StreamingFieldDeserializer {
+                descriptors: ds,
+                descriptor: d,
+                options: self.options,
+                input: self.input,
+                source: self.source,
+                wire_type,
+                tag: &mut self.tag,
+                optional: is_optional(d),
+            }),
+            StreamingField::Read(d, ReadValue::Borrowed(v)) => {
+                seed.deserialize(BorrowedFieldDeserializer::new(d, v))
+            }
+            StreamingField::Read(d, ReadValue::Owned(v)) => {
+                let field = value::Field::Singular(Some(v));
+                seed.deserialize(MessageFieldDeserializer::new(ds, d, self.options, field))
+            }
+            StreamingField::Decoded(d, field) => {
+                seed.deserialize(MessageFieldDeserializer::new(ds, d, self.options, field))
+            }
+            StreamingField::MissingOneof(_) => {
+                seed.deserialize(OneofDeserializer::<StreamingFieldDeserializer> {
+                    member: None,
+                    keys: self.options.field_keys,
+                })
+            }
+            StreamingField::PresentOneof(_, d, wire_type) => {
+                let value = StreamingFieldDeserializer {
+                    descriptors: ds,
+                    descriptor: d,
+                    options: self.options,
+                    input: self.input,
+                    source: self.source,
+                    wire_type,
+                    tag: &mut self.tag,
+                    optional: false,
+                };
+                seed.deserialize(OneofDeserializer {
+                    member: Some((d, value)),
+                    keys: self.options.field_keys,
+                })
+            }
+            StreamingField::DecodedOneof(_, d, field) => {
+                let value = MessageFieldDeserializer::new(ds, d, self.options, field);
+                seed.deserialize(OneofDeserializer {
+                    member: Some((d, value)),
+                    keys: self.options.field_keys,
+                })
+            }
+            StreamingField::Unknown => seed.deserialize(UnknownFieldsDeserializer {
+                fields: mem::replace(&mut self.unknown, protobuf::UnknownFields::new()),
+            }),
+        }
+    }
+}
+
+impl<'de, 'b> serde::Deserializer<'de> for StreamingFieldDeserializer<'de, 'b> {
+    type Error = error::CompatError;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier
+    }
+
+    #[inline]
+    fn deserialize_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        self.deserialize_field(None, visitor)
+    }
+
+    #[inline]
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        self.deserialize_field(Some(fields), visitor)
+    }
+
+    #[inline]
+    fn deserialize_ignored_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        skip_field(self.input, self.wire_type)?;
+        if self.descriptor.is_repeated() {
+            // Skip the rest of the values as long as they follow each other in the input
+            while !self.input.eof()? {
+                let (number, wire_type) = self.input.read_tag_unpack()?;
+                if number as i32 == self.descriptor.number() {
+                    skip_field(self.input, wire_type)?;
+                } else {
+                    *self.tag = Some((number, wire_type));
+                    break;
+                }
+            }
+        }
+        visitor.visit_unit()
+    }
+}
+
+impl<'de, 'b> StreamingFieldDeserializer<'de, 'b> {
+    #[inline]
+    fn deserialize_field<V>(
+        self,
+        selected: Option<&'static [&'static str]>,
+        visitor: V,
+    ) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        let ds = self.descriptors;
+        let d = self.descriptor;
+        let optional = self.optional;
+
+        if d.is_map() {
+            let field = read_map(ds, d, self.options, self.input, self.wire_type, self.tag)?;
+            return visitor.visit_map(MapValueVisitor::new(ds, d, self.options, field)?);
+        }
+
+        if d.is_repeated() {
+            let mut values = StreamingRepeatedValueVisitor {
+                descriptors: ds,
+                descriptor: d,
+                options: self.options,
+                input: self.input,
+                source: self.source,
+                wire_type: Some(self.wire_type),
+                packed_limit: None,
+                tag: self.tag,
+            };
+            let result = visitor.visit_seq(&mut values)?;
+            // The visitor may have stopped before the end of the sequence
+            values.finish()?;
+            return Ok(result);
+        }
+
+        if let descriptor::FieldType::Message(m) = d.field_type(ds) {
+            check_wire_type(self.wire_type, wire_format::WireTypeLengthDelimited)?;
+            let message = StreamingMessageDeserializer {
+                descriptors: ds,
+                descriptor: m,
+                options: self.options,
+                input: self.input,
+                source: self.source,
+            };
+            if optional {
+                visitor.visit_some(message)
             } else {
-                panic!("A field with an enum value doesn't have an enum type!")
+                message.deserialize_message(selected, visitor)
+            }
+        } else if let Some(v) = read_borrowed_value(ds, d, self.input, self.source, self.wire_type)?
+        {
+            if optional {
+                visitor.visit_some(v)
+            } else {
+                serde::Deserializer::deserialize_any(v, visitor)
+            }
+        } else {
+            let v = read_value(ds, d, self.options, self.input, self.wire_type)?;
+            if optional {
+                visitor.visit_some(ValueDeserializer::new(ds, d, self.options, v))
+            } else {
+                visit_value(ds, d, self.options, v, visitor)
             }
         }
     }
 }
+
+impl<'de> serde::Deserializer<'de> for UnknownFieldsDeserializer {
+    type Error = error::CompatError;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+
+    #[inline]
+    fn deserialize_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        let mut fields = match self.fields.fields {
+            Some(fields) => fields.into_iter().collect::<Vec<_>>(),
+            None => Vec::new(),
+        };
+        fields.sort_by_key(|&(number, _)| number);
+        let fields = fields
+            .into_iter()
+            .map(|(number, values)| (number, UnknownValuesDeserializer { values }));
+        visitor.visit_map(serde::de::value::MapDeserializer::new(fields))
+    }
+}
+
+impl<'de> serde::de::IntoDeserializer<'de, error::CompatError> for UnknownValuesDeserializer {
+    type Deserializer = Self;
+
+    #[inline]
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'de> serde::Deserializer<'de> for UnknownValuesDeserializer {
+    type Error = error::CompatError;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+
+    #[inline]
+    fn deserialize_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        use self::UnknownValueListDeserializer::*;
+
+        let values = self.values;
+        let lists = vec![
+            ("varint", Varint(values.varint)),
+            ("fixed32", Fixed32(values.fixed32)),
+            ("fixed64", Fixed64(values.fixed64)),
+            ("length_delimited", LengthDelimited(values.length_delimited)),
+        ];
+        let lists = lists.into_iter().filter(|(_, list)| !list.is_empty());
+        visitor.visit_map(serde::de::value::MapDeserializer::new(lists))
+    }
+}
+
+impl UnknownValueListDeserializer {
+    #[inline]
+    fn is_empty(&self) -> bool {
+        match *self {
+            UnknownValueListDeserializer::Varint(ref v) => v.is_empty(),
+            UnknownValueListDeserializer::Fixed32(ref v) => v.is_empty(),
+            UnknownValueListDeserializer::Fixed64(ref v) => v.is_empty(),
+            UnknownValueListDeserializer::LengthDelimited(ref v) => v.is_empty(),
+        }
+    }
+}
+
+impl<'de> serde::de::IntoDeserializer<'de, error::CompatError> for UnknownValueListDeserializer {
+    type Deserializer = Self;
+
+    #[inline]
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'de> serde::Deserializer<'de> for UnknownValueListDeserializer {
+    type Error = error::CompatError;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+
+    #[inline]
+    fn deserialize_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        use serde::de::value::SeqDeserializer;
+
+        match self {
+            UnknownValueListDeserializer::Varint(v) => {
+                visitor.visit_seq(SeqDeserializer::new(v.into_iter()))
+            }
+            UnknownValueListDeserializer::Fixed32(v) => {
+                visitor.visit_seq(SeqDeserializer::new(v.into_iter()))
+            }
+            UnknownValueListDeserializer::Fixed64(v) => {
+                visitor.visit_seq(SeqDeserializer::new(v.into_iter()))
+            }
+            UnknownValueListDeserializer::LengthDelimited(v) => {
+                let values = v.into_iter().map(|value| ByteBufDeserializer { value });
+                visitor.visit_seq(SeqDeserializer::new(values))
+            }
+        }
+    }
+}
+
+impl<'de, D> serde::Deserializer<'de> for OneofDeserializer<'de, D>
+where
+    D: serde::Deserializer<'de, Error = error::CompatError>,
+{
+    type Error = error::CompatError;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+
+    #[inline]
+    fn deserialize_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        match self.member {
+            Some((descriptor, value)) => visitor.visit_some(OneofVariantDeserializer {
+                descriptor,
+                keys: self.keys,
+                value: Some(value),
+                visited_key: false,
+            }),
+            None => visitor.visit_none(),
+        }
+    }
+
+    #[inline]
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        match self.member {
+            Some((descriptor, value)) => visitor.visit_enum(OneofVariantDeserializer {
+                descriptor,
+                keys: self.keys,
+                value: Some(value),
+                visited_key: false,
+            }),
+            None => visitor.visit_none(),
+        }
+    }
+}
+
+impl<'de, D> serde::Deserializer<'de> for OneofVariantDeserializer<'de, D>
+where
+    D: serde::Deserializer<'de, Error = error::CompatError>,
+{
+    type Error = error::CompatError;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+
+    #[inline]
+    fn deserialize_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        visitor.visit_map(self)
+    }
+
+    #[inline]
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        visitor.visit_enum(self)
+    }
+}
+
+impl<'de, D> serde::de::MapAccess<'de> for OneofVariantDeserializer<'de, D>
+where
+    D: serde::Deserializer<'de, Error = error::CompatError>,
+{
+    type Error = error::CompatError;
+
+    #[inline]
+    fn next_key_seed<K>(&mut self, seed: K) -> error::CompatResult<Option<K::Value>>
+    where
+        K: serde::de::DeserializeSeed<'de>,
+    {
+        if self.visited_key {
+            return Ok(None);
+        }
+        self.visited_key = true;
+        let key = seed.deserialize(MessageKeyDeserializer::new(self.descriptor, self.keys))?;
+        Ok(Some(key))
+    }
+
+    #[inline]
+    fn next_value_seed<V>(&mut self, seed: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::DeserializeSeed<'de>,
+    {
+        let value = self
+            .value
+            .take()
+            .expect("visit_value was called before visit_key");
+        seed.deserialize(value)
+    }
+}
+
+impl<'de, D> serde::de::EnumAccess<'de> for OneofVariantDeserializer<'de, D>
+where
+    D: serde::Deserializer<'de, Error = error::CompatError>,
+{
+    type Error = error::CompatError;
+    type Variant = OneofValueDeserializer<D>;
+
+    #[inline]
+    fn variant_seed<V>(self, seed: V) -> error::CompatResult<(V::Value, Self::Variant)>
+    where
+        V: serde::de::DeserializeSeed<'de>,
+    {
+        let variant = seed.deserialize(MessageKeyDeserializer::new(self.descriptor, self.keys))?;
+        let value = self.value.expect("Lost track of oneof value");
+        Ok((variant, OneofValueDeserializer { value }))
+    }
+}
+
+impl<'de, D> serde::de::VariantAccess<'de> for OneofValueDeserializer<D>
+where
+    D: serde::Deserializer<'de, Error = error::CompatError>,
+{
+    type Error = error::CompatError;
+
+    #[inline]
+    fn unit_variant(self) -> error::CompatResult<()> {
+        // The value still has to be consumed from the input
+        serde::Deserializer::deserialize_ignored_any(self.value, serde::de::IgnoredAny)?;
+        Ok(())
+    }
+
+    #[inline]
+    fn newtype_variant_seed<T>(self, seed: T) -> error::CompatResult<T::Value>
+    where
+        T: serde::de::DeserializeSeed<'de>,
+    {
+        seed.deserialize(self.value)
+    }
+
+    #[inline]
+    fn tuple_variant<V>(self, len: usize, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        serde::Deserializer::deserialize_tuple(self.value, len, visitor)
+    }
+
+    #[inline]
+    fn struct_variant<V>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        serde::Deserializer::deserialize_struct(self.value, "", fields, visitor)
+    }
+}
+
+impl<'de> serde::Deserializer<'de> for WellKnownDeserializer<'de> {
+    type Error = error::CompatError;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+
+    #[inline]
+    fn deserialize_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        visit_well_known(
+            self.descriptors,
+            self.descriptor,
+            self.well_known_type,
+            self.options,
+            self.message,
+            visitor,
+        )
+    }
+}
+
+impl<'de> serde::de::MapAccess<'de> for AnyVisitor<'de> {
+    type Error = error::CompatError;
+
+    #[inline]
+    fn next_key_seed<K>(&mut self, seed: K) -> error::CompatResult<Option<K::Value>>
+    where
+        K: serde::de::DeserializeSeed<'de>,
+    {
+        use serde::de::value::BorrowedStrDeserializer;
+
+        if self.visited == 0 {
+            self.visited += 1;
+            let key = BorrowedStrDeserializer::<error::CompatError>::new(TYPE_URL_KEY);
+            return Ok(Some(seed.deserialize(key)?));
+        }
+        match self.payload {
+            AnyPayload::Fields(ref mut fields) => fields.next_key_seed(seed),
+            AnyPayload::WellKnown(Some(_)) => {
+                let key = BorrowedStrDeserializer::<error::CompatError>::new("value");
+                Ok(Some(seed.deserialize(key)?))
+            }
+            AnyPayload::WellKnown(None) => Ok(None),
+        }
+    }
+
+    #[inline]
+    fn next_value_seed<V>(&mut self, seed: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::DeserializeSeed<'de>,
+    {
+        use serde::de::IntoDeserializer;
+
+        if self.visited == 1 {
+            self.visited += 1;
+            let type_url = mem::take(&mut self.type_url);
+            return seed.deserialize(type_url.into_deserializer());
+        }
+        match self.payload {
+            AnyPayload::Fields(ref mut fields) => fields.next_value_seed(seed),
+            AnyPayload::WellKnown(ref mut value) => seed.deserialize(
+                value
+                    .take()
+                    .expect("visit_value was called before visit_key"),
+            ),
+        }
+    }
+}
+
+impl<'de> serde::de::MapAccess<'de> for SecsNanosVisitor {
+    type Error = error::CompatError;
+
+    #[inline]
+    fn next_key_seed<K>(&mut self, seed: K) -> error::CompatResult<Option<K::Value>>
+    where
+        K: serde::de::DeserializeSeed<'de>,
+    {
+        let key = match self.visited {
+            0 => "secs",
+            1 => "nanos",
+            _ => return Ok(None),
+        };
+        let key = serde::de::value::BorrowedStrDeserializer::<error::CompatError>::new(key);
+        Ok(Some(seed.deserialize(key)?))
+    }
+
+    #[inline]
+    fn next_value_seed<V>(&mut self, seed: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::DeserializeSeed<'de>,
+    {
+        use serde::de::IntoDeserializer;
+
+        self.visited += 1;
+        match self.visited {
+            1 => seed.deserialize(self.secs.into_deserializer()),
+            _ => seed.deserialize(self.nanos.into_deserializer()),
+        }
+    }
+}
+
+impl<'de> serde::de::IntoDeserializer<'de, error::CompatError> for ByteBufDeserializer {
+    type Deserializer = Self;
+
+    #[inline]
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'de> serde::Deserializer<'de> for ByteBufDeserializer {
+    type Error = error::CompatError;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+
+    #[inline]
+    fn deserialize_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        visitor.visit_byte_buf(self.value)
+    }
+}
+
+impl<'de> BorrowedFieldDeserializer<'de> {
+    #[inline]
+    fn new(
+        descriptor: &'de descriptor::FieldDescriptor,
+        value: BorrowedValue<'de>,
+    ) -> BorrowedFieldDeserializer<'de> {
+        BorrowedFieldDeserializer { descriptor, value }
+    }
+}
+
+impl<'de> serde::Deserializer<'de> for BorrowedFieldDeserializer<'de> {
+    type Error = error::CompatError;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+
+    #[inline]
+    fn deserialize_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        if is_optional(self.descriptor) {
+            visitor.visit_some(self.value)
+        } else {
+            serde::Deserializer::deserialize_any(self.value, visitor)
+        }
+    }
+}
+
+impl<'de> serde::Deserializer<'de> for BorrowedValue<'de> {
+    type Error = error::CompatError;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+
+    #[inline]
+    fn deserialize_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        match self {
+            BorrowedValue::String(v) => visitor.visit_borrowed_str(v),
+            BorrowedValue::Bytes(v) => visitor.visit_borrowed_bytes(v),
+        }
+    }
+}
+
+impl<'de, 'b> StreamingRepeatedValueVisitor<'de, 'b> {
+    /// Moves on to the next value of this field, if the input has more of them.
+    #[inline]
+    fn advance(&mut self) -> error::Result<()> {
+        if let Some(old_limit) = self.packed_limit {
+            if !self.input.eof()? {
+                return Ok(());
+            }
+            self.input.pop_limit(old_limit);
+            self.packed_limit = None;
+        }
+
+        if !self.input.eof()? {
+            let (number, wire_type) = self.input.read_tag_unpack()?;
+            if number as i32 == self.descriptor.number() {
+                self.wire_type = Some(wire_type);
+            } else {
+                *self.tag = Some((number, wire_type));
+            }
+        }
+        Ok(())
+    }
+
+    /// Skips over the values that weren't visited, leaving the tag of the next field behind.
+    #[inline]
+    fn finish(&mut self) -> error::Result<()> {
+        loop {
+            if self.packed_limit.is_some() {
+                let remaining = self.input.bytes_until_limit();
+                self.input.skip_raw_bytes(remaining as u32)?;
+            } else if let Some(wire_type) = self.wire_type.take() {
+                skip_field(self.input, wire_type)?;
+            } else {
+                return Ok(());
+            }
+            self.advance()?;
+        }
+    }
+}
+
+impl<'de, 'b> serde::de::SeqAccess<'de> for StreamingRepeatedValueVisitor<'de, 'b> {
+    type Error = error::CompatError;
+
+    #[inline]
+    fn next_element_seed<A>(&mut self, seed: A) -> error::CompatResult<Option<A::Value>>
+    where
+        A: serde::de::DeserializeSeed<'de>,
+    {
+        let ds = self.descriptors;
+        let d = self.descriptor;
+        let field_type = d.field_type(ds);
+
+        let wire_type = match self.packed_limit {
+            Some(_) => packed_wire_type(&field_type),
+            None => self.wire_type.take(),
+        };
+        let mut wire_type = match wire_type {
+            Some(wire_type) => wire_type,
+            None => return Ok(None),
+        };
+
+        if self.packed_limit.is_none() && wire_type == wire_format::WireTypeLengthDelimited {
+            if let Some(packed) = packed_wire_type(&field_type) {
+                let len = self.input.read_raw_varint64()?;
+                self.packed_limit = Some(self.input.push_limit(len)?);
+                if self.input.eof()? {
+                    self.advance()?;
+                    return self.next_element_seed(seed);
+                }
+                wire_type = packed;
+            }
+        }
+
+        let element = if let descriptor::FieldType::Message(m) = field_type {
+            check_wire_type(wire_type, wire_format::WireTypeLengthDelimited)?;
+            seed.deserialize(StreamingMessageDeserializer {
+                descriptors: ds,
+                descriptor: m,
+                options: self.options,
+                input: self.input,
+                source: self.source,
+            })?
+        } else if let Some(v) = read_borrowed_value(ds, d, self.input, self.source, wire_type)? {
+            seed.deserialize(v)?
+        } else {
+            let v = read_value(ds, d, self.options, self.input, wire_type)?;
+            seed.deserialize(ValueDeserializer::new(ds, d, self.options, v))?
+        };
+
+        self.advance()?;
+        Ok(Some(element))
+    }
+}
+
+impl<'de, 'b> StreamingMessageDeserializer<'de, 'b> {
+    #[inline]
+    fn deserialize_message<V>(
+        self,
+        selected: Option<&'static [&'static str]>,
+        visitor: V,
+    ) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        let ds = self.descriptors;
+        let d = self.descriptor;
+        let len = self.input.read_raw_varint64()?;
+        let old_limit = self.input.push_limit(len)?;
+
+        // Well-known types have to be decoded as a whole anyway
+        if let Some(well_known_type) = self.options.well_known_type(d) {
+            let mut message = value::Message::new(d);
+            message.merge_from(ds, d, self.input)?;
+            self.input.pop_limit(old_limit);
+            return visit_well_known(ds, d, well_known_type, self.options, message, visitor);
+        }
+
+        let result = visitor.visit_map(StreamingMessageVisitor::new(
+            self.descriptors,
+            self.descriptor,
+            self.options,
+            self.input,
+            self.source,
+            selected,
+        ))?;
+        // The visitor might not have consumed the whole message
+        let rest = self.input.bytes_until_limit();
+        self.input.skip_raw_bytes(rest as u32)?;
+        self.input.pop_limit(old_limit);
+        Ok(result)
+    }
+}
+
+impl<'de, 'b> serde::Deserializer<'de> for StreamingMessageDeserializer<'de, 'b> {
+    type Error = error::CompatError;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier
+    }
+
+    #[inline]
+    fn deserialize_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        self.deserialize_message(None, visitor)
+    }
+
+    #[inline]
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        self.deserialize_message(Some(fields), visitor)
+    }
+
+    #[inline]
+    fn deserialize_ignored_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        skip_field(self.input, wire_format::WireTypeLengthDelimited)?;
+        visitor.visit_unit()
+    }
+}
+
+/// Reads a message from the input, only decoding the fields with the specified keys.
+///
+/// Unknown fields are kept if enabled and `UNKNOWN_FIELDS_KEY` is one of the keys.
+#[inline]
+fn read_selected_message(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    input: &mut protobuf::CodedInputStream,
+    selected: &[&str],
+    options: Options,
+) -> error::Result<value::Message> {
+    let unknown_fields = options.unknown_fields && selected.contains(&UNKNOWN_FIELDS_KEY);
+    let mut message = if options.present_fields_only {
+        value::Message::empty()
+    } else {
+        let mut message = value::Message::new(descriptor);
+        message
+            .fields
+            .retain(|&number, _| match descriptor.field_by_number(number) {
+                Some(f) => options.selects(descriptor, f, selected),
+                None => false,
+            });
+        message
+    };
+
+    // Undeclared values of closed enums are moved to the unknown fields like in
+    // `Message::merge_from`, but are dropped if unknown fields aren't kept
+    let mut dropped = protobuf::UnknownFields::new();
+    let unknown = if unknown_fields {
+        &mut message.unknown
+    } else {
+        &mut dropped
+    };
+    while !input.eof()? {
+        let (number, wire_type) = input.read_tag_unpack()?;
+        match descriptors.field_or_extension(descriptor, number as i32) {
+            Some(f) if options.selects(descriptor, f, selected) => {
+                let field = message
+                    .fields
+                    .entry(f.number())
+                    .or_insert_with(|| value::Field::new(f));
+                field.merge_value_from(
+                    descriptors,
+                    f,
+                    input,
+                    wire_type,
+                    Some(&mut *unknown),
+                    !options.present_fields_only,
+                )?;
+            }
+            None if unknown_fields => {
+                use protobuf::rt::read_unknown_or_skip_group as u;
+                u(number, wire_type, input, unknown)?;
+            }
+            _ => skip_field(input, wire_type)?,
+        }
+    }
+    Ok(message)
+}
+
+/// Skips over a single value with the specified wire type without decoding it.
+#[inline]
+fn skip_field(
+    input: &mut protobuf::CodedInputStream,
+    wire_type: wire_format::WireType,
+) -> error::Result<()> {
+    match wire_type {
+        wire_format::WireTypeLengthDelimited => {
+            let len = input.read_raw_varint32()?;
+            input.skip_raw_bytes(len)?;
+        }
+        wire_format::WireTypeStartGroup => loop {
+            let (_, wire_type) = input.read_tag_unpack()?;
+            if wire_type == wire_format::WireTypeEndGroup {
+                break;
+            }
+            skip_field(input, wire_type)?;
+        },
+        wire_format::WireTypeEndGroup => return Err(error::Error::BadWireType { wire_type }),
+        _ => {
+            input.read_unknown(wire_type)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads a single non-message value of the specified field.
+#[inline]
+fn read_value(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::FieldDescriptor,
+    options: Options,
+    input: &mut protobuf::CodedInputStream,
+    wire_type: wire_format::WireType,
+) -> error::Result<value::Value> {
+    let mut field = value::Field::Singular(None);
+    if options.present_fields_only {
+        field.merge_present_from(descriptors, descriptor, input, wire_type)?;
+    } else {
+        field.merge_from(descriptors, descriptor, input, wire_type)?;
+    }
+    match field {
+        value::Field::Singular(Some(v)) => Ok(v),
+        _ => Err(error::Error::EndOfStream),
+    }
+}
+
+/// Reads the entries of a map field for as long as they follow each other in the input.
+///
+/// Entries are decoded up front, so that a later entry with the same key replaces an earlier one.
+#[inline]
+fn read_map(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::FieldDescriptor,
+    options: Options,
+    input: &mut protobuf::CodedInputStream,
+    mut wire_type: wire_format::WireType,
+    tag: &mut Option<(u32, wire_format::WireType)>,
+) -> error::Result<collections::BTreeMap<value::MapKey, value::Value>> {
+    let mut field = value::Field::new(descriptor);
+    loop {
+        if options.present_fields_only {
+            field.merge_present_from(descriptors, descriptor, input, wire_type)?;
+        } else {
+            field.merge_from(descriptors, descriptor, input, wire_type)?;
+        }
+        if input.eof()? {
+            break;
+        }
+        let (number, next_wire_type) = input.read_tag_unpack()?;
+        if number as i32 == descriptor.number() {
+            wire_type = next_wire_type;
+        } else {
+            *tag = Some((number, next_wire_type));
+            break;
+        }
+    }
+    match field {
+        value::Field::Map(entries) => Ok(entries),
+        _ => Err(error::Error::BadFieldValue {
+            field: descriptor.name().to_owned(),
+        }),
+    }
+}
+
+#[inline]
+fn unknown_fields_key<'de>() -> serde::de::value::BorrowedStrDeserializer<'de, error::CompatError> {
+    serde::de::value::BorrowedStrDeserializer::new(UNKNOWN_FIELDS_KEY)
+}
+
+#[inline]
+fn oneof_key(
+    keys: FieldKeys,
+    oneof: &descriptor::OneofDescriptor,
+) -> serde::de::value::BorrowedStrDeserializer<'_, error::CompatError> {
+    serde::de::value::BorrowedStrDeserializer::new(keys.oneof_key(oneof))
+}
+
+/// Finds the payloads of the length-delimited fields of an encoded message, so that decoded values
+/// can be matched up with the bytes that they were decoded from.
+///
+/// The message has already been decoded, so scanning simply stops at malformed input.
+fn scan_payloads(bytes: &[u8]) -> Payloads<'_> {
+    fn scan<'de>(
+        bytes: &'de [u8],
+        input: &mut protobuf::CodedInputStream,
+        payloads: &mut Payloads<'de>,
+    ) -> error::Result<()> {
+        while !input.eof()? {
+            let (number, wire_type) = input.read_tag_unpack()?;
+            if wire_type != wire_format::WireTypeLengthDelimited {
+                skip_field(input, wire_type)?;
+                continue;
+            }
+            let len = input.read_raw_varint32()?;
+            let start = input.pos() as usize;
+            input.skip_raw_bytes(len)?;
+            payloads
+                .entry(number as i32)
+                .or_default()
+                .push(&bytes[start..start + len as usize]);
+        }
+        Ok(())
+    }
+
+    let mut payloads = Payloads::new();
+    let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+    let _ = scan(bytes, &mut input, &mut payloads);
+    payloads
+}
+
+/// The payload that a decoded singular value was read from, if it can be told: the last one for
+/// strings and bytes, where the last value wins, and the only one for messages, which are merged.
+#[inline]
+fn singular_payload<'de>(mut payloads: Vec<&'de [u8]>, value: &value::Value) -> Option<&'de [u8]> {
+    match *value {
+        value::Value::Message(_) if payloads.len() != 1 => None,
+        _ => payloads.pop(),
+    }
+}
+
+/// Reads a single string or bytes value of the specified field by borrowing it from the source
+/// slice.
+///
+/// Returns `None` without consuming any input if there is no source slice or if the field has some
+/// other type.
+#[inline]
+fn read_borrowed_value<'de>(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::FieldDescriptor,
+    input: &mut protobuf::CodedInputStream<'de>,
+    source: Option<&'de [u8]>,
+    wire_type: wire_format::WireType,
+) -> error::Result<Option<BorrowedValue<'de>>> {
+    let source = match source {
+        Some(source) => source,
+        None => return Ok(None),
+    };
+    let is_string = match descriptor.field_type(descriptors) {
+        descriptor::FieldType::String => true,
+        descriptor::FieldType::Bytes => false,
+        _ => return Ok(None),
+    };
+    check_wire_type(wire_type, wire_format::WireTypeLengthDelimited)?;
+
+    let len = input.read_raw_varint32()?;
+    let start = input.pos() as usize;
+    input.skip_raw_bytes(len)?;
+    let bytes = &source[start..start + len as usize];
+
+    if is_string {
+        match str::from_utf8(bytes) {
+            Ok(s) => Ok(Some(BorrowedValue::String(s))),
+            Err(_) => Err(error::Error::Protobuf(protobuf::ProtobufError::WireError(
+                protobuf::error::WireError::Utf8Error,
+            ))),
+        }
+    } else {
+        Ok(Some(BorrowedValue::Bytes(bytes)))
+    }
+}
+
+/// Whether the specified field is of a closed enum type, whose undeclared values are treated like
+/// unknown fields.
+#[inline]
+fn is_closed_enum(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::FieldDescriptor,
+) -> bool {
+    match descriptor.field_type(descriptors) {
+        descriptor::FieldType::Enum(e) => e.is_closed(),
+        _ => false,
+    }
+}
+
+/// Whether values of the specified field are visited as options, which is the case for optional
+/// fields that track presence.
+#[inline]
+fn is_optional(descriptor: &descriptor::FieldDescriptor) -> bool {
+    descriptor.field_label() == descriptor::FieldLabel::Optional && descriptor.has_presence()
+}
+
+/// Whether a singular value of the specified field can be the default value of the field.
+#[inline]
+fn can_be_default(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::FieldDescriptor,
+) -> bool {
+    match descriptor.field_type(descriptors) {
+        _ if descriptor.is_repeated() => false,
+        descriptor::FieldType::Message(_) | descriptor::FieldType::Group(_) => false,
+        _ => true,
+    }
+}
+
+/// Whether the value is the default value of the specified field, which is either the declared
+/// default value or the zero value of the type of the field.  Messages are never default values.
+#[inline]
+fn is_default_value(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::FieldDescriptor,
+    value: &value::Value,
+) -> bool {
+    use crate::value::Value::*;
+
+    if let Some(default) = descriptor.default_value() {
+        return match (default, value) {
+            (&Bool(d), &Bool(v)) => d == v,
+            (&I32(d), &I32(v)) => d == v,
+            (&I64(d), &I64(v)) => d == v,
+            (&U32(d), &U32(v)) => d == v,
+            (&U64(d), &U64(v)) => d == v,
+            (&F32(d), &F32(v)) => d.to_bits() == v.to_bits(),
+            (&F64(d), &F64(v)) => d.to_bits() == v.to_bits(),
+            (Bytes(d), Bytes(v)) => d == v,
+            (String(d), String(v)) => d == v,
+            (&Enum(d), &Enum(v)) => d == v,
+            _ => false,
+        };
+    }
+
+    match *value {
+        Bool(v) => !v,
+        I32(v) => v == 0,
+        I64(v) => v == 0,
+        U32(v) => v == 0,
+        U64(v) => v == 0,
+        F32(v) => v.to_bits() == 0,
+        F64(v) => v.to_bits() == 0,
+        Bytes(ref v) => v.is_empty(),
+        String(ref v) => v.is_empty(),
+        Enum(v) => match descriptor.field_type(descriptors) {
+            descriptor::FieldType::Enum(e) => match e.values().first() {
+                Some(first) => first.number() == v,
+                None => false,
+            },
+            _ => false,
+        },
+        Message(_) => false,
+    }
+}
+
+/// Borrows the default value of the specified field from its descriptor, if it is a string or bytes
+/// value.
+#[inline]
+fn borrow_default_value<'de>(
+    descriptor: &'de descriptor::FieldDescriptor,
+) -> Option<BorrowedValue<'de>> {
+    match descriptor.default_value() {
+        Some(value::Value::String(v)) => Some(BorrowedValue::String(v)),
+        Some(value::Value::Bytes(v)) => Some(BorrowedValue::Bytes(v)),
+        _ => None,
+    }
+}
+
+/// The wire type of the elements of a packed repeated field of the specified type, if the type
+/// can be packed.
+#[inline]
+fn packed_wire_type(field_type: &descriptor::FieldType) -> Option<wire_format::WireType> {
+    use crate::descriptor::FieldType::*;
+    match *field_type {
+        Bool | Int32 | Int64 | SInt32 | SInt64 | UInt32 | UInt64 | Enum(_) => {
+            Some(wire_format::WireTypeVarint)
+        }
+        Fixed32 | SFixed32 | Float => Some(wire_format::WireTypeFixed32),
+        Fixed64 | SFixed64 | Double => Some(wire_format::WireTypeFixed64),
+        _ => None,
+    }
+}
+
+#[inline]
+fn check_wire_type(
+    actual: wire_format::WireType,
+    expected: wire_format::WireType,
+) -> error::Result<()> {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(error::Error::BadWireType { wire_type: actual })
+    }
+}
+
+#[inline]
+fn visit_value<'de, V>(
+    descriptors: &'de descriptor::Descriptors,
+    descriptor: &'de descriptor::FieldDescriptor,
+    options: Options,
+    value: value::Value,
+    visitor: V,
+) -> error::CompatResult<V::Value>
+where
+    V: serde::de::Visitor<'de>,
+{
+    visit_value_from(descriptors, descriptor, options, value, None, visitor)
+}
+
+/// Visits a decoded value, borrowing string and bytes values from the bytes in the source slice
+/// that the value was decoded from, if they are specified and hold the same data, or from the
+/// default value of the field.
+fn visit_value_from<'de, V>(
+    descriptors: &'de descriptor::Descriptors,
+    descriptor: &'de descriptor::FieldDescriptor,
+    options: Options,
+    value: value::Value,
+    payload: Option<&'de [u8]>,
+    visitor: V,
+) -> error::CompatResult<V::Value>
+where
+    V: serde::de::Visitor<'de>,
+{
+    match value {
+        value::Value::Bool(v) => visitor.visit_bool(v),
+        value::Value::I32(v) => visitor.visit_i32(v),
+        value::Value::I64(v) => visitor.visit_i64(v),
+        value::Value::U32(v) => visitor.visit_u32(v),
+        value::Value::U64(v) => visitor.visit_u64(v),
+        value::Value::F32(v) => visitor.visit_f32(v),
+        value::Value::F64(v) => visitor.visit_f64(v),
+        value::Value::Bytes(v) => match (payload, descriptor.default_value()) {
+            (Some(payload), _) if payload == &v[..] => visitor.visit_borrowed_bytes(payload),
+            (_, Some(value::Value::Bytes(d))) if *d == v => visitor.visit_borrowed_bytes(d),
+            _ => visitor.visit_byte_buf(v),
+        },
+        value::Value::String(v) => {
+            match (payload.map(str::from_utf8), descriptor.default_value()) {
+                (Some(Ok(payload)), _) if payload == v => visitor.visit_borrowed_str(payload),
+                (_, Some(value::Value::String(d))) if *d == v => visitor.visit_borrowed_str(d),
+                _ => visitor.visit_string(v),
+            }
+        }
+        value::Value::Message(m) => match descriptor.field_type(descriptors) {
+            descriptor::FieldType::Message(d) | descriptor::FieldType::Group(d) => {
+                match options.well_known_type(d) {
+                    Some(well_known_type) => {
+                        visit_well_known(descriptors, d, well_known_type, options, m, visitor)
+                    }
+                    None => visitor.visit_map(MessageVisitor::borrowing(
+                        descriptors,
+                        d,
+                        options,
+                        m,
+                        payload,
+                    )),
+                }
+            }
+            _ => panic!("A field with a message value doesn't have a message type!"),
+        },
+        value::Value::Enum(e) => {
+            if let descriptor::FieldType::Enum(d) = descriptor.field_type(descriptors) {
+                match d.value_by_number(e) {
+                    Some(v) => visitor.visit_borrowed_str(v.name()),
+                    None => match options.unknown_enum_values {
+                        UnknownEnumValues::Error => {
+                            Err(error::Error::UnknownEnumValue { value: e }.into())
+                        }
+                        UnknownEnumValues::Number => visitor.visit_i32(e),
+                        UnknownEnumValues::Name => {
+                            visitor.visit_string(format!("UNKNOWN_ENUM_VALUE_{}", e))
+                        }
+                    },
+                }
+            } else {
+                panic!("A field with an enum value doesn't have an enum type!")
+            }
+        }
+    }
+}
+
+/// Visits a message that was already decoded, the same way that `Deserializer` does outside of
+/// streaming mode.
+pub(crate) fn visit_message<'de, V>(
+    descriptors: &'de descriptor::Descriptors,
+    descriptor: &'de descriptor::MessageDescriptor,
+    options: Options,
+    message: value::Message,
+    visitor: V,
+) -> error::CompatResult<V::Value>
+where
+    V: serde::de::Visitor<'de>,
+{
+    match options.well_known_type(descriptor) {
+        Some(well_known_type) => visit_well_known(
+            descriptors,
+            descriptor,
+            well_known_type,
+            options,
+            message,
+            visitor,
+        ),
+        None => visitor.visit_map(MessageVisitor::new(
+            descriptors,
+            descriptor,
+            options,
+            message,
+        )),
+    }
+}
+
+/// Visits a message of a well-known type using its special representation.
+fn visit_well_known<'de, V>(
+    descriptors: &'de descriptor::Descriptors,
+    descriptor: &'de descriptor::MessageDescriptor,
+    well_known_type: WellKnownType,
+    options: Options,
+    mut message: value::Message,
+    visitor: V,
+) -> error::CompatResult<V::Value>
+where
+    V: serde::de::Visitor<'de>,
+{
+    let bad = || error::Error::BadWellKnownValue {
+        name: descriptor.name().to_owned(),
+    };
+
+    match well_known_type {
+        WellKnownType::Any => {
+            let type_url = match message.fields.get(&1) {
+                Some(value::Field::Singular(Some(value::Value::String(v)))) => v.clone(),
+                _ => String::new(),
+            };
+            let (ds, d) = match descriptors.message_by_type_url(&type_url) {
+                Some(resolved) => resolved,
+                None => {
+                    let fields = MessageVisitor::new(descriptors, descriptor, options, message);
+                    return visitor.visit_map(fields);
+                }
+            };
+            let bytes = match message.fields.remove(&2) {
+                Some(value::Field::Singular(Some(value::Value::Bytes(v)))) => v,
+                _ => Vec::new(),
+            };
+
+            let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+            let payload = if options.present_fields_only {
+                let mut payload = value::Message::empty();
+                payload.merge_present_from(ds, d, &mut input)?;
+                payload
+            } else {
+                let mut payload = value::Message::new(d);
+                payload.merge_from(ds, d, &mut input)?;
+                payload
+            };
+            let payload = match options.well_known_type(d) {
+                Some(well_known_type) => AnyPayload::WellKnown(Some(WellKnownDeserializer {
+                    descriptors: ds,
+                    descriptor: d,
+                    well_known_type,
+                    options,
+                    message: payload,
+                })),
+                None => AnyPayload::Fields(Box::new(MessageVisitor::new(ds, d, options, payload))),
+            };
+            visitor.visit_map(AnyVisitor {
+                type_url,
+                visited: 0,
+                payload,
+            })
+        }
+        WellKnownType::Timestamp | WellKnownType::Duration => {
+            let secs = match take_field(descriptor, &mut message, 1)?.1 {
+                value::Field::Singular(Some(value::Value::I64(v))) => v,
+                value::Field::Singular(None) => 0,
+                _ => return Err(bad().into()),
+            };
+            let nanos = match take_field(descriptor, &mut message, 2)?.1 {
+                value::Field::Singular(Some(value::Value::I32(v))) => v,
+                value::Field::Singular(None) => 0,
+                _ => return Err(bad().into()),
+            };
+            match options.time_format {
+                TimeFormat::String => {
+                    let formatted = if well_known_type == WellKnownType::Timestamp {
+                        well_known::format_timestamp(secs, nanos)
+                    } else {
+                        well_known::format_duration(secs, nanos)
+                    };
+                    visitor.visit_string(formatted.ok_or_else(bad)?)
+                }
+                TimeFormat::SecsNanos => visitor.visit_map(SecsNanosVisitor {
+                    secs,
+                    nanos,
+                    visited: 0,
+                }),
+            }
+        }
+        WellKnownType::Empty => visitor.visit_unit(),
+        WellKnownType::Wrapper => match take_field(descriptor, &mut message, 1)? {
+            (d, value::Field::Singular(Some(v))) => {
+                visit_value(descriptors, d, options, v, visitor)
+            }
+            _ => Err(bad().into()),
+        },
+        WellKnownType::Struct => match take_field(descriptor, &mut message, 1)? {
+            (d, value::Field::Map(entries)) => {
+                visitor.visit_map(MapValueVisitor::new(descriptors, d, options, entries)?)
+            }
+            _ => Err(bad().into()),
+        },
+        WellKnownType::Value => {
+            // The kind of value is a oneof, where `null_value` (number 1) is the `NullValue` enum
+            for number in 1..=6 {
+                if let Some(value::Field::Singular(Some(v))) = message.fields.remove(&number) {
+                    let d = descriptor.field_by_number(number).ok_or_else(bad)?;
+                    return if number == 1 {
+                        visitor.visit_unit()
+                    } else {
+                        visit_value(descriptors, d, options, v, visitor)
+                    };
+                }
+            }
+            visitor.visit_unit()
+        }
+        WellKnownType::FieldMask | WellKnownType::ListValue => {
+            match take_field(descriptor, &mut message, 1)? {
+                (d, value::Field::Repeated(vs)) => visitor.visit_seq(
+                    &mut RepeatedValueVisitor::new(descriptors, d, options, vs.into_iter()),
+                ),
+                _ => Err(bad().into()),
+            }
+        }
+    }
+}
+
+/// The key that an extension is visited with when fields are keyed by name, i.e.
+/// `[foo.package.extension]`.
+#[inline]
+fn extension_key(field: &descriptor::FieldDescriptor) -> String {
+    format!("[{}]", &field.full_name()[1..])
+}
+
+/// Removes the specified field from a decoded message, falling back to the value that the field
+/// has when it is missing from the input.
+#[inline]
+fn take_field<'de>(
+    descriptor: &'de descriptor::MessageDescriptor,
+    message: &mut value::Message,
+    number: i32,
+) -> error::Result<(&'de descriptor::FieldDescriptor, value::Field)> {
+    match descriptor.field_by_number(number) {
+        Some(d) => {
+            let field = message
+                .fields
+                .remove(&number)
+                .unwrap_or_else(|| missing_field(d));
+            Ok((d, field))
+        }
+        None => Err(error::Error::BadWellKnownValue {
+            name: descriptor.name().to_owned(),
+        }),
+    }
+}
+
+/// The value of the specified field when it is missing from the input.
+#[inline]
+fn missing_field(descriptor: &descriptor::FieldDescriptor) -> value::Field {
+    if descriptor.is_repeated() {
+        value::Field::new(descriptor)
+    } else {
+        value::Field::Singular(descriptor.default_value().cloned())
+    }
+}
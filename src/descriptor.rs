@@ -73,6 +73,30 @@
 //! descriptors.add_enum(e);
 //! ```
 //!
+//! The type names of message, enum and group fields may also be relative (i.e. `Color` or
+//! `mypackage.Color`), in which case they are resolved the way `protoc` does, starting in the
+//! scope of the message that the field belongs to.
+//!
+//! ## Validating schemas
+//!
+//! `Descriptors::from_proto` is lenient and silently skips anything it can't make sense of.  A
+//! `DescriptorsBuilder` instead checks the schema once everything has been added, and returns an
+//! error listing every problem it found:
+//!
+//! ```
+//! # extern crate serde_protobuf;
+//! # extern crate protobuf;
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::*;
+//! # fn main() {
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let mut builder = DescriptorsBuilder::new();
+//! builder.add_file_set_proto(&proto);
+//! let descriptors = builder.build().unwrap();
+//! # }
+//! ```
+//!
 //! ## Exploring descriptors
 //!
 //! The descriptors contain various indices that can be used to quickly look up information:
@@ -93,6 +117,84 @@
 //! # }
 //! ```
 //!
+//! ## Services
+//!
+//! Services and their methods are registered along with the types.  A method can be looked up by
+//! the path that gRPC sends requests to, which gives the message types of its request and response
+//! bodies:
+//!
+//! ```
+//! # extern crate serde_protobuf;
+//! # extern crate protobuf;
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! # fn main() {
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let method = descriptors.method_by_path("/protobuf_unittest.TestService/Foo").unwrap();
+//! assert_eq!(".protobuf_unittest.FooRequest", method.input_type(&descriptors).unwrap().name());
+//! assert_eq!(".protobuf_unittest.FooResponse", method.output_type(&descriptors).unwrap().name());
+//! # }
+//! ```
+//!
+//! ## Files
+//!
+//! Every file that was added to the registry has a `FileDescriptor` with its package, syntax and
+//! imports, and messages and enums know the file that they were declared in.  The import graph can
+//! be walked so that every file comes after the files that it imports, which fails if files import
+//! each other in a cycle:
+//!
+//! ```
+//! # extern crate serde_protobuf;
+//! # extern crate protobuf;
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! # fn main() {
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! for file in descriptors.files_in_import_order().unwrap() {
+//!     for message in file.messages(&descriptors) {
+//!         println!("{}: {}", file.name(), message.name());
+//!     }
+//! }
+//! # }
+//! ```
+//!
+//! ## Source code info
+//!
+//! When a file descriptor set is compiled with source code info, the descriptors of messages,
+//! fields, enums, enum values, services and methods have a `source_location` with the span of
+//! their definition and the comments around it, as `protoc` attached them:
+//!
+//! ```text
+//! protoc schema.proto --include_source_info -o testdata/descriptors.pb
+//! ```
+//!
+//! ## Options
+//!
+//! The standard options that affect how data is interpreted, such as `deprecated`, `packed` and
+//! `allow_alias`, are available as accessors on the descriptors.  The full options of a message,
+//! field, enum or enum value can be decoded with its `options` method, which also decodes custom
+//! options for which the registry has extensions of the options type:
+//!
+//! ```
+//! # extern crate serde_protobuf;
+//! # extern crate protobuf;
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! # fn main() {
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let field = descriptors.message_by_name(".protobuf_unittest.TestDeprecatedFields").unwrap()
+//!                        .field_by_name("deprecated_int32").unwrap();
+//! assert!(field.is_deprecated());
+//! let options = field.options(&descriptors).unwrap();
+//! # }
+//! ```
+//!
 //! ## Optimizing reference look-ups
 //!
 //! Certain descriptor look-ups require following references that can be quite expensive to look up.
@@ -125,9 +227,43 @@
 //! # }
 //! ```
 //!
+//! ## Resolving type URLs
+//!
+//! Messages packed into a `google.protobuf.Any` are identified by a type URL like
+//! `type.googleapis.com/mypackage.Person`, whose last path segment is the name of the message type.
+//! `Descriptors::message_by_type_url` looks up that name, and falls back to a `TypeResolver` for
+//! types that aren't part of the registry:
+//!
+//! ```
+//! use serde_protobuf::descriptor::*;
+//!
+//! #[derive(Debug)]
+//! struct Fallback(Descriptors);
+//!
+//! impl TypeResolver for Fallback {
+//!     fn resolve(&self, type_url: &str) -> Option<(&Descriptors, &MessageDescriptor)> {
+//!         let name = format!(".{}", type_url.rsplit('/').next()?);
+//!         self.0.message_by_name(&name).map(|m| (&self.0, m))
+//!     }
+//! }
+//!
+//! let mut fallback = Descriptors::new();
+//! fallback.add_message(MessageDescriptor::new(".mypackage.Person"));
+//!
+//! let mut descriptors = Descriptors::new();
+//! descriptors.set_type_resolver(Fallback(fallback));
+//! let (_, person) = descriptors
+//!     .message_by_type_url("type.googleapis.com/mypackage.Person")
+//!     .unwrap();
+//! assert_eq!(".mypackage.Person", person.name());
+//! ```
+//!
 //! [1]: https://github.com/google/protobuf/blob/master/src/google/protobuf/descriptor.proto
+use std::collections;
 use std::f32;
 use std::f64;
+use std::fmt;
+use std::ops;
 
 use linked_hash_map;
 use protobuf::descriptor;
@@ -155,36 +291,245 @@ struct EnumValueId(usize);
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 struct FieldId(usize);
 
+/// An ID used for internal tracking of oneofs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct OneofId(usize);
+
+/// An ID used for internal tracking of extensions.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct ExtensionId(usize);
+
+/// An ID used for internal tracking of services.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct ServiceId(usize);
+
+/// An ID used for internal tracking of service methods.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct MethodId(usize);
+
+/// An ID used for internal tracking of files.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct FileId(usize);
+
 /// A registry for any number of protocol buffer descriptors.
 #[derive(Debug, Default)]
 pub struct Descriptors {
     // All found descriptors
     messages: Vec<MessageDescriptor>,
     enums: Vec<EnumDescriptor>,
+    extensions: Vec<FieldDescriptor>,
+    services: Vec<ServiceDescriptor>,
+    files: Vec<FileDescriptor>,
 
     // Indices
     messages_by_name: linked_hash_map::LinkedHashMap<String, MessageId>,
     enums_by_name: linked_hash_map::LinkedHashMap<String, EnumId>,
+    extensions_by_name: linked_hash_map::LinkedHashMap<String, ExtensionId>,
+    extensions_by_number: linked_hash_map::LinkedHashMap<(String, i32), ExtensionId>,
+    services_by_name: linked_hash_map::LinkedHashMap<String, ServiceId>,
+    files_by_name: linked_hash_map::LinkedHashMap<String, FileId>,
+
+    type_resolver: Option<Box<dyn TypeResolver>>,
+}
+
+/// Builds a descriptor registry while checking the schema for problems, so that an inconsistent
+/// schema can be rejected up front.
+///
+/// Unlike the methods of `Descriptors`, which skip over problems, `build` fails with an
+/// `Error::InvalidDescriptors` that lists every problem that was found.
+#[derive(Debug, Default)]
+pub struct DescriptorsBuilder {
+    descriptors: Descriptors,
+    type_names: collections::HashSet<String>,
+    // Default values of enum fields, which can only be checked once all types are known
+    enum_defaults: Vec<EnumDefault>,
+    problems: Vec<Problem>,
+}
+
+#[derive(Debug)]
+struct EnumDefault {
+    message: String,
+    field: String,
+    // The scope that the type name is resolved in
+    scope: String,
+    type_name: String,
+    value: String,
+}
+
+/// A problem with a schema that was found by a `DescriptorsBuilder`.
+///
+/// Problems with extensions name the message that is extended, and the extension by its fully
+/// qualified name.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    /// A field refers to a type that doesn't exist, or that isn't of the kind that the field
+    /// expects.
+    UnknownType {
+        /// The fully qualified name of the message.
+        message: String,
+        /// The name of the field.
+        field: String,
+        /// The type name as it was written.
+        type_name: String,
+        /// The fully qualified names that were tried, in order.
+        tried: Vec<String>,
+    },
+    /// Several types have the same fully qualified name.
+    DuplicateType {
+        /// The fully qualified name of the type.
+        name: String,
+    },
+    /// Several fields of a message have the same number.
+    DuplicateFieldNumber {
+        /// The fully qualified name of the message.
+        message: String,
+        /// The number of the fields.
+        number: i32,
+    },
+    /// Several fields of a message have the same name.
+    DuplicateFieldName {
+        /// The fully qualified name of the message.
+        message: String,
+        /// The name of the fields.
+        field: String,
+    },
+    /// A field has a default value that can't be parsed as the type of the field.
+    BadDefaultValue {
+        /// The fully qualified name of the message.
+        message: String,
+        /// The name of the field.
+        field: String,
+        /// The default value that couldn't be parsed.
+        default_value: String,
+    },
+    /// A field has a number in the range 19000 to 19999, which is reserved for the protocol
+    /// buffer implementation.
+    ReservedFieldNumber {
+        /// The fully qualified name of the message.
+        message: String,
+        /// The name of the field.
+        field: String,
+        /// The number of the field.
+        number: i32,
+    },
+    /// A service method takes or returns a message type that doesn't exist.
+    UnknownMethodType {
+        /// The fully qualified name of the service.
+        service: String,
+        /// The name of the method.
+        method: String,
+        /// The type name as it was written.
+        type_name: String,
+        /// The fully qualified names that were tried, in order.
+        tried: Vec<String>,
+    },
+    /// An extension extends a message that doesn't exist.
+    UnknownExtendee {
+        /// The fully qualified name of the extension.
+        extension: String,
+        /// The name of the extended message as it was written.
+        extendee: String,
+    },
+    /// Several extensions of a message have the same number.
+    DuplicateExtensionNumber {
+        /// The fully qualified name of the extended message.
+        extendee: String,
+        /// The fully qualified name of the extension that was added first.
+        first: String,
+        /// The fully qualified name of the extension that was added later.
+        second: String,
+        /// The number of the extensions.
+        number: i32,
+    },
+    /// An extension has a number that the extended message doesn't declare as an extension range.
+    ExtensionOutOfRange {
+        /// The fully qualified name of the extension.
+        extension: String,
+        /// The fully qualified name of the extended message.
+        extendee: String,
+        /// The number of the extension.
+        number: i32,
+    },
+    /// A file imports a file that isn't part of the schema.
+    UnknownDependency {
+        /// The name of the importing file.
+        file: String,
+        /// The name of the imported file.
+        dependency: String,
+    },
+    /// Files import each other in a cycle.
+    ImportCycle {
+        /// The names of the files in the cycle, starting and ending with the same file.
+        files: Vec<String>,
+    },
+}
+
+/// Resolves type URLs of messages that aren't part of a descriptor registry; see the
+/// [module documentation](index.html#resolving-type-urls).
+pub trait TypeResolver: fmt::Debug + Send + Sync {
+    /// Finds the message type with the specified type URL, along with the registry that its field
+    /// types can be looked up in.
+    fn resolve(&self, type_url: &str) -> Option<(&Descriptors, &MessageDescriptor)>;
+}
+
+/// A descriptor for a single `.proto` file.
+///
+/// The types that the file defines are found through the registry that the file belongs to.
+#[derive(Debug)]
+pub struct FileDescriptor {
+    name: String,
+    package: String,
+    syntax: Syntax,
+    dependencies: Vec<String>,
+    public_dependencies: Vec<String>,
 }
 
 /// A descriptor for a single protocol buffer message type.
-// TODO: Support oneof?
 #[derive(Debug)]
 pub struct MessageDescriptor {
     name: String,
+    // The name of the file that the message was declared in, if it was read from one
+    file: Option<String>,
+    syntax: Syntax,
+    map_entry: bool,
+    deprecated: bool,
+    // The encoded `MessageOptions`, including custom options
+    options: Vec<u8>,
+    location: Option<SourceLocation>,
 
     // All found descriptors
     fields: Vec<FieldDescriptor>,
+    oneofs: Vec<OneofDescriptor>,
+    extension_ranges: Vec<ops::Range<i32>>,
 
     // Indices
     fields_by_name: linked_hash_map::LinkedHashMap<String, FieldId>,
     fields_by_number: linked_hash_map::LinkedHashMap<i32, FieldId>,
+    oneofs_by_name: linked_hash_map::LinkedHashMap<String, OneofId>,
+}
+
+/// A descriptor for a single oneof within a protocol buffer message type.
+#[derive(Debug)]
+pub struct OneofDescriptor {
+    name: String,
+    json_name: String,
+    // Numbers of the member fields, in the order they were added
+    fields: Vec<i32>,
+    synthetic: bool,
 }
 
 /// A descriptor for a single protocol buffer enum type.
 #[derive(Debug)]
 pub struct EnumDescriptor {
     name: String,
+    // The name of the file that the enum was declared in, if it was read from one
+    file: Option<String>,
+    closed: bool,
+    allow_alias: bool,
+    deprecated: bool,
+    // The encoded `EnumOptions`, including custom options
+    options: Vec<u8>,
+    location: Option<SourceLocation>,
 
     // All found descriptors
     values: Vec<EnumValueDescriptor>,
@@ -199,6 +544,46 @@ pub struct EnumDescriptor {
 pub struct EnumValueDescriptor {
     name: String,
     number: i32,
+    deprecated: bool,
+    // The encoded `EnumValueOptions`, including custom options
+    options: Vec<u8>,
+    location: Option<SourceLocation>,
+}
+
+/// A descriptor for a single protocol buffer service.
+#[derive(Debug)]
+pub struct ServiceDescriptor {
+    name: String,
+    location: Option<SourceLocation>,
+
+    // All found descriptors
+    methods: Vec<MethodDescriptor>,
+
+    // Indices
+    methods_by_name: linked_hash_map::LinkedHashMap<String, MethodId>,
+}
+
+/// A descriptor for a single method of a protocol buffer service.
+#[derive(Debug)]
+pub struct MethodDescriptor {
+    name: String,
+    input_type: InternalFieldType,
+    output_type: InternalFieldType,
+    client_streaming: bool,
+    server_streaming: bool,
+    // The name of the service that the method belongs to, which relative type names are resolved in
+    scope: String,
+    location: Option<SourceLocation>,
+}
+
+/// The syntax of the file that a type was declared in.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Syntax {
+    /// The `proto2` syntax, which is assumed if a file doesn't declare its syntax.
+    #[default]
+    Proto2,
+    /// The `proto3` syntax.
+    Proto3,
 }
 
 /// A label that a field can be given to indicate its cardinality.
@@ -221,6 +606,8 @@ pub enum FieldType<'a> {
     UnresolvedMessage(&'a str),
     /// An enum that is yet to be resolved.
     UnresolvedEnum(&'a str),
+    /// A group that is yet to be resolved.
+    UnresolvedGroup(&'a str),
     /// The `double` type.
     Double,
     /// The `float` type.
@@ -239,8 +626,8 @@ pub enum FieldType<'a> {
     Bool,
     /// The `string` type.
     String,
-    /// The `group` type.
-    Group,
+    /// A resolved `group` type, with the message descriptor that describes its contents.
+    Group(&'a MessageDescriptor),
     /// A resolved message type.
     Message(&'a MessageDescriptor),
     /// The `bytes` type.
@@ -269,6 +656,8 @@ pub enum InternalFieldType {
     UnresolvedMessage(String),
     /// An enum that is yet to be resolved.
     UnresolvedEnum(String),
+    /// A group that is yet to be resolved.
+    UnresolvedGroup(String),
     /// The `double` type.
     Double,
     /// The `float` type.
@@ -287,8 +676,8 @@ pub enum InternalFieldType {
     Bool,
     /// The `string` type.
     String,
-    /// The `group` type.
-    Group,
+    /// A resolved `group` type.
+    Group(MessageId),
     /// A resolved message type.
     Message(MessageId),
     /// The `bytes` type.
@@ -311,21 +700,61 @@ pub enum InternalFieldType {
 #[derive(Debug)]
 pub struct FieldDescriptor {
     name: String,
+    json_name: String,
     number: i32,
     field_label: FieldLabel,
     field_type: InternalFieldType,
     default_value: Option<value::Value>,
+    presence: bool,
+    proto3_optional: bool,
+    oneof_index: Option<usize>,
+    map: bool,
+    // The name of the message that the field belongs to, which relative type names are resolved in
+    scope: String,
+    extendee: Option<String>,
+    deprecated: bool,
+    packed: Option<bool>,
+    // The encoded `FieldOptions`, including custom options
+    options: Vec<u8>,
+    location: Option<SourceLocation>,
 }
 
+/// Where a definition is found in its `.proto` file, along with the comments around it.
+///
+/// Lines and columns start at 0, as in `descriptor.proto`.  The span ends at the end of the
+/// definition, and the end column is exclusive.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SourceLocation {
+    start_line: usize,
+    start_column: usize,
+    end_line: usize,
+    end_column: usize,
+    leading_comments: Option<String>,
+    trailing_comments: Option<String>,
+    leading_detached_comments: Vec<String>,
+}
+
+/// The locations of a `SourceCodeInfo` message, by their path.
+type SourceLocations<'a> = collections::HashMap<Vec<i32>, &'a descriptor::SourceCodeInfo_Location>;
+
 impl Descriptors {
     /// Creates a new empty descriptor set.
     pub fn new() -> Descriptors {
         Descriptors {
             messages: Vec::new(),
             enums: Vec::new(),
+            extensions: Vec::new(),
+            services: Vec::new(),
+            files: Vec::new(),
 
             messages_by_name: linked_hash_map::LinkedHashMap::new(),
             enums_by_name: linked_hash_map::LinkedHashMap::new(),
+            extensions_by_name: linked_hash_map::LinkedHashMap::new(),
+            extensions_by_number: linked_hash_map::LinkedHashMap::new(),
+            services_by_name: linked_hash_map::LinkedHashMap::new(),
+            files_by_name: linked_hash_map::LinkedHashMap::new(),
+
+            type_resolver: None,
         }
     }
 
@@ -348,6 +777,162 @@ impl Descriptors {
         self.enums_by_name.get(name).map(|e| &self.enums[e.0])
     }
 
+    /// Looks up an extension by its fully qualified name (i.e. `.foo.package.extension`, or
+    /// `.foo.package.Message.extension` for an extension declared within a message).
+    #[inline]
+    pub fn extension_by_name(&self, name: &str) -> Option<&FieldDescriptor> {
+        self.extensions_by_name
+            .get(name)
+            .map(|e| &self.extensions[e.0])
+    }
+
+    /// Looks up an extension by the fully qualified name of the message that it extends and its
+    /// field number.
+    #[inline]
+    pub fn extension_by_number(&self, extendee: &str, number: i32) -> Option<&FieldDescriptor> {
+        self.extensions_by_number
+            .get(&(extendee.to_owned(), number))
+            .map(|e| &self.extensions[e.0])
+    }
+
+    /// Looks up an extension of the specified message by its fully qualified name, which may also
+    /// be written without the leading dot as in the `[foo.package.extension]` keys that extensions
+    /// are presented with.
+    pub fn extension_of(
+        &self,
+        message: &MessageDescriptor,
+        name: &str,
+    ) -> Option<&FieldDescriptor> {
+        let extension = if name.starts_with('.') {
+            self.extension_by_name(name)
+        } else {
+            self.extension_by_name(&format!(".{}", name))
+        };
+        extension.filter(|e| e.extendee() == Some(message.name()))
+    }
+
+    /// All of the extensions in the registry.
+    pub fn extensions(&self) -> &[FieldDescriptor] {
+        &self.extensions
+    }
+
+    /// Looks up a service by its fully qualified name (i.e. `.foo.package.Service`).
+    #[inline]
+    pub fn service_by_name(&self, name: &str) -> Option<&ServiceDescriptor> {
+        self.services_by_name.get(name).map(|s| &self.services[s.0])
+    }
+
+    /// Looks up a service method by the path that gRPC sends requests to (i.e.
+    /// `/foo.package.Service/Method`).
+    pub fn method_by_path(&self, path: &str) -> Option<&MethodDescriptor> {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let (service, method) = path.split_at(path.rfind('/')?);
+        self.service_by_name(&format!(".{}", service))?
+            .method_by_name(&method[1..])
+    }
+
+    /// All of the services in the registry.
+    pub fn services(&self) -> &[ServiceDescriptor] {
+        &self.services
+    }
+
+    /// Looks up a file by the name that it is imported by (i.e. `foo/package/file.proto`).
+    #[inline]
+    pub fn file_by_name(&self, name: &str) -> Option<&FileDescriptor> {
+        self.files_by_name.get(name).map(|f| &self.files[f.0])
+    }
+
+    /// All of the files in the registry, in the order that they were added.
+    pub fn files(&self) -> &[FileDescriptor] {
+        &self.files
+    }
+
+    /// All of the files in the registry, ordered so that every file comes after the files that it
+    /// imports.
+    ///
+    /// Files that don't depend on each other keep the order that they were added in.  Imports of
+    /// files that aren't part of the registry are skipped, and an `Error::ImportCycle` is returned
+    /// if files import each other.
+    pub fn files_in_import_order(&self) -> error::Result<Vec<&FileDescriptor>> {
+        let mut walk = ImportWalk::new(self);
+        for id in 0..self.files.len() {
+            walk.visit(id)?;
+        }
+        Ok(walk.order)
+    }
+
+    /// All of the files that the specified file imports directly or indirectly, ordered so that
+    /// every file comes after the files that it imports.
+    ///
+    /// Imports of files that aren't part of the registry are skipped, and an
+    /// `Error::ImportCycle` is returned if files import each other.
+    pub fn transitive_dependencies(
+        &self,
+        file: &FileDescriptor,
+    ) -> error::Result<Vec<&FileDescriptor>> {
+        let mut walk = ImportWalk::new(self);
+        match self.files_by_name.get(&file.name) {
+            Some(id) => {
+                walk.visit(id.0)?;
+                // The file itself comes last
+                walk.order.pop();
+            }
+            None => {
+                for dependency in &file.dependencies {
+                    if let Some(id) = self.files_by_name.get(dependency) {
+                        walk.visit(id.0)?;
+                    }
+                }
+            }
+        }
+        Ok(walk.order)
+    }
+
+    /// Finds a field of the specified message by field number, falling back to the extensions of
+    /// the message.
+    #[inline]
+    pub fn field_or_extension<'a>(
+        &'a self,
+        message: &'a MessageDescriptor,
+        number: i32,
+    ) -> Option<&'a FieldDescriptor> {
+        message
+            .field_by_number(number)
+            .or_else(|| self.extension_by_number(message.name(), number))
+    }
+
+    /// Looks up a message by a type URL as used by `google.protobuf.Any` (i.e.
+    /// `type.googleapis.com/foo.package.Message`), along with the registry that its field types
+    /// can be looked up in.
+    ///
+    /// The last path segment of the URL is looked up as a message name in this registry first,
+    /// and the type resolver is consulted if there is no such message.
+    pub fn message_by_type_url(
+        &self,
+        type_url: &str,
+    ) -> Option<(&Descriptors, &MessageDescriptor)> {
+        let name = match type_url.rfind('/') {
+            Some(i) => &type_url[i + 1..],
+            None => type_url,
+        };
+        if !name.is_empty() {
+            if let Some(message) = self.message_by_name(&format!(".{}", name)) {
+                return Some((self, message));
+            }
+        }
+        self.type_resolver
+            .as_ref()
+            .and_then(|resolver| resolver.resolve(type_url))
+    }
+
+    /// Sets the resolver to consult for type URLs of messages that aren't part of this registry.
+    pub fn set_type_resolver<R>(&mut self, type_resolver: R)
+    where
+        R: TypeResolver + 'static,
+    {
+        self.type_resolver = Some(Box::new(type_resolver));
+    }
+
     /// Adds all types defined in the specified protocol buffer file descriptor set to this
     /// registry.
     pub fn add_file_set_proto(&mut self, file_set_proto: &descriptor::FileDescriptorSet) {
@@ -356,13 +941,20 @@ impl Descriptors {
         }
     }
 
-    /// Adds all types defined in the specified protocol buffer file descriptor to this registry.
+    /// Adds the specified protocol buffer file descriptor to this registry, along with all types
+    /// defined in it.
     pub fn add_file_proto(&mut self, file_proto: &descriptor::FileDescriptorProto) {
         let path = if file_proto.has_package() {
             format!(".{}", file_proto.get_package())
         } else {
             "".to_owned()
         };
+        let first_message = self.messages.len();
+        let first_enum = self.enums.len();
+
+        let file = FileDescriptor::from_proto(file_proto);
+        let file_name = file.name.clone();
+        self.add_file(file);
 
         for message_proto in file_proto.get_message_type().iter() {
             self.add_message_proto(&path, message_proto);
@@ -371,6 +963,41 @@ impl Descriptors {
         for enum_proto in file_proto.get_enum_type().iter() {
             self.add_enum(EnumDescriptor::from_proto(&path, enum_proto));
         }
+
+        for extension_proto in file_proto.get_extension().iter() {
+            self.add_extension(path.clone(), FieldDescriptor::from_proto(extension_proto));
+        }
+
+        for service_proto in file_proto.get_service().iter() {
+            self.add_service(ServiceDescriptor::from_proto(&path, service_proto));
+        }
+
+        if Syntax::from_proto(file_proto.get_syntax()) == Syntax::Proto3 {
+            for message_descriptor in &mut self.messages[first_message..] {
+                message_descriptor.syntax = Syntax::Proto3;
+                for field in &mut message_descriptor.fields {
+                    if field.has_implicit_presence() {
+                        field.set_has_presence(false);
+                    }
+                    // Packable repeated fields are packed by default in proto3
+                    if field.packed.is_none() && field.is_repeated() && field.is_packable() {
+                        field.packed = Some(true);
+                    }
+                }
+            }
+            for enum_descriptor in &mut self.enums[first_enum..] {
+                enum_descriptor.closed = false;
+            }
+        }
+
+        for message_descriptor in &mut self.messages[first_message..] {
+            message_descriptor.file = Some(file_name.clone());
+        }
+        for enum_descriptor in &mut self.enums[first_enum..] {
+            enum_descriptor.file = Some(file_name.clone());
+        }
+
+        self.add_source_code_info(&path, file_proto);
     }
 
     /// Adds a message and all nested types within that message from the specified protocol buffer
@@ -389,6 +1016,13 @@ impl Descriptors {
             ));
         }
 
+        for extension_proto in message_proto.get_extension().iter() {
+            self.add_extension(
+                message_descriptor.name(),
+                FieldDescriptor::from_proto(extension_proto),
+            );
+        }
+
         self.add_message(message_descriptor);
     }
 
@@ -406,100 +1040,1105 @@ impl Descriptors {
         self.enums_by_name.insert(name, enum_id);
     }
 
-    /// Resolves all internal descriptor type references, making them cheaper to follow.
-    pub fn resolve_refs(&mut self) {
-        for m in &mut self.messages {
-            for f in &mut m.fields {
-                let field_type = &mut f.field_type;
-                let new = match *field_type {
-                    InternalFieldType::UnresolvedMessage(ref name) => {
-                        if let Some(res) = self.messages_by_name.get(name) {
-                            Some(InternalFieldType::Message(*res))
-                        } else {
-                            warn!("Inconsistent schema; unknown message type {}", name);
-                            None
-                        }
-                    }
-                    InternalFieldType::UnresolvedEnum(ref name) => {
-                        if let Some(res) = self.enums_by_name.get(name) {
-                            Some(InternalFieldType::Enum(*res))
-                        } else {
-                            warn!("Inconsistent schema; unknown enum type {}", name);
-                            None
-                        }
-                    }
-                    _ => None,
-                };
+    /// Adds a single custom built service descriptor.
+    pub fn add_service(&mut self, descriptor: ServiceDescriptor) {
+        let name = descriptor.name.clone();
+        let service_id = ServiceId(store(&mut self.services, descriptor));
+        self.services_by_name.insert(name, service_id);
+    }
 
-                if let Some(t) = new {
-                    *field_type = t;
-                }
-            }
-        }
+    /// Adds a single custom built file descriptor.
+    ///
+    /// Messages and enums are associated with the file with `MessageDescriptor::set_file_name`
+    /// and `EnumDescriptor::set_file_name`.
+    pub fn add_file(&mut self, descriptor: FileDescriptor) {
+        let name = descriptor.name.clone();
+        let file_id = FileId(store(&mut self.files, descriptor));
+        self.files_by_name.insert(name, file_id);
     }
-}
 
-impl MessageDescriptor {
-    /// Creates a new message descriptor with the specified message name.
-    pub fn new<S>(name: S) -> MessageDescriptor
+    /// Adds a single custom built extension, declared in the specified scope.
+    ///
+    /// The scope is the fully qualified name of the package or message that the extension is
+    /// declared in, and the extension has to name the message that it extends with
+    /// `FieldDescriptor::set_extendee`.  Extensions without an extendee are ignored.
+    pub fn add_extension<S>(&mut self, scope: S, mut descriptor: FieldDescriptor)
     where
         S: Into<String>,
     {
-        MessageDescriptor {
-            name: name.into(),
-            fields: Vec::new(),
-            fields_by_name: linked_hash_map::LinkedHashMap::new(),
-            fields_by_number: linked_hash_map::LinkedHashMap::new(),
-        }
+        descriptor.scope = scope.into();
+        let extendee = match descriptor.extendee {
+            Some(ref extendee) => extendee.clone(),
+            None => {
+                warn!(
+                    "Inconsistent schema; extension {} doesn't extend any message",
+                    descriptor.full_name()
+                );
+                return;
+            }
+        };
+        let name = descriptor.full_name();
+        let number = descriptor.number;
+        let extension_id = ExtensionId(store(&mut self.extensions, descriptor));
+        self.extensions_by_name.insert(name, extension_id);
+        self.extensions_by_number
+            .insert((extendee, number), extension_id);
     }
 
-    /// Reads a message descriptor from a parsed Protobuf descriptor.
-    pub fn from_proto(path: &str, proto: &descriptor::DescriptorProto) -> MessageDescriptor {
-        let name = format!("{}.{}", path, proto.get_name());
-        let mut message_descriptor = MessageDescriptor::new(name);
+    /// Resolves a type name the way `protoc` does, and returns the fully qualified name of the
+    /// message or enum that it refers to.
+    ///
+    /// Fully qualified names (i.e. `.foo.package.Message`) are looked up as they are.  Relative
+    /// names (i.e. `Message` or `package.Message`) are looked up in the specified scope, which is
+    /// the fully qualified name of a message or package, and then in each enclosing scope in turn.
+    /// As in `protoc`, once the first component of a compound name is found to be a message, the
+    /// rest of the name has to be found in that message.
+    ///
+    /// If there is no such type, the error lists the fully qualified names that were tried.
+    pub fn resolve_type_name(&self, name: &str, scope: &str) -> error::Result<String> {
+        resolve_type_name(&self.messages_by_name, name, scope, |n| {
+            self.messages_by_name.contains_key(n) || self.enums_by_name.contains_key(n)
+        })
+        .map_err(|tried| error::Error::UnknownType {
+            name: name.to_owned(),
+            tried,
+        })
+    }
 
-        for field_proto in proto.get_field().iter() {
-            message_descriptor.add_field(FieldDescriptor::from_proto(field_proto));
+    fn find_message(&self, name: &str, scope: &str) -> Option<&MessageDescriptor> {
+        if name.starts_with('.') {
+            return self.message_by_name(name);
         }
-
-        message_descriptor
+        resolve_type_name(&self.messages_by_name, name, scope, |n| {
+            self.messages_by_name.contains_key(n)
+        })
+        .ok()
+        .and_then(|n| self.message_by_name(&n))
     }
 
-    /// All of the fields in the descriptor.
-    pub fn fields(&self) -> &[FieldDescriptor] {
-        &self.fields
+    fn find_enum(&self, name: &str, scope: &str) -> Option<&EnumDescriptor> {
+        if name.starts_with('.') {
+            return self.enum_by_name(name);
+        }
+        resolve_type_name(&self.messages_by_name, name, scope, |n| {
+            self.enums_by_name.contains_key(n)
+        })
+        .ok()
+        .and_then(|n| self.enum_by_name(&n))
     }
 
-    /// The name of the message.
-    #[inline]
-    pub fn name(&self) -> &str {
-        &self.name
-    }
+    /// Attaches the locations of the source code info of a file to the descriptors that were added
+    /// for it.
+    ///
+    /// Locations are identified by the path of field numbers and indices that leads from the file
+    /// descriptor to a definition; only the first location for every path is used.
+    fn add_source_code_info(&mut self, path: &str, file_proto: &descriptor::FileDescriptorProto) {
+        let mut locations = SourceLocations::new();
+        for location in file_proto.get_source_code_info().get_location().iter() {
+            locations
+                .entry(location.get_path().to_vec())
+                .or_insert(location);
+        }
+        if locations.is_empty() {
+            return;
+        }
 
-    /// Finds a field by field name.
-    #[inline]
-    pub fn field_by_name(&self, name: &str) -> Option<&FieldDescriptor> {
-        self.fields_by_name.get(name).map(|f| &self.fields[f.0])
-    }
+        for (i, message_proto) in file_proto.get_message_type().iter().enumerate() {
+            self.add_message_locations(path, message_proto, &[4, i as i32], &locations);
+        }
 
-    /// Finds a field by field number.
-    #[inline]
-    pub fn field_by_number(&self, number: i32) -> Option<&FieldDescriptor> {
-        self.fields_by_number
-            .get(&number)
-            .map(|f| &self.fields[f.0])
-    }
+        for (i, enum_proto) in file_proto.get_enum_type().iter().enumerate() {
+            self.add_enum_locations(path, enum_proto, &[5, i as i32], &locations);
+        }
+
+        for (i, service_proto) in file_proto.get_service().iter().enumerate() {
+            let name = format!("{}.{}", path, service_proto.get_name());
+            if let Some(&service_id) = self.services_by_name.get(&name) {
+                let service = &mut self.services[service_id.0];
+                service.location = source_location(&locations, &[6, i as i32]);
+                for (j, method_proto) in service_proto.get_method().iter().enumerate() {
+                    if let Some(&method_id) = service.methods_by_name.get(method_proto.get_name()) {
+                        service.methods[method_id.0].location =
+                            source_location(&locations, &[6, i as i32, 2, j as i32]);
+                    }
+                }
+            }
+        }
+
+        for (i, extension_proto) in file_proto.get_extension().iter().enumerate() {
+            self.add_extension_location(path, extension_proto, &[7, i as i32], &locations);
+        }
+    }
+
+    fn add_message_locations(
+        &mut self,
+        scope: &str,
+        message_proto: &descriptor::DescriptorProto,
+        source_path: &[i32],
+        locations: &SourceLocations,
+    ) {
+        let name = format!("{}.{}", scope, message_proto.get_name());
+        if let Some(&message_id) = self.messages_by_name.get(&name) {
+            let message = &mut self.messages[message_id.0];
+            message.location = source_location(locations, source_path);
+            for (i, field_proto) in message_proto.get_field().iter().enumerate() {
+                if let Some(&field_id) = message.fields_by_name.get(field_proto.get_name()) {
+                    message.fields[field_id.0].location =
+                        source_location(locations, &[source_path, &[2, i as i32]].concat());
+                }
+            }
+        }
+
+        for (i, nested_proto) in message_proto.get_nested_type().iter().enumerate() {
+            let nested_path = [source_path, &[3, i as i32]].concat();
+            self.add_message_locations(&name, nested_proto, &nested_path, locations);
+        }
+
+        for (i, enum_proto) in message_proto.get_enum_type().iter().enumerate() {
+            let enum_path = [source_path, &[4, i as i32]].concat();
+            self.add_enum_locations(&name, enum_proto, &enum_path, locations);
+        }
+
+        for (i, extension_proto) in message_proto.get_extension().iter().enumerate() {
+            let extension_path = [source_path, &[6, i as i32]].concat();
+            self.add_extension_location(&name, extension_proto, &extension_path, locations);
+        }
+    }
+
+    fn add_enum_locations(
+        &mut self,
+        scope: &str,
+        enum_proto: &descriptor::EnumDescriptorProto,
+        source_path: &[i32],
+        locations: &SourceLocations,
+    ) {
+        let name = format!("{}.{}", scope, enum_proto.get_name());
+        if let Some(&enum_id) = self.enums_by_name.get(&name) {
+            let enum_descriptor = &mut self.enums[enum_id.0];
+            enum_descriptor.location = source_location(locations, source_path);
+            for (i, value_proto) in enum_proto.get_value().iter().enumerate() {
+                if let Some(&value_id) = enum_descriptor.values_by_name.get(value_proto.get_name())
+                {
+                    enum_descriptor.values[value_id.0].location =
+                        source_location(locations, &[source_path, &[2, i as i32]].concat());
+                }
+            }
+        }
+    }
+
+    fn add_extension_location(
+        &mut self,
+        scope: &str,
+        extension_proto: &descriptor::FieldDescriptorProto,
+        source_path: &[i32],
+        locations: &SourceLocations,
+    ) {
+        let name = format!("{}.{}", scope, extension_proto.get_name());
+        if let Some(&extension_id) = self.extensions_by_name.get(&name) {
+            self.extensions[extension_id.0].location = source_location(locations, source_path);
+        }
+    }
+
+    /// Resolves all internal descriptor type references, making them cheaper to follow.
+    ///
+    /// Relative type names are resolved like `resolve_type_name` does, in the scope of the message
+    /// that the field belongs to, the scope that the extension is declared in, or the service that
+    /// the method belongs to.  The extendees of extensions are resolved in the same way.
+    pub fn resolve_refs(&mut self) {
+        let messages_by_name = &self.messages_by_name;
+        let enums_by_name = &self.enums_by_name;
+        for m in &mut self.messages {
+            for f in &mut m.fields {
+                resolve_field_type(messages_by_name, enums_by_name, f);
+            }
+        }
+        for (i, e) in self.extensions.iter_mut().enumerate() {
+            resolve_field_type(messages_by_name, enums_by_name, e);
+
+            let extendee = match e.extendee {
+                Some(ref extendee) if !extendee.starts_with('.') => extendee.clone(),
+                _ => continue,
+            };
+            let resolved = resolve_type_name(messages_by_name, &extendee, &e.scope, |n| {
+                messages_by_name.contains_key(n)
+            });
+            if let Ok(resolved) = resolved {
+                // The extension is looked up by the fully qualified name of its extendee
+                let key = (extendee, e.number);
+                if self.extensions_by_number.get(&key) == Some(&ExtensionId(i)) {
+                    self.extensions_by_number.remove(&key);
+                }
+                self.extensions_by_number
+                    .insert((resolved.clone(), e.number), ExtensionId(i));
+                e.extendee = Some(resolved);
+            }
+        }
+        for s in &mut self.services {
+            for m in &mut s.methods {
+                resolve_type(messages_by_name, enums_by_name, &m.scope, &mut m.input_type);
+                resolve_type(
+                    messages_by_name,
+                    enums_by_name,
+                    &m.scope,
+                    &mut m.output_type,
+                );
+            }
+        }
+    }
+}
+
+impl DescriptorsBuilder {
+    /// Creates a new builder for an empty descriptor set.
+    pub fn new() -> DescriptorsBuilder {
+        DescriptorsBuilder::default()
+    }
+
+    /// Adds all types defined in the specified protocol buffer file descriptor set.
+    pub fn add_file_set_proto(&mut self, file_set_proto: &descriptor::FileDescriptorSet) {
+        for file_proto in file_set_proto.get_file().iter() {
+            self.add_file_proto(file_proto);
+        }
+    }
+
+    /// Adds all types defined in the specified protocol buffer file descriptor.
+    pub fn add_file_proto(&mut self, file_proto: &descriptor::FileDescriptorProto) {
+        let path = if file_proto.has_package() {
+            format!(".{}", file_proto.get_package())
+        } else {
+            "".to_owned()
+        };
+        for message_proto in file_proto.get_message_type().iter() {
+            self.check_message_proto(&path, message_proto);
+        }
+        for enum_proto in file_proto.get_enum_type().iter() {
+            self.check_type_name(format!("{}.{}", path, enum_proto.get_name()));
+        }
+        for extension_proto in file_proto.get_extension().iter() {
+            self.check_extension_proto(&path, extension_proto);
+        }
+        for service_proto in file_proto.get_service().iter() {
+            self.check_type_name(format!("{}.{}", path, service_proto.get_name()));
+        }
+        self.descriptors.add_file_proto(file_proto);
+    }
+
+    /// Adds a single custom built message descriptor.
+    pub fn add_message(&mut self, descriptor: MessageDescriptor) {
+        self.check_type_name(descriptor.name.clone());
+        self.descriptors.add_message(descriptor);
+    }
+
+    /// Adds a single custom built enum descriptor.
+    pub fn add_enum(&mut self, descriptor: EnumDescriptor) {
+        self.check_type_name(descriptor.name.clone());
+        self.descriptors.add_enum(descriptor);
+    }
+
+    /// Adds a single custom built service descriptor.
+    pub fn add_service(&mut self, descriptor: ServiceDescriptor) {
+        self.check_type_name(descriptor.name.clone());
+        self.descriptors.add_service(descriptor);
+    }
+
+    /// Adds a single custom built file descriptor.
+    pub fn add_file(&mut self, descriptor: FileDescriptor) {
+        self.descriptors.add_file(descriptor);
+    }
+
+    /// Checks the added types for consistency, and returns the descriptor set with all type
+    /// references resolved if there were no problems.
+    pub fn build(self) -> error::Result<Descriptors> {
+        let DescriptorsBuilder {
+            mut descriptors,
+            enum_defaults,
+            mut problems,
+            ..
+        } = self;
+
+        for message in &descriptors.messages {
+            let mut numbers = collections::HashSet::new();
+            let mut names = collections::HashSet::new();
+            for field in &message.fields {
+                if !numbers.insert(field.number) {
+                    problems.push(Problem::DuplicateFieldNumber {
+                        message: message.name.clone(),
+                        number: field.number,
+                    });
+                }
+                if !names.insert(&field.name) {
+                    problems.push(Problem::DuplicateFieldName {
+                        message: message.name.clone(),
+                        field: field.name.clone(),
+                    });
+                }
+                if (19000..=19999).contains(&field.number) {
+                    problems.push(Problem::ReservedFieldNumber {
+                        message: message.name.clone(),
+                        field: field.name.clone(),
+                        number: field.number,
+                    });
+                }
+
+                let (type_name, resolved) = match field.field_type {
+                    InternalFieldType::UnresolvedMessage(ref name)
+                    | InternalFieldType::UnresolvedGroup(ref name) => (
+                        name,
+                        resolve_type_name(
+                            &descriptors.messages_by_name,
+                            name,
+                            &message.name,
+                            |n| descriptors.messages_by_name.contains_key(n),
+                        ),
+                    ),
+                    InternalFieldType::UnresolvedEnum(ref name) => (
+                        name,
+                        resolve_type_name(
+                            &descriptors.messages_by_name,
+                            name,
+                            &message.name,
+                            |n| descriptors.enums_by_name.contains_key(n),
+                        ),
+                    ),
+                    _ => continue,
+                };
+                if let Err(tried) = resolved {
+                    problems.push(Problem::UnknownType {
+                        message: message.name.clone(),
+                        field: field.name.clone(),
+                        type_name: type_name.clone(),
+                        tried,
+                    });
+                }
+            }
+        }
+
+        let mut extension_numbers = collections::HashMap::<(&str, i32), String>::new();
+        for extension in &descriptors.extensions {
+            let name = extension.full_name();
+            let written = extension.extendee().expect("Lost track of extendee");
+            let resolved = resolve_type_name(
+                &descriptors.messages_by_name,
+                written,
+                &extension.scope,
+                |n| descriptors.messages_by_name.contains_key(n),
+            );
+            let extendee = match resolved {
+                Ok(ref extendee) => extendee.as_str(),
+                Err(_) => written,
+            };
+            match descriptors.message_by_name(extendee) {
+                Some(message) => {
+                    let number = extension.number;
+                    if message.field_by_number(number).is_some() {
+                        problems.push(Problem::DuplicateFieldNumber {
+                            message: extendee.to_owned(),
+                            number,
+                        });
+                    } else if let Some(first) = extension_numbers.get(&(message.name(), number)) {
+                        problems.push(Problem::DuplicateExtensionNumber {
+                            extendee: extendee.to_owned(),
+                            first: first.clone(),
+                            second: name.clone(),
+                            number,
+                        });
+                    } else if !message.is_extension_number(number) {
+                        problems.push(Problem::ExtensionOutOfRange {
+                            extension: name.clone(),
+                            extendee: extendee.to_owned(),
+                            number,
+                        });
+                    }
+                    extension_numbers
+                        .entry((message.name(), number))
+                        .or_insert_with(|| name.clone());
+                }
+                None => problems.push(Problem::UnknownExtendee {
+                    extension: name.clone(),
+                    extendee: written.to_owned(),
+                }),
+            }
+
+            let resolved = match extension.field_type {
+                InternalFieldType::UnresolvedMessage(ref type_name)
+                | InternalFieldType::UnresolvedGroup(ref type_name) => resolve_type_name(
+                    &descriptors.messages_by_name,
+                    type_name,
+                    &extension.scope,
+                    |n| descriptors.messages_by_name.contains_key(n),
+                )
+                .map_err(|tried| (type_name, tried)),
+                InternalFieldType::UnresolvedEnum(ref type_name) => resolve_type_name(
+                    &descriptors.messages_by_name,
+                    type_name,
+                    &extension.scope,
+                    |n| descriptors.enums_by_name.contains_key(n),
+                )
+                .map_err(|tried| (type_name, tried)),
+                _ => continue,
+            };
+            if let Err((type_name, tried)) = resolved {
+                problems.push(Problem::UnknownType {
+                    message: extendee.to_owned(),
+                    field: name,
+                    type_name: type_name.clone(),
+                    tried,
+                });
+            }
+        }
+
+        for service in &descriptors.services {
+            for method in &service.methods {
+                for t in &[&method.input_type, &method.output_type] {
+                    let type_name = match **t {
+                        InternalFieldType::UnresolvedMessage(ref name) => name,
+                        _ => continue,
+                    };
+                    let resolved = resolve_type_name(
+                        &descriptors.messages_by_name,
+                        type_name,
+                        &method.scope,
+                        |n| descriptors.messages_by_name.contains_key(n),
+                    );
+                    if let Err(tried) = resolved {
+                        problems.push(Problem::UnknownMethodType {
+                            service: service.name.clone(),
+                            method: method.name.clone(),
+                            type_name: type_name.clone(),
+                            tried,
+                        });
+                    }
+                }
+            }
+        }
+
+        for file in &descriptors.files {
+            for dependency in &file.dependencies {
+                if !descriptors.files_by_name.contains_key(dependency) {
+                    problems.push(Problem::UnknownDependency {
+                        file: file.name.clone(),
+                        dependency: dependency.clone(),
+                    });
+                }
+            }
+        }
+        if let Err(error::Error::ImportCycle { files }) = descriptors.files_in_import_order() {
+            problems.push(Problem::ImportCycle { files });
+        }
+
+        for default in enum_defaults {
+            if let Some(e) = descriptors.find_enum(&default.type_name, &default.scope) {
+                if e.value_by_name(&default.value).is_none() {
+                    problems.push(Problem::BadDefaultValue {
+                        message: default.message,
+                        field: default.field,
+                        default_value: default.value,
+                    });
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            descriptors.resolve_refs();
+            Ok(descriptors)
+        } else {
+            let summary = problems
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("; ");
+            Err(error::Error::InvalidDescriptors { summary, problems })
+        }
+    }
+
+    fn check_type_name(&mut self, name: String) {
+        if self.type_names.contains(&name) {
+            self.problems.push(Problem::DuplicateType { name });
+        } else {
+            self.type_names.insert(name);
+        }
+    }
+
+    fn check_message_proto(&mut self, path: &str, message_proto: &descriptor::DescriptorProto) {
+        let name = format!("{}.{}", path, message_proto.get_name());
+
+        for field_proto in message_proto.get_field().iter() {
+            self.check_default_value(&name, field_proto.get_name(), &name, field_proto);
+        }
+        for extension_proto in message_proto.get_extension().iter() {
+            self.check_extension_proto(&name, extension_proto);
+        }
+
+        for nested_message_proto in message_proto.get_nested_type().iter() {
+            self.check_message_proto(&name, nested_message_proto);
+        }
+        for nested_enum_proto in message_proto.get_enum_type().iter() {
+            self.check_type_name(format!("{}.{}", name, nested_enum_proto.get_name()));
+        }
+        self.check_type_name(name);
+    }
+
+    fn check_extension_proto(
+        &mut self,
+        scope: &str,
+        extension_proto: &descriptor::FieldDescriptorProto,
+    ) {
+        let name = format!("{}.{}", scope, extension_proto.get_name());
+        self.check_default_value(
+            extension_proto.get_extendee(),
+            &name,
+            scope,
+            extension_proto,
+        );
+    }
+
+    fn check_default_value(
+        &mut self,
+        message: &str,
+        field: &str,
+        scope: &str,
+        field_proto: &descriptor::FieldDescriptorProto,
+    ) {
+        if !field_proto.has_default_value() {
+            return;
+        }
+        let field_type = InternalFieldType::from_proto(
+            field_proto.get_field_type(),
+            field_proto.get_type_name(),
+        );
+        let default_value = field_proto.get_default_value();
+        if let InternalFieldType::UnresolvedEnum(ref type_name) = field_type {
+            self.enum_defaults.push(EnumDefault {
+                message: message.to_owned(),
+                field: field.to_owned(),
+                scope: scope.to_owned(),
+                type_name: type_name.clone(),
+                value: default_value.to_owned(),
+            });
+        } else if parse_default_value(default_value, &field_type).is_err() {
+            self.problems.push(Problem::BadDefaultValue {
+                message: message.to_owned(),
+                field: field.to_owned(),
+                default_value: default_value.to_owned(),
+            });
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::UnknownType {
+                ref message,
+                ref field,
+                ref type_name,
+                ref tried,
+            } => write!(
+                f,
+                "field {} of {} has unknown type {} (tried {})",
+                field,
+                message,
+                type_name,
+                tried.join(", ")
+            ),
+            Problem::DuplicateType { ref name } => write!(f, "type {} is defined twice", name),
+            Problem::DuplicateFieldNumber {
+                ref message,
+                number,
+            } => write!(f, "{} has several fields with number {}", message, number),
+            Problem::DuplicateFieldName {
+                ref message,
+                ref field,
+            } => write!(f, "{} has several fields named {}", message, field),
+            Problem::BadDefaultValue {
+                ref message,
+                ref field,
+                ref default_value,
+            } => write!(
+                f,
+                "field {} of {} has bad default value {:?}",
+                field, message, default_value
+            ),
+            Problem::ReservedFieldNumber {
+                ref message,
+                ref field,
+                number,
+            } => write!(
+                f,
+                "field {} of {} uses reserved number {}",
+                field, message, number
+            ),
+            Problem::UnknownMethodType {
+                ref service,
+                ref method,
+                ref type_name,
+                ref tried,
+            } => write!(
+                f,
+                "method {} of {} has unknown type {} (tried {})",
+                method,
+                service,
+                type_name,
+                tried.join(", ")
+            ),
+            Problem::UnknownExtendee {
+                ref extension,
+                ref extendee,
+            } => write!(
+                f,
+                "extension {} extends unknown message {}",
+                extension, extendee
+            ),
+            Problem::DuplicateExtensionNumber {
+                ref extendee,
+                ref first,
+                ref second,
+                number,
+            } => write!(
+                f,
+                "extensions {} and {} of {} both have number {}",
+                first, second, extendee, number
+            ),
+            Problem::ExtensionOutOfRange {
+                ref extension,
+                ref extendee,
+                number,
+            } => write!(
+                f,
+                "extension {} of {} uses number {} outside of the extension ranges",
+                extension, extendee, number
+            ),
+            Problem::UnknownDependency {
+                ref file,
+                ref dependency,
+            } => write!(f, "file {} imports unknown file {}", file, dependency),
+            Problem::ImportCycle { ref files } => {
+                write!(f, "import cycle: {}", files.join(" -> "))
+            }
+        }
+    }
+}
+
+impl FileDescriptor {
+    /// Creates a new file descriptor with the specified file name.
+    pub fn new<S>(name: S) -> FileDescriptor
+    where
+        S: Into<String>,
+    {
+        FileDescriptor {
+            name: name.into(),
+            package: String::new(),
+            syntax: Syntax::Proto2,
+            dependencies: Vec::new(),
+            public_dependencies: Vec::new(),
+        }
+    }
+
+    /// Reads a file descriptor from a parsed Protobuf descriptor, without the types defined in it.
+    pub fn from_proto(proto: &descriptor::FileDescriptorProto) -> FileDescriptor {
+        let mut file_descriptor = FileDescriptor::new(proto.get_name());
+        file_descriptor.set_package(proto.get_package());
+        file_descriptor.set_syntax(Syntax::from_proto(proto.get_syntax()));
+
+        let dependencies = proto.get_dependency();
+        for dependency in dependencies.iter() {
+            file_descriptor.add_dependency(dependency.as_str());
+        }
+        for &index in proto.get_public_dependency().iter() {
+            if let Some(dependency) = dependencies.get(index as usize) {
+                file_descriptor.public_dependencies.push(dependency.clone());
+            }
+        }
+
+        file_descriptor
+    }
+
+    /// The name of the file, as it is imported by other files (i.e. `foo/package/file.proto`).
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The package declared by the file (i.e. `foo.package`), or an empty string if there is none.
+    #[inline]
+    pub fn package(&self) -> &str {
+        &self.package
+    }
+
+    /// Changes the package of the file.
+    pub fn set_package<S>(&mut self, package: S)
+    where
+        S: Into<String>,
+    {
+        self.package = package.into();
+    }
+
+    /// The syntax of the file.
+    #[inline]
+    pub fn syntax(&self) -> Syntax {
+        self.syntax
+    }
+
+    /// Changes the syntax of the file.
+    ///
+    /// This does not change the types defined in the file; see `MessageDescriptor::set_syntax`.
+    pub fn set_syntax(&mut self, syntax: Syntax) {
+        self.syntax = syntax;
+    }
+
+    /// The names of the files that the file imports, in declaration order.
+    pub fn dependencies(&self) -> &[String] {
+        &self.dependencies
+    }
+
+    /// The names of the files that the file imports with `import public`, in declaration order.
+    ///
+    /// These are also part of `dependencies`.
+    pub fn public_dependencies(&self) -> &[String] {
+        &self.public_dependencies
+    }
+
+    /// Adds an import of the file with the specified name.
+    pub fn add_dependency<S>(&mut self, name: S)
+    where
+        S: Into<String>,
+    {
+        self.dependencies.push(name.into());
+    }
+
+    /// Adds a public import of the file with the specified name.
+    pub fn add_public_dependency<S>(&mut self, name: S)
+    where
+        S: Into<String>,
+    {
+        let name = name.into();
+        self.dependencies.push(name.clone());
+        self.public_dependencies.push(name);
+    }
+
+    /// All of the messages defined in the file, including nested messages, in the order that they
+    /// were added to the registry.
+    pub fn messages<'a>(
+        &'a self,
+        descriptors: &'a Descriptors,
+    ) -> impl Iterator<Item = &'a MessageDescriptor> + 'a {
+        descriptors
+            .messages
+            .iter()
+            .filter(move |m| m.file_name() == Some(self.name.as_str()))
+    }
+
+    /// All of the enums defined in the file, including nested enums, in the order that they were
+    /// added to the registry.
+    pub fn enums<'a>(
+        &'a self,
+        descriptors: &'a Descriptors,
+    ) -> impl Iterator<Item = &'a EnumDescriptor> + 'a {
+        descriptors
+            .enums
+            .iter()
+            .filter(move |e| e.file_name() == Some(self.name.as_str()))
+    }
+}
+
+impl MessageDescriptor {
+    /// Creates a new message descriptor with the specified message name.
+    pub fn new<S>(name: S) -> MessageDescriptor
+    where
+        S: Into<String>,
+    {
+        MessageDescriptor {
+            name: name.into(),
+            file: None,
+            syntax: Syntax::Proto2,
+            map_entry: false,
+            deprecated: false,
+            options: Vec::new(),
+            location: None,
+            fields: Vec::new(),
+            oneofs: Vec::new(),
+            extension_ranges: Vec::new(),
+            fields_by_name: linked_hash_map::LinkedHashMap::new(),
+            fields_by_number: linked_hash_map::LinkedHashMap::new(),
+            oneofs_by_name: linked_hash_map::LinkedHashMap::new(),
+        }
+    }
+
+    /// Reads a message descriptor from a parsed Protobuf descriptor.
+    pub fn from_proto(path: &str, proto: &descriptor::DescriptorProto) -> MessageDescriptor {
+        let name = format!("{}.{}", path, proto.get_name());
+        let mut message_descriptor = MessageDescriptor::new(name);
+
+        for oneof_proto in proto.get_oneof_decl().iter() {
+            message_descriptor.add_oneof(OneofDescriptor::from_proto(oneof_proto));
+        }
+
+        // Map fields refer to the entry messages that were synthesized for them as nested types
+        let map_entries = proto
+            .get_nested_type()
+            .iter()
+            .filter(|nested| nested.get_options().get_map_entry())
+            .map(|nested| format!("{}.{}", message_descriptor.name, nested.get_name()))
+            .collect::<Vec<_>>();
+
+        for field_proto in proto.get_field().iter() {
+            let mut field = FieldDescriptor::from_proto(field_proto);
+            if field.is_repeated() && map_entries.iter().any(|e| e == field_proto.get_type_name()) {
+                field.set_map(true);
+            }
+            message_descriptor.add_field(field);
+        }
+
+        for range_proto in proto.get_extension_range().iter() {
+            message_descriptor.add_extension_range(range_proto.get_start()..range_proto.get_end());
+        }
+
+        if proto.has_options() {
+            let options = proto.get_options();
+            message_descriptor.set_map_entry(options.get_map_entry());
+            message_descriptor.set_deprecated(options.get_deprecated());
+            message_descriptor.options = encode_options(options);
+        }
+
+        message_descriptor
+    }
+
+    /// All of the fields in the descriptor.
+    pub fn fields(&self) -> &[FieldDescriptor] {
+        &self.fields
+    }
+
+    /// The name of the message.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The name of the file that the message was declared in, if it is known.
+    #[inline]
+    pub fn file_name(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// Changes the name of the file that the message was declared in.
+    pub fn set_file_name<S>(&mut self, file_name: S)
+    where
+        S: Into<String>,
+    {
+        self.file = Some(file_name.into());
+    }
+
+    /// The file that the message was declared in, if it is part of the specified registry.
+    pub fn file<'a>(&self, descriptors: &'a Descriptors) -> Option<&'a FileDescriptor> {
+        descriptors.file_by_name(self.file.as_ref()?)
+    }
+
+    /// The syntax of the file that the message was declared in.
+    #[inline]
+    pub fn syntax(&self) -> Syntax {
+        self.syntax
+    }
+
+    /// Changes the syntax of the message.
+    ///
+    /// This does not change the presence of its fields; see `FieldDescriptor::set_has_presence`.
+    pub fn set_syntax(&mut self, syntax: Syntax) {
+        self.syntax = syntax;
+    }
+
+    /// Whether the message is the synthesized entry type of a map field.
+    #[inline]
+    pub fn is_map_entry(&self) -> bool {
+        self.map_entry
+    }
+
+    /// Changes whether the message is the entry type of a map field.
+    ///
+    /// The key and value fields of map entries don't track presence; a missing key or value is
+    /// the zero value of its type.  This only affects the fields that were already added.
+    pub fn set_map_entry(&mut self, map_entry: bool) {
+        self.map_entry = map_entry;
+        if map_entry {
+            for field in &mut self.fields {
+                field.set_has_presence(false);
+            }
+        }
+    }
+
+    /// Where the message was declared, along with its comments, if the file that it was read from
+    /// included source code info.
+    #[inline]
+    pub fn source_location(&self) -> Option<&SourceLocation> {
+        self.location.as_ref()
+    }
+
+    /// Changes where the message was declared.
+    pub fn set_source_location(&mut self, location: SourceLocation) {
+        self.location = Some(location);
+    }
+
+    /// Whether the message is marked as deprecated.
+    #[inline]
+    pub fn is_deprecated(&self) -> bool {
+        self.deprecated
+    }
+
+    /// Changes whether the message is marked as deprecated.
+    pub fn set_deprecated(&mut self, deprecated: bool) {
+        self.deprecated = deprecated;
+    }
+
+    /// Decodes the options of the message as a `google.protobuf.MessageOptions` message.
+    ///
+    /// Custom options are decoded using the extensions of `MessageOptions` that are registered in
+    /// the specified registry, and end up as unknown fields otherwise.
+    pub fn options(&self, descriptors: &Descriptors) -> error::Result<value::Message> {
+        decode_options(
+            descriptors,
+            ".google.protobuf.MessageOptions",
+            &self.options,
+        )
+    }
+
+    /// Finds a field by field name.
+    #[inline]
+    pub fn field_by_name(&self, name: &str) -> Option<&FieldDescriptor> {
+        self.fields_by_name.get(name).map(|f| &self.fields[f.0])
+    }
+
+    /// Finds a field by field number.
+    #[inline]
+    pub fn field_by_number(&self, number: i32) -> Option<&FieldDescriptor> {
+        self.fields_by_number
+            .get(&number)
+            .map(|f| &self.fields[f.0])
+    }
 
     /// Adds a new field to the descriptor.
-    pub fn add_field(&mut self, descriptor: FieldDescriptor) {
+    ///
+    /// If the field has a oneof index, it becomes a member of the oneof with that index.
+    pub fn add_field(&mut self, mut descriptor: FieldDescriptor) {
+        descriptor.scope = self.name.clone();
         let name = descriptor.name.clone();
         let number = descriptor.number;
 
+        if let Some(oneof) = descriptor.oneof_index.and_then(|i| self.oneofs.get_mut(i)) {
+            oneof.add_member(&descriptor);
+        }
+
         let field_id = FieldId(store(&mut self.fields, descriptor));
 
         self.fields_by_name.insert(name, field_id);
         self.fields_by_number.insert(number, field_id);
     }
+
+    /// The ranges of field numbers that are reserved for extensions of the message.
+    pub fn extension_ranges(&self) -> &[ops::Range<i32>] {
+        &self.extension_ranges
+    }
+
+    /// Whether the specified field number is in one of the extension ranges of the message.
+    pub fn is_extension_number(&self, number: i32) -> bool {
+        self.extension_ranges.iter().any(|r| r.contains(&number))
+    }
+
+    /// Adds a range of field numbers that is reserved for extensions, excluding the end of the
+    /// range as in `descriptor.proto`.
+    pub fn add_extension_range(&mut self, range: ops::Range<i32>) {
+        self.extension_ranges.push(range);
+    }
+
+    /// All of the oneofs in the descriptor, in declaration order.
+    pub fn oneofs(&self) -> &[OneofDescriptor] {
+        &self.oneofs
+    }
+
+    /// Finds a oneof by name.
+    #[inline]
+    pub fn oneof_by_name(&self, name: &str) -> Option<&OneofDescriptor> {
+        self.oneofs_by_name.get(name).map(|o| &self.oneofs[o.0])
+    }
+
+    /// Finds the oneof that the specified field is a member of.
+    #[inline]
+    pub fn oneof_of_field(&self, field: &FieldDescriptor) -> Option<&OneofDescriptor> {
+        field.oneof_index.and_then(|i| self.oneofs.get(i))
+    }
+
+    /// The member fields of the specified oneof.
+    pub fn oneof_fields<'a>(&'a self, oneof: &'a OneofDescriptor) -> Vec<&'a FieldDescriptor> {
+        oneof
+            .fields
+            .iter()
+            .filter_map(|&number| self.field_by_number(number))
+            .collect()
+    }
+
+    /// Adds a new oneof to the descriptor, with the next oneof index.
+    ///
+    /// Fields that were already added with that oneof index become members of the oneof.
+    pub fn add_oneof(&mut self, mut descriptor: OneofDescriptor) {
+        let index = self.oneofs.len();
+        for field in &self.fields {
+            if field.oneof_index == Some(index) {
+                descriptor.add_member(field);
+            }
+        }
+
+        let name = descriptor.name.clone();
+        let oneof_id = OneofId(store(&mut self.oneofs, descriptor));
+        self.oneofs_by_name.insert(name, oneof_id);
+    }
+}
+
+impl OneofDescriptor {
+    /// Creates a new oneof descriptor with the specified oneof name.
+    pub fn new<S>(name: S) -> OneofDescriptor
+    where
+        S: Into<String>,
+    {
+        let name = name.into();
+        let json_name = default_json_name(&name);
+        OneofDescriptor {
+            name,
+            json_name,
+            fields: Vec::new(),
+            synthetic: false,
+        }
+    }
+
+    /// Reads a oneof descriptor from a parsed Protobuf descriptor.
+    pub fn from_proto(proto: &descriptor::OneofDescriptorProto) -> OneofDescriptor {
+        OneofDescriptor::new(proto.get_name())
+    }
+
+    /// The name of the oneof.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The name of the oneof converted to lowerCamelCase, like the JSON names of fields.
+    #[inline]
+    pub fn json_name(&self) -> &str {
+        &self.json_name
+    }
+
+    /// The numbers of the member fields of the oneof.
+    #[inline]
+    pub fn field_numbers(&self) -> &[i32] {
+        &self.fields
+    }
+
+    /// Whether the oneof was generated for a `proto3` `optional` field, rather than declared.
+    #[inline]
+    pub fn is_synthetic(&self) -> bool {
+        self.synthetic
+    }
+
+    fn add_member(&mut self, field: &FieldDescriptor) {
+        self.fields.push(field.number);
+        self.synthetic = field.proto3_optional;
+    }
 }
 
 impl EnumDescriptor {
@@ -510,6 +2149,12 @@ impl EnumDescriptor {
     {
         EnumDescriptor {
             name: name.into(),
+            file: None,
+            closed: true,
+            allow_alias: false,
+            deprecated: false,
+            options: Vec::new(),
+            location: None,
             values: Vec::new(),
             values_by_name: linked_hash_map::LinkedHashMap::new(),
             values_by_number: linked_hash_map::LinkedHashMap::new(),
@@ -526,13 +2171,95 @@ impl EnumDescriptor {
             enum_descriptor.add_value(EnumValueDescriptor::from_proto(value_proto));
         }
 
+        if proto.has_options() {
+            let options = proto.get_options();
+            enum_descriptor.set_allow_alias(options.get_allow_alias());
+            enum_descriptor.set_deprecated(options.get_deprecated());
+            enum_descriptor.options = encode_options(options);
+        }
+
         enum_descriptor
     }
 
-    /// The name of the enum.
-    #[inline]
-    pub fn name(&self) -> &str {
-        &self.name
+    /// The name of the enum.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The name of the file that the enum was declared in, if it is known.
+    #[inline]
+    pub fn file_name(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// Changes the name of the file that the enum was declared in.
+    pub fn set_file_name<S>(&mut self, file_name: S)
+    where
+        S: Into<String>,
+    {
+        self.file = Some(file_name.into());
+    }
+
+    /// The file that the enum was declared in, if it is part of the specified registry.
+    pub fn file<'a>(&self, descriptors: &'a Descriptors) -> Option<&'a FileDescriptor> {
+        descriptors.file_by_name(self.file.as_ref()?)
+    }
+
+    /// Whether the enum is closed, meaning that fields of this enum type can only hold one of its
+    /// declared values.
+    ///
+    /// Enums defined in `proto2` files are closed, while those defined in `proto3` files are open.
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Changes whether the enum is closed.
+    pub fn set_closed(&mut self, closed: bool) {
+        self.closed = closed;
+    }
+
+    /// Whether several values of the enum are allowed to have the same number.
+    #[inline]
+    pub fn allows_alias(&self) -> bool {
+        self.allow_alias
+    }
+
+    /// Changes whether several values of the enum are allowed to have the same number.
+    pub fn set_allow_alias(&mut self, allow_alias: bool) {
+        self.allow_alias = allow_alias;
+    }
+
+    /// Where the enum was declared, along with its comments, if the file that it was read from
+    /// included source code info.
+    #[inline]
+    pub fn source_location(&self) -> Option<&SourceLocation> {
+        self.location.as_ref()
+    }
+
+    /// Changes where the enum was declared.
+    pub fn set_source_location(&mut self, location: SourceLocation) {
+        self.location = Some(location);
+    }
+
+    /// Whether the enum is marked as deprecated.
+    #[inline]
+    pub fn is_deprecated(&self) -> bool {
+        self.deprecated
+    }
+
+    /// Changes whether the enum is marked as deprecated.
+    pub fn set_deprecated(&mut self, deprecated: bool) {
+        self.deprecated = deprecated;
+    }
+
+    /// Decodes the options of the enum as a `google.protobuf.EnumOptions` message.
+    ///
+    /// Custom options are decoded using the extensions of `EnumOptions` that are registered in the
+    /// specified registry, and end up as unknown fields otherwise.
+    pub fn options(&self, descriptors: &Descriptors) -> error::Result<value::Message> {
+        decode_options(descriptors, ".google.protobuf.EnumOptions", &self.options)
     }
 
     /// Adds an enum value to the enum.
@@ -546,6 +2273,11 @@ impl EnumDescriptor {
         self.values_by_number.insert(number, value_id);
     }
 
+    /// All of the values in the descriptor, in declaration order.
+    pub fn values(&self) -> &[EnumValueDescriptor] {
+        &self.values
+    }
+
     /// Finds a value by name.
     #[inline]
     pub fn value_by_name(&self, name: &str) -> Option<&EnumValueDescriptor> {
@@ -568,12 +2300,25 @@ impl EnumValueDescriptor {
         S: Into<String>,
     {
         let name = name.into();
-        EnumValueDescriptor { name, number }
+        EnumValueDescriptor {
+            name,
+            number,
+            deprecated: false,
+            options: Vec::new(),
+            location: None,
+        }
     }
 
     /// Reads an enum value descriptor from a parsed Protobuf descriptor.
     pub fn from_proto(proto: &descriptor::EnumValueDescriptorProto) -> EnumValueDescriptor {
-        EnumValueDescriptor::new(proto.get_name().to_owned(), proto.get_number())
+        let mut value_descriptor =
+            EnumValueDescriptor::new(proto.get_name().to_owned(), proto.get_number());
+        if proto.has_options() {
+            let options = proto.get_options();
+            value_descriptor.set_deprecated(options.get_deprecated());
+            value_descriptor.options = encode_options(options);
+        }
+        value_descriptor
     }
 
     /// The name of the enum value.
@@ -587,6 +2332,215 @@ impl EnumValueDescriptor {
     pub fn number(&self) -> i32 {
         self.number
     }
+
+    /// Where the enum value was declared, along with its comments, if the file that it was read from
+    /// included source code info.
+    #[inline]
+    pub fn source_location(&self) -> Option<&SourceLocation> {
+        self.location.as_ref()
+    }
+
+    /// Changes where the enum value was declared.
+    pub fn set_source_location(&mut self, location: SourceLocation) {
+        self.location = Some(location);
+    }
+
+    /// Whether the enum value is marked as deprecated.
+    #[inline]
+    pub fn is_deprecated(&self) -> bool {
+        self.deprecated
+    }
+
+    /// Changes whether the enum value is marked as deprecated.
+    pub fn set_deprecated(&mut self, deprecated: bool) {
+        self.deprecated = deprecated;
+    }
+
+    /// Decodes the options of the enum value as a `google.protobuf.EnumValueOptions` message.
+    ///
+    /// Custom options are decoded using the extensions of `EnumValueOptions` that are registered
+    /// in the specified registry, and end up as unknown fields otherwise.
+    pub fn options(&self, descriptors: &Descriptors) -> error::Result<value::Message> {
+        decode_options(
+            descriptors,
+            ".google.protobuf.EnumValueOptions",
+            &self.options,
+        )
+    }
+}
+
+impl ServiceDescriptor {
+    /// Creates a new service descriptor with the specified service name.
+    pub fn new<S>(name: S) -> ServiceDescriptor
+    where
+        S: Into<String>,
+    {
+        ServiceDescriptor {
+            name: name.into(),
+            location: None,
+            methods: Vec::new(),
+            methods_by_name: linked_hash_map::LinkedHashMap::new(),
+        }
+    }
+
+    /// Reads a service descriptor from a parsed Protobuf descriptor.
+    pub fn from_proto(path: &str, proto: &descriptor::ServiceDescriptorProto) -> ServiceDescriptor {
+        let name = format!("{}.{}", path, proto.get_name());
+        let mut service_descriptor = ServiceDescriptor::new(name);
+
+        for method_proto in proto.get_method().iter() {
+            service_descriptor.add_method(MethodDescriptor::from_proto(method_proto));
+        }
+
+        service_descriptor
+    }
+
+    /// The fully qualified name of the service.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// All of the methods of the service, in declaration order.
+    #[inline]
+    pub fn methods(&self) -> &[MethodDescriptor] {
+        &self.methods
+    }
+
+    /// Finds a method by its name.
+    #[inline]
+    pub fn method_by_name(&self, name: &str) -> Option<&MethodDescriptor> {
+        self.methods_by_name.get(name).map(|m| &self.methods[m.0])
+    }
+
+    /// Where the service was declared, along with its comments, if the file that it was read from
+    /// included source code info.
+    #[inline]
+    pub fn source_location(&self) -> Option<&SourceLocation> {
+        self.location.as_ref()
+    }
+
+    /// Changes where the service was declared.
+    pub fn set_source_location(&mut self, location: SourceLocation) {
+        self.location = Some(location);
+    }
+
+    /// Adds a new method to the descriptor.
+    pub fn add_method(&mut self, mut descriptor: MethodDescriptor) {
+        descriptor.scope = self.name.clone();
+        let name = descriptor.name.clone();
+        let method_id = MethodId(store(&mut self.methods, descriptor));
+        self.methods_by_name.insert(name, method_id);
+    }
+}
+
+impl MethodDescriptor {
+    /// Creates a new method descriptor with the specified name, taking messages of the input type
+    /// and returning messages of the output type.
+    pub fn new<S, I, O>(name: S, input_type: I, output_type: O) -> MethodDescriptor
+    where
+        S: Into<String>,
+        I: Into<String>,
+        O: Into<String>,
+    {
+        MethodDescriptor {
+            name: name.into(),
+            input_type: InternalFieldType::UnresolvedMessage(input_type.into()),
+            output_type: InternalFieldType::UnresolvedMessage(output_type.into()),
+            client_streaming: false,
+            server_streaming: false,
+            scope: String::new(),
+            location: None,
+        }
+    }
+
+    /// Reads a method descriptor from a parsed Protobuf descriptor.
+    pub fn from_proto(proto: &descriptor::MethodDescriptorProto) -> MethodDescriptor {
+        let mut method = MethodDescriptor::new(
+            proto.get_name(),
+            proto.get_input_type(),
+            proto.get_output_type(),
+        );
+        method.set_client_streaming(proto.get_client_streaming());
+        method.set_server_streaming(proto.get_server_streaming());
+        method
+    }
+
+    /// The name of the method.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The message type of the requests that the method takes.
+    ///
+    /// Type names that haven't been resolved with `Descriptors::resolve_refs` are looked up on
+    /// every call, relative to the service that the method belongs to.
+    #[inline]
+    pub fn input_type<'a>(&'a self, descriptors: &'a Descriptors) -> Option<&'a MessageDescriptor> {
+        match self.input_type.resolve(descriptors, &self.scope) {
+            FieldType::Message(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    /// The message type of the responses that the method returns.
+    ///
+    /// Type names are resolved like for `input_type`.
+    #[inline]
+    pub fn output_type<'a>(
+        &'a self,
+        descriptors: &'a Descriptors,
+    ) -> Option<&'a MessageDescriptor> {
+        match self.output_type.resolve(descriptors, &self.scope) {
+            FieldType::Message(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    /// Where the method was declared, along with its comments, if the file that it was read from
+    /// included source code info.
+    #[inline]
+    pub fn source_location(&self) -> Option<&SourceLocation> {
+        self.location.as_ref()
+    }
+
+    /// Changes where the method was declared.
+    pub fn set_source_location(&mut self, location: SourceLocation) {
+        self.location = Some(location);
+    }
+
+    /// Whether the client sends a stream of requests.
+    #[inline]
+    pub fn is_client_streaming(&self) -> bool {
+        self.client_streaming
+    }
+
+    /// Changes whether the client sends a stream of requests.
+    pub fn set_client_streaming(&mut self, client_streaming: bool) {
+        self.client_streaming = client_streaming;
+    }
+
+    /// Whether the server sends a stream of responses.
+    #[inline]
+    pub fn is_server_streaming(&self) -> bool {
+        self.server_streaming
+    }
+
+    /// Changes whether the server sends a stream of responses.
+    pub fn set_server_streaming(&mut self, server_streaming: bool) {
+        self.server_streaming = server_streaming;
+    }
+}
+
+impl Syntax {
+    /// Converts the `syntax` of a proto file descriptor.
+    pub fn from_proto(syntax: &str) -> Syntax {
+        match syntax {
+            "proto3" => Syntax::Proto3,
+            _ => Syntax::Proto2,
+        }
+    }
 }
 
 impl FieldLabel {
@@ -625,7 +2579,7 @@ impl InternalFieldType {
             TYPE_FIXED32 => InternalFieldType::Fixed32,
             TYPE_BOOL => InternalFieldType::Bool,
             TYPE_STRING => InternalFieldType::String,
-            TYPE_GROUP => InternalFieldType::Group,
+            TYPE_GROUP => InternalFieldType::UnresolvedGroup(type_name.to_owned()),
             TYPE_MESSAGE => InternalFieldType::UnresolvedMessage(type_name.to_owned()),
             TYPE_BYTES => InternalFieldType::Bytes,
             TYPE_UINT32 => InternalFieldType::UInt32,
@@ -638,22 +2592,29 @@ impl InternalFieldType {
     }
 
     #[inline]
-    fn resolve<'a>(&'a self, descriptors: &'a Descriptors) -> FieldType<'a> {
+    fn resolve<'a>(&'a self, descriptors: &'a Descriptors, scope: &str) -> FieldType<'a> {
         match *self {
             InternalFieldType::UnresolvedMessage(ref n) => {
-                if let Some(m) = descriptors.message_by_name(n) {
+                if let Some(m) = descriptors.find_message(n, scope) {
                     FieldType::Message(m)
                 } else {
                     FieldType::UnresolvedMessage(n)
                 }
             }
             InternalFieldType::UnresolvedEnum(ref n) => {
-                if let Some(e) = descriptors.enum_by_name(n) {
+                if let Some(e) = descriptors.find_enum(n, scope) {
                     FieldType::Enum(e)
                 } else {
                     FieldType::UnresolvedEnum(n)
                 }
             }
+            InternalFieldType::UnresolvedGroup(ref n) => {
+                if let Some(m) = descriptors.find_message(n, scope) {
+                    FieldType::Group(m)
+                } else {
+                    FieldType::UnresolvedGroup(n)
+                }
+            }
             InternalFieldType::Double => FieldType::Double,
             InternalFieldType::Float => FieldType::Float,
             InternalFieldType::Int64 => FieldType::Int64,
@@ -663,7 +2624,7 @@ impl InternalFieldType {
             InternalFieldType::Fixed32 => FieldType::Fixed32,
             InternalFieldType::Bool => FieldType::Bool,
             InternalFieldType::String => FieldType::String,
-            InternalFieldType::Group => FieldType::Group,
+            InternalFieldType::Group(m) => FieldType::Group(&descriptors.messages[m.0]),
             InternalFieldType::Message(m) => FieldType::Message(&descriptors.messages[m.0]),
             InternalFieldType::Bytes => FieldType::Bytes,
             InternalFieldType::UInt32 => FieldType::UInt32,
@@ -689,67 +2650,652 @@ impl FieldDescriptor {
         S: Into<String>,
     {
         let name = name.into();
+        let json_name = default_json_name(&name);
         FieldDescriptor {
             name,
+            json_name,
             number,
             field_label,
             field_type,
             default_value,
+            presence: true,
+            proto3_optional: false,
+            oneof_index: None,
+            map: false,
+            scope: String::new(),
+            extendee: None,
+            deprecated: false,
+            packed: None,
+            options: Vec::new(),
+            location: None,
+        }
+    }
+
+    /// Reads a field descriptor from a parsed Protobuf descriptor.
+    pub fn from_proto(proto: &descriptor::FieldDescriptorProto) -> FieldDescriptor {
+        let name = proto.get_name().to_owned();
+        let number = proto.get_number();
+        let field_label = FieldLabel::from_proto(proto.get_label());
+        let field_type =
+            InternalFieldType::from_proto(proto.get_field_type(), proto.get_type_name());
+        let default_value = if proto.has_default_value() {
+            // Invalid default values are ignored here; `DescriptorsBuilder` reports them
+            parse_default_value(proto.get_default_value(), &field_type).ok()
+        } else {
+            None
+        };
+
+        let mut field = FieldDescriptor::new(name, number, field_label, field_type, default_value);
+        if proto.has_json_name() {
+            field.set_json_name(proto.get_json_name());
+        }
+        if proto.has_oneof_index() {
+            field.set_oneof_index(Some(proto.get_oneof_index() as usize));
+        }
+        if proto.has_extendee() {
+            field.set_extendee(proto.get_extendee());
+        }
+        if proto.has_options() {
+            let options = proto.get_options();
+            field.set_deprecated(options.get_deprecated());
+            if options.has_packed() {
+                field.set_packed(options.get_packed());
+            }
+            field.options = encode_options(options);
+        }
+        // `proto3_optional` (field 17) is newer than the descriptor types of the protobuf crate
+        field.proto3_optional = match protobuf::Message::get_unknown_fields(proto).get(17) {
+            Some(values) => match values.varint.last() {
+                Some(&v) => v != 0,
+                None => false,
+            },
+            None => false,
+        };
+        field
+    }
+
+    /// The name of the field.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The fully qualified name of the field (i.e. `.foo.package.Message.field`).
+    ///
+    /// Extensions are named after the scope that they are declared in rather than the message that
+    /// they extend (i.e. `.foo.package.extension`).
+    pub fn full_name(&self) -> String {
+        format!("{}.{}", self.scope, self.name)
+    }
+
+    /// The JSON name of the field.
+    ///
+    /// Unless set explicitly, this is the name of the field converted to lowerCamelCase, the same
+    /// way that `protoc` derives it.
+    #[inline]
+    pub fn json_name(&self) -> &str {
+        &self.json_name
+    }
+
+    /// Changes the JSON name of the field.
+    pub fn set_json_name<S>(&mut self, json_name: S)
+    where
+        S: Into<String>,
+    {
+        self.json_name = json_name.into();
+    }
+
+    /// The number of the field.
+    #[inline]
+    pub fn number(&self) -> i32 {
+        self.number
+    }
+
+    /// The label of the field.
+    #[inline]
+    pub fn field_label(&self) -> FieldLabel {
+        self.field_label
+    }
+
+    /// Whether the field is repeated.
+    #[inline]
+    pub fn is_repeated(&self) -> bool {
+        self.field_label == FieldLabel::Repeated
+    }
+
+    /// The type of the field.
+    ///
+    /// Type names that haven't been resolved with `Descriptors::resolve_refs` are looked up on
+    /// every call, relative to the message that the field belongs to.
+    #[inline]
+    pub fn field_type<'a>(&'a self, descriptors: &'a Descriptors) -> FieldType<'a> {
+        self.field_type.resolve(descriptors, &self.scope)
+    }
+
+    /// The default value of the field.
+    ///
+    /// For fields with implicit presence, this is the zero value of the field type.
+    #[inline]
+    pub fn default_value(&self) -> Option<&value::Value> {
+        self.default_value.as_ref()
+    }
+
+    /// The zero value of the field type, if it isn't a message type.
+    #[inline]
+    pub(crate) fn zero_value(&self) -> Option<value::Value> {
+        zero_value(&self.field_type)
+    }
+
+    /// Whether the field tracks presence, i.e. whether an unset field can be told apart from one
+    /// that is set to its default value.
+    ///
+    /// This is the case for all singular fields except the plain scalar fields of `proto3`
+    /// messages.  Repeated fields never track presence.
+    #[inline]
+    pub fn has_presence(&self) -> bool {
+        self.presence && !self.is_repeated()
+    }
+
+    /// Changes whether the field tracks presence.
+    ///
+    /// A field without presence that doesn't have a default value yet is given the zero value of
+    /// its type as default value.
+    pub fn set_has_presence(&mut self, presence: bool) {
+        self.presence = presence;
+        if !presence && self.default_value.is_none() {
+            self.default_value = zero_value(&self.field_type);
+        }
+    }
+
+    /// The index of the oneof within its message that the field is a member of, if any.
+    #[inline]
+    pub fn oneof_index(&self) -> Option<usize> {
+        self.oneof_index
+    }
+
+    /// Changes the oneof that the field is a member of.
+    ///
+    /// This has to be done before the field is added to its message.
+    pub fn set_oneof_index(&mut self, oneof_index: Option<usize>) {
+        self.oneof_index = oneof_index;
+    }
+
+    /// Whether the field is a map field, i.e. a repeated field of a map entry message type.
+    #[inline]
+    pub fn is_map(&self) -> bool {
+        self.map
+    }
+
+    /// Changes whether the field is a map field.
+    ///
+    /// The type of a map field must be a message whose key and value fields are numbered 1 and 2.
+    pub fn set_map(&mut self, map: bool) {
+        self.map = map;
+    }
+
+    /// The fully qualified name of the message that the field extends, if it is an extension.
+    #[inline]
+    pub fn extendee(&self) -> Option<&str> {
+        self.extendee.as_deref()
+    }
+
+    /// Whether the field is an extension of another message.
+    #[inline]
+    pub fn is_extension(&self) -> bool {
+        self.extendee.is_some()
+    }
+
+    /// Makes the field an extension of the message with the specified fully qualified name.
+    ///
+    /// Extensions are added to a registry with `Descriptors::add_extension` instead of to the
+    /// message that they extend.
+    pub fn set_extendee<S>(&mut self, extendee: S)
+    where
+        S: Into<String>,
+    {
+        self.extendee = Some(extendee.into());
+    }
+
+    /// Where the field was declared, along with its comments, if the file that it was read from
+    /// included source code info.
+    #[inline]
+    pub fn source_location(&self) -> Option<&SourceLocation> {
+        self.location.as_ref()
+    }
+
+    /// Changes where the field was declared.
+    pub fn set_source_location(&mut self, location: SourceLocation) {
+        self.location = Some(location);
+    }
+
+    /// Whether the field is marked as deprecated.
+    #[inline]
+    pub fn is_deprecated(&self) -> bool {
+        self.deprecated
+    }
+
+    /// Changes whether the field is marked as deprecated.
+    pub fn set_deprecated(&mut self, deprecated: bool) {
+        self.deprecated = deprecated;
+    }
+
+    /// Whether the field uses the packed encoding.
+    ///
+    /// Unless set explicitly with the `packed` option, this is the case for repeated fields of
+    /// scalar types in `proto3` messages only.
+    #[inline]
+    pub fn is_packed(&self) -> bool {
+        self.packed.unwrap_or(false)
+    }
+
+    /// Changes whether the field uses the packed encoding.
+    pub fn set_packed(&mut self, packed: bool) {
+        self.packed = Some(packed);
+    }
+
+    /// Decodes the options of the field as a `google.protobuf.FieldOptions` message.
+    ///
+    /// Custom options are decoded using the extensions of `FieldOptions` that are registered in
+    /// the specified registry, and end up as unknown fields otherwise.
+    pub fn options(&self, descriptors: &Descriptors) -> error::Result<value::Message> {
+        decode_options(descriptors, ".google.protobuf.FieldOptions", &self.options)
+    }
+
+    /// Whether the field was declared with the `optional` keyword in a `proto3` file.
+    #[inline]
+    pub fn is_proto3_optional(&self) -> bool {
+        self.proto3_optional
+    }
+
+    /// Whether values of the field type can use the packed encoding.
+    #[inline]
+    fn is_packable(&self) -> bool {
+        !matches!(
+            self.field_type,
+            InternalFieldType::UnresolvedMessage(_)
+                | InternalFieldType::UnresolvedGroup(_)
+                | InternalFieldType::Message(_)
+                | InternalFieldType::Group(_)
+                | InternalFieldType::String
+                | InternalFieldType::Bytes
+        )
+    }
+
+    /// Whether the field has implicit presence, assuming that it belongs to a `proto3` message.
+    #[inline]
+    fn has_implicit_presence(&self) -> bool {
+        let is_message = matches!(
+            self.field_type,
+            InternalFieldType::UnresolvedMessage(_)
+                | InternalFieldType::UnresolvedGroup(_)
+                | InternalFieldType::Message(_)
+                | InternalFieldType::Group(_)
+        );
+        self.field_label == FieldLabel::Optional
+            && !is_message
+            && !self.proto3_optional
+            && self.oneof_index.is_none()
+    }
+}
+
+/// Converts a field name to lowerCamelCase by removing underscores and capitalizing the letter
+/// following each of them.
+fn default_json_name(name: &str) -> String {
+    let mut json_name = String::with_capacity(name.len());
+    let mut capitalize = false;
+    for c in name.chars() {
+        if c == '_' {
+            capitalize = true;
+        } else if capitalize {
+            json_name.extend(c.to_uppercase());
+            capitalize = false;
+        } else {
+            json_name.push(c);
+        }
+    }
+    json_name
+}
+
+/// The zero value of the specified type, if it isn't a message type.
+///
+/// The zero value of an enum type is `0`, which has to be its first value in a `proto3` file.
+fn zero_value(field_type: &InternalFieldType) -> Option<value::Value> {
+    match *field_type {
+        InternalFieldType::UnresolvedMessage(_)
+        | InternalFieldType::UnresolvedGroup(_)
+        | InternalFieldType::Message(_)
+        | InternalFieldType::Group(_) => None,
+        InternalFieldType::UnresolvedEnum(_) | InternalFieldType::Enum(_) => {
+            Some(value::Value::Enum(0))
+        }
+        InternalFieldType::Bool => Some(value::Value::Bool(false)),
+        InternalFieldType::Double => Some(value::Value::F64(0.0)),
+        InternalFieldType::Float => Some(value::Value::F32(0.0)),
+        InternalFieldType::Int32 | InternalFieldType::SFixed32 | InternalFieldType::SInt32 => {
+            Some(value::Value::I32(0))
+        }
+        InternalFieldType::Int64 | InternalFieldType::SFixed64 | InternalFieldType::SInt64 => {
+            Some(value::Value::I64(0))
+        }
+        InternalFieldType::UInt32 | InternalFieldType::Fixed32 => Some(value::Value::U32(0)),
+        InternalFieldType::UInt64 | InternalFieldType::Fixed64 => Some(value::Value::U64(0)),
+        InternalFieldType::String => Some(value::Value::String(String::new())),
+        InternalFieldType::Bytes => Some(value::Value::Bytes(Vec::new())),
+    }
+}
+
+/// Resolves a possibly relative type name in a scope, returning the first fully qualified name
+/// that `is_match` accepts or all of the names that were tried.
+fn resolve_type_name<F>(
+    messages_by_name: &linked_hash_map::LinkedHashMap<String, MessageId>,
+    name: &str,
+    scope: &str,
+    is_match: F,
+) -> Result<String, Vec<String>>
+where
+    F: Fn(&str) -> bool,
+{
+    if name.starts_with('.') {
+        return if is_match(name) {
+            Ok(name.to_owned())
+        } else {
+            Err(vec![name.to_owned()])
+        };
+    }
+
+    let first = name.split('.').next().unwrap_or(name);
+    let mut tried = Vec::new();
+    let mut scope = scope.trim_end_matches('.');
+    loop {
+        let candidate = format!("{}.{}", scope, name);
+        if is_match(&candidate) {
+            return Ok(candidate);
+        }
+        tried.push(candidate);
+        if first.len() < name.len()
+            && messages_by_name.contains_key(&format!("{}.{}", scope, first))
+        {
+            return Err(tried);
+        }
+        if scope.is_empty() {
+            return Err(tried);
+        }
+        scope = &scope[..scope.rfind('.').unwrap_or(0)];
+    }
+}
+
+/// Resolves the type reference of a field, if it has one, in the scope of the field.
+fn resolve_field_type(
+    messages_by_name: &linked_hash_map::LinkedHashMap<String, MessageId>,
+    enums_by_name: &linked_hash_map::LinkedHashMap<String, EnumId>,
+    field: &mut FieldDescriptor,
+) {
+    resolve_type(
+        messages_by_name,
+        enums_by_name,
+        &field.scope,
+        &mut field.field_type,
+    );
+}
+
+/// Resolves a type reference, if it is one, in the specified scope.
+fn resolve_type(
+    messages_by_name: &linked_hash_map::LinkedHashMap<String, MessageId>,
+    enums_by_name: &linked_hash_map::LinkedHashMap<String, EnumId>,
+    scope: &str,
+    field_type: &mut InternalFieldType,
+) {
+    let new = match *field_type {
+        InternalFieldType::UnresolvedMessage(ref name) => {
+            match resolve_type_name(messages_by_name, name, scope, |n| {
+                messages_by_name.contains_key(n)
+            }) {
+                Ok(n) => Some(InternalFieldType::Message(messages_by_name[&n])),
+                Err(tried) => {
+                    warn!(
+                        "Inconsistent schema; unknown message type {} (tried {})",
+                        name,
+                        tried.join(", ")
+                    );
+                    None
+                }
+            }
+        }
+        InternalFieldType::UnresolvedEnum(ref name) => {
+            match resolve_type_name(messages_by_name, name, scope, |n| {
+                enums_by_name.contains_key(n)
+            }) {
+                Ok(n) => Some(InternalFieldType::Enum(enums_by_name[&n])),
+                Err(tried) => {
+                    warn!(
+                        "Inconsistent schema; unknown enum type {} (tried {})",
+                        name,
+                        tried.join(", ")
+                    );
+                    None
+                }
+            }
+        }
+        InternalFieldType::UnresolvedGroup(ref name) => {
+            match resolve_type_name(messages_by_name, name, scope, |n| {
+                messages_by_name.contains_key(n)
+            }) {
+                Ok(n) => Some(InternalFieldType::Group(messages_by_name[&n])),
+                Err(tried) => {
+                    warn!(
+                        "Inconsistent schema; unknown group type {} (tried {})",
+                        name,
+                        tried.join(", ")
+                    );
+                    None
+                }
+            }
         }
+        _ => None,
+    };
+
+    if let Some(t) = new {
+        *field_type = t;
     }
+}
 
-    /// Reads a field descriptor from a parsed Protobuf descriptor.
-    pub fn from_proto(proto: &descriptor::FieldDescriptorProto) -> FieldDescriptor {
-        let name = proto.get_name().to_owned();
-        let number = proto.get_number();
-        let field_label = FieldLabel::from_proto(proto.get_label());
-        let field_type =
-            InternalFieldType::from_proto(proto.get_field_type(), proto.get_type_name());
-        let default_value = if proto.has_default_value() {
-            // TODO: report error?
-            parse_default_value(proto.get_default_value(), &field_type).ok()
-        } else {
-            None
+impl SourceLocation {
+    /// Creates a new source location spanning from the specified start to the specified end,
+    /// without any comments.
+    pub fn new(
+        start_line: usize,
+        start_column: usize,
+        end_line: usize,
+        end_column: usize,
+    ) -> SourceLocation {
+        SourceLocation {
+            start_line,
+            start_column,
+            end_line,
+            end_column,
+            ..SourceLocation::default()
+        }
+    }
+
+    /// Reads a source location from a parsed Protobuf descriptor.
+    pub fn from_proto(proto: &descriptor::SourceCodeInfo_Location) -> SourceLocation {
+        let span = proto
+            .get_span()
+            .iter()
+            .map(|&n| n.max(0) as usize)
+            .collect::<Vec<_>>();
+        // The end line is left out if it is the same as the start line
+        let mut location = match span[..] {
+            [start_line, start_column, end_line, end_column] => {
+                SourceLocation::new(start_line, start_column, end_line, end_column)
+            }
+            [line, start_column, end_column] => {
+                SourceLocation::new(line, start_column, line, end_column)
+            }
+            _ => SourceLocation::default(),
         };
 
-        FieldDescriptor::new(name, number, field_label, field_type, default_value)
+        if proto.has_leading_comments() {
+            location.set_leading_comments(proto.get_leading_comments());
+        }
+        if proto.has_trailing_comments() {
+            location.set_trailing_comments(proto.get_trailing_comments());
+        }
+        for comment in proto.get_leading_detached_comments().iter() {
+            location.add_leading_detached_comment(comment.as_str());
+        }
+
+        location
     }
 
-    /// The name of the field.
+    /// The line that the definition starts on.
     #[inline]
-    pub fn name(&self) -> &str {
-        &self.name
+    pub fn start_line(&self) -> usize {
+        self.start_line
     }
 
-    /// The number of the field.
+    /// The column that the definition starts at.
     #[inline]
-    pub fn number(&self) -> i32 {
-        self.number
+    pub fn start_column(&self) -> usize {
+        self.start_column
     }
 
-    /// The label of the field.
+    /// The line that the definition ends on.
     #[inline]
-    pub fn field_label(&self) -> FieldLabel {
-        self.field_label
+    pub fn end_line(&self) -> usize {
+        self.end_line
     }
 
-    /// Whether the field is repeated.
+    /// The column just after the end of the definition.
     #[inline]
-    pub fn is_repeated(&self) -> bool {
-        self.field_label == FieldLabel::Repeated
+    pub fn end_column(&self) -> usize {
+        self.end_column
     }
 
-    /// The type of the field.
+    /// The comment directly before the definition, without comment markers.
     #[inline]
-    pub fn field_type<'a>(&'a self, descriptors: &'a Descriptors) -> FieldType<'a> {
-        self.field_type.resolve(descriptors)
+    pub fn leading_comments(&self) -> Option<&str> {
+        self.leading_comments.as_deref()
     }
 
-    /// The default value of the field.
+    /// Changes the comment directly before the definition.
+    pub fn set_leading_comments<S>(&mut self, comments: S)
+    where
+        S: Into<String>,
+    {
+        self.leading_comments = Some(comments.into());
+    }
+
+    /// The comment directly after the definition, without comment markers.
     #[inline]
-    pub fn default_value(&self) -> Option<&value::Value> {
-        self.default_value.as_ref()
+    pub fn trailing_comments(&self) -> Option<&str> {
+        self.trailing_comments.as_deref()
+    }
+
+    /// Changes the comment directly after the definition.
+    pub fn set_trailing_comments<S>(&mut self, comments: S)
+    where
+        S: Into<String>,
+    {
+        self.trailing_comments = Some(comments.into());
+    }
+
+    /// The comments before the definition that are separated from it and from each other by blank
+    /// lines, in order.
+    #[inline]
+    pub fn leading_detached_comments(&self) -> &[String] {
+        &self.leading_detached_comments
+    }
+
+    /// Adds a comment before the definition that is separated from it by a blank line.
+    pub fn add_leading_detached_comment<S>(&mut self, comment: S)
+    where
+        S: Into<String>,
+    {
+        self.leading_detached_comments.push(comment.into());
+    }
+}
+
+/// A depth-first walk of the import graph that collects files after the files they import.
+struct ImportWalk<'a> {
+    descriptors: &'a Descriptors,
+    // The files that are being visited, from the outermost importer inwards
+    path: Vec<usize>,
+    visited: collections::HashSet<usize>,
+    order: Vec<&'a FileDescriptor>,
+}
+
+impl<'a> ImportWalk<'a> {
+    fn new(descriptors: &'a Descriptors) -> ImportWalk<'a> {
+        ImportWalk {
+            descriptors,
+            path: Vec::new(),
+            visited: collections::HashSet::new(),
+            order: Vec::new(),
+        }
+    }
+
+    fn visit(&mut self, id: usize) -> error::Result<()> {
+        let descriptors = self.descriptors;
+        if self.visited.contains(&id) {
+            return Ok(());
+        }
+        if let Some(start) = self.path.iter().position(|&i| i == id) {
+            let files = self.path[start..]
+                .iter()
+                .chain(Some(&id))
+                .map(|&i| descriptors.files[i].name.clone())
+                .collect();
+            return Err(error::Error::ImportCycle { files });
+        }
+
+        let file = &descriptors.files[id];
+        self.path.push(id);
+        for dependency in &file.dependencies {
+            if let Some(dependency_id) = descriptors.files_by_name.get(dependency) {
+                self.visit(dependency_id.0)?;
+            }
+        }
+        self.path.pop();
+
+        self.visited.insert(id);
+        self.order.push(file);
+        Ok(())
+    }
+}
+
+/// Looks up the location with the specified path, if the source code info has one.
+fn source_location(locations: &SourceLocations, path: &[i32]) -> Option<SourceLocation> {
+    locations.get(path).copied().map(SourceLocation::from_proto)
+}
+
+/// Encodes an options message, so that its custom options can be decoded once the extensions that
+/// define them are known.
+fn encode_options<M>(options: &M) -> Vec<u8>
+where
+    M: protobuf::Message,
+{
+    options.write_to_bytes().unwrap_or_default()
+}
+
+/// Decodes encoded options as a message of the specified options type.
+///
+/// If `descriptor.proto` isn't part of the registry, only custom options are known.
+fn decode_options(
+    descriptors: &Descriptors,
+    name: &str,
+    options: &[u8],
+) -> error::Result<value::Message> {
+    let mut message = value::Message::empty();
+    let mut input = protobuf::CodedInputStream::from_bytes(options);
+    match descriptors.message_by_name(name) {
+        Some(descriptor) => message.merge_present_from(descriptors, descriptor, &mut input)?,
+        None => {
+            let descriptor = MessageDescriptor::new(name);
+            message.merge_present_from(descriptors, &descriptor, &mut input)?
+        }
     }
+    Ok(message)
 }
 
 fn store<A>(vec: &mut Vec<A>, elem: A) -> usize {
@@ -770,8 +3316,10 @@ fn parse_default_value(value: &str, field_type: &InternalFieldType) -> error::Re
     match *field_type {
         InternalFieldType::UnresolvedMessage(_)
         | InternalFieldType::UnresolvedEnum(_)
+        | InternalFieldType::UnresolvedGroup(_)
         | InternalFieldType::Message(_)
-        | InternalFieldType::Enum(_) => Err(bad(value)),
+        | InternalFieldType::Enum(_)
+        | InternalFieldType::Group(_) => Err(bad(value)),
         InternalFieldType::Bool => bool::from_str(value)
             .map(value::Value::Bool)
             .map_err(|_| bad(value)),
@@ -808,11 +3356,63 @@ fn parse_default_value(value: &str, field_type: &InternalFieldType) -> error::Re
             .map(value::Value::U64)
             .map_err(|_| bad(value)),
         InternalFieldType::String => Ok(value::Value::String(value.to_owned())),
-        InternalFieldType::Group => unimplemented!(),
-        InternalFieldType::Bytes => Ok(value::Value::Bytes(
-            value.chars().map(|c| c as u8).collect(),
-        )),
+        InternalFieldType::Bytes => unescape_bytes(value)
+            .map(value::Value::Bytes)
+            .ok_or_else(|| bad(value)),
+    }
+}
+
+/// Reverses the C-style escaping that `protoc` applies to the default values of bytes fields.
+fn unescape_bytes(value: &str) -> Option<Vec<u8>> {
+    let mut result = Vec::new();
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            if (c as u32) > 0xff {
+                return None;
+            }
+            result.push(c as u8);
+            continue;
+        }
+        let c = chars.next()?;
+        let byte = match c {
+            'n' => b'\n',
+            'r' => b'\r',
+            't' => b'\t',
+            'a' => 0x07,
+            'b' => 0x08,
+            'f' => 0x0c,
+            'v' => 0x0b,
+            '\\' | '\'' | '"' | '?' => c as u8,
+            '0'..='7' => {
+                let mut v = c.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match chars.peek().and_then(|c| c.to_digit(8)) {
+                        Some(d) => {
+                            v = v * 8 + d;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                if v > 0xff {
+                    return None;
+                }
+                v as u8
+            }
+            'x' => {
+                let mut v = chars.next().and_then(|c| c.to_digit(16))?;
+                if let Some(d) = chars.peek().and_then(|c| c.to_digit(16)) {
+                    v = v * 16 + d;
+                    chars.next();
+                }
+                v as u8
+            }
+            _ => return None,
+        };
+        result.push(byte);
     }
+    Some(result)
 }
 
 #[cfg(test)]
@@ -1204,4 +3804,940 @@ mod test {
         "FOREIGN_BAZ",
         6
     );
+
+    #[test]
+    fn group_field() {
+        use protobuf::descriptor::FieldDescriptorProto_Type::*;
+
+        let mut group_field = descriptor::FieldDescriptorProto::new();
+        group_field.set_name("a".to_owned());
+        group_field.set_number(17);
+        group_field.set_field_type(TYPE_INT32);
+        let mut group = descriptor::DescriptorProto::new();
+        group.set_name("OptionalGroup".to_owned());
+        group.mut_field().push(group_field);
+
+        let mut field = descriptor::FieldDescriptorProto::new();
+        field.set_name("optionalgroup".to_owned());
+        field.set_number(16);
+        field.set_field_type(TYPE_GROUP);
+        field.set_type_name(".test.Groups.OptionalGroup".to_owned());
+        let mut message = descriptor::DescriptorProto::new();
+        message.set_name("Groups".to_owned());
+        message.mut_field().push(field);
+        message.mut_nested_type().push(group);
+
+        let mut d = Descriptors::new();
+        d.add_message_proto(".test", &message);
+
+        for resolve in &[false, true] {
+            if *resolve {
+                d.resolve_refs();
+            }
+            let msg = d.message_by_name(".test.Groups").unwrap();
+            let field = msg.field_by_name("optionalgroup").unwrap();
+            match field.field_type(&d) {
+                Group(m) => assert_eq!(m.name(), ".test.Groups.OptionalGroup"),
+                t => panic!("Expected a group type, got {:?}", t),
+            }
+        }
+    }
+
+    #[test]
+    fn enum_closed_by_syntax() {
+        for &(syntax, closed) in &[("", true), ("proto2", true), ("proto3", false)] {
+            let mut enum_proto = descriptor::EnumDescriptorProto::new();
+            enum_proto.set_name("E".to_owned());
+            let mut message_proto = descriptor::DescriptorProto::new();
+            message_proto.set_name("M".to_owned());
+            message_proto.mut_enum_type().push(enum_proto.clone());
+            let mut file_proto = descriptor::FileDescriptorProto::new();
+            file_proto.set_package("test".to_owned());
+            file_proto.set_syntax(syntax.to_owned());
+            file_proto.mut_enum_type().push(enum_proto);
+            file_proto.mut_message_type().push(message_proto);
+
+            let mut d = Descriptors::new();
+            d.add_file_proto(&file_proto);
+            assert_eq!(d.enum_by_name(".test.E").unwrap().is_closed(), closed);
+            assert_eq!(d.enum_by_name(".test.M.E").unwrap().is_closed(), closed);
+        }
+    }
+
+    #[test]
+    fn json_name() {
+        let d = load_descriptors();
+        let msg = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        let field = msg.field_by_name("optional_nested_message").unwrap();
+        assert_eq!(field.json_name(), "optionalNestedMessage");
+
+        let mut field =
+            FieldDescriptor::new("foo_bar_2_baz", 1, Optional, InternalFieldType::Int32, None);
+        assert_eq!(field.json_name(), "fooBar2Baz");
+        field.set_json_name("custom");
+        assert_eq!(field.json_name(), "custom");
+    }
+
+    #[test]
+    fn proto3_presence() {
+        use protobuf::Message;
+
+        let mut scalar = descriptor::FieldDescriptorProto::new();
+        scalar.set_name("scalar".to_owned());
+        scalar.set_number(1);
+        scalar.set_label(descriptor::FieldDescriptorProto_Label::LABEL_OPTIONAL);
+        scalar.set_field_type(descriptor::FieldDescriptorProto_Type::TYPE_STRING);
+        let mut optional = scalar.clone();
+        optional.set_name("optional".to_owned());
+        optional.set_number(2);
+        optional.set_oneof_index(0);
+        optional.mut_unknown_fields().add_varint(17, 1);
+        let mut message = scalar.clone();
+        message.set_name("message".to_owned());
+        message.set_number(3);
+        message.set_field_type(descriptor::FieldDescriptorProto_Type::TYPE_MESSAGE);
+        message.set_type_name(".test.M".to_owned());
+
+        let mut message_proto = descriptor::DescriptorProto::new();
+        message_proto.set_name("M".to_owned());
+        message_proto.mut_field().push(scalar);
+        message_proto.mut_field().push(optional);
+        message_proto.mut_field().push(message);
+
+        for &(syntax, expected) in &[(Syntax::Proto2, true), (Syntax::Proto3, false)] {
+            let mut file_proto = descriptor::FileDescriptorProto::new();
+            file_proto.set_package("test".to_owned());
+            if syntax == Syntax::Proto3 {
+                file_proto.set_syntax("proto3".to_owned());
+            }
+            file_proto.mut_message_type().push(message_proto.clone());
+
+            let mut d = Descriptors::new();
+            d.add_file_proto(&file_proto);
+            let msg = d.message_by_name(".test.M").unwrap();
+            assert_eq!(msg.syntax(), syntax);
+
+            let scalar = msg.field_by_name("scalar").unwrap();
+            assert_eq!(scalar.has_presence(), expected);
+            match scalar.default_value() {
+                Some(value::Value::String(v)) => assert_eq!(v, ""),
+                None => assert!(expected),
+                v => panic!("Expected a string default, got {:?}", v),
+            }
+
+            let optional = msg.field_by_name("optional").unwrap();
+            assert!(optional.has_presence());
+            assert!(optional.is_proto3_optional());
+            assert!(optional.default_value().is_none());
+
+            assert!(msg.field_by_name("message").unwrap().has_presence());
+        }
+    }
+
+    #[test]
+    fn oneof() {
+        let d = load_descriptors();
+        let msg = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        assert_eq!(msg.oneofs().len(), 1);
+
+        let oneof = msg.oneof_by_name("oneof_field").unwrap();
+        assert_eq!(oneof.name(), "oneof_field");
+        assert_eq!(oneof.json_name(), "oneofField");
+        assert!(!oneof.is_synthetic());
+        let names = msg
+            .oneof_fields(oneof)
+            .iter()
+            .map(|f| f.name())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            &[
+                "oneof_uint32",
+                "oneof_nested_message",
+                "oneof_string",
+                "oneof_bytes"
+            ]
+        );
+
+        let field = msg.field_by_name("oneof_string").unwrap();
+        assert_eq!(field.oneof_index(), Some(0));
+        assert_eq!(msg.oneof_of_field(field).unwrap().name(), "oneof_field");
+        let field = msg.field_by_name("optional_string").unwrap();
+        assert!(msg.oneof_of_field(field).is_none());
+    }
+
+    #[test]
+    fn map_entry() {
+        let mut key = descriptor::FieldDescriptorProto::new();
+        key.set_name("key".to_owned());
+        key.set_number(1);
+        key.set_label(descriptor::FieldDescriptorProto_Label::LABEL_OPTIONAL);
+        key.set_field_type(descriptor::FieldDescriptorProto_Type::TYPE_STRING);
+        let mut value = key.clone();
+        value.set_name("value".to_owned());
+        value.set_number(2);
+        value.set_field_type(descriptor::FieldDescriptorProto_Type::TYPE_INT64);
+        let mut entry = descriptor::DescriptorProto::new();
+        entry.set_name("CountsEntry".to_owned());
+        entry.mut_field().push(key);
+        entry.mut_field().push(value);
+        let mut not_entry = entry.clone();
+        not_entry.set_name("Pair".to_owned());
+        entry.mut_options().set_map_entry(true);
+
+        let mut counts = descriptor::FieldDescriptorProto::new();
+        counts.set_name("counts".to_owned());
+        counts.set_number(1);
+        counts.set_label(descriptor::FieldDescriptorProto_Label::LABEL_REPEATED);
+        counts.set_field_type(descriptor::FieldDescriptorProto_Type::TYPE_MESSAGE);
+        counts.set_type_name(".test.M.CountsEntry".to_owned());
+        let mut pairs = counts.clone();
+        pairs.set_name("pairs".to_owned());
+        pairs.set_number(2);
+        pairs.set_type_name(".test.M.Pair".to_owned());
+
+        let mut message_proto = descriptor::DescriptorProto::new();
+        message_proto.set_name("M".to_owned());
+        message_proto.mut_field().push(counts);
+        message_proto.mut_field().push(pairs);
+        message_proto.mut_nested_type().push(entry);
+        message_proto.mut_nested_type().push(not_entry);
+
+        let mut file_proto = descriptor::FileDescriptorProto::new();
+        file_proto.set_package("test".to_owned());
+        file_proto.mut_message_type().push(message_proto);
+        let mut d = Descriptors::new();
+        d.add_file_proto(&file_proto);
+
+        let msg = d.message_by_name(".test.M").unwrap();
+        assert!(!msg.is_map_entry());
+        assert!(msg.field_by_name("counts").unwrap().is_map());
+        assert!(!msg.field_by_name("pairs").unwrap().is_map());
+
+        let entry = d.message_by_name(".test.M.CountsEntry").unwrap();
+        assert!(entry.is_map_entry());
+        let value = entry.field_by_name("value").unwrap();
+        assert!(!value.has_presence());
+        match value.default_value() {
+            Some(&value::Value::I64(v)) => assert_eq!(v, 0),
+            v => panic!("Expected an i64 default, got {:?}", v),
+        }
+        assert!(!d.message_by_name(".test.M.Pair").unwrap().is_map_entry());
+    }
+
+    fn relative_descriptors() -> Descriptors {
+        let message = |name: &str, type_name: &str| {
+            let mut m = MessageDescriptor::new(name);
+            let field_type = InternalFieldType::UnresolvedMessage(type_name.to_owned());
+            m.add_field(FieldDescriptor::new("m", 1, Optional, field_type, None));
+            m
+        };
+        let mut outer = message(".pkg.Outer", "Inner");
+        let color = InternalFieldType::UnresolvedEnum("Color".to_owned());
+        outer.add_field(FieldDescriptor::new("c", 2, Optional, color, None));
+
+        let mut d = Descriptors::new();
+        d.add_message(outer);
+        d.add_message(message(".pkg.Outer.Inner", "Outer.Inner"));
+        d.add_message(message(".pkg.Other", "pkg.Outer"));
+        d.add_message(message(".Top", ".pkg.Other"));
+        d.add_enum(EnumDescriptor::new(".pkg.Color"));
+        d
+    }
+
+    fn check_relative_types(d: &Descriptors) {
+        let expected = [
+            (".pkg.Outer", ".pkg.Outer.Inner"),
+            (".pkg.Outer.Inner", ".pkg.Outer.Inner"),
+            (".pkg.Other", ".pkg.Outer"),
+            (".Top", ".pkg.Other"),
+        ];
+        for &(message, field_message) in &expected {
+            let field = d.message_by_name(message).unwrap().field_by_name("m");
+            match field.unwrap().field_type(d) {
+                Message(m) => assert_eq!(m.name(), field_message),
+                t => panic!("Expected a message type, got {:?}", t),
+            }
+        }
+        let outer = d.message_by_name(".pkg.Outer").unwrap();
+        match outer.field_by_name("c").unwrap().field_type(d) {
+            Enum(e) => assert_eq!(e.name(), ".pkg.Color"),
+            t => panic!("Expected an enum type, got {:?}", t),
+        }
+    }
+
+    #[test]
+    fn relative_type_names() {
+        let mut d = relative_descriptors();
+        check_relative_types(&d);
+        d.resolve_refs();
+        check_relative_types(&d);
+    }
+
+    #[test]
+    fn resolve_type_name() {
+        let d = relative_descriptors();
+        assert_eq!(
+            d.resolve_type_name("Color", ".pkg.Outer.Inner").unwrap(),
+            ".pkg.Color"
+        );
+        assert_eq!(d.resolve_type_name(".pkg.Outer", "").unwrap(), ".pkg.Outer");
+
+        match d.resolve_type_name("Missing", ".pkg.Outer") {
+            Err(error::Error::UnknownType { name, tried }) => {
+                assert_eq!(name, "Missing");
+                assert_eq!(
+                    tried,
+                    vec![".pkg.Outer.Missing", ".pkg.Missing", ".Missing"]
+                );
+            }
+            r => panic!("Expected an unknown type error, got {:?}", r),
+        }
+        // Once the first component names a message, the rest must be found inside it
+        match d.resolve_type_name("Inner.Color", ".pkg.Outer") {
+            Err(error::Error::UnknownType { tried, .. }) => {
+                assert_eq!(tried, vec![".pkg.Outer.Inner.Color"]);
+            }
+            r => panic!("Expected an unknown type error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn builder_valid() {
+        let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+        let proto = protobuf::parse_from_reader(&mut file).unwrap();
+        let mut builder = DescriptorsBuilder::new();
+        builder.add_file_set_proto(&proto);
+        let d = builder.build().unwrap();
+
+        assert!(d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .is_some());
+    }
+
+    #[test]
+    fn unescape_bytes_default() {
+        assert_eq!(
+            unescape_bytes(r#"\0\001\a\b\f\n\r\t\v\\\'\"\xfe"#),
+            Some(b"\0\x01\x07\x08\x0c\n\r\t\x0b\\'\"\xfe".to_vec())
+        );
+        assert_eq!(unescape_bytes(r"\q"), None);
+        assert_eq!(unescape_bytes(r"\x"), None);
+    }
+
+    #[test]
+    fn builder_problems() {
+        use protobuf::descriptor::FieldDescriptorProto_Label::*;
+        use protobuf::descriptor::FieldDescriptorProto_Type::*;
+
+        fn field(
+            name: &str,
+            number: i32,
+            field_type: descriptor::FieldDescriptorProto_Type,
+        ) -> descriptor::FieldDescriptorProto {
+            let mut field = descriptor::FieldDescriptorProto::new();
+            field.set_name(name.to_owned());
+            field.set_number(number);
+            field.set_label(LABEL_OPTIONAL);
+            field.set_field_type(field_type);
+            field
+        }
+
+        let mut a = field("a", 1, TYPE_INT32);
+        a.set_default_value("abc".to_owned());
+        let mut e = field("e", 3, TYPE_ENUM);
+        e.set_type_name(".test.E".to_owned());
+        e.set_default_value("NOPE".to_owned());
+        let mut m = field("m", 19500, TYPE_MESSAGE);
+        m.set_type_name("Missing".to_owned());
+
+        let mut message_proto = descriptor::DescriptorProto::new();
+        message_proto.set_name("M".to_owned());
+        message_proto.mut_field().push(a);
+        message_proto.mut_field().push(field("b", 1, TYPE_INT32));
+        message_proto.mut_field().push(field("a", 2, TYPE_STRING));
+        message_proto.mut_field().push(e);
+        message_proto.mut_field().push(m);
+
+        let mut duplicate_proto = descriptor::DescriptorProto::new();
+        duplicate_proto.set_name("M".to_owned());
+
+        let mut enum_value_proto = descriptor::EnumValueDescriptorProto::new();
+        enum_value_proto.set_name("X".to_owned());
+        let mut enum_proto = descriptor::EnumDescriptorProto::new();
+        enum_proto.set_name("E".to_owned());
+        enum_proto.mut_value().push(enum_value_proto);
+
+        let mut file_proto = descriptor::FileDescriptorProto::new();
+        file_proto.set_package("test".to_owned());
+        file_proto.mut_message_type().push(message_proto);
+        file_proto.mut_message_type().push(duplicate_proto);
+        file_proto.mut_enum_type().push(enum_proto);
+
+        let mut builder = DescriptorsBuilder::new();
+        builder.add_file_proto(&file_proto);
+        builder.add_enum(EnumDescriptor::new(".test.E"));
+
+        let m = ".test.M".to_owned();
+        let expected = vec![
+            Problem::BadDefaultValue {
+                message: m.clone(),
+                field: "a".to_owned(),
+                default_value: "abc".to_owned(),
+            },
+            Problem::DuplicateType { name: m.clone() },
+            Problem::DuplicateType {
+                name: ".test.E".to_owned(),
+            },
+            Problem::DuplicateFieldNumber {
+                message: m.clone(),
+                number: 1,
+            },
+            Problem::DuplicateFieldName {
+                message: m.clone(),
+                field: "a".to_owned(),
+            },
+            Problem::ReservedFieldNumber {
+                message: m.clone(),
+                field: "m".to_owned(),
+                number: 19500,
+            },
+            Problem::UnknownType {
+                message: m.clone(),
+                field: "m".to_owned(),
+                type_name: "Missing".to_owned(),
+                tried: vec![
+                    ".test.M.Missing".to_owned(),
+                    ".test.Missing".to_owned(),
+                    ".Missing".to_owned(),
+                ],
+            },
+            Problem::BadDefaultValue {
+                message: m.clone(),
+                field: "e".to_owned(),
+                default_value: "NOPE".to_owned(),
+            },
+        ];
+        match builder.build() {
+            Err(error::Error::InvalidDescriptors { problems, .. }) => {
+                assert_eq!(problems, expected)
+            }
+            r => panic!("Expected invalid descriptors, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn builder_extension_problems() {
+        use protobuf::descriptor::FieldDescriptorProto_Label::*;
+        use protobuf::descriptor::FieldDescriptorProto_Type::*;
+
+        fn extension(name: &str, extendee: &str, number: i32) -> descriptor::FieldDescriptorProto {
+            let mut field = descriptor::FieldDescriptorProto::new();
+            field.set_name(name.to_owned());
+            field.set_extendee(extendee.to_owned());
+            field.set_number(number);
+            field.set_label(LABEL_OPTIONAL);
+            field.set_field_type(TYPE_INT32);
+            field
+        }
+
+        let mut field = extension("a", "", 1);
+        field.clear_extendee();
+        let mut range = descriptor::DescriptorProto_ExtensionRange::new();
+        range.set_start(100);
+        range.set_end(200);
+        let mut message_proto = descriptor::DescriptorProto::new();
+        message_proto.set_name("M".to_owned());
+        message_proto.mut_field().push(field);
+        message_proto.mut_extension_range().push(range);
+
+        let mut missing = extension("x4", ".test.M", 100);
+        missing.set_field_type(TYPE_MESSAGE);
+        missing.set_type_name("Missing".to_owned());
+
+        let mut file_proto = descriptor::FileDescriptorProto::new();
+        file_proto.set_package("test".to_owned());
+        file_proto.mut_message_type().push(message_proto);
+        file_proto
+            .mut_extension()
+            .push(extension("x1", ".test.M", 1));
+        file_proto
+            .mut_extension()
+            .push(extension("x2", ".test.M", 50));
+        file_proto
+            .mut_extension()
+            .push(extension("x3", ".test.Nope", 100));
+        file_proto.mut_extension().push(missing);
+        file_proto
+            .mut_extension()
+            .push(extension("x5", ".test.M", 100));
+        file_proto.mut_extension().push(extension("x6", "M", 100));
+        file_proto.mut_extension().push(extension("x7", "M", 101));
+
+        let mut builder = DescriptorsBuilder::new();
+        builder.add_file_proto(&file_proto);
+
+        let m = ".test.M".to_owned();
+        let expected = vec![
+            Problem::DuplicateFieldNumber {
+                message: m.clone(),
+                number: 1,
+            },
+            Problem::ExtensionOutOfRange {
+                extension: ".test.x2".to_owned(),
+                extendee: m.clone(),
+                number: 50,
+            },
+            Problem::UnknownExtendee {
+                extension: ".test.x3".to_owned(),
+                extendee: ".test.Nope".to_owned(),
+            },
+            Problem::UnknownType {
+                message: m.clone(),
+                field: ".test.x4".to_owned(),
+                type_name: "Missing".to_owned(),
+                tried: vec![".test.Missing".to_owned(), ".Missing".to_owned()],
+            },
+            Problem::DuplicateExtensionNumber {
+                extendee: m.clone(),
+                first: ".test.x4".to_owned(),
+                second: ".test.x5".to_owned(),
+                number: 100,
+            },
+            Problem::DuplicateExtensionNumber {
+                extendee: m.clone(),
+                first: ".test.x4".to_owned(),
+                second: ".test.x6".to_owned(),
+                number: 100,
+            },
+        ];
+        match builder.build() {
+            Err(error::Error::InvalidDescriptors { problems, .. }) => {
+                assert_eq!(problems, expected)
+            }
+            r => panic!("Expected invalid descriptors, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn builder_relative_extendee() {
+        use protobuf::descriptor::FieldDescriptorProto_Label::*;
+        use protobuf::descriptor::FieldDescriptorProto_Type::*;
+
+        let mut range = descriptor::DescriptorProto_ExtensionRange::new();
+        range.set_start(100);
+        range.set_end(200);
+        let mut message_proto = descriptor::DescriptorProto::new();
+        message_proto.set_name("M".to_owned());
+        message_proto.mut_extension_range().push(range);
+
+        let mut extension = descriptor::FieldDescriptorProto::new();
+        extension.set_name("x".to_owned());
+        extension.set_extendee("M".to_owned());
+        extension.set_number(100);
+        extension.set_label(LABEL_OPTIONAL);
+        extension.set_field_type(TYPE_INT32);
+
+        let mut file_proto = descriptor::FileDescriptorProto::new();
+        file_proto.set_package("test".to_owned());
+        file_proto.mut_message_type().push(message_proto);
+        file_proto.mut_extension().push(extension);
+
+        let mut builder = DescriptorsBuilder::new();
+        builder.add_file_proto(&file_proto);
+        let d = builder.build().unwrap();
+
+        let extension = d.extension_by_number(".test.M", 100).unwrap();
+        assert_eq!(Some(".test.M"), extension.extendee());
+        assert!(d.extension_by_number("M", 100).is_none());
+        let message = d.message_by_name(".test.M").unwrap();
+        assert_eq!(100, d.extension_of(message, "test.x").unwrap().number());
+    }
+
+    #[test]
+    fn services() {
+        let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+        let proto = protobuf::parse_from_reader(&mut file).unwrap();
+        let mut d = Descriptors::from_proto(&proto);
+        d.resolve_refs();
+
+        let service = d.service_by_name(".protobuf_unittest.TestService").unwrap();
+        let names = service
+            .methods()
+            .iter()
+            .map(|m| m.name())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["Foo", "Bar"], names);
+
+        let method = d
+            .method_by_path("/protobuf_unittest.TestService/Bar")
+            .unwrap();
+        assert_eq!("Bar", method.name());
+        assert_eq!(
+            ".protobuf_unittest.BarRequest",
+            method.input_type(&d).unwrap().name()
+        );
+        assert_eq!(
+            ".protobuf_unittest.BarResponse",
+            method.output_type(&d).unwrap().name()
+        );
+        assert!(!method.is_client_streaming());
+        assert!(!method.is_server_streaming());
+
+        assert!(d
+            .method_by_path("/protobuf_unittest.TestService/Baz")
+            .is_none());
+        assert!(d.method_by_path("/protobuf_unittest.TestService").is_none());
+        assert!(d
+            .method_by_path("/protobuf_unittest.NoService/Foo")
+            .is_none());
+    }
+
+    #[test]
+    fn services_manual() {
+        let mut method = MethodDescriptor::new("Chat", "Message", ".test.Message");
+        method.set_client_streaming(true);
+        method.set_server_streaming(true);
+        let mut service = ServiceDescriptor::new(".test.Chatter");
+        service.add_method(method);
+        service.add_method(MethodDescriptor::new("Lost", "Missing", ".test.Message"));
+
+        let mut builder = DescriptorsBuilder::new();
+        builder.add_message(MessageDescriptor::new(".test.Message"));
+        builder.add_service(service);
+        let problems = match builder.build() {
+            Err(error::Error::InvalidDescriptors { problems, .. }) => problems,
+            r => panic!("Expected invalid descriptors, got {:?}", r),
+        };
+        assert_eq!(
+            vec![Problem::UnknownMethodType {
+                service: ".test.Chatter".to_owned(),
+                method: "Lost".to_owned(),
+                type_name: "Missing".to_owned(),
+                tried: vec![
+                    ".test.Chatter.Missing".to_owned(),
+                    ".test.Missing".to_owned(),
+                    ".Missing".to_owned(),
+                ],
+            }],
+            problems
+        );
+
+        let mut d = Descriptors::new();
+        d.add_message(MessageDescriptor::new(".test.Message"));
+        let mut service = ServiceDescriptor::new(".test.Chatter");
+        let mut method = MethodDescriptor::new("Chat", "Message", ".test.Message");
+        method.set_client_streaming(true);
+        service.add_method(method);
+        d.add_service(service);
+
+        let method = d.method_by_path("/test.Chatter/Chat").unwrap();
+        assert_eq!(".test.Message", method.input_type(&d).unwrap().name());
+        assert!(method.is_client_streaming());
+        assert!(!method.is_server_streaming());
+    }
+
+    #[test]
+    fn files() {
+        let d = load_descriptors();
+        let names = d.files().iter().map(|f| f.name()).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "google/protobuf/unittest_import_public.proto",
+                "google/protobuf/unittest_import.proto",
+                "google/protobuf/unittest.proto",
+            ],
+            names
+        );
+
+        let file = d
+            .file_by_name("google/protobuf/unittest_import.proto")
+            .unwrap();
+        assert_eq!("protobuf_unittest_import", file.package());
+        assert_eq!(Syntax::Proto2, file.syntax());
+        assert_eq!(
+            &["google/protobuf/unittest_import_public.proto".to_owned()],
+            file.dependencies()
+        );
+        assert_eq!(file.dependencies(), file.public_dependencies());
+        assert!(file
+            .enums(&d)
+            .any(|e| e.name() == ".protobuf_unittest_import.ImportEnum"));
+
+        let msg = d
+            .message_by_name(".protobuf_unittest.TestAllTypes.NestedMessage")
+            .unwrap();
+        assert_eq!(Some("google/protobuf/unittest.proto"), msg.file_name());
+        let file = msg.file(&d).unwrap();
+        assert!(file.messages(&d).any(|m| m.name() == msg.name()));
+        assert!(file
+            .messages(&d)
+            .all(|m| m.name().starts_with(".protobuf_unittest.")));
+        let deps = d.transitive_dependencies(file).unwrap();
+        let names = deps.iter().map(|f| f.name()).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "google/protobuf/unittest_import_public.proto",
+                "google/protobuf/unittest_import.proto",
+            ],
+            names
+        );
+        assert!(MessageDescriptor::new(".test.M").file(&d).is_none());
+    }
+
+    #[test]
+    fn import_order() {
+        let mut c = FileDescriptor::new("c.proto");
+        c.add_dependency("b.proto");
+        c.add_public_dependency("a.proto");
+        let mut b = FileDescriptor::new("b.proto");
+        b.add_dependency("a.proto");
+        b.add_dependency("missing.proto");
+        let mut d = Descriptors::new();
+        d.add_file(c);
+        d.add_file(b);
+        d.add_file(FileDescriptor::new("a.proto"));
+
+        let order = d.files_in_import_order().unwrap();
+        let names = order.iter().map(|f| f.name()).collect::<Vec<_>>();
+        assert_eq!(vec!["a.proto", "b.proto", "c.proto"], names);
+
+        let mut builder = DescriptorsBuilder::new();
+        let mut a = FileDescriptor::new("a.proto");
+        a.add_dependency("b.proto");
+        let mut b = FileDescriptor::new("b.proto");
+        b.add_dependency("c.proto");
+        let mut c = FileDescriptor::new("c.proto");
+        c.add_dependency("b.proto");
+        c.add_dependency("missing.proto");
+        builder.add_file(a);
+        builder.add_file(b);
+        builder.add_file(c);
+
+        match builder.descriptors.files_in_import_order() {
+            Err(error::Error::ImportCycle { files }) => {
+                assert_eq!(vec!["b.proto", "c.proto", "b.proto"], files)
+            }
+            r => panic!("Expected an import cycle, got {:?}", r),
+        }
+        let problems = match builder.build() {
+            Err(error::Error::InvalidDescriptors { problems, .. }) => problems,
+            r => panic!("Expected invalid descriptors, got {:?}", r),
+        };
+        assert_eq!(
+            vec![
+                Problem::UnknownDependency {
+                    file: "c.proto".to_owned(),
+                    dependency: "missing.proto".to_owned(),
+                },
+                Problem::ImportCycle {
+                    files: vec![
+                        "b.proto".to_owned(),
+                        "c.proto".to_owned(),
+                        "b.proto".to_owned(),
+                    ],
+                },
+            ],
+            problems
+        );
+    }
+
+    #[test]
+    fn source_code_info() {
+        use protobuf::descriptor::FieldDescriptorProto_Type::*;
+
+        let mut value_proto = descriptor::EnumValueDescriptorProto::new();
+        value_proto.set_name("A".to_owned());
+        let mut enum_proto = descriptor::EnumDescriptorProto::new();
+        enum_proto.set_name("E".to_owned());
+        enum_proto.mut_value().push(value_proto);
+        let mut field_proto = descriptor::FieldDescriptorProto::new();
+        field_proto.set_name("f".to_owned());
+        field_proto.set_number(1);
+        field_proto.set_field_type(TYPE_INT32);
+        let mut message_proto = descriptor::DescriptorProto::new();
+        message_proto.set_name("M".to_owned());
+        message_proto.mut_field().push(field_proto);
+        message_proto.mut_enum_type().push(enum_proto);
+        let mut extension_proto = descriptor::FieldDescriptorProto::new();
+        extension_proto.set_name("ext".to_owned());
+        extension_proto.set_number(100);
+        extension_proto.set_field_type(TYPE_INT32);
+        extension_proto.set_extendee(".test.M".to_owned());
+        let mut method_proto = descriptor::MethodDescriptorProto::new();
+        method_proto.set_name("Call".to_owned());
+        method_proto.set_input_type(".test.M".to_owned());
+        method_proto.set_output_type(".test.M".to_owned());
+        let mut service_proto = descriptor::ServiceDescriptorProto::new();
+        service_proto.set_name("S".to_owned());
+        service_proto.mut_method().push(method_proto);
+
+        let mut file_proto = descriptor::FileDescriptorProto::new();
+        file_proto.set_name("test.proto".to_owned());
+        file_proto.set_package("test".to_owned());
+        file_proto.mut_message_type().push(message_proto);
+        file_proto.mut_extension().push(extension_proto);
+        file_proto.mut_service().push(service_proto);
+
+        let info = file_proto.mut_source_code_info();
+        for &(path, span, comment) in &[
+            (&[4, 0][..], &[2, 0, 5, 1][..], "A message."),
+            (&[4, 0, 2, 0], &[3, 2, 14], "A field."),
+            (&[4, 0, 4, 0], &[4, 2, 20], "An enum."),
+            (&[4, 0, 4, 0, 2, 0], &[4, 11, 17], "A value."),
+            (&[7, 0], &[6, 0, 30], "An extension."),
+            (&[6, 0], &[7, 0, 9, 1], "A service."),
+            (&[6, 0, 2, 0], &[8, 2, 24], "A method."),
+        ] {
+            let mut location = descriptor::SourceCodeInfo_Location::new();
+            location.set_path(path.to_vec());
+            location.set_span(span.to_vec());
+            location.set_leading_comments(comment.to_owned());
+            info.mut_location().push(location);
+        }
+        // Only the first location of a path is used
+        let mut location = descriptor::SourceCodeInfo_Location::new();
+        location.set_path(vec![4, 0]);
+        location.set_span(vec![10, 0, 1]);
+        info.mut_location().push(location);
+        let location = &mut info.mut_location()[0];
+        location.set_trailing_comments(" Trailing.".to_owned());
+        location
+            .mut_leading_detached_comments()
+            .push(" Detached.".to_owned());
+
+        let mut d = Descriptors::new();
+        d.add_file_proto(&file_proto);
+
+        let msg = d.message_by_name(".test.M").unwrap();
+        let location = msg.source_location().unwrap();
+        assert_eq!(
+            (2, 0, 5, 1),
+            (
+                location.start_line(),
+                location.start_column(),
+                location.end_line(),
+                location.end_column()
+            )
+        );
+        assert_eq!(Some("A message."), location.leading_comments());
+        assert_eq!(Some(" Trailing."), location.trailing_comments());
+        assert_eq!(
+            &[" Detached.".to_owned()],
+            location.leading_detached_comments()
+        );
+
+        let location = msg.field_by_name("f").unwrap().source_location().unwrap();
+        assert_eq!((3, 3), (location.start_line(), location.end_line()));
+        assert_eq!(Some("A field."), location.leading_comments());
+        assert_eq!(None, location.trailing_comments());
+
+        let e = d.enum_by_name(".test.M.E").unwrap();
+        assert_eq!(
+            Some("An enum."),
+            e.source_location().unwrap().leading_comments()
+        );
+        let location = e.value_by_name("A").unwrap().source_location().unwrap();
+        assert_eq!(
+            SourceLocation {
+                leading_comments: Some("A value.".to_owned()),
+                ..SourceLocation::new(4, 11, 4, 17)
+            },
+            *location
+        );
+
+        let ext = d.extension_by_name(".test.ext").unwrap();
+        assert_eq!(
+            Some("An extension."),
+            ext.source_location().unwrap().leading_comments()
+        );
+        let service = d.service_by_name(".test.S").unwrap();
+        assert_eq!(
+            Some("A service."),
+            service.source_location().unwrap().leading_comments()
+        );
+        let method = d.method_by_path("/test.S/Call").unwrap();
+        assert_eq!(
+            Some("A method."),
+            method.source_location().unwrap().leading_comments()
+        );
+
+        let d = load_descriptors();
+        let msg = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        assert!(msg.source_location().is_none());
+    }
+
+    #[test]
+    fn standard_options() {
+        let d = load_descriptors();
+        let msg = d
+            .message_by_name(".protobuf_unittest.TestDeprecatedFields")
+            .unwrap();
+        assert!(!msg.is_deprecated());
+        assert!(msg
+            .field_by_name("deprecated_int32")
+            .unwrap()
+            .is_deprecated());
+
+        let msg = d
+            .message_by_name(".protobuf_unittest.TestPackedTypes")
+            .unwrap();
+        assert!(msg.field_by_name("packed_int32").unwrap().is_packed());
+        let msg = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        assert!(!msg.field_by_name("repeated_int32").unwrap().is_packed());
+
+        let e = d
+            .enum_by_name(".protobuf_unittest.TestEnumWithDupValue")
+            .unwrap();
+        assert!(e.allows_alias());
+        assert!(!d
+            .enum_by_name(".protobuf_unittest.ForeignEnum")
+            .unwrap()
+            .allows_alias());
+    }
+
+    #[test]
+    fn packed_by_syntax() {
+        use protobuf::descriptor::FieldDescriptorProto_Label::*;
+        use protobuf::descriptor::FieldDescriptorProto_Type::*;
+
+        for &(syntax, packed) in &[("proto2", false), ("proto3", true)] {
+            let mut message_proto = descriptor::DescriptorProto::new();
+            message_proto.set_name("M".to_owned());
+            for &(name, number, field_type, explicit) in &[
+                ("ints", 1, TYPE_INT32, None),
+                ("strings", 2, TYPE_STRING, None),
+                ("unpacked", 3, TYPE_INT32, Some(false)),
+            ] {
+                let mut field = descriptor::FieldDescriptorProto::new();
+                field.set_name(name.to_owned());
+                field.set_number(number);
+                field.set_label(LABEL_REPEATED);
+                field.set_field_type(field_type);
+                if let Some(explicit) = explicit {
+                    field.mut_options().set_packed(explicit);
+                }
+                message_proto.mut_field().push(field);
+            }
+            let mut file_proto = descriptor::FileDescriptorProto::new();
+            file_proto.set_package("test".to_owned());
+            file_proto.set_syntax(syntax.to_owned());
+            file_proto.mut_message_type().push(message_proto);
+
+            let mut d = Descriptors::new();
+            d.add_file_proto(&file_proto);
+            let msg = d.message_by_name(".test.M").unwrap();
+            assert_eq!(packed, msg.field_by_name("ints").unwrap().is_packed());
+            assert!(!msg.field_by_name("strings").unwrap().is_packed());
+            assert!(!msg.field_by_name("unpacked").unwrap().is_packed());
+        }
+    }
 }
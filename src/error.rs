@@ -7,6 +7,8 @@ use protobuf;
 use protobuf::stream::wire_format;
 use serde;
 
+use crate::descriptor;
+
 /// A result whose error type is `Error`.
 pub type Result<A> = result::Result<A, Error>;
 
@@ -37,12 +39,87 @@ pub enum Error {
         /// The name of the message.
         name: String,
     },
+    /// A type name that doesn't refer to any known message or enum was encountered.
+    #[fail(display = "unknown type: {} (tried {:?})", name, tried)]
+    UnknownType {
+        /// The type name as it was written.
+        name: String,
+        /// The fully qualified names that were tried, in order.
+        tried: Vec<String>,
+    },
     /// An unexpected wire type was received.
     #[fail(display = "bad wire type: {:?}", wire_type)]
     BadWireType {
         /// The encountered wire type.
         wire_type: wire_format::WireType,
     },
+    /// An unknown message field was encountered.
+    #[fail(display = "unknown field: {}", name)]
+    UnknownField {
+        /// The name (or number) of the field.
+        name: String,
+    },
+    /// A value that doesn't fit the type of a message field was received.
+    #[fail(display = "bad value for field: {}", field)]
+    BadFieldValue {
+        /// The name of the field.
+        field: String,
+    },
+    /// Something other than a message was received where a message was expected.
+    #[fail(display = "expected a message of type: {}", name)]
+    ExpectedMessage {
+        /// The name of the message.
+        name: String,
+    },
+    /// A well-known type holds a value that can't be represented, such as an out of range
+    /// timestamp.
+    #[fail(display = "bad value for well-known type: {}", name)]
+    BadWellKnownValue {
+        /// The name of the well-known type.
+        name: String,
+    },
+    /// A JSON error.
+    #[cfg(feature = "json")]
+    #[fail(display = "JSON error")]
+    Json(#[cause] serde_json::Error),
+    /// Text based input that can't be parsed was received.
+    #[fail(display = "{}:{}: {}", line, column, message)]
+    Syntax {
+        /// The line of the input where the problem was found, starting at 1.
+        line: usize,
+        /// The column of the input where the problem was found, in characters starting at 1.
+        column: usize,
+        /// A description of the problem.
+        message: String,
+    },
+    /// A `.proto` schema file that can't be parsed or resolved was received.
+    #[fail(display = "{}:{}:{}: {}", file, line, column, message)]
+    BadProtoFile {
+        /// The name of the file, as it was imported.
+        file: String,
+        /// The line of the file where the problem was found, starting at 1, or 0 if the problem
+        /// isn't tied to a location.
+        line: usize,
+        /// The column of the file where the problem was found, in characters starting at 1, or 0
+        /// if the problem isn't tied to a location.
+        column: usize,
+        /// A description of the problem.
+        message: String,
+    },
+    /// A descriptor set that failed validation was received.
+    #[fail(display = "invalid descriptors: {}", summary)]
+    InvalidDescriptors {
+        /// A description of all problems.
+        summary: String,
+        /// The problems that were found.
+        problems: Vec<descriptor::Problem>,
+    },
+    /// Files that import each other were encountered.
+    #[fail(display = "import cycle: {:?}", files)]
+    ImportCycle {
+        /// The names of the files in the cycle, starting and ending with the same file.
+        files: Vec<String>,
+    },
     /// A default value that can't be parsed was received.
     #[fail(display = "bad default value: {:?}", default_value)]
     BadDefaultValue {
@@ -70,6 +147,13 @@ impl From<protobuf::error::ProtobufError> for Error {
     }
 }
 
+#[cfg(feature = "json")]
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
 impl CompatError {
     /// Converts this compatibility error into the underlying error.
     pub fn into_error(self) -> Error {
@@ -85,6 +169,12 @@ impl fmt::Display for CompatError {
 
 impl error::Error for CompatError {}
 
+impl From<protobuf::error::ProtobufError> for CompatError {
+    fn from(e: protobuf::error::ProtobufError) -> Self {
+        Error::from(e).into()
+    }
+}
+
 impl From<Error> for CompatError {
     fn from(e: Error) -> Self {
         use failure::Fail;
@@ -106,3 +196,18 @@ impl serde::de::Error for CompatError {
         )
     }
 }
+
+impl serde::ser::Error for CompatError {
+    fn custom<T>(msg: T) -> CompatError
+    where
+        T: fmt::Display,
+    {
+        use failure::Fail;
+        CompatError(
+            Error::Custom {
+                message: msg.to_string(),
+            }
+            .compat(),
+        )
+    }
+}
@@ -0,0 +1,1047 @@
+//! Conversion between binary protocol buffer encoded data and the [proto3 JSON format][1].
+//!
+//! Like deserialization, all conversions require a previously loaded set of schema descriptors;
+//! see the [`descriptor`](../descriptor/index.html) module for more information.  Messages are
+//! converted to and from `serde_json::Value`s, or directly to and from JSON text.
+//!
+//! ```
+//! extern crate protobuf;
+//! extern crate serde_protobuf;
+//!
+//! use std::fs;
+//! use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::json;
+//!
+//! # use std::io;
+//! # #[derive(Debug)] struct Error;
+//! # impl From<protobuf::ProtobufError> for Error {
+//! #   fn from(a: protobuf::ProtobufError) -> Error {
+//! #     Error
+//! #   }
+//! # }
+//! # impl From<io::Error> for Error {
+//! #   fn from(a: io::Error) -> Error {
+//! #     Error
+//! #   }
+//! # }
+//! # impl From<serde_protobuf::error::Error> for Error {
+//! #   fn from(a: serde_protobuf::error::Error) -> Error {
+//! #     Error
+//! #   }
+//! # }
+//! # fn foo() -> Result<(), Error> {
+//! // Load a descriptor registry (see descriptor module)
+//! let mut file = fs::File::open("testdata/descriptors.pb")?;
+//! let proto = protobuf::parse_from_reader(&mut file)?;
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let message = descriptors.message_by_name(".protobuf_unittest.TestAllTypes").unwrap();
+//!
+//! // Binary to JSON
+//! let options = json::Options::default();
+//! let text = json::to_string(&descriptors, message, &[8, 42], options)?;
+//! assert_eq!(text, r#"{"optionalInt32":42}"#);
+//!
+//! // JSON to binary
+//! let data = json::from_str(&descriptors, message, r#"{"optional_int32": "42"}"#, options)?;
+//! assert_eq!(data, vec![8, 42]);
+//! # Ok(())
+//! # }
+//! # fn main() {
+//! #   foo().unwrap();
+//! # }
+//! ```
+//!
+//! ## Mapping
+//!
+//! Messages are printed as JSON objects keyed by the JSON names of their fields.  When parsing,
+//! both the JSON name and the name declared in the `.proto` file are accepted, and `null` leaves a
+//! field unset.
+//!
+//!   * 64-bit integers are printed as strings, since many JSON implementations can't represent
+//!     them exactly.  Integers of any width are parsed from numbers as well as strings.
+//!   * `float` and `double` values are printed as numbers, except for the strings `"NaN"`,
+//!     `"Infinity"` and `"-Infinity"`.
+//!   * `bytes` values are printed in standard base64 with padding.  Both the standard and the URL
+//!     safe alphabet are accepted when parsing, with or without padding.
+//!   * Enum values are printed by name, or as a number if the value isn't declared by the enum.
+//!     Names as well as numbers are accepted when parsing.
+//!   * Map fields are printed as objects, with the map keys converted to strings.
+//!   * Fields with implicit presence that hold the zero value of their type are omitted, as are
+//!     empty repeated and map fields, unless enabled with `Options::with_emit_defaults`.
+//!   * Extensions that are registered for a message are keyed by their fully qualified name in
+//!     brackets, e.g. `"[foo.package.extension]"`.
+//!
+//! ## Well-known types
+//!
+//! The `google.protobuf` well-known types use the special representation of the proto3 JSON
+//! mapping.  Timestamps are RFC 3339 strings like `"1972-01-01T10:00:20.021Z"`, durations are
+//! strings like `"1.500s"`, field masks are strings of comma separated lowerCamelCase paths,
+//! wrappers are the plain wrapped value, and `Struct`, `Value` and `ListValue` are arbitrary JSON
+//! objects, values and arrays respectively.
+//!
+//! A `google.protobuf.Any` is an object with an `@type` key holding the type URL, next to the
+//! fields of the payload message, or next to a `value` key if the payload is itself a well-known
+//! type.  Type URLs are looked up with `Descriptors::message_by_type_url`, and converting an `Any`
+//! whose type can't be resolved fails with `Error::UnknownMessage`.
+//!
+//! [1]: https://developers.google.com/protocol-buffers/docs/proto3#json
+use std::collections;
+use std::convert::TryFrom;
+use std::f32;
+use std::f64;
+
+use protobuf;
+use serde_json;
+
+use crate::descriptor;
+use crate::error;
+use crate::value;
+use crate::well_known::{self, WellKnownType};
+
+type Json = serde_json::Value;
+
+/// Options that control how messages are converted to and from JSON.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    emit_defaults: bool,
+    proto_field_names: bool,
+    ignore_unknown_fields: bool,
+}
+
+impl Options {
+    /// Print fields with implicit presence even if they hold the zero value of their type, as
+    /// well as empty repeated and map fields.
+    ///
+    /// Fields that track presence are still only printed when they are set.
+    pub fn with_emit_defaults(mut self, emit_defaults: bool) -> Options {
+        self.emit_defaults = emit_defaults;
+        self
+    }
+
+    /// Whether fields holding default values are printed.
+    pub fn emit_defaults(self) -> bool {
+        self.emit_defaults
+    }
+
+    /// Key fields by the name declared in the `.proto` file, e.g. `optional_int32`, instead of
+    /// their JSON name, e.g. `optionalInt32`, when printing.
+    pub fn with_proto_field_names(mut self, proto_field_names: bool) -> Options {
+        self.proto_field_names = proto_field_names;
+        self
+    }
+
+    /// Whether fields are keyed by their `.proto` name when printing.
+    pub fn proto_field_names(self) -> bool {
+        self.proto_field_names
+    }
+
+    /// Skip object keys that don't name a field, and enum names that aren't declared by the enum
+    /// type of their field, when parsing.
+    ///
+    /// By default, such keys and names fail with an `Error::UnknownField` or an
+    /// `Error::BadFieldValue` error respectively.
+    pub fn with_ignore_unknown_fields(mut self, ignore_unknown_fields: bool) -> Options {
+        self.ignore_unknown_fields = ignore_unknown_fields;
+        self
+    }
+
+    /// Whether unknown keys and enum names are skipped when parsing.
+    pub fn ignore_unknown_fields(self) -> bool {
+        self.ignore_unknown_fields
+    }
+}
+
+/// Reads a message of the specified type from the input stream and converts it to JSON.
+pub fn to_value(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    input: &mut protobuf::CodedInputStream,
+    options: Options,
+) -> error::Result<Json> {
+    let mut message = value::Message::empty();
+    message.merge_present_from(descriptors, descriptor, input)?;
+    message_to_value(descriptors, descriptor, &message, options)
+}
+
+/// Converts a binary encoded message of the specified type to JSON text.
+pub fn to_string(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    bytes: &[u8],
+    options: Options,
+) -> error::Result<String> {
+    let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+    let json = to_value(descriptors, descriptor, &mut input, options)?;
+    Ok(serde_json::to_string(&json)?)
+}
+
+/// Converts a decoded message of the specified type to JSON.
+///
+/// Fields that track presence are printed whenever the message holds a value for them, so a
+/// message created with `Message::new` prints all of its fields with default values.
+pub fn message_to_value(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    message: &value::Message,
+    options: Options,
+) -> error::Result<Json> {
+    if let Some(well_known_type) = WellKnownType::of(descriptor) {
+        return print_well_known(descriptors, descriptor, well_known_type, message, options);
+    }
+
+    let mut object = serde_json::Map::new();
+    for field in descriptor.fields() {
+        let value = match message.fields.get(&field.number()) {
+            Some(value) => print_field(descriptors, field, value, options)?,
+            None => None,
+        };
+        let value = match value {
+            Some(value) => value,
+            None if options.emit_defaults && !field.has_presence() => {
+                default_json(descriptors, field, options)?
+            }
+            None => continue,
+        };
+        let key = if options.proto_field_names {
+            field.name()
+        } else {
+            field.json_name()
+        };
+        object.insert(key.to_owned(), value);
+    }
+    for (&number, value) in &message.fields {
+        if descriptor.field_by_number(number).is_some() {
+            continue;
+        }
+        if let Some(extension) = descriptors.extension_by_number(descriptor.name(), number) {
+            if let Some(value) = print_field(descriptors, extension, value, options)? {
+                object.insert(format!("[{}]", &extension.full_name()[1..]), value);
+            }
+        }
+    }
+    Ok(Json::Object(object))
+}
+
+/// Converts JSON to a message of the specified type, and writes it to the output stream.
+pub fn from_value(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    json: &Json,
+    output: &mut protobuf::CodedOutputStream,
+    options: Options,
+) -> error::Result<()> {
+    let message = message_from_value(descriptors, descriptor, json, options)?;
+    message.write_to(descriptors, descriptor, output)
+}
+
+/// Converts JSON text to a binary encoded message of the specified type.
+pub fn from_str(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    s: &str,
+    options: Options,
+) -> error::Result<Vec<u8>> {
+    let json = serde_json::from_str(s)?;
+    let mut bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+        from_value(descriptors, descriptor, &json, &mut output, options)?;
+        output.flush()?;
+    }
+    Ok(bytes)
+}
+
+/// Converts JSON to a decoded message of the specified type.
+///
+/// The message only holds the fields that were present in the JSON, and fields with implicit
+/// presence are left unset when they hold the zero value of their type.
+pub fn message_from_value(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    json: &Json,
+    options: Options,
+) -> error::Result<value::Message> {
+    if let Some(well_known_type) = WellKnownType::of(descriptor) {
+        return parse_well_known(descriptors, descriptor, well_known_type, json, options);
+    }
+
+    let object = match json {
+        Json::Object(object) => object,
+        _ => return Err(expected_message(descriptor)),
+    };
+
+    let mut message = value::Message::empty();
+    let mut oneofs = collections::BTreeSet::new();
+    for (key, json) in object {
+        let field = match key.strip_prefix('[').and_then(|k| k.strip_suffix(']')) {
+            Some(name) => descriptors.extension_of(descriptor, name),
+            None => descriptor
+                .fields()
+                .iter()
+                .find(|f| f.json_name() == key || f.name() == key),
+        };
+        let field = match field {
+            Some(field) => field,
+            None if options.ignore_unknown_fields => continue,
+            None => return Err(error::Error::UnknownField { name: key.clone() }),
+        };
+        if message.fields.contains_key(&field.number()) {
+            // Both the JSON name and the proto name of the field were used
+            return Err(bad_field(field));
+        }
+        if json.is_null() && !accepts_null(descriptors, field) {
+            continue;
+        }
+        if let Some(oneof_index) = field.oneof_index() {
+            if !oneofs.insert(oneof_index) {
+                return Err(bad_field(field));
+            }
+        }
+        if let Some(value) = parse_field(descriptors, field, json, options)? {
+            message.fields.insert(field.number(), value);
+        }
+    }
+    Ok(message)
+}
+
+fn print_field(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    value: &value::Field,
+    options: Options,
+) -> error::Result<Option<Json>> {
+    match value {
+        value::Field::Singular(None) => Ok(None),
+        value::Field::Singular(Some(v)) => {
+            if !field.has_presence() && !options.emit_defaults && v.is_zero() {
+                Ok(None)
+            } else {
+                print_value(descriptors, field, v, options).map(Some)
+            }
+        }
+        value::Field::Repeated(vs) => {
+            if vs.is_empty() && !options.emit_defaults {
+                return Ok(None);
+            }
+            let values = vs
+                .iter()
+                .map(|v| print_value(descriptors, field, v, options))
+                .collect::<error::Result<_>>()?;
+            Ok(Some(Json::Array(values)))
+        }
+        value::Field::Map(entries) => {
+            if entries.is_empty() && !options.emit_defaults {
+                return Ok(None);
+            }
+            let value_field = map_entry_field(descriptors, field, 2)?;
+            let mut object = serde_json::Map::new();
+            for (k, v) in entries {
+                let key = match k {
+                    value::MapKey::Bool(k) => k.to_string(),
+                    value::MapKey::I32(k) => k.to_string(),
+                    value::MapKey::I64(k) => k.to_string(),
+                    value::MapKey::U32(k) => k.to_string(),
+                    value::MapKey::U64(k) => k.to_string(),
+                    value::MapKey::String(k) => k.clone(),
+                };
+                object.insert(key, print_value(descriptors, value_field, v, options)?);
+            }
+            Ok(Some(Json::Object(object)))
+        }
+    }
+}
+
+fn print_value(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    value: &value::Value,
+    options: Options,
+) -> error::Result<Json> {
+    use crate::descriptor::FieldType;
+
+    let json = match (field.field_type(descriptors), value) {
+        (FieldType::Bool, &value::Value::Bool(v)) => Json::Bool(v),
+        (FieldType::Int32, &value::Value::I32(v))
+        | (FieldType::SInt32, &value::Value::I32(v))
+        | (FieldType::SFixed32, &value::Value::I32(v)) => Json::from(v),
+        (FieldType::UInt32, &value::Value::U32(v))
+        | (FieldType::Fixed32, &value::Value::U32(v)) => Json::from(v),
+        (FieldType::Int64, &value::Value::I64(v))
+        | (FieldType::SInt64, &value::Value::I64(v))
+        | (FieldType::SFixed64, &value::Value::I64(v)) => Json::String(v.to_string()),
+        (FieldType::UInt64, &value::Value::U64(v))
+        | (FieldType::Fixed64, &value::Value::U64(v)) => Json::String(v.to_string()),
+        // Go through the shortest representation of the `f32`, so that e.g. `0.1` isn't printed
+        // as `0.10000000149011612`
+        (FieldType::Float, &value::Value::F32(v)) => {
+            print_float(v.to_string().parse().unwrap_or_else(|_| f64::from(v)))
+        }
+        (FieldType::Double, &value::Value::F64(v)) => print_float(v),
+        (FieldType::String, value::Value::String(v)) => Json::String(v.clone()),
+        (FieldType::Bytes, value::Value::Bytes(v)) => Json::String(encode_base64(v)),
+        (FieldType::Enum(e), &value::Value::Enum(v)) => {
+            if e.name() == ".google.protobuf.NullValue" {
+                Json::Null
+            } else {
+                match e.value_by_number(v) {
+                    Some(ev) => Json::String(ev.name().to_owned()),
+                    None => Json::from(v),
+                }
+            }
+        }
+        (FieldType::Message(m), value::Value::Message(v))
+        | (FieldType::Group(m), value::Value::Message(v)) => {
+            message_to_value(descriptors, m, v, options)?
+        }
+        (FieldType::UnresolvedMessage(name), _) | (FieldType::UnresolvedGroup(name), _) => {
+            return Err(error::Error::UnknownMessage {
+                name: name.to_owned(),
+            });
+        }
+        (FieldType::UnresolvedEnum(name), _) => {
+            return Err(error::Error::UnknownEnum {
+                name: name.to_owned(),
+            });
+        }
+        _ => return Err(bad_field(field)),
+    };
+    Ok(json)
+}
+
+fn print_float(v: f64) -> Json {
+    if v.is_nan() {
+        Json::String("NaN".to_owned())
+    } else if v == f64::INFINITY {
+        Json::String("Infinity".to_owned())
+    } else if v == f64::NEG_INFINITY {
+        Json::String("-Infinity".to_owned())
+    } else {
+        Json::from(v)
+    }
+}
+
+fn default_json(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    options: Options,
+) -> error::Result<Json> {
+    if field.is_map() {
+        Ok(Json::Object(serde_json::Map::new()))
+    } else if field.is_repeated() {
+        Ok(Json::Array(Vec::new()))
+    } else {
+        match field.default_value() {
+            Some(v) => print_value(descriptors, field, v, options),
+            None => Ok(Json::Null),
+        }
+    }
+}
+
+fn print_well_known(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    well_known_type: WellKnownType,
+    message: &value::Message,
+    options: Options,
+) -> error::Result<Json> {
+    let bad = || bad_well_known(descriptor);
+    let field = |number| match message.fields.get(&number) {
+        Some(value::Field::Singular(v)) => Ok(v.as_ref()),
+        None => Ok(None),
+        Some(_) => Err(bad()),
+    };
+
+    match well_known_type {
+        WellKnownType::Any => {
+            let type_url = match field(1)? {
+                Some(value::Value::String(v)) => v,
+                None => return Ok(Json::Object(serde_json::Map::new())),
+                Some(_) => return Err(bad()),
+            };
+            let bytes = match field(2)? {
+                Some(value::Value::Bytes(v)) => &v[..],
+                None => &[],
+                Some(_) => return Err(bad()),
+            };
+            let (ds, d) = descriptors.message_by_type_url(type_url).ok_or_else(|| {
+                error::Error::UnknownMessage {
+                    name: type_url.clone(),
+                }
+            })?;
+            let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+            let payload = to_value(ds, d, &mut input, options)?;
+
+            let mut object = serde_json::Map::new();
+            object.insert("@type".to_owned(), Json::String(type_url.clone()));
+            match payload {
+                Json::Object(fields) if WellKnownType::of(d).is_none() => object.extend(fields),
+                payload => {
+                    object.insert("value".to_owned(), payload);
+                }
+            }
+            Ok(Json::Object(object))
+        }
+        WellKnownType::Timestamp | WellKnownType::Duration => {
+            let secs = match field(1)? {
+                Some(&value::Value::I64(v)) => v,
+                None => 0,
+                Some(_) => return Err(bad()),
+            };
+            let nanos = match field(2)? {
+                Some(&value::Value::I32(v)) => v,
+                None => 0,
+                Some(_) => return Err(bad()),
+            };
+            let formatted = if well_known_type == WellKnownType::Timestamp {
+                well_known::format_timestamp(secs, nanos)
+            } else {
+                well_known::format_duration(secs, nanos)
+            };
+            Ok(Json::String(formatted.ok_or_else(bad)?))
+        }
+        WellKnownType::FieldMask => {
+            let paths = match message.fields.get(&1) {
+                Some(value::Field::Repeated(vs)) => &vs[..],
+                None => &[],
+                Some(_) => return Err(bad()),
+            };
+            let mut joined = Vec::with_capacity(paths.len());
+            for path in paths {
+                match path {
+                    value::Value::String(path) => {
+                        joined.push(snake_to_camel(path).ok_or_else(bad)?)
+                    }
+                    _ => return Err(bad()),
+                }
+            }
+            Ok(Json::String(joined.join(",")))
+        }
+        WellKnownType::Wrapper => {
+            let d = descriptor.field_by_number(1).ok_or_else(bad)?;
+            match field(1)?.or_else(|| d.default_value()) {
+                Some(v) => print_value(descriptors, d, v, options),
+                None => Err(bad()),
+            }
+        }
+        WellKnownType::Struct => {
+            let d = descriptor.field_by_number(1).ok_or_else(bad)?;
+            match message.fields.get(&1) {
+                Some(v) => Ok(
+                    print_field(descriptors, d, v, options.with_emit_defaults(true))?
+                        .unwrap_or_else(|| Json::Object(serde_json::Map::new())),
+                ),
+                None => Ok(Json::Object(serde_json::Map::new())),
+            }
+        }
+        WellKnownType::Value => {
+            // The kind of value is a oneof, where `null_value` (number 1) is the `NullValue` enum
+            for number in 1..=6 {
+                if let Some(v) = field(number)? {
+                    let d = descriptor.field_by_number(number).ok_or_else(bad)?;
+                    return print_value(descriptors, d, v, options);
+                }
+            }
+            Ok(Json::Null)
+        }
+        WellKnownType::ListValue => {
+            let d = descriptor.field_by_number(1).ok_or_else(bad)?;
+            match message.fields.get(&1) {
+                Some(v) => Ok(
+                    print_field(descriptors, d, v, options.with_emit_defaults(true))?
+                        .unwrap_or_else(|| Json::Array(Vec::new())),
+                ),
+                None => Ok(Json::Array(Vec::new())),
+            }
+        }
+        WellKnownType::Empty => Ok(Json::Object(serde_json::Map::new())),
+    }
+}
+
+fn parse_field(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    json: &Json,
+    options: Options,
+) -> error::Result<Option<value::Field>> {
+    if field.is_map() {
+        let object = match json {
+            Json::Object(object) => object,
+            _ => return Err(bad_field(field)),
+        };
+        let key_field = map_entry_field(descriptors, field, 1)?;
+        let value_field = map_entry_field(descriptors, field, 2)?;
+        let mut entries = collections::BTreeMap::new();
+        for (k, v) in object {
+            let key = parse_map_key(descriptors, key_field, k).ok_or_else(|| bad_field(field))?;
+            if let Some(value) = parse_element(descriptors, value_field, v, options)? {
+                entries.insert(key, value);
+            }
+        }
+        Ok(Some(value::Field::Map(entries)))
+    } else if field.is_repeated() {
+        let array = match json {
+            Json::Array(array) => array,
+            _ => return Err(bad_field(field)),
+        };
+        let mut values = Vec::with_capacity(array.len());
+        for json in array {
+            if let Some(value) = parse_element(descriptors, field, json, options)? {
+                values.push(value);
+            }
+        }
+        Ok(Some(value::Field::Repeated(values)))
+    } else {
+        match parse_value(descriptors, field, json, options)? {
+            Some(ref v) if !field.has_presence() && v.is_zero() => Ok(None),
+            Some(v) => Ok(Some(value::Field::Singular(Some(v)))),
+            None => Ok(None),
+        }
+    }
+}
+
+fn parse_element(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    json: &Json,
+    options: Options,
+) -> error::Result<Option<value::Value>> {
+    if json.is_null() && !accepts_null(descriptors, field) {
+        Err(bad_field(field))
+    } else {
+        parse_value(descriptors, field, json, options)
+    }
+}
+
+/// Parses a single value of the type of the field, or returns `None` for an unknown enum name
+/// that should be ignored.
+fn parse_value(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    json: &Json,
+    options: Options,
+) -> error::Result<Option<value::Value>> {
+    use crate::descriptor::FieldType;
+
+    let bad = || bad_field(field);
+    let value = match field.field_type(descriptors) {
+        FieldType::Bool => value::Value::Bool(json.as_bool().ok_or_else(bad)?),
+        FieldType::Int32 | FieldType::SInt32 | FieldType::SFixed32 => value::Value::I32(
+            parse_int(json)
+                .and_then(|v| i32::try_from(v).ok())
+                .ok_or_else(bad)?,
+        ),
+        FieldType::UInt32 | FieldType::Fixed32 => value::Value::U32(
+            parse_int(json)
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(bad)?,
+        ),
+        FieldType::Int64 | FieldType::SInt64 | FieldType::SFixed64 => value::Value::I64(
+            parse_int(json)
+                .and_then(|v| i64::try_from(v).ok())
+                .ok_or_else(bad)?,
+        ),
+        FieldType::UInt64 | FieldType::Fixed64 => value::Value::U64(
+            parse_int(json)
+                .and_then(|v| u64::try_from(v).ok())
+                .ok_or_else(bad)?,
+        ),
+        FieldType::Float => {
+            let v = parse_float(json).ok_or_else(bad)?;
+            if v.is_finite() && v.abs() > f64::from(f32::MAX) {
+                return Err(bad());
+            }
+            value::Value::F32(v as f32)
+        }
+        FieldType::Double => value::Value::F64(parse_float(json).ok_or_else(bad)?),
+        FieldType::String => value::Value::String(json.as_str().ok_or_else(bad)?.to_owned()),
+        FieldType::Bytes => {
+            let s = json.as_str().ok_or_else(bad)?;
+            value::Value::Bytes(decode_base64(s).ok_or_else(bad)?)
+        }
+        FieldType::Enum(e) => {
+            if e.name() == ".google.protobuf.NullValue" && json.is_null() {
+                value::Value::Enum(0)
+            } else if let Some(name) = json.as_str() {
+                match e.value_by_name(name) {
+                    Some(ev) => value::Value::Enum(ev.number()),
+                    None if options.ignore_unknown_fields => return Ok(None),
+                    None => return Err(bad()),
+                }
+            } else {
+                let number = parse_int(json)
+                    .and_then(|v| i32::try_from(v).ok())
+                    .ok_or_else(bad)?;
+                if e.is_closed() && e.value_by_number(number).is_none() {
+                    return Err(bad());
+                }
+                value::Value::Enum(number)
+            }
+        }
+        FieldType::Message(m) | FieldType::Group(m) => {
+            value::Value::Message(message_from_value(descriptors, m, json, options)?)
+        }
+        FieldType::UnresolvedMessage(name) | FieldType::UnresolvedGroup(name) => {
+            return Err(error::Error::UnknownMessage {
+                name: name.to_owned(),
+            });
+        }
+        FieldType::UnresolvedEnum(name) => {
+            return Err(error::Error::UnknownEnum {
+                name: name.to_owned(),
+            });
+        }
+    };
+    Ok(Some(value))
+}
+
+fn parse_map_key(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    key: &str,
+) -> Option<value::MapKey> {
+    use crate::descriptor::FieldType;
+
+    match field.field_type(descriptors) {
+        FieldType::Bool => match key {
+            "true" => Some(value::MapKey::Bool(true)),
+            "false" => Some(value::MapKey::Bool(false)),
+            _ => None,
+        },
+        FieldType::Int32 | FieldType::SInt32 | FieldType::SFixed32 => {
+            key.parse().ok().map(value::MapKey::I32)
+        }
+        FieldType::UInt32 | FieldType::Fixed32 => key.parse().ok().map(value::MapKey::U32),
+        FieldType::Int64 | FieldType::SInt64 | FieldType::SFixed64 => {
+            key.parse().ok().map(value::MapKey::I64)
+        }
+        FieldType::UInt64 | FieldType::Fixed64 => key.parse().ok().map(value::MapKey::U64),
+        FieldType::String => Some(value::MapKey::String(key.to_owned())),
+        _ => None,
+    }
+}
+
+fn parse_well_known(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    well_known_type: WellKnownType,
+    json: &Json,
+    options: Options,
+) -> error::Result<value::Message> {
+    let bad = || bad_well_known(descriptor);
+    let mut message = value::Message::empty();
+
+    match well_known_type {
+        WellKnownType::Any => {
+            let object = match json {
+                Json::Object(object) => object,
+                _ => return Err(expected_message(descriptor)),
+            };
+            if object.is_empty() {
+                return Ok(message);
+            }
+            let type_url = match object.get("@type") {
+                Some(Json::String(type_url)) => type_url,
+                _ => return Err(bad()),
+            };
+            let (ds, d) = descriptors.message_by_type_url(type_url).ok_or_else(|| {
+                error::Error::UnknownMessage {
+                    name: type_url.clone(),
+                }
+            })?;
+
+            let payload = if WellKnownType::of(d).is_some() {
+                if object.keys().any(|k| k != "@type" && k != "value") {
+                    return Err(bad());
+                }
+                let payload = object.get("value").ok_or_else(bad)?;
+                message_from_value(ds, d, payload, options)?
+            } else {
+                let mut fields = object.clone();
+                fields.remove("@type");
+                message_from_value(ds, d, &Json::Object(fields), options)?
+            };
+            let mut bytes = Vec::new();
+            {
+                let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+                payload.write_to(ds, d, &mut output)?;
+                output.flush()?;
+            }
+
+            set_singular(&mut message, 1, value::Value::String(type_url.clone()));
+            if !bytes.is_empty() {
+                set_singular(&mut message, 2, value::Value::Bytes(bytes));
+            }
+        }
+        WellKnownType::Timestamp | WellKnownType::Duration => {
+            let s = json.as_str().ok_or_else(bad)?;
+            let parsed = if well_known_type == WellKnownType::Timestamp {
+                well_known::parse_timestamp(s)
+            } else {
+                well_known::parse_duration(s)
+            };
+            let (secs, nanos) = parsed.ok_or_else(bad)?;
+            if secs != 0 {
+                set_singular(&mut message, 1, value::Value::I64(secs));
+            }
+            if nanos != 0 {
+                set_singular(&mut message, 2, value::Value::I32(nanos));
+            }
+        }
+        WellKnownType::FieldMask => {
+            let s = json.as_str().ok_or_else(bad)?;
+            let mut paths = Vec::new();
+            if !s.is_empty() {
+                for path in s.split(',') {
+                    let path = camel_to_snake(path).ok_or_else(bad)?;
+                    paths.push(value::Value::String(path));
+                }
+            }
+            if !paths.is_empty() {
+                message.fields.insert(1, value::Field::Repeated(paths));
+            }
+        }
+        WellKnownType::Wrapper => {
+            let d = descriptor.field_by_number(1).ok_or_else(bad)?;
+            if let Some(v) = parse_field(descriptors, d, json, options)? {
+                message.fields.insert(1, v);
+            }
+        }
+        WellKnownType::Struct => {
+            if !json.is_object() {
+                return Err(bad());
+            }
+            let d = descriptor.field_by_number(1).ok_or_else(bad)?;
+            if let Some(v) = parse_field(descriptors, d, json, options)? {
+                message.fields.insert(1, v);
+            }
+        }
+        WellKnownType::Value => {
+            let number = match json {
+                Json::Null => 1,
+                Json::Number(_) => 2,
+                Json::String(_) => 3,
+                Json::Bool(_) => 4,
+                Json::Object(_) => 5,
+                Json::Array(_) => 6,
+            };
+            let d = descriptor.field_by_number(number).ok_or_else(bad)?;
+            let v = match number {
+                // Numbers are always `number_value`, and special float strings are strings here
+                2 => value::Value::F64(json.as_f64().ok_or_else(bad)?),
+                _ => parse_value(descriptors, d, json, options)?.ok_or_else(bad)?,
+            };
+            set_singular(&mut message, number, v);
+        }
+        WellKnownType::ListValue => {
+            if !json.is_array() {
+                return Err(bad());
+            }
+            let d = descriptor.field_by_number(1).ok_or_else(bad)?;
+            if let Some(v) = parse_field(descriptors, d, json, options)? {
+                message.fields.insert(1, v);
+            }
+        }
+        WellKnownType::Empty => match json {
+            Json::Object(object) if object.is_empty() => {}
+            _ => return Err(bad()),
+        },
+    }
+    Ok(message)
+}
+
+fn set_singular(message: &mut value::Message, number: i32, value: value::Value) {
+    message
+        .fields
+        .insert(number, value::Field::Singular(Some(value)));
+}
+
+/// Whether `null` is a value of the type of the field rather than a way to leave it unset.
+fn accepts_null(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+) -> bool {
+    use crate::descriptor::FieldType;
+
+    match field.field_type(descriptors) {
+        FieldType::Message(m) => m.name() == ".google.protobuf.Value",
+        FieldType::Enum(e) => e.name() == ".google.protobuf.NullValue",
+        _ => false,
+    }
+}
+
+fn map_entry_field<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    field: &'a descriptor::FieldDescriptor,
+    number: i32,
+) -> error::Result<&'a descriptor::FieldDescriptor> {
+    match field.field_type(descriptors) {
+        descriptor::FieldType::Message(m) => {
+            m.field_by_number(number).ok_or_else(|| bad_field(field))
+        }
+        _ => Err(bad_field(field)),
+    }
+}
+
+/// Parses an integer from a JSON number or a string holding one.
+///
+/// Numbers with a fraction or an exponent are accepted as long as they are integral.  The result
+/// is wide enough for the range of every integer field type to be checked by the caller.
+fn parse_int(json: &Json) -> Option<i128> {
+    let n = match json {
+        Json::Number(n) => n.clone(),
+        Json::String(s) => {
+            if let Ok(v) = s.parse() {
+                return Some(v);
+            }
+            // Don't accept surrounding whitespace or anything else that JSON doesn't allow
+            serde_json::from_str::<serde_json::Number>(s).ok()?
+        }
+        _ => return None,
+    };
+    if let Some(v) = n.as_i64() {
+        Some(i128::from(v))
+    } else if let Some(v) = n.as_u64() {
+        Some(i128::from(v))
+    } else {
+        let v = n.as_f64()?;
+        // Every float beyond 2^64 in magnitude is out of range for all integer types
+        if v.fract() == 0.0 && v.abs() < 18_446_744_073_709_551_616.0 {
+            Some(v as i128)
+        } else {
+            None
+        }
+    }
+}
+
+/// Parses a float from a JSON number, a string holding one, or one of the special strings.
+fn parse_float(json: &Json) -> Option<f64> {
+    match json {
+        Json::Number(n) => n.as_f64(),
+        Json::String(s) => match s.as_str() {
+            "NaN" => Some(f64::NAN),
+            "Infinity" => Some(f64::INFINITY),
+            "-Infinity" => Some(f64::NEG_INFINITY),
+            // Rust accepts strings like "inf" that JSON doesn't, so parse it as a JSON number
+            s => serde_json::from_str::<serde_json::Number>(s).ok()?.as_f64(),
+        },
+        _ => None,
+    }
+}
+
+/// Converts a field mask path to lowerCamelCase, or returns `None` if the result couldn't be
+/// converted back to the same path.
+fn snake_to_camel(path: &str) -> Option<String> {
+    let mut result = String::with_capacity(path.len());
+    let mut after_underscore = false;
+    for c in path.chars() {
+        if c.is_ascii_uppercase() {
+            return None;
+        } else if after_underscore {
+            if !c.is_ascii_lowercase() {
+                return None;
+            }
+            result.push(c.to_ascii_uppercase());
+            after_underscore = false;
+        } else if c == '_' {
+            after_underscore = true;
+        } else {
+            result.push(c);
+        }
+    }
+    if after_underscore {
+        None
+    } else {
+        Some(result)
+    }
+}
+
+/// Converts a lowerCamelCase field mask path to snake_case, or returns `None` if it contains
+/// underscores.
+fn camel_to_snake(path: &str) -> Option<String> {
+    let mut result = String::with_capacity(path.len() + 4);
+    for c in path.chars() {
+        if c == '_' {
+            return None;
+        } else if c.is_ascii_uppercase() {
+            result.push('_');
+            result.push(c.to_ascii_lowercase());
+        } else {
+            result.push(c);
+        }
+    }
+    Some(result)
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).cloned().unwrap_or(0),
+            chunk.get(2).cloned().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(char::from(
+                    BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize],
+                ));
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+/// Decodes base64 in either the standard or the URL safe alphabet, with optional padding.
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut result = Vec::with_capacity(s.len() * 3 / 4);
+    let mut n = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
+        };
+        n = (n << 6) | u32::from(v);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((n >> bits) as u8);
+            n &= (1 << bits) - 1;
+        }
+    }
+    // A single character left over can't encode a whole byte
+    if bits >= 6 {
+        None
+    } else {
+        Some(result)
+    }
+}
+
+fn bad_field(field: &descriptor::FieldDescriptor) -> error::Error {
+    error::Error::BadFieldValue {
+        field: field.name().to_owned(),
+    }
+}
+
+fn bad_well_known(descriptor: &descriptor::MessageDescriptor) -> error::Error {
+    error::Error::BadWellKnownValue {
+        name: descriptor.name().to_owned(),
+    }
+}
+
+fn expected_message(descriptor: &descriptor::MessageDescriptor) -> error::Error {
+    error::Error::ExpectedMessage {
+        name: descriptor.name().to_owned(),
+    }
+}
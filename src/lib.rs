@@ -8,8 +8,14 @@
 //!     buffer decoded data (but the representation is heavily coupled with a schema).
 //!   * The [`de`](de/index.html) module can be used to deserialize binary encoded protocol buffer
 //!     messages given some schema descriptors.
-//!
-//! Serialization is not yet implemented in this version.
+//!   * The [`ser`](ser/index.html) module can be used to serialize values into binary encoded
+//!     protocol buffer messages given some schema descriptors.
+//!   * The [`text`](text/index.html) module reads and writes messages in the protocol buffer text
+//!     format, and can deserialize them given some schema descriptors.
+//!   * The [`json`](json/index.html) module converts between binary encoded protocol buffer
+//!     messages and the proto3 JSON format given some schema descriptors.
+//!   * The [`parser`](parser/index.html) module parses `.proto` schema files into schema
+//!     descriptors, without the need for `protoc`.
 //!
 //! [1]: https://developers.google.com/protocol-buffers/
 #![deny(warnings)]
@@ -33,6 +39,12 @@ extern crate serde;
 pub mod de;
 pub mod descriptor;
 pub mod error;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod parser;
+pub mod ser;
+pub mod text;
 pub mod value;
+mod well_known;
 
 pub use crate::error::Error;
@@ -0,0 +1,2310 @@
+//! Parsing of `.proto` schema files into descriptors.
+//!
+//! This removes the need to run `protoc` ahead of time: a `Parser` reads schema files, follows
+//! their imports and resolves all type references, producing either a protocol buffer file
+//! descriptor set (as `protoc -o` would, including all imports) or a `Descriptors` registry
+//! directly.
+//!
+//! Files are looked up by the name they are imported with, first among the sources added with
+//! `add_source` and then relative to every include path in turn:
+//!
+//! ```
+//! use serde_protobuf::parser::Parser;
+//!
+//! let mut parser = Parser::new();
+//! parser.add_source("person.proto", r#"
+//!     syntax = "proto3";
+//!     package mypackage;
+//!
+//!     message Person {
+//!         string name = 1;
+//!         int32 age = 2;
+//!         repeated Color favorite_colors = 3;
+//!     }
+//!
+//!     enum Color {
+//!         BLUE = 0;
+//!         RED = 1;
+//!     }
+//! "#);
+//!
+//! let descriptors = parser.parse_descriptors(&["person.proto"]).unwrap();
+//! let person = descriptors.message_by_name(".mypackage.Person").unwrap();
+//! assert_eq!(person.field_by_name("age").unwrap().number(), 2);
+//! ```
+//!
+//! Both proto2 and proto3 files are supported.  Standard options and custom options with scalar
+//! or enum values are interpreted; `google/protobuf/descriptor.proto` is built in so that custom
+//! options can be declared without having it on an include path.  Problems are reported as
+//! `Error::BadProtoFile` with the file, line and column where they were found.
+use std::collections;
+use std::f64;
+use std::fs;
+use std::io;
+use std::path;
+
+use protobuf;
+use protobuf::descriptor;
+use protobuf::Message;
+
+use crate::descriptor::Descriptors;
+use crate::error;
+
+const MAX_FIELD_NUMBER: u64 = 536_870_911;
+const DESCRIPTOR_PROTO: &str = "google/protobuf/descriptor.proto";
+
+/// A parser for `.proto` schema files.
+#[derive(Debug, Default)]
+pub struct Parser {
+    include_paths: Vec<path::PathBuf>,
+    sources: collections::HashMap<String, String>,
+}
+
+/// A position in a source file.
+#[derive(Clone, Copy, Debug, Default)]
+struct Pos {
+    line: usize,
+    column: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Int(u64),
+    Float(f64),
+    Str(Vec<u8>),
+    Symbol(char),
+    Eof,
+}
+
+struct Lexer<'a> {
+    file: &'a str,
+    chars: Vec<char>,
+    index: usize,
+    pos: Pos,
+}
+
+/// The element of a file that an option or type reference applies to.  Messages are identified
+/// by their path of indices, starting at the top-level message types of the file; an empty path
+/// refers to the file itself.
+#[derive(Clone, Debug)]
+enum Target {
+    File,
+    Message(Vec<i32>),
+    Field(Vec<i32>, i32),
+    Extension(Vec<i32>, i32),
+    Oneof(Vec<i32>, i32),
+    Enum(Vec<i32>, i32),
+    EnumValue(Vec<i32>, i32, i32),
+    Service(i32),
+    Method(i32, i32),
+}
+
+#[derive(Debug)]
+enum RefKind {
+    FieldType(Target),
+    Extendee(Target),
+    Input(i32, i32),
+    Output(i32, i32),
+}
+
+/// A reference to a type that can only be resolved once all files have been parsed.
+#[derive(Debug)]
+struct TypeRef {
+    kind: RefKind,
+    name: String,
+    scope: String,
+    pos: Pos,
+}
+
+/// The value of an option or field default.
+#[derive(Clone, Debug)]
+enum Constant {
+    Ident(String),
+    Int(bool, u64),
+    Float(f64),
+    Str(Vec<u8>),
+    Aggregate,
+}
+
+/// An option that can only be interpreted once all types are known.
+#[derive(Debug)]
+struct PendingOption {
+    target: Target,
+    name: Vec<(String, bool)>,
+    value: Constant,
+    scope: String,
+    pos: Pos,
+}
+
+/// A file that has been parsed, but whose type references haven't been resolved yet.
+#[derive(Debug)]
+struct ParsedFile {
+    proto: descriptor::FileDescriptorProto,
+    imports: Vec<(String, Pos)>,
+    refs: Vec<TypeRef>,
+    options: Vec<PendingOption>,
+    enum_defaults: Vec<(Target, Pos)>,
+}
+
+struct FileParser<'a> {
+    file: &'a str,
+    tokens: Vec<(Token, Pos)>,
+    index: usize,
+    proto3: bool,
+    refs: Vec<TypeRef>,
+    options: Vec<PendingOption>,
+    enum_defaults: Vec<(Target, Pos)>,
+}
+
+/// Where a field declaration ends up.
+#[derive(Clone, Copy)]
+enum Owner<'a> {
+    Message,
+    Oneof(i32),
+    Extend(&'a str, Pos),
+}
+
+struct Loader<'a> {
+    parser: &'a Parser,
+    files: Vec<ParsedFile>,
+    deps: Vec<Vec<usize>>,
+    by_name: collections::HashMap<String, usize>,
+    stack: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SymbolKind {
+    Package,
+    Message,
+    Enum,
+    Extension,
+    Service,
+}
+
+enum Item<'a> {
+    Message,
+    Enum(&'a descriptor::EnumDescriptorProto),
+    Extension(&'a descriptor::FieldDescriptorProto),
+    Service,
+}
+
+/// Everything that is known about the types of a set of parsed files.
+struct Schema {
+    symbols: collections::HashMap<String, (SymbolKind, Option<usize>)>,
+    enums: collections::HashMap<String, Vec<(String, i32)>>,
+    extensions: collections::HashMap<String, descriptor::FieldDescriptorProto>,
+}
+
+impl Parser {
+    /// Creates a new parser without any include paths or sources.
+    pub fn new() -> Parser {
+        Parser::default()
+    }
+
+    /// Adds a directory that imported files are looked up in.
+    pub fn add_include_path<P>(&mut self, path: P)
+    where
+        P: Into<path::PathBuf>,
+    {
+        self.include_paths.push(path.into());
+    }
+
+    /// Adds an in-memory source file with the specified import name, which takes precedence over
+    /// files on the include paths.
+    pub fn add_source<N, S>(&mut self, name: N, source: S)
+    where
+        N: Into<String>,
+        S: Into<String>,
+    {
+        self.sources.insert(name.into(), source.into());
+    }
+
+    /// Parses the specified files and everything they import into a file descriptor set, in which
+    /// every file comes after the files it imports.
+    pub fn parse_files<S>(&self, names: &[S]) -> error::Result<descriptor::FileDescriptorSet>
+    where
+        S: AsRef<str>,
+    {
+        let mut loader = Loader {
+            parser: self,
+            files: Vec::new(),
+            deps: Vec::new(),
+            by_name: collections::HashMap::new(),
+            stack: Vec::new(),
+        };
+        for name in names {
+            loader.load(name.as_ref(), None)?;
+        }
+        let Loader {
+            mut files, deps, ..
+        } = loader;
+
+        let visible = (0..files.len())
+            .map(|i| visible_files(&files, &deps, i))
+            .collect::<Vec<_>>();
+        let mut schema = Schema::new(&files)?;
+        for (i, file) in files.iter_mut().enumerate() {
+            schema.resolve_refs(file, &visible[i])?;
+        }
+        schema.add_extensions(&files);
+        for (i, file) in files.iter_mut().enumerate() {
+            schema.apply_options(file, &visible[i])?;
+        }
+
+        let mut file_set = descriptor::FileDescriptorSet::new();
+        for file in files {
+            // Round-trip through the binary encoding so that interpreted standard options, which
+            // are written as unknown fields, become known fields.
+            let bytes = file.proto.write_to_bytes()?;
+            file_set
+                .mut_file()
+                .push(protobuf::parse_from_bytes(&bytes)?);
+        }
+        Ok(file_set)
+    }
+
+    /// Parses the specified files and everything they import into a descriptor registry.
+    pub fn parse_descriptors<S>(&self, names: &[S]) -> error::Result<Descriptors>
+    where
+        S: AsRef<str>,
+    {
+        Ok(Descriptors::from_proto(&self.parse_files(names)?))
+    }
+
+    fn read(&self, name: &str) -> io::Result<Option<String>> {
+        if let Some(source) = self.sources.get(name) {
+            return Ok(Some(source.clone()));
+        }
+        for include_path in &self.include_paths {
+            match fs::read_to_string(include_path.join(name)) {
+                Ok(source) => return Ok(Some(source)),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a> Loader<'a> {
+    fn load(&mut self, name: &str, importer: Option<(&str, Pos)>) -> error::Result<usize> {
+        if let Some(&index) = self.by_name.get(name) {
+            return Ok(index);
+        }
+        let fail = |message: String| match importer {
+            Some((file, pos)) => file_error(file, pos, message),
+            None => file_error(name, Pos::default(), message),
+        };
+
+        if let Some(start) = self.stack.iter().position(|n| n == name) {
+            let mut cycle = self.stack[start..].to_vec();
+            cycle.push(name.to_owned());
+            return Err(fail(format!("import cycle: {}", cycle.join(" -> "))));
+        }
+
+        let parsed = match self.parser.read(name) {
+            Ok(Some(source)) => parse_file(name, &source)?,
+            Ok(None) if name == DESCRIPTOR_PROTO => ParsedFile {
+                proto: descriptor::file_descriptor_proto().clone(),
+                imports: Vec::new(),
+                refs: Vec::new(),
+                options: Vec::new(),
+                enum_defaults: Vec::new(),
+            },
+            Ok(None) => return Err(fail(format!("file not found: {}", name))),
+            Err(e) => return Err(fail(format!("can't read {}: {}", name, e))),
+        };
+
+        self.stack.push(name.to_owned());
+        let mut deps = Vec::new();
+        for &(ref import, pos) in &parsed.imports {
+            deps.push(self.load(import, Some((name, pos)))?);
+        }
+        self.stack.pop();
+
+        self.files.push(parsed);
+        self.deps.push(deps);
+        self.by_name.insert(name.to_owned(), self.files.len() - 1);
+        Ok(self.files.len() - 1)
+    }
+}
+
+/// Returns the files whose types can be used by the specified file: the file itself, the files it
+/// imports, and the files that those publicly import.
+fn visible_files(
+    files: &[ParsedFile],
+    deps: &[Vec<usize>],
+    index: usize,
+) -> collections::HashSet<usize> {
+    let mut visible = collections::HashSet::new();
+    visible.insert(index);
+    let mut pending = deps[index].clone();
+    while let Some(dep) = pending.pop() {
+        if visible.insert(dep) {
+            for &public in files[dep].proto.get_public_dependency() {
+                if let Some(&d) = deps[dep].get(public as usize) {
+                    pending.push(d);
+                }
+            }
+        }
+    }
+    visible
+}
+
+fn file_error<S>(file: &str, pos: Pos, message: S) -> error::Error
+where
+    S: Into<String>,
+{
+    error::Error::BadProtoFile {
+        file: file.to_owned(),
+        line: pos.line,
+        column: pos.column,
+        message: message.into(),
+    }
+}
+
+fn join(scope: &str, name: &str) -> String {
+    if scope.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}.{}", scope, name)
+    }
+}
+
+fn tokenize(file: &str, source: &str) -> error::Result<Vec<(Token, Pos)>> {
+    let mut lexer = Lexer {
+        file,
+        chars: source.chars().collect(),
+        index: 0,
+        pos: Pos { line: 1, column: 1 },
+    };
+    let mut tokens = Vec::new();
+    loop {
+        lexer.skip_whitespace()?;
+        let pos = lexer.pos;
+        match lexer.next_token()? {
+            Some(token) => tokens.push((token, pos)),
+            None => {
+                tokens.push((Token::Eof, pos));
+                return Ok(tokens);
+            }
+        }
+    }
+}
+
+impl<'a> Lexer<'a> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).cloned()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.index + offset).cloned()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.index += 1;
+        if c == '\n' {
+            self.pos.line += 1;
+            self.pos.column = 1;
+        } else {
+            self.pos.column += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) -> error::Result<()> {
+        loop {
+            match (self.peek(), self.peek_at(1)) {
+                (Some(c), _) if c.is_whitespace() => {
+                    self.bump();
+                }
+                (Some('/'), Some('/')) => {
+                    while let Some(c) = self.bump() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                }
+                (Some('/'), Some('*')) => {
+                    let start = self.pos;
+                    self.bump();
+                    self.bump();
+                    loop {
+                        match self.bump() {
+                            Some('*') if self.peek() == Some('/') => {
+                                self.bump();
+                                break;
+                            }
+                            Some(_) => {}
+                            None => {
+                                return Err(file_error(self.file, start, "unterminated comment"))
+                            }
+                        }
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> error::Result<Option<Token>> {
+        let start = self.pos;
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        if c.is_ascii_alphabetic() || c == '_' {
+            let mut ident = String::new();
+            while let Some(c) = self
+                .peek()
+                .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+            {
+                ident.push(c);
+                self.bump();
+            }
+            Ok(Some(Token::Ident(ident)))
+        } else if c.is_ascii_digit()
+            || (c == '.' && self.peek_at(1).is_some_and(|c| c.is_ascii_digit()))
+        {
+            let mut text = String::new();
+            while let Some(c) = self.peek() {
+                let hex = text.starts_with("0x") || text.starts_with("0X");
+                let exponent_sign = (c == '+' || c == '-') && !hex && text.ends_with(['e', 'E']);
+                if c.is_ascii_alphanumeric() || c == '_' || c == '.' || exponent_sign {
+                    text.push(c);
+                    self.bump();
+                } else {
+                    break;
+                }
+            }
+            parse_number(&text)
+                .map(Some)
+                .ok_or_else(|| file_error(self.file, start, format!("invalid number: {}", text)))
+        } else if c == '"' || c == '\'' {
+            self.bump();
+            let mut bytes = Vec::new();
+            loop {
+                let pos = self.pos;
+                match self.bump() {
+                    Some(q) if q == c => break,
+                    Some('\\') => self.parse_escape(pos, &mut bytes)?,
+                    Some('\n') | None => {
+                        return Err(file_error(self.file, start, "unterminated string"))
+                    }
+                    Some(other) => {
+                        let mut buf = [0; 4];
+                        bytes.extend_from_slice(other.encode_utf8(&mut buf).as_bytes());
+                    }
+                }
+            }
+            Ok(Some(Token::Str(bytes)))
+        } else if "{}[]()<>;,=.-+:/".contains(c) {
+            self.bump();
+            Ok(Some(Token::Symbol(c)))
+        } else {
+            Err(file_error(
+                self.file,
+                start,
+                format!("unexpected character: {:?}", c),
+            ))
+        }
+    }
+
+    fn parse_escape(&mut self, start: Pos, bytes: &mut Vec<u8>) -> error::Result<()> {
+        let c = match self.bump() {
+            Some(c) => c,
+            None => return Err(file_error(self.file, start, "unterminated string")),
+        };
+        let byte = match c {
+            'n' => b'\n',
+            'r' => b'\r',
+            't' => b'\t',
+            'a' => 0x07,
+            'b' => 0x08,
+            'f' => 0x0c,
+            'v' => 0x0b,
+            '\\' | '\'' | '"' | '?' => c as u8,
+            '0'..='7' => {
+                let mut v = c.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match self.peek().and_then(|c| c.to_digit(8)) {
+                        Some(d) => {
+                            v = v * 8 + d;
+                            self.bump();
+                        }
+                        None => break,
+                    }
+                }
+                if v > 0xff {
+                    return Err(file_error(self.file, start, "octal escape out of range"));
+                }
+                v as u8
+            }
+            'x' | 'X' => {
+                let mut v = 0;
+                let mut digits = 0;
+                while digits < 2 {
+                    match self.peek().and_then(|c| c.to_digit(16)) {
+                        Some(d) => {
+                            v = v * 16 + d;
+                            self.bump();
+                            digits += 1;
+                        }
+                        None => break,
+                    }
+                }
+                if digits == 0 {
+                    return Err(file_error(self.file, start, "bad hex escape"));
+                }
+                v as u8
+            }
+            'u' | 'U' => {
+                let len = if c == 'u' { 4 } else { 8 };
+                let mut v = 0u32;
+                for _ in 0..len {
+                    match self.peek().and_then(|c| c.to_digit(16)) {
+                        Some(d) => {
+                            v = v * 16 + d;
+                            self.bump();
+                        }
+                        None => return Err(file_error(self.file, start, "bad unicode escape")),
+                    }
+                }
+                let c = std::char::from_u32(v)
+                    .ok_or_else(|| file_error(self.file, start, "bad unicode escape"))?;
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                return Ok(());
+            }
+            _ => {
+                return Err(file_error(
+                    self.file,
+                    start,
+                    format!("unknown escape: \\{}", c),
+                ))
+            }
+        };
+        bytes.push(byte);
+        Ok(())
+    }
+}
+
+fn parse_number(text: &str) -> Option<Token> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16).ok().map(Token::Int)
+    } else if text.contains(['.', 'e', 'E']) {
+        text.parse().ok().map(Token::Float)
+    } else if text.len() > 1 && text.starts_with('0') {
+        u64::from_str_radix(&text[1..], 8).ok().map(Token::Int)
+    } else {
+        text.parse().ok().map(Token::Int)
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match *token {
+        Token::Ident(ref ident) => format!("'{}'", ident),
+        Token::Int(_) | Token::Float(_) => "number".to_owned(),
+        Token::Str(_) => "string".to_owned(),
+        Token::Symbol(c) => format!("'{}'", c),
+        Token::Eof => "end of file".to_owned(),
+    }
+}
+
+fn parse_file(name: &str, source: &str) -> error::Result<ParsedFile> {
+    let mut parser = FileParser {
+        file: name,
+        tokens: tokenize(name, source)?,
+        index: 0,
+        proto3: false,
+        refs: Vec::new(),
+        options: Vec::new(),
+        enum_defaults: Vec::new(),
+    };
+    let (proto, imports) = parser.parse()?;
+    Ok(ParsedFile {
+        proto,
+        imports,
+        refs: parser.refs,
+        options: parser.options,
+        enum_defaults: parser.enum_defaults,
+    })
+}
+
+impl<'a> FileParser<'a> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.index].0
+    }
+
+    fn peek_at(&self, offset: usize) -> &Token {
+        let index = (self.index + offset).min(self.tokens.len() - 1);
+        &self.tokens[index].0
+    }
+
+    fn pos(&self) -> Pos {
+        self.tokens[self.index].1
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.index].0.clone();
+        if self.index < self.tokens.len() - 1 {
+            self.index += 1;
+        }
+        token
+    }
+
+    fn error<S>(&self, pos: Pos, message: S) -> error::Error
+    where
+        S: Into<String>,
+    {
+        file_error(self.file, pos, message)
+    }
+
+    fn unexpected(&self, expected: &str) -> error::Error {
+        self.error(
+            self.pos(),
+            format!("expected {}, found {}", expected, describe(self.peek())),
+        )
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        match *self.peek() {
+            Token::Ident(ref ident) => ident == keyword,
+            _ => false,
+        }
+    }
+
+    fn consume_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.advance();
+        }
+        found
+    }
+
+    fn consume_symbol(&mut self, c: char) -> bool {
+        let found = *self.peek() == Token::Symbol(c);
+        if found {
+            self.advance();
+        }
+        found
+    }
+
+    fn expect_symbol(&mut self, c: char) -> error::Result<()> {
+        if self.consume_symbol(c) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("'{}'", c)))
+        }
+    }
+
+    fn expect_ident(&mut self) -> error::Result<String> {
+        match *self.peek() {
+            Token::Ident(ref ident) => {
+                let ident = ident.clone();
+                self.advance();
+                Ok(ident)
+            }
+            _ => Err(self.unexpected("an identifier")),
+        }
+    }
+
+    fn expect_int(&mut self) -> error::Result<u64> {
+        match *self.peek() {
+            Token::Int(i) => {
+                self.advance();
+                Ok(i)
+            }
+            _ => Err(self.unexpected("an integer")),
+        }
+    }
+
+    fn expect_string(&mut self) -> error::Result<Vec<u8>> {
+        let mut bytes = match *self.peek() {
+            Token::Str(ref bytes) => bytes.clone(),
+            _ => return Err(self.unexpected("a string")),
+        };
+        self.advance();
+        while let Token::Str(ref more) = *self.peek() {
+            bytes.extend_from_slice(more);
+            self.advance();
+        }
+        Ok(bytes)
+    }
+
+    fn expect_utf8(&mut self) -> error::Result<String> {
+        let pos = self.pos();
+        let bytes = self.expect_string()?;
+        String::from_utf8(bytes).map_err(|_| self.error(pos, "string is not valid UTF-8"))
+    }
+
+    fn parse_full_ident(&mut self) -> error::Result<String> {
+        let mut ident = self.expect_ident()?;
+        while self.consume_symbol('.') {
+            ident.push('.');
+            ident.push_str(&self.expect_ident()?);
+        }
+        Ok(ident)
+    }
+
+    fn parse_type_name(&mut self) -> error::Result<String> {
+        if self.consume_symbol('.') {
+            Ok(format!(".{}", self.parse_full_ident()?))
+        } else {
+            self.parse_full_ident()
+        }
+    }
+
+    fn parse(&mut self) -> error::Result<(descriptor::FileDescriptorProto, Vec<(String, Pos)>)> {
+        let mut file = descriptor::FileDescriptorProto::new();
+        file.set_name(self.file.to_owned());
+        let mut imports = Vec::new();
+        let mut package = String::new();
+        let mut messages = Vec::new();
+        let mut enums = Vec::new();
+        let mut services = Vec::new();
+        let mut extensions = Vec::new();
+
+        if self.consume_keyword("syntax") {
+            self.expect_symbol('=')?;
+            let pos = self.pos();
+            let syntax = self.expect_utf8()?;
+            match syntax.as_str() {
+                "proto2" => {}
+                "proto3" => self.proto3 = true,
+                _ => return Err(self.error(pos, format!("unknown syntax: {:?}", syntax))),
+            }
+            self.expect_symbol(';')?;
+            // Like protoc, only record the syntax if it isn't the default one.
+            if self.proto3 {
+                file.set_syntax(syntax);
+            }
+        }
+
+        loop {
+            let pos = self.pos();
+            let keyword = match self.advance() {
+                Token::Eof => break,
+                Token::Symbol(';') => continue,
+                Token::Ident(keyword) => keyword,
+                token => {
+                    return Err(self.error(
+                        pos,
+                        format!("expected a top-level statement, found {}", describe(&token)),
+                    ))
+                }
+            };
+            match keyword.as_str() {
+                "import" => {
+                    let index = imports.len() as i32;
+                    if self.consume_keyword("public") {
+                        file.mut_public_dependency().push(index);
+                    } else if self.consume_keyword("weak") {
+                        file.mut_weak_dependency().push(index);
+                    }
+                    let name_pos = self.pos();
+                    let name = self.expect_utf8()?;
+                    self.expect_symbol(';')?;
+                    file.mut_dependency().push(name.clone());
+                    imports.push((name, name_pos));
+                }
+                "package" => {
+                    if file.has_package() {
+                        return Err(self.error(pos, "multiple package definitions"));
+                    }
+                    package = self.parse_full_ident()?;
+                    self.expect_symbol(';')?;
+                    file.set_package(package.clone());
+                }
+                "option" => self.parse_option_statement(Target::File, &package)?,
+                "message" => {
+                    let name = self.expect_ident()?;
+                    let path = vec![messages.len() as i32];
+                    let message = self.parse_message(name, path, &package)?;
+                    messages.push(message);
+                }
+                "enum" => {
+                    let index = enums.len() as i32;
+                    enums.push(self.parse_enum(&[], index, &package)?);
+                }
+                "service" => {
+                    let index = services.len() as i32;
+                    services.push(self.parse_service(index, &package)?);
+                }
+                "extend" => self.parse_extend(&[], &package, &mut extensions, &mut messages)?,
+                "syntax" => {
+                    return Err(
+                        self.error(pos, "the syntax must be declared at the top of the file")
+                    )
+                }
+                "edition" => return Err(self.error(pos, "editions are not supported")),
+                _ => {
+                    return Err(self.error(
+                        pos,
+                        format!("expected a top-level statement, found '{}'", keyword),
+                    ))
+                }
+            }
+        }
+
+        file.set_message_type(protobuf::RepeatedField::from_vec(messages));
+        file.set_enum_type(protobuf::RepeatedField::from_vec(enums));
+        file.set_service(protobuf::RepeatedField::from_vec(services));
+        file.set_extension(protobuf::RepeatedField::from_vec(extensions));
+        Ok((file, imports))
+    }
+
+    fn parse_message(
+        &mut self,
+        name: String,
+        path: Vec<i32>,
+        scope: &str,
+    ) -> error::Result<descriptor::DescriptorProto> {
+        let full_name = join(scope, &name);
+        let mut message = descriptor::DescriptorProto::new();
+        message.set_name(name);
+        self.expect_symbol('{')?;
+        self.parse_message_body(&mut message, &path, &full_name)?;
+        Ok(message)
+    }
+
+    /// Parses the declarations of a message up to and including the closing brace.
+    fn parse_message_body(
+        &mut self,
+        message: &mut descriptor::DescriptorProto,
+        path: &[i32],
+        scope: &str,
+    ) -> error::Result<()> {
+        let mut fields = Vec::new();
+        let mut nested = Vec::new();
+        let mut enums = Vec::new();
+        let mut extensions = Vec::new();
+        let mut oneofs = Vec::new();
+        let mut proto3_optionals = Vec::new();
+
+        loop {
+            let keyword = match *self.peek() {
+                Token::Symbol('}') => {
+                    self.advance();
+                    break;
+                }
+                Token::Symbol(';') => {
+                    self.advance();
+                    continue;
+                }
+                Token::Ident(ref keyword) => keyword.clone(),
+                _ => return Err(self.unexpected("a message member or '}'")),
+            };
+            match keyword.as_str() {
+                "message" => {
+                    self.advance();
+                    let name = self.expect_ident()?;
+                    let mut nested_path = path.to_vec();
+                    nested_path.push(nested.len() as i32);
+                    let nested_message = self.parse_message(name, nested_path, scope)?;
+                    nested.push(nested_message);
+                }
+                "enum" => {
+                    self.advance();
+                    let index = enums.len() as i32;
+                    enums.push(self.parse_enum(path, index, scope)?);
+                }
+                "extend" => {
+                    self.advance();
+                    self.parse_extend(path, scope, &mut extensions, &mut nested)?;
+                }
+                "extensions" => {
+                    self.advance();
+                    for (start, end) in self.parse_ranges(1, MAX_FIELD_NUMBER as i64)? {
+                        let mut range = descriptor::DescriptorProto_ExtensionRange::new();
+                        range.set_start(start);
+                        range.set_end(end + 1);
+                        message.mut_extension_range().push(range);
+                    }
+                    // Extension range options aren't part of the descriptors, so they are only
+                    // checked for syntax.
+                    self.parse_bracket_options(&Target::Message(path.to_vec()), scope)?;
+                    self.expect_symbol(';')?;
+                }
+                "reserved" => {
+                    self.advance();
+                    if let Token::Str(_) = *self.peek() {
+                        for name in self.parse_reserved_names()? {
+                            message.mut_reserved_name().push(name);
+                        }
+                    } else {
+                        for (start, end) in self.parse_ranges(1, MAX_FIELD_NUMBER as i64)? {
+                            let mut range = descriptor::DescriptorProto_ReservedRange::new();
+                            range.set_start(start);
+                            range.set_end(end + 1);
+                            message.mut_reserved_range().push(range);
+                        }
+                    }
+                    self.expect_symbol(';')?;
+                }
+                "option" => {
+                    self.advance();
+                    self.parse_option_statement(Target::Message(path.to_vec()), scope)?;
+                }
+                "oneof" => {
+                    self.advance();
+                    self.parse_oneof(path, scope, &mut fields, &mut nested, &mut oneofs)?;
+                }
+                "map" if *self.peek_at(1) == Token::Symbol('<') => {
+                    self.advance();
+                    self.parse_map_field(path, scope, &mut fields, &mut nested)?;
+                }
+                _ => {
+                    if self.parse_field(Owner::Message, path, scope, &mut fields, &mut nested)? {
+                        proto3_optionals.push(fields.len() - 1);
+                    }
+                }
+            }
+        }
+
+        // Like protoc, represent every proto3 optional field as a synthetic oneof that comes
+        // after all real oneofs.
+        for index in proto3_optionals {
+            let field: &mut descriptor::FieldDescriptorProto = &mut fields[index];
+            let mut oneof = descriptor::OneofDescriptorProto::new();
+            oneof.set_name(format!("_{}", field.get_name()));
+            field.set_oneof_index(oneofs.len() as i32);
+            oneofs.push(oneof);
+        }
+
+        message.set_field(protobuf::RepeatedField::from_vec(fields));
+        message.set_nested_type(protobuf::RepeatedField::from_vec(nested));
+        message.set_enum_type(protobuf::RepeatedField::from_vec(enums));
+        message.set_extension(protobuf::RepeatedField::from_vec(extensions));
+        message.set_oneof_decl(protobuf::RepeatedField::from_vec(oneofs));
+        Ok(())
+    }
+
+    /// Parses a field declaration, adding the field to `fields` and the message type of a group
+    /// to `nested`.  Returns whether the field is a proto3 `optional` field.
+    fn parse_field(
+        &mut self,
+        owner: Owner,
+        path: &[i32],
+        scope: &str,
+        fields: &mut Vec<descriptor::FieldDescriptorProto>,
+        nested: &mut Vec<descriptor::DescriptorProto>,
+    ) -> error::Result<bool> {
+        use protobuf::descriptor::FieldDescriptorProto_Label::*;
+
+        let start = self.pos();
+        let label = if self.consume_keyword("optional") {
+            Some(LABEL_OPTIONAL)
+        } else if self.consume_keyword("required") {
+            Some(LABEL_REQUIRED)
+        } else if self.consume_keyword("repeated") {
+            Some(LABEL_REPEATED)
+        } else {
+            None
+        };
+        let in_oneof = matches!(owner, Owner::Oneof(_));
+        if in_oneof && label.is_some() {
+            return Err(self.error(start, "fields in oneofs must not have labels"));
+        }
+        if !in_oneof && !self.proto3 && label.is_none() {
+            return Err(self.unexpected("'required', 'optional' or 'repeated'"));
+        }
+        if self.proto3 && label == Some(LABEL_REQUIRED) {
+            return Err(self.error(start, "required fields are not allowed in proto3"));
+        }
+        let proto3_optional =
+            self.proto3 && label == Some(LABEL_OPTIONAL) && matches!(owner, Owner::Message);
+
+        let mut field = descriptor::FieldDescriptorProto::new();
+        field.set_label(label.unwrap_or(LABEL_OPTIONAL));
+        if let Owner::Oneof(index) = owner {
+            field.set_oneof_index(index);
+        }
+        let target = match owner {
+            Owner::Extend(..) => Target::Extension(path.to_vec(), fields.len() as i32),
+            _ => Target::Field(path.to_vec(), fields.len() as i32),
+        };
+
+        let type_pos = self.pos();
+        if self.is_keyword("group") {
+            if let Token::Ident(_) = *self.peek_at(1) {
+                self.advance();
+                if self.proto3 {
+                    return Err(self.error(type_pos, "groups are not allowed in proto3"));
+                }
+                let name_pos = self.pos();
+                let name = self.expect_ident()?;
+                if !name.starts_with(|c: char| c.is_ascii_uppercase()) {
+                    return Err(
+                        self.error(name_pos, "group names must start with a capital letter")
+                    );
+                }
+                field.set_name(name.to_ascii_lowercase());
+                field.set_field_type(descriptor::FieldDescriptorProto_Type::TYPE_GROUP);
+                field.set_type_name(format!(".{}", join(scope, &name)));
+                self.parse_field_number(&mut field)?;
+                self.parse_field_options(&mut field, &target, scope)?;
+
+                let mut group_path = path.to_vec();
+                group_path.push(nested.len() as i32);
+                let group = self.parse_message(name, group_path, scope)?;
+                nested.push(group);
+                return self.finish_field(owner, field, target, scope, fields, proto3_optional);
+            }
+        }
+
+        let type_name = self.parse_type_name()?;
+        match scalar_type(&type_name) {
+            Some(field_type) => field.set_field_type(field_type),
+            None => {
+                field.set_type_name(type_name.clone());
+                self.refs.push(TypeRef {
+                    kind: RefKind::FieldType(target.clone()),
+                    name: type_name,
+                    scope: scope.to_owned(),
+                    pos: type_pos,
+                });
+            }
+        }
+        field.set_name(self.expect_ident()?);
+        self.parse_field_number(&mut field)?;
+        self.parse_field_options(&mut field, &target, scope)?;
+        self.expect_symbol(';')?;
+        self.finish_field(owner, field, target, scope, fields, proto3_optional)
+    }
+
+    fn finish_field(
+        &mut self,
+        owner: Owner,
+        mut field: descriptor::FieldDescriptorProto,
+        target: Target,
+        scope: &str,
+        fields: &mut Vec<descriptor::FieldDescriptorProto>,
+        proto3_optional: bool,
+    ) -> error::Result<bool> {
+        if !field.has_json_name() {
+            field.set_json_name(json_name(field.get_name()));
+        }
+        if let Owner::Extend(extendee, pos) = owner {
+            field.set_extendee(extendee.to_owned());
+            self.refs.push(TypeRef {
+                kind: RefKind::Extendee(target),
+                name: extendee.to_owned(),
+                scope: scope.to_owned(),
+                pos,
+            });
+        }
+        if proto3_optional {
+            // The descriptor.proto supported by the protobuf crate predates the
+            // `proto3_optional` field, so it is stored as an unknown field.
+            field.mut_unknown_fields().add_varint(17, 1);
+        }
+        fields.push(field);
+        Ok(proto3_optional)
+    }
+
+    fn parse_field_number(
+        &mut self,
+        field: &mut descriptor::FieldDescriptorProto,
+    ) -> error::Result<()> {
+        self.expect_symbol('=')?;
+        let pos = self.pos();
+        let number = self.expect_int()?;
+        if number == 0 || number > MAX_FIELD_NUMBER {
+            return Err(self.error(pos, format!("field number out of range: {}", number)));
+        }
+        field.set_number(number as i32);
+        Ok(())
+    }
+
+    fn parse_field_options(
+        &mut self,
+        field: &mut descriptor::FieldDescriptorProto,
+        target: &Target,
+        scope: &str,
+    ) -> error::Result<()> {
+        for option in self.parse_bracket_options(target, scope)? {
+            let pos = option.pos;
+            let name = match option.name.as_slice() {
+                [(name, false)] => name.as_str(),
+                _ => "",
+            };
+            match name {
+                "default" => {
+                    if self.proto3 {
+                        return Err(
+                            self.error(option.pos, "default values are not allowed in proto3")
+                        );
+                    }
+                    let default_value = self.default_value(field, option.value, option.pos)?;
+                    if !field.has_field_type() {
+                        self.enum_defaults.push((target.clone(), option.pos));
+                    }
+                    field.set_default_value(default_value);
+                }
+                "json_name" => match option.value {
+                    Constant::Str(bytes) => field.set_json_name(
+                        String::from_utf8(bytes)
+                            .map_err(|_| self.error(pos, "string is not valid UTF-8"))?,
+                    ),
+                    _ => return Err(self.error(pos, "json_name must be a string")),
+                },
+                _ => self.options.push(option),
+            }
+        }
+        Ok(())
+    }
+
+    /// Formats a field default the way `descriptor.proto` expects it.
+    fn default_value(
+        &self,
+        field: &descriptor::FieldDescriptorProto,
+        value: Constant,
+        pos: Pos,
+    ) -> error::Result<String> {
+        use protobuf::descriptor::FieldDescriptorProto_Type::*;
+
+        let bad = || {
+            self.error(
+                pos,
+                format!("bad default value for field {}", field.get_name()),
+            )
+        };
+        if !field.has_field_type() {
+            // The field has a named type, which has to be an enum; the value is checked once the
+            // type has been resolved.
+            return match value {
+                Constant::Ident(name) => Ok(name),
+                _ => Err(bad()),
+            };
+        }
+        match (field.get_field_type(), value) {
+            (TYPE_BOOL, Constant::Ident(ref b)) if b == "true" || b == "false" => Ok(b.clone()),
+            (TYPE_STRING, Constant::Str(bytes)) => {
+                String::from_utf8(bytes).map_err(|_| self.error(pos, "string is not valid UTF-8"))
+            }
+            (TYPE_BYTES, Constant::Str(bytes)) => Ok(c_escape(&bytes)),
+            (field_type @ TYPE_FLOAT, ref value) | (field_type @ TYPE_DOUBLE, ref value) => {
+                let f = float_constant(value).ok_or_else(bad)?;
+                Ok(format_float(f, field_type == TYPE_FLOAT))
+            }
+            (field_type, Constant::Int(negative, magnitude)) => {
+                int_constant(field_type, negative, magnitude)
+                    .map(|i| i.to_string())
+                    .ok_or_else(bad)
+            }
+            _ => Err(bad()),
+        }
+    }
+
+    fn parse_bracket_options(
+        &mut self,
+        target: &Target,
+        scope: &str,
+    ) -> error::Result<Vec<PendingOption>> {
+        let mut options = Vec::new();
+        if !self.consume_symbol('[') {
+            return Ok(options);
+        }
+        loop {
+            let pos = self.pos();
+            let name = self.parse_option_name()?;
+            self.expect_symbol('=')?;
+            let value = self.parse_constant()?;
+            options.push(PendingOption {
+                target: target.clone(),
+                name,
+                value,
+                scope: scope.to_owned(),
+                pos,
+            });
+            if self.consume_symbol(']') {
+                return Ok(options);
+            }
+            self.expect_symbol(',')?;
+        }
+    }
+
+    fn parse_option_statement(&mut self, target: Target, scope: &str) -> error::Result<()> {
+        let pos = self.pos();
+        let name = self.parse_option_name()?;
+        self.expect_symbol('=')?;
+        let value = self.parse_constant()?;
+        self.expect_symbol(';')?;
+        self.options.push(PendingOption {
+            target,
+            name,
+            value,
+            scope: scope.to_owned(),
+            pos,
+        });
+        Ok(())
+    }
+
+    /// Parses an option name; every part is paired with whether it names an extension.
+    fn parse_option_name(&mut self) -> error::Result<Vec<(String, bool)>> {
+        let mut parts = Vec::new();
+        loop {
+            if self.consume_symbol('(') {
+                parts.push((self.parse_type_name()?, true));
+                self.expect_symbol(')')?;
+            } else {
+                parts.push((self.expect_ident()?, false));
+            }
+            if !self.consume_symbol('.') {
+                return Ok(parts);
+            }
+        }
+    }
+
+    fn parse_constant(&mut self) -> error::Result<Constant> {
+        if let Token::Str(_) = *self.peek() {
+            return Ok(Constant::Str(self.expect_string()?));
+        }
+        if self.consume_symbol('{') {
+            let mut depth = 1;
+            while depth > 0 {
+                match self.advance() {
+                    Token::Symbol('{') => depth += 1,
+                    Token::Symbol('}') => depth -= 1,
+                    Token::Eof => return Err(self.unexpected("'}'")),
+                    _ => {}
+                }
+            }
+            return Ok(Constant::Aggregate);
+        }
+
+        let pos = self.pos();
+        let negative = self.consume_symbol('-');
+        if !negative {
+            self.consume_symbol('+');
+        }
+        match *self.peek() {
+            Token::Int(i) => {
+                self.advance();
+                Ok(Constant::Int(negative, i))
+            }
+            Token::Float(f) => {
+                self.advance();
+                Ok(Constant::Float(if negative { -f } else { f }))
+            }
+            Token::Ident(ref ident) if negative => {
+                let f = match ident.as_str() {
+                    "inf" | "infinity" => f64::NEG_INFINITY,
+                    "nan" => f64::NAN,
+                    _ => return Err(self.error(pos, "expected a number")),
+                };
+                self.advance();
+                Ok(Constant::Float(f))
+            }
+            Token::Ident(ref ident) => {
+                let ident = ident.clone();
+                self.advance();
+                Ok(Constant::Ident(ident))
+            }
+            _ => Err(self.unexpected("a constant")),
+        }
+    }
+
+    /// Parses comma separated numbers and ranges, returning the inclusive bounds of each.
+    fn parse_ranges(&mut self, min: i64, max: i64) -> error::Result<Vec<(i32, i32)>> {
+        let mut ranges = Vec::new();
+        loop {
+            let pos = self.pos();
+            let start = self.parse_range_bound(min, max)?;
+            let end = if self.consume_keyword("to") {
+                if self.consume_keyword("max") {
+                    max
+                } else {
+                    self.parse_range_bound(min, max)?
+                }
+            } else {
+                start
+            };
+            if end < start {
+                return Err(self.error(pos, "range end must not be smaller than its start"));
+            }
+            ranges.push((start as i32, end as i32));
+            if !self.consume_symbol(',') {
+                return Ok(ranges);
+            }
+        }
+    }
+
+    fn parse_range_bound(&mut self, min: i64, max: i64) -> error::Result<i64> {
+        let pos = self.pos();
+        let negative = self.consume_symbol('-');
+        let magnitude = self.expect_int()? as i128;
+        let value = if negative { -magnitude } else { magnitude };
+        if value < i128::from(min) || value > i128::from(max) {
+            return Err(self.error(pos, format!("number out of range: {}", value)));
+        }
+        Ok(value as i64)
+    }
+
+    fn parse_reserved_names(&mut self) -> error::Result<Vec<String>> {
+        let mut names = Vec::new();
+        loop {
+            names.push(self.expect_utf8()?);
+            if !self.consume_symbol(',') {
+                return Ok(names);
+            }
+        }
+    }
+
+    fn parse_oneof(
+        &mut self,
+        path: &[i32],
+        scope: &str,
+        fields: &mut Vec<descriptor::FieldDescriptorProto>,
+        nested: &mut Vec<descriptor::DescriptorProto>,
+        oneofs: &mut Vec<descriptor::OneofDescriptorProto>,
+    ) -> error::Result<()> {
+        let index = oneofs.len() as i32;
+        let mut oneof = descriptor::OneofDescriptorProto::new();
+        oneof.set_name(self.expect_ident()?);
+        oneofs.push(oneof);
+        self.expect_symbol('{')?;
+        loop {
+            match *self.peek() {
+                Token::Symbol('}') => {
+                    self.advance();
+                    return Ok(());
+                }
+                Token::Symbol(';') => {
+                    self.advance();
+                }
+                Token::Ident(_) if self.is_keyword("option") => {
+                    self.advance();
+                    self.parse_option_statement(Target::Oneof(path.to_vec(), index), scope)?;
+                }
+                Token::Ident(_) => {
+                    self.parse_field(Owner::Oneof(index), path, scope, fields, nested)?;
+                }
+                _ => return Err(self.unexpected("a field or '}'")),
+            }
+        }
+    }
+
+    fn parse_map_field(
+        &mut self,
+        path: &[i32],
+        scope: &str,
+        fields: &mut Vec<descriptor::FieldDescriptorProto>,
+        nested: &mut Vec<descriptor::DescriptorProto>,
+    ) -> error::Result<()> {
+        use protobuf::descriptor::FieldDescriptorProto_Label::*;
+        use protobuf::descriptor::FieldDescriptorProto_Type::*;
+
+        self.expect_symbol('<')?;
+        let key_pos = self.pos();
+        let key_type_name = self.parse_type_name()?;
+        let key_type = match scalar_type(&key_type_name) {
+            Some(TYPE_FLOAT) | Some(TYPE_DOUBLE) | Some(TYPE_BYTES) | None => {
+                return Err(self.error(key_pos, format!("invalid map key type: {}", key_type_name)))
+            }
+            Some(key_type) => key_type,
+        };
+        self.expect_symbol(',')?;
+        let value_pos = self.pos();
+        let value_type_name = self.parse_type_name()?;
+        self.expect_symbol('>')?;
+        let name = self.expect_ident()?;
+
+        let entry_name = map_entry_name(&name);
+        let entry_full_name = join(scope, &entry_name);
+        let mut entry_path = path.to_vec();
+        entry_path.push(nested.len() as i32);
+
+        let mut key = descriptor::FieldDescriptorProto::new();
+        key.set_name("key".to_owned());
+        key.set_number(1);
+        key.set_label(LABEL_OPTIONAL);
+        key.set_field_type(key_type);
+        key.set_json_name("key".to_owned());
+
+        let mut value = descriptor::FieldDescriptorProto::new();
+        value.set_name("value".to_owned());
+        value.set_number(2);
+        value.set_json_name("value".to_owned());
+        value.set_label(LABEL_OPTIONAL);
+        match scalar_type(&value_type_name) {
+            Some(value_type) => value.set_field_type(value_type),
+            None => {
+                value.set_type_name(value_type_name.clone());
+                self.refs.push(TypeRef {
+                    kind: RefKind::FieldType(Target::Field(entry_path, 1)),
+                    name: value_type_name,
+                    scope: entry_full_name.clone(),
+                    pos: value_pos,
+                });
+            }
+        }
+
+        let mut entry = descriptor::DescriptorProto::new();
+        entry.set_name(entry_name);
+        entry.mut_field().push(key);
+        entry.mut_field().push(value);
+        entry.mut_options().set_map_entry(true);
+
+        let target = Target::Field(path.to_vec(), fields.len() as i32);
+        let mut field = descriptor::FieldDescriptorProto::new();
+        field.set_json_name(json_name(&name));
+        field.set_name(name);
+        field.set_label(LABEL_REPEATED);
+        field.set_field_type(TYPE_MESSAGE);
+        field.set_type_name(format!(".{}", entry_full_name));
+        self.parse_field_number(&mut field)?;
+        self.parse_field_options(&mut field, &target, scope)?;
+        self.expect_symbol(';')?;
+
+        nested.push(entry);
+        fields.push(field);
+        Ok(())
+    }
+
+    fn parse_extend(
+        &mut self,
+        path: &[i32],
+        scope: &str,
+        extensions: &mut Vec<descriptor::FieldDescriptorProto>,
+        nested: &mut Vec<descriptor::DescriptorProto>,
+    ) -> error::Result<()> {
+        let pos = self.pos();
+        let extendee = self.parse_type_name()?;
+        self.expect_symbol('{')?;
+        loop {
+            match *self.peek() {
+                Token::Symbol('}') => {
+                    self.advance();
+                    return Ok(());
+                }
+                Token::Symbol(';') => {
+                    self.advance();
+                }
+                Token::Ident(_) => {
+                    let owner = Owner::Extend(&extendee, pos);
+                    self.parse_field(owner, path, scope, extensions, nested)?;
+                }
+                _ => return Err(self.unexpected("a field or '}'")),
+            }
+        }
+    }
+
+    fn parse_enum(
+        &mut self,
+        path: &[i32],
+        index: i32,
+        scope: &str,
+    ) -> error::Result<descriptor::EnumDescriptorProto> {
+        let name_pos = self.pos();
+        let name = self.expect_ident()?;
+        let full_name = join(scope, &name);
+        let mut result = descriptor::EnumDescriptorProto::new();
+        result.set_name(name);
+        self.expect_symbol('{')?;
+
+        let mut first_value = None;
+        loop {
+            let pos = self.pos();
+            let keyword = match *self.peek() {
+                Token::Symbol('}') => {
+                    self.advance();
+                    break;
+                }
+                Token::Symbol(';') => {
+                    self.advance();
+                    continue;
+                }
+                Token::Ident(ref keyword) => keyword.clone(),
+                _ => return Err(self.unexpected("an enum value or '}'")),
+            };
+            if keyword == "option" && *self.peek_at(1) != Token::Symbol('=') {
+                self.advance();
+                self.parse_option_statement(Target::Enum(path.to_vec(), index), &full_name)?;
+            } else if keyword == "reserved" && *self.peek_at(1) != Token::Symbol('=') {
+                // Enum reserved ranges and names aren't part of the descriptors, so they are only
+                // checked for syntax.
+                self.advance();
+                if let Token::Str(_) = *self.peek() {
+                    self.parse_reserved_names()?;
+                } else {
+                    self.parse_ranges(i64::from(i32::MIN), i64::from(i32::MAX))?;
+                }
+                self.expect_symbol(';')?;
+            } else {
+                self.advance();
+                self.expect_symbol('=')?;
+                let number =
+                    self.parse_range_bound(i64::from(i32::MIN), i64::from(i32::MAX))? as i32;
+                let target =
+                    Target::EnumValue(path.to_vec(), index, result.get_value().len() as i32);
+                let options = self.parse_bracket_options(&target, &full_name)?;
+                self.options.extend(options);
+                self.expect_symbol(';')?;
+
+                let mut value = descriptor::EnumValueDescriptorProto::new();
+                value.set_name(keyword);
+                value.set_number(number);
+                result.mut_value().push(value);
+                first_value.get_or_insert((number, pos));
+            }
+        }
+
+        match first_value {
+            None => Err(self.error(name_pos, "enums must contain at least one value")),
+            Some((number, pos)) if self.proto3 && number != 0 => {
+                Err(self.error(pos, "the first enum value must be zero in proto3"))
+            }
+            Some(_) => Ok(result),
+        }
+    }
+
+    fn parse_service(
+        &mut self,
+        index: i32,
+        scope: &str,
+    ) -> error::Result<descriptor::ServiceDescriptorProto> {
+        let name = self.expect_ident()?;
+        let full_name = join(scope, &name);
+        let mut service = descriptor::ServiceDescriptorProto::new();
+        service.set_name(name);
+        self.expect_symbol('{')?;
+        loop {
+            let keyword = match *self.peek() {
+                Token::Symbol('}') => {
+                    self.advance();
+                    return Ok(service);
+                }
+                Token::Symbol(';') => {
+                    self.advance();
+                    continue;
+                }
+                Token::Ident(ref keyword) => keyword.clone(),
+                _ => return Err(self.unexpected("an rpc or '}'")),
+            };
+            match keyword.as_str() {
+                "option" => {
+                    self.advance();
+                    self.parse_option_statement(Target::Service(index), &full_name)?;
+                }
+                "rpc" => {
+                    self.advance();
+                    let method_index = service.get_method().len() as i32;
+                    let method = self.parse_method(index, method_index, &full_name)?;
+                    service.mut_method().push(method);
+                }
+                _ => return Err(self.unexpected("an rpc or '}'")),
+            }
+        }
+    }
+
+    fn parse_method(
+        &mut self,
+        service_index: i32,
+        index: i32,
+        scope: &str,
+    ) -> error::Result<descriptor::MethodDescriptorProto> {
+        let mut method = descriptor::MethodDescriptorProto::new();
+        method.set_name(self.expect_ident()?);
+
+        self.expect_symbol('(')?;
+        if self.parse_stream_keyword() {
+            method.set_client_streaming(true);
+        }
+        let pos = self.pos();
+        let input_type = self.parse_type_name()?;
+        method.set_input_type(input_type.clone());
+        self.refs.push(TypeRef {
+            kind: RefKind::Input(service_index, index),
+            name: input_type,
+            scope: scope.to_owned(),
+            pos,
+        });
+        self.expect_symbol(')')?;
+
+        if !self.consume_keyword("returns") {
+            return Err(self.unexpected("'returns'"));
+        }
+        self.expect_symbol('(')?;
+        if self.parse_stream_keyword() {
+            method.set_server_streaming(true);
+        }
+        let pos = self.pos();
+        let output_type = self.parse_type_name()?;
+        method.set_output_type(output_type.clone());
+        self.refs.push(TypeRef {
+            kind: RefKind::Output(service_index, index),
+            name: output_type,
+            scope: scope.to_owned(),
+            pos,
+        });
+        self.expect_symbol(')')?;
+
+        if self.consume_symbol('{') {
+            loop {
+                match *self.peek() {
+                    Token::Symbol('}') => {
+                        self.advance();
+                        break;
+                    }
+                    Token::Symbol(';') => {
+                        self.advance();
+                    }
+                    _ if self.is_keyword("option") => {
+                        self.advance();
+                        let target = Target::Method(service_index, index);
+                        self.parse_option_statement(target, scope)?;
+                    }
+                    _ => return Err(self.unexpected("an option or '}'")),
+                }
+            }
+        } else {
+            self.expect_symbol(';')?;
+        }
+        Ok(method)
+    }
+
+    /// Consumes a `stream` keyword, unless it is actually the name of the streamed type.
+    fn parse_stream_keyword(&mut self) -> bool {
+        if self.is_keyword("stream") {
+            if let Token::Ident(_) | Token::Symbol('.') = *self.peek_at(1) {
+                self.advance();
+                return true;
+            }
+        }
+        false
+    }
+}
+
+fn scalar_type(name: &str) -> Option<descriptor::FieldDescriptorProto_Type> {
+    use protobuf::descriptor::FieldDescriptorProto_Type::*;
+
+    Some(match name {
+        "double" => TYPE_DOUBLE,
+        "float" => TYPE_FLOAT,
+        "int64" => TYPE_INT64,
+        "uint64" => TYPE_UINT64,
+        "int32" => TYPE_INT32,
+        "fixed64" => TYPE_FIXED64,
+        "fixed32" => TYPE_FIXED32,
+        "bool" => TYPE_BOOL,
+        "string" => TYPE_STRING,
+        "bytes" => TYPE_BYTES,
+        "uint32" => TYPE_UINT32,
+        "sfixed32" => TYPE_SFIXED32,
+        "sfixed64" => TYPE_SFIXED64,
+        "sint32" => TYPE_SINT32,
+        "sint64" => TYPE_SINT64,
+        _ => return None,
+    })
+}
+
+/// Returns the JSON name that protoc derives from a field name.
+fn json_name(field_name: &str) -> String {
+    let mut result = String::new();
+    let mut upper = false;
+    for c in field_name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            result.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Returns the name of the message type that protoc generates for a map field.
+fn map_entry_name(field_name: &str) -> String {
+    let mut result = String::new();
+    let mut upper = true;
+    for c in field_name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            result.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            result.push(c);
+        }
+    }
+    result.push_str("Entry");
+    result
+}
+
+/// Returns the value of an integer constant, if it fits in the specified integral field type.
+fn int_constant(
+    field_type: descriptor::FieldDescriptorProto_Type,
+    negative: bool,
+    magnitude: u64,
+) -> Option<i128> {
+    use protobuf::descriptor::FieldDescriptorProto_Type::*;
+
+    let (min, max) = match field_type {
+        TYPE_INT32 | TYPE_SINT32 | TYPE_SFIXED32 => (i128::from(i32::MIN), i128::from(i32::MAX)),
+        TYPE_INT64 | TYPE_SINT64 | TYPE_SFIXED64 => (i128::from(i64::MIN), i128::from(i64::MAX)),
+        TYPE_UINT32 | TYPE_FIXED32 => (0, i128::from(u32::MAX)),
+        TYPE_UINT64 | TYPE_FIXED64 => (0, i128::from(u64::MAX)),
+        _ => return None,
+    };
+    let value = if negative {
+        -i128::from(magnitude)
+    } else {
+        i128::from(magnitude)
+    };
+    if (min..=max).contains(&value) {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+fn float_constant(value: &Constant) -> Option<f64> {
+    match *value {
+        Constant::Int(negative, magnitude) if negative => Some(-(magnitude as f64)),
+        Constant::Int(_, magnitude) => Some(magnitude as f64),
+        Constant::Float(f) => Some(f),
+        Constant::Ident(ref ident) => match ident.as_str() {
+            "inf" | "infinity" => Some(f64::INFINITY),
+            "nan" => Some(f64::NAN),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Formats a floating point default value the way protoc does, which is like `printf("%g")` with
+/// the smallest precision that round trips.
+fn format_float(f: f64, single: bool) -> String {
+    if f.is_nan() {
+        return "nan".to_owned();
+    } else if f.is_infinite() {
+        return if f > 0.0 { "inf" } else { "-inf" }.to_owned();
+    }
+
+    let (scientific, fixed) = if single {
+        (format!("{:e}", f as f32), (f as f32).to_string())
+    } else {
+        (format!("{:e}", f), f.to_string())
+    };
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap());
+    let exponent = exponent[1..].parse::<i32>().unwrap();
+    let digits = mantissa.chars().filter(char::is_ascii_digit).count();
+    let precision = match (single, digits) {
+        (true, 0..=6) => 6,
+        (true, _) => 9,
+        (false, 0..=15) => 15,
+        (false, _) => 17,
+    };
+    if exponent < -4 || exponent >= precision {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+    } else {
+        fixed
+    }
+}
+
+/// Escapes bytes the way protoc does for the default values of bytes fields.
+fn c_escape(bytes: &[u8]) -> String {
+    let mut result = String::new();
+    for &b in bytes {
+        match b {
+            b'\n' => result.push_str("\\n"),
+            b'\r' => result.push_str("\\r"),
+            b'\t' => result.push_str("\\t"),
+            b'"' => result.push_str("\\\""),
+            b'\'' => result.push_str("\\'"),
+            b'\\' => result.push_str("\\\\"),
+            0x20..=0x7e => result.push(b as char),
+            _ => result.push_str(&format!("\\{:03o}", b)),
+        }
+    }
+    result
+}
+
+/// Calls `visit` with the full name of every type and extension defined in the specified file.
+fn visit_file<F>(file: &descriptor::FileDescriptorProto, visit: &mut F)
+where
+    F: FnMut(String, Item),
+{
+    let scope = file.get_package();
+    for message in file.get_message_type() {
+        visit_message(scope, message, visit);
+    }
+    for enum_type in file.get_enum_type() {
+        visit(join(scope, enum_type.get_name()), Item::Enum(enum_type));
+    }
+    for extension in file.get_extension() {
+        visit(
+            join(scope, extension.get_name()),
+            Item::Extension(extension),
+        );
+    }
+    for service in file.get_service() {
+        visit(join(scope, service.get_name()), Item::Service);
+    }
+}
+
+fn visit_message<F>(scope: &str, message: &descriptor::DescriptorProto, visit: &mut F)
+where
+    F: FnMut(String, Item),
+{
+    let full_name = join(scope, message.get_name());
+    for nested in message.get_nested_type() {
+        visit_message(&full_name, nested, visit);
+    }
+    for enum_type in message.get_enum_type() {
+        visit(
+            join(&full_name, enum_type.get_name()),
+            Item::Enum(enum_type),
+        );
+    }
+    for extension in message.get_extension() {
+        visit(
+            join(&full_name, extension.get_name()),
+            Item::Extension(extension),
+        );
+    }
+    visit(full_name, Item::Message);
+}
+
+fn message_mut<'a>(
+    file: &'a mut descriptor::FileDescriptorProto,
+    path: &[i32],
+) -> &'a mut descriptor::DescriptorProto {
+    let mut message = &mut file.mut_message_type()[path[0] as usize];
+    for &index in &path[1..] {
+        message = &mut message.mut_nested_type()[index as usize];
+    }
+    message
+}
+
+fn field_mut<'a>(
+    file: &'a mut descriptor::FileDescriptorProto,
+    target: &Target,
+) -> &'a mut descriptor::FieldDescriptorProto {
+    match *target {
+        Target::Field(ref path, index) => &mut message_mut(file, path).mut_field()[index as usize],
+        Target::Extension(ref path, index) if path.is_empty() => {
+            &mut file.mut_extension()[index as usize]
+        }
+        Target::Extension(ref path, index) => {
+            &mut message_mut(file, path).mut_extension()[index as usize]
+        }
+        _ => unreachable!("not a field target: {:?}", target),
+    }
+}
+
+fn enum_mut<'a>(
+    file: &'a mut descriptor::FileDescriptorProto,
+    path: &[i32],
+    index: i32,
+) -> &'a mut descriptor::EnumDescriptorProto {
+    if path.is_empty() {
+        &mut file.mut_enum_type()[index as usize]
+    } else {
+        &mut message_mut(file, path).mut_enum_type()[index as usize]
+    }
+}
+
+/// Returns the name of the options message of the specified target, and its unknown fields.
+fn options_fields<'a>(
+    file: &'a mut descriptor::FileDescriptorProto,
+    target: &Target,
+) -> (&'static str, &'a mut protobuf::UnknownFields) {
+    match *target {
+        Target::File => ("FileOptions", file.mut_options().mut_unknown_fields()),
+        Target::Message(ref path) => (
+            "MessageOptions",
+            message_mut(file, path).mut_options().mut_unknown_fields(),
+        ),
+        Target::Field(..) | Target::Extension(..) => (
+            "FieldOptions",
+            field_mut(file, target).mut_options().mut_unknown_fields(),
+        ),
+        Target::Oneof(ref path, index) => (
+            "OneofOptions",
+            message_mut(file, path).mut_oneof_decl()[index as usize]
+                .mut_options()
+                .mut_unknown_fields(),
+        ),
+        Target::Enum(ref path, index) => (
+            "EnumOptions",
+            enum_mut(file, path, index)
+                .mut_options()
+                .mut_unknown_fields(),
+        ),
+        Target::EnumValue(ref path, index, value) => (
+            "EnumValueOptions",
+            enum_mut(file, path, index).mut_value()[value as usize]
+                .mut_options()
+                .mut_unknown_fields(),
+        ),
+        Target::Service(index) => (
+            "ServiceOptions",
+            file.mut_service()[index as usize]
+                .mut_options()
+                .mut_unknown_fields(),
+        ),
+        Target::Method(service, index) => (
+            "MethodOptions",
+            file.mut_service()[service as usize].mut_method()[index as usize]
+                .mut_options()
+                .mut_unknown_fields(),
+        ),
+    }
+}
+
+impl Schema {
+    fn new(files: &[ParsedFile]) -> error::Result<Schema> {
+        let mut schema = Schema {
+            symbols: collections::HashMap::new(),
+            enums: collections::HashMap::new(),
+            extensions: collections::HashMap::new(),
+        };
+
+        // Standard options can use the enums of descriptor.proto even if it isn't imported.
+        let mut add_enums = |name: String, item: Item| {
+            if let Item::Enum(enum_type) = item {
+                let values = enum_type
+                    .get_value()
+                    .iter()
+                    .map(|v| (v.get_name().to_owned(), v.get_number()))
+                    .collect();
+                schema.enums.insert(format!(".{}", name), values);
+            }
+        };
+        visit_file(descriptor::file_descriptor_proto(), &mut add_enums);
+        for file in files {
+            visit_file(&file.proto, &mut add_enums);
+        }
+
+        for (index, file) in files.iter().enumerate() {
+            let package = file.proto.get_package();
+            for (i, _) in package.match_indices('.').chain(Some((package.len(), ""))) {
+                if i > 0 {
+                    schema
+                        .symbols
+                        .entry(package[..i].to_owned())
+                        .or_insert((SymbolKind::Package, None));
+                }
+            }
+
+            let mut duplicate = None;
+            visit_file(&file.proto, &mut |name, item| {
+                let kind = match item {
+                    Item::Message => SymbolKind::Message,
+                    Item::Enum(_) => SymbolKind::Enum,
+                    Item::Extension(_) => SymbolKind::Extension,
+                    Item::Service => SymbolKind::Service,
+                };
+                match schema.symbols.get(&name) {
+                    Some(&(SymbolKind::Package, _)) | None => {
+                        schema.symbols.insert(name, (kind, Some(index)));
+                    }
+                    Some(&(_, other)) => {
+                        duplicate.get_or_insert((name, other));
+                    }
+                }
+            });
+            if let Some((name, other)) = duplicate {
+                let other = other.map_or("", |o| files[o].proto.get_name());
+                return Err(file_error(
+                    file.proto.get_name(),
+                    Pos::default(),
+                    format!("{} is already defined in {}", name, other),
+                ));
+            }
+        }
+        Ok(schema)
+    }
+
+    fn add_extensions(&mut self, files: &[ParsedFile]) {
+        for file in files {
+            visit_file(&file.proto, &mut |name, item| {
+                if let Item::Extension(extension) = item {
+                    self.extensions.insert(name, extension.clone());
+                }
+            });
+        }
+    }
+
+    fn find(&self, name: &str, visible: &collections::HashSet<usize>) -> Option<SymbolKind> {
+        match self.symbols.get(name) {
+            Some(&(kind, None)) => Some(kind),
+            Some(&(kind, Some(file))) if visible.contains(&file) => Some(kind),
+            _ => None,
+        }
+    }
+
+    /// Resolves a type name relative to a scope like protoc does: the first component of the name
+    /// is looked up in the scope and then in each enclosing scope, and the rest of the name must
+    /// then be found within the first match.  On failure, returns the full names that were tried.
+    fn resolve(
+        &self,
+        name: &str,
+        scope: &str,
+        visible: &collections::HashSet<usize>,
+        accept: &[SymbolKind],
+    ) -> Result<(String, SymbolKind), Vec<String>> {
+        if let Some(absolute) = name.strip_prefix('.') {
+            return match self.find(absolute, visible) {
+                Some(kind) if accept.contains(&kind) => Ok((absolute.to_owned(), kind)),
+                _ => Err(vec![absolute.to_owned()]),
+            };
+        }
+
+        let first = name.split('.').next().unwrap_or(name);
+        let mut tried = Vec::new();
+        let mut scope = scope;
+        loop {
+            let candidate = join(scope, name);
+            tried.push(candidate.clone());
+            let found = self.find(&join(scope, first), visible);
+            if first.len() == name.len() {
+                // A symbol of the wrong kind doesn't stop the search.
+                if let Some(kind) = found.filter(|kind| accept.contains(kind)) {
+                    return Ok((candidate, kind));
+                }
+            } else if let Some(SymbolKind::Message) | Some(SymbolKind::Package) = found {
+                return match self.find(&candidate, visible) {
+                    Some(kind) if accept.contains(&kind) => Ok((candidate, kind)),
+                    _ => Err(tried),
+                };
+            }
+            if scope.is_empty() {
+                return Err(tried);
+            }
+            scope = scope.rfind('.').map_or("", |i| &scope[..i]);
+        }
+    }
+
+    fn resolve_refs(
+        &self,
+        file: &mut ParsedFile,
+        visible: &collections::HashSet<usize>,
+    ) -> error::Result<()> {
+        use protobuf::descriptor::FieldDescriptorProto_Type::*;
+
+        let name = file.proto.get_name().to_owned();
+        for type_ref in &file.refs {
+            let accept: &[SymbolKind] = match type_ref.kind {
+                RefKind::FieldType(_) => &[SymbolKind::Message, SymbolKind::Enum],
+                _ => &[SymbolKind::Message],
+            };
+            let (full_name, kind) = self
+                .resolve(&type_ref.name, &type_ref.scope, visible, accept)
+                .map_err(|tried| {
+                    file_error(
+                        &name,
+                        type_ref.pos,
+                        format!(
+                            "unknown type: {} (tried {})",
+                            type_ref.name,
+                            tried.join(", ")
+                        ),
+                    )
+                })?;
+            let type_name = format!(".{}", full_name);
+            match type_ref.kind {
+                RefKind::FieldType(ref target) => {
+                    let field = field_mut(&mut file.proto, target);
+                    if !field.has_field_type() {
+                        field.set_field_type(if kind == SymbolKind::Enum {
+                            TYPE_ENUM
+                        } else {
+                            TYPE_MESSAGE
+                        });
+                    }
+                    field.set_type_name(type_name);
+                }
+                RefKind::Extendee(ref target) => {
+                    field_mut(&mut file.proto, target).set_extendee(type_name)
+                }
+                RefKind::Input(service, method) => file.proto.mut_service()[service as usize]
+                    .mut_method()[method as usize]
+                    .set_input_type(type_name),
+                RefKind::Output(service, method) => file.proto.mut_service()[service as usize]
+                    .mut_method()[method as usize]
+                    .set_output_type(type_name),
+            }
+        }
+
+        for &(ref target, pos) in &file.enum_defaults {
+            let field = field_mut(&mut file.proto, target);
+            let known = self.enums.get(field.get_type_name()).map(|values| {
+                values
+                    .iter()
+                    .any(|(value, _)| value == field.get_default_value())
+            });
+            match known {
+                Some(true) => {}
+                Some(false) => {
+                    return Err(file_error(
+                        &name,
+                        pos,
+                        format!(
+                            "unknown enum value for default: {}",
+                            field.get_default_value()
+                        ),
+                    ))
+                }
+                None => {
+                    return Err(file_error(
+                        &name,
+                        pos,
+                        "only scalar and enum fields can have default values",
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_options(
+        &self,
+        file: &mut ParsedFile,
+        visible: &collections::HashSet<usize>,
+    ) -> error::Result<()> {
+        let name = file.proto.get_name().to_owned();
+        for option in &file.options {
+            let (options_name, unknown_fields) = options_fields(&mut file.proto, &option.target);
+            let field = match option.name.as_slice() {
+                [(option_name, false)] => descriptor::file_descriptor_proto()
+                    .get_message_type()
+                    .iter()
+                    .find(|m| m.get_name() == options_name)
+                    .and_then(|m| m.get_field().iter().find(|f| f.get_name() == option_name))
+                    .ok_or_else(|| {
+                        file_error(
+                            &name,
+                            option.pos,
+                            format!("unknown option: {}", option_name),
+                        )
+                    })?,
+                [(option_name, true)] => {
+                    let (full_name, _) = self
+                        .resolve(
+                            option_name,
+                            &option.scope,
+                            visible,
+                            &[SymbolKind::Extension],
+                        )
+                        .map_err(|tried| {
+                            file_error(
+                                &name,
+                                option.pos,
+                                format!(
+                                    "unknown option: ({}) (tried {})",
+                                    option_name,
+                                    tried.join(", ")
+                                ),
+                            )
+                        })?;
+                    let extension = &self.extensions[&full_name];
+                    if extension.get_extendee() != format!(".google.protobuf.{}", options_name) {
+                        return Err(file_error(
+                            &name,
+                            option.pos,
+                            format!(
+                                "({}) is not an extension of google.protobuf.{}",
+                                option_name, options_name
+                            ),
+                        ));
+                    }
+                    extension
+                }
+                _ => {
+                    return Err(file_error(
+                        &name,
+                        option.pos,
+                        "options with sub-fields are not supported",
+                    ))
+                }
+            };
+            self.encode_option(field, &option.value, unknown_fields)
+                .map_err(|message| {
+                    file_error(
+                        &name,
+                        option.pos,
+                        format!("bad value for option {}: {}", field.get_name(), message),
+                    )
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Adds the value of an option to the unknown fields of an options message.
+    fn encode_option(
+        &self,
+        field: &descriptor::FieldDescriptorProto,
+        value: &Constant,
+        unknown_fields: &mut protobuf::UnknownFields,
+    ) -> Result<(), String> {
+        use protobuf::descriptor::FieldDescriptorProto_Type::*;
+
+        let number = field.get_number() as u32;
+        match (field.get_field_type(), value) {
+            (TYPE_BOOL, Constant::Ident(b)) if b == "true" || b == "false" => {
+                unknown_fields.add_varint(number, (b == "true") as u64);
+            }
+            (TYPE_BOOL, _) => return Err("expected true or false".to_owned()),
+            (TYPE_STRING, Constant::Str(bytes)) => {
+                if std::str::from_utf8(bytes).is_err() {
+                    return Err("string is not valid UTF-8".to_owned());
+                }
+                unknown_fields.add_length_delimited(number, bytes.clone());
+            }
+            (TYPE_BYTES, Constant::Str(bytes)) => {
+                unknown_fields.add_length_delimited(number, bytes.clone());
+            }
+            (TYPE_STRING, _) | (TYPE_BYTES, _) => return Err("expected a string".to_owned()),
+            (TYPE_FLOAT, _) | (TYPE_DOUBLE, _) => {
+                let f = float_constant(value).ok_or_else(|| "expected a number".to_owned())?;
+                if field.get_field_type() == TYPE_FLOAT {
+                    unknown_fields.add_fixed32(number, (f as f32).to_bits());
+                } else {
+                    unknown_fields.add_fixed64(number, f.to_bits());
+                }
+            }
+            (TYPE_ENUM, Constant::Ident(ident)) => {
+                let number_value = self
+                    .enums
+                    .get(field.get_type_name())
+                    .and_then(|values| values.iter().find(|(v, _)| v == ident))
+                    .map(|&(_, n)| n)
+                    .ok_or_else(|| format!("unknown enum value: {}", ident))?;
+                unknown_fields.add_varint(number, i64::from(number_value) as u64);
+            }
+            (TYPE_ENUM, _) => return Err("expected an enum value name".to_owned()),
+            (TYPE_MESSAGE, _) | (TYPE_GROUP, _) => {
+                return Err("message options are not supported".to_owned())
+            }
+            (field_type, &Constant::Int(negative, magnitude)) => {
+                let v = int_constant(field_type, negative, magnitude)
+                    .ok_or_else(|| "integer out of range".to_owned())?;
+                match field_type {
+                    TYPE_SINT32 => {
+                        let v = v as i32;
+                        unknown_fields.add_varint(number, u64::from(((v << 1) ^ (v >> 31)) as u32));
+                    }
+                    TYPE_SINT64 => {
+                        let v = v as i64;
+                        unknown_fields.add_varint(number, ((v << 1) ^ (v >> 63)) as u64);
+                    }
+                    TYPE_FIXED32 | TYPE_SFIXED32 => unknown_fields.add_fixed32(number, v as u32),
+                    TYPE_FIXED64 | TYPE_SFIXED64 => unknown_fields.add_fixed64(number, v as u64),
+                    _ => unknown_fields.add_varint(number, v as u64),
+                }
+            }
+            _ => return Err("expected an integer".to_owned()),
+        }
+        Ok(())
+    }
+}
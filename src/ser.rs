@@ -0,0 +1,1420 @@
+//! Serialization of binary protocol buffer encoded data.
+//!
+//! All serialization operations require a previously loaded set of schema descriptors; see the
+//! [`descriptor`](../descriptor/index.html) module for more information.
+//!
+//! Provided that a set of descriptors have been loaded, a `Serializer` can be used to serialize
+//! anything that implements `Serialize` into a stream of bytes.  The value being serialized must
+//! look like a map or struct; its keys are matched against the field names of the message type.
+//! Extensions of the message are set with their fully qualified names in brackets as keys, i.e.
+//! `[mypackage.nickname]`.  Repeated fields take sequences, and map fields take maps whose
+//! entries are each written as an entry message.
+//!
+//! A oneof can also be set with its name as key, and a value like the deserializer visits with
+//! `Options::with_oneofs`: `None`, an externally tagged enum whose variant is the name of the
+//! member field to set, or a map with the member field as its single entry.
+//!
+//! ```
+//! extern crate serde;
+//! extern crate protobuf;
+//! extern crate serde_protobuf;
+//! extern crate serde_value;
+//!
+//! use std::collections::BTreeMap;
+//! use std::fs;
+//! use serde::ser::Serialize;
+//! use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::ser::Serializer;
+//! use serde_value::Value;
+//!
+//! # use std::io;
+//! # #[derive(Debug)] struct Error;
+//! # impl From<protobuf::ProtobufError> for Error {
+//! #   fn from(a: protobuf::ProtobufError) -> Error {
+//! #     Error
+//! #   }
+//! # }
+//! # impl From<io::Error> for Error {
+//! #   fn from(a: io::Error) -> Error {
+//! #     Error
+//! #   }
+//! # }
+//! # impl From<serde_protobuf::error::Error> for Error {
+//! #   fn from(a: serde_protobuf::error::Error) -> Error {
+//! #     Error
+//! #   }
+//! # }
+//! # impl From<serde_protobuf::error::CompatError> for Error {
+//! #   fn from(a: serde_protobuf::error::CompatError) -> Error {
+//! #     Error
+//! #   }
+//! # }
+//! # fn foo() -> Result<(), Error> {
+//! // Load a descriptor registry (see descriptor module)
+//! let mut file = fs::File::open("testdata/descriptors.pb")?;
+//! let proto = protobuf::parse_from_reader(&mut file)?;
+//! let descriptors = Descriptors::from_proto(&proto);
+//!
+//! // Set up some data to write
+//! let mut map = BTreeMap::new();
+//! map.insert(Value::String("optional_int32".to_owned()), Value::I32(42));
+//! let value = Value::Map(map);
+//!
+//! // Create a serializer
+//! let mut data = Vec::new();
+//! {
+//!     let mut output = protobuf::CodedOutputStream::vec(&mut data);
+//!     let name = ".protobuf_unittest.TestAllTypes";
+//!     let mut serializer = Serializer::for_named_message(&descriptors, name, &mut output)?;
+//!
+//!     // Serialize the value
+//!     value.serialize(&mut serializer)?;
+//! }
+//! assert_eq!(vec![8, 42], data);
+//! # Ok(())
+//! # }
+//! # fn main() {
+//! #   foo().unwrap();
+//! # }
+//! ```
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::ptr;
+
+use crate::descriptor;
+use crate::error;
+use crate::value;
+
+use protobuf;
+use protobuf::stream::wire_format;
+use serde;
+
+/// A serializer that can serialize a single message type.
+pub struct Serializer<'a, 'b> {
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::MessageDescriptor,
+    output: &'a mut protobuf::CodedOutputStream<'b>,
+}
+
+/// Serializes the fields of a message, either straight into the output stream or into a buffer
+/// that later becomes a length-delimited nested message.
+pub struct MessageSerializer<'a, 'b> {
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::MessageDescriptor,
+    output: Output<'a, 'b>,
+    field: Option<MessageKey<'a>>,
+}
+
+enum Output<'a, 'b> {
+    Stream(&'a mut protobuf::CodedOutputStream<'b>),
+    Nested(&'a mut protobuf::CodedOutputStream<'b>, u32, Vec<u8>),
+    Group(&'a mut protobuf::CodedOutputStream<'b>, u32),
+    // The entries of a map field, each buffered until its value has been serialized
+    Entries(&'a mut protobuf::CodedOutputStream<'b>, u32, Vec<u8>),
+}
+
+struct MessageKeySerializer<'a> {
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::MessageDescriptor,
+}
+
+/// What the key of a message refers to.
+#[derive(Clone, Copy)]
+enum MessageKey<'a> {
+    Field(&'a descriptor::FieldDescriptor),
+    // A oneof, whose value selects the member field to set
+    Oneof(&'a descriptor::OneofDescriptor),
+}
+
+struct MessageFieldSerializer<'a, 'b, 'c> {
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::FieldDescriptor,
+    output: &'c mut protobuf::CodedOutputStream<'b>,
+}
+
+struct OneofSerializer<'a, 'b, 'c> {
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::MessageDescriptor,
+    oneof: &'a descriptor::OneofDescriptor,
+    output: &'c mut protobuf::CodedOutputStream<'b>,
+    // The member field of the map key that was serialized last, if the value is a map
+    field: Option<&'a descriptor::FieldDescriptor>,
+}
+
+struct RepeatedValueSerializer<'a, 'b, 'c> {
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::FieldDescriptor,
+    output: &'c mut protobuf::CodedOutputStream<'b>,
+    // The elements written so far, if the field uses the packed encoding
+    packed: Option<Vec<u8>>,
+}
+
+struct ValueSerializer<'a, 'b, 'c> {
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::FieldDescriptor,
+    output: &'c mut protobuf::CodedOutputStream<'b>,
+    // Whether the value is an element of a packed field, which is written without a tag
+    packed: bool,
+}
+
+/// Generates `serde::Serializer` methods that reject their input with `self.unexpected()`.
+macro_rules! reject {
+    ($($kind:ident)*) => {
+        $(reject!(@ $kind);)*
+    };
+    (@ bool) => {
+        fn serialize_bool(self, _: bool) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ i8) => {
+        fn serialize_i8(self, _: i8) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ i16) => {
+        fn serialize_i16(self, _: i16) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ i32) => {
+        fn serialize_i32(self, _: i32) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ i64) => {
+        fn serialize_i64(self, _: i64) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ u8) => {
+        fn serialize_u8(self, _: u8) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ u16) => {
+        fn serialize_u16(self, _: u16) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ u32) => {
+        fn serialize_u32(self, _: u32) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ u64) => {
+        fn serialize_u64(self, _: u64) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ f32) => {
+        fn serialize_f32(self, _: f32) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ f64) => {
+        fn serialize_f64(self, _: f64) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ char) => {
+        fn serialize_char(self, _: char) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ str) => {
+        fn serialize_str(self, _: &str) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ bytes) => {
+        fn serialize_bytes(self, _: &[u8]) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ none) => {
+        fn serialize_none(self) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ unit) => {
+        fn serialize_unit(self) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ unit_struct) => {
+        fn serialize_unit_struct(self, _: &'static str) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ unit_variant) => {
+        fn serialize_unit_variant(
+            self,
+            _: &'static str,
+            _: u32,
+            _: &'static str,
+        ) -> error::CompatResult<Self::Ok> {
+            Err(self.unexpected())
+        }
+    };
+    (@ newtype_variant) => {
+        fn serialize_newtype_variant<T>(
+            self,
+            _: &'static str,
+            _: u32,
+            _: &'static str,
+            _: &T,
+        ) -> error::CompatResult<Self::Ok>
+        where
+            T: ?Sized + serde::Serialize,
+        {
+            Err(self.unexpected())
+        }
+    };
+    (@ seq) => {
+        fn serialize_seq(self, _: Option<usize>) -> error::CompatResult<Self::SerializeSeq> {
+            Err(self.unexpected())
+        }
+    };
+    (@ tuple) => {
+        fn serialize_tuple(self, _: usize) -> error::CompatResult<Self::SerializeTuple> {
+            Err(self.unexpected())
+        }
+    };
+    (@ tuple_struct) => {
+        fn serialize_tuple_struct(
+            self,
+            _: &'static str,
+            _: usize,
+        ) -> error::CompatResult<Self::SerializeTupleStruct> {
+            Err(self.unexpected())
+        }
+    };
+    (@ tuple_variant) => {
+        fn serialize_tuple_variant(
+            self,
+            _: &'static str,
+            _: u32,
+            _: &'static str,
+            _: usize,
+        ) -> error::CompatResult<Self::SerializeTupleVariant> {
+            Err(self.unexpected())
+        }
+    };
+    (@ map) => {
+        fn serialize_map(self, _: Option<usize>) -> error::CompatResult<Self::SerializeMap> {
+            Err(self.unexpected())
+        }
+    };
+    (@ struct) => {
+        fn serialize_struct(
+            self,
+            _: &'static str,
+            _: usize,
+        ) -> error::CompatResult<Self::SerializeStruct> {
+            Err(self.unexpected())
+        }
+    };
+    (@ struct_variant) => {
+        fn serialize_struct_variant(
+            self,
+            _: &'static str,
+            _: u32,
+            _: &'static str,
+            _: usize,
+        ) -> error::CompatResult<Self::SerializeStructVariant> {
+            Err(self.unexpected())
+        }
+    };
+}
+
+impl<'a, 'b> Serializer<'a, 'b> {
+    /// Constructs a new protocol buffer serializer for the specified message type.
+    ///
+    /// The caller must ensure that all of the information needed by the specified message
+    /// descriptor is available in the associated descriptors registry.
+    pub fn new(
+        descriptors: &'a descriptor::Descriptors,
+        descriptor: &'a descriptor::MessageDescriptor,
+        output: &'a mut protobuf::CodedOutputStream<'b>,
+    ) -> Serializer<'a, 'b> {
+        Serializer {
+            descriptors,
+            descriptor,
+            output,
+        }
+    }
+
+    /// Constructs a new protocol buffer serializer for the specified named message type.
+    ///
+    /// The message type name must be fully quailified (for example
+    /// `".google.protobuf.FileDescriptorSet"`).
+    pub fn for_named_message(
+        descriptors: &'a descriptor::Descriptors,
+        message_name: &str,
+        output: &'a mut protobuf::CodedOutputStream<'b>,
+    ) -> error::Result<Serializer<'a, 'b>> {
+        if let Some(message) = descriptors.message_by_name(message_name) {
+            Ok(Serializer::new(descriptors, message, output))
+        } else {
+            Err(error::Error::UnknownMessage {
+                name: message_name.to_owned(),
+            })
+        }
+    }
+
+    fn unexpected(&self) -> error::CompatError {
+        error::Error::ExpectedMessage {
+            name: self.descriptor.name().to_owned(),
+        }
+        .into()
+    }
+}
+
+impl<'a, 'b> fmt::Debug for Serializer<'a, 'b> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Serializer").finish()
+    }
+}
+
+impl<'a, 'b> fmt::Debug for MessageSerializer<'a, 'b> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MessageSerializer").finish()
+    }
+}
+
+impl<'a, 'b, 'c> serde::Serializer for &'c mut Serializer<'a, 'b> {
+    type Ok = ();
+    type Error = error::CompatError;
+    type SerializeSeq = serde::ser::Impossible<(), error::CompatError>;
+    type SerializeTuple = serde::ser::Impossible<(), error::CompatError>;
+    type SerializeTupleStruct = serde::ser::Impossible<(), error::CompatError>;
+    type SerializeTupleVariant = serde::ser::Impossible<(), error::CompatError>;
+    type SerializeMap = MessageSerializer<'c, 'b>;
+    type SerializeStruct = MessageSerializer<'c, 'b>;
+    type SerializeStructVariant = serde::ser::Impossible<(), error::CompatError>;
+
+    reject! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str bytes none unit_variant
+        newtype_variant seq tuple tuple_struct tuple_variant struct_variant
+    }
+
+    #[inline]
+    fn serialize_some<T>(self, value: &T) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        value.serialize(self)
+    }
+
+    #[inline]
+    fn serialize_unit(self) -> error::CompatResult<()> {
+        Ok(self.output.flush()?)
+    }
+
+    #[inline]
+    fn serialize_unit_struct(self, _: &'static str) -> error::CompatResult<()> {
+        self.serialize_unit()
+    }
+
+    #[inline]
+    fn serialize_newtype_struct<T>(self, _: &'static str, value: &T) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        value.serialize(self)
+    }
+
+    #[inline]
+    fn serialize_map(self, _: Option<usize>) -> error::CompatResult<Self::SerializeMap> {
+        Ok(MessageSerializer::new(
+            self.descriptors,
+            self.descriptor,
+            Output::Stream(self.output),
+        ))
+    }
+
+    #[inline]
+    fn serialize_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> error::CompatResult<Self::SerializeStruct> {
+        self.serialize_map(None)
+    }
+}
+
+impl<'a, 'b> MessageSerializer<'a, 'b> {
+    #[inline]
+    fn new(
+        descriptors: &'a descriptor::Descriptors,
+        descriptor: &'a descriptor::MessageDescriptor,
+        output: Output<'a, 'b>,
+    ) -> MessageSerializer<'a, 'b> {
+        MessageSerializer {
+            descriptors,
+            descriptor,
+            output,
+            field: None,
+        }
+    }
+
+    #[inline]
+    fn serialize_field_value<T>(
+        &mut self,
+        key: MessageKey<'a>,
+        value: &T,
+    ) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        let descriptors = self.descriptors;
+        let descriptor = self.descriptor;
+        match self.output {
+            Output::Stream(ref mut output) | Output::Group(ref mut output, _) => {
+                key.serialize_value(descriptors, descriptor, output, value)
+            }
+            Output::Nested(_, _, ref mut buffer) | Output::Entries(_, _, ref mut buffer) => {
+                let mut output = protobuf::CodedOutputStream::vec(buffer);
+                key.serialize_value(descriptors, descriptor, &mut output, value)?;
+                Ok(output.flush()?)
+            }
+        }
+    }
+
+    #[inline]
+    fn finish(self) -> error::Result<()> {
+        match self.output {
+            Output::Stream(output) => output.flush()?,
+            Output::Nested(output, number, buffer) => output.write_bytes(number, &buffer)?,
+            Output::Group(output, number) => {
+                output.write_tag(number, wire_format::WireTypeEndGroup)?
+            }
+            Output::Entries(..) => (),
+        }
+        Ok(())
+    }
+}
+
+impl<'a, 'b> serde::ser::SerializeMap for MessageSerializer<'a, 'b> {
+    type Ok = ();
+    type Error = error::CompatError;
+
+    #[inline]
+    fn serialize_key<T>(&mut self, key: &T) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        if let Output::Entries(..) = self.output {
+            let (key_field, value_field) = map_entry_fields(self.descriptor)?;
+            self.serialize_field_value(MessageKey::Field(key_field), key)?;
+            self.field = Some(MessageKey::Field(value_field));
+            return Ok(());
+        }
+        self.field =
+            Some(key.serialize(MessageKeySerializer::new(self.descriptors, self.descriptor))?);
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_value<T>(&mut self, value: &T) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        let field = self
+            .field
+            .take()
+            .expect("serialize_value was called before serialize_key");
+        self.serialize_field_value(field, value)?;
+        if let Output::Entries(ref mut output, number, ref mut buffer) = self.output {
+            output.write_bytes(number, buffer)?;
+            buffer.clear();
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn end(self) -> error::CompatResult<()> {
+        Ok(self.finish()?)
+    }
+}
+
+impl<'a, 'b> serde::ser::SerializeStruct for MessageSerializer<'a, 'b> {
+    type Ok = ();
+    type Error = error::CompatError;
+
+    #[inline]
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        let key = MessageKeySerializer::new(self.descriptors, self.descriptor).by_name(key)?;
+        self.serialize_field_value(key, value)
+    }
+
+    #[inline]
+    fn end(self) -> error::CompatResult<()> {
+        Ok(self.finish()?)
+    }
+}
+
+impl<'a> MessageKeySerializer<'a> {
+    #[inline]
+    fn new(
+        descriptors: &'a descriptor::Descriptors,
+        descriptor: &'a descriptor::MessageDescriptor,
+    ) -> MessageKeySerializer<'a> {
+        MessageKeySerializer {
+            descriptors,
+            descriptor,
+        }
+    }
+
+    /// Finds a field or oneof by name, or an extension by its fully qualified name in brackets.
+    #[inline]
+    fn by_name(self, name: &str) -> error::CompatResult<MessageKey<'a>> {
+        let key = match name.strip_prefix('[').and_then(|n| n.strip_suffix(']')) {
+            Some(n) => self
+                .descriptors
+                .extension_of(self.descriptor, n)
+                .map(MessageKey::Field),
+            None => match self.descriptor.field_by_name(name) {
+                Some(field) => Some(MessageKey::Field(field)),
+                // The oneofs of `proto3` `optional` fields are set through their field instead
+                None => self
+                    .descriptor
+                    .oneof_by_name(name)
+                    .filter(|o| !o.is_synthetic())
+                    .map(MessageKey::Oneof),
+            },
+        };
+        key.ok_or_else(|| {
+            error::Error::UnknownField {
+                name: name.to_owned(),
+            }
+            .into()
+        })
+    }
+
+    #[inline]
+    fn by_number(self, number: i64) -> error::CompatResult<MessageKey<'a>> {
+        i32::try_from(number)
+            .ok()
+            .and_then(|n| self.descriptors.field_or_extension(self.descriptor, n))
+            .map(MessageKey::Field)
+            .ok_or_else(|| {
+                error::Error::UnknownField {
+                    name: number.to_string(),
+                }
+                .into()
+            })
+    }
+
+    fn unexpected(&self) -> error::CompatError {
+        error::Error::Custom {
+            message: "expected a field name or number as message key".to_owned(),
+        }
+        .into()
+    }
+}
+
+impl<'a> serde::Serializer for MessageKeySerializer<'a> {
+    type Ok = MessageKey<'a>;
+    type Error = error::CompatError;
+    type SerializeSeq = serde::ser::Impossible<Self::Ok, error::CompatError>;
+    type SerializeTuple = serde::ser::Impossible<Self::Ok, error::CompatError>;
+    type SerializeTupleStruct = serde::ser::Impossible<Self::Ok, error::CompatError>;
+    type SerializeTupleVariant = serde::ser::Impossible<Self::Ok, error::CompatError>;
+    type SerializeMap = serde::ser::Impossible<Self::Ok, error::CompatError>;
+    type SerializeStruct = serde::ser::Impossible<Self::Ok, error::CompatError>;
+    type SerializeStructVariant = serde::ser::Impossible<Self::Ok, error::CompatError>;
+
+    reject! {
+        bool f32 f64 bytes none unit unit_struct newtype_variant seq tuple tuple_struct
+        tuple_variant map struct struct_variant
+    }
+
+    #[inline]
+    fn serialize_i8(self, v: i8) -> error::CompatResult<Self::Ok> {
+        self.by_number(i64::from(v))
+    }
+
+    #[inline]
+    fn serialize_i16(self, v: i16) -> error::CompatResult<Self::Ok> {
+        self.by_number(i64::from(v))
+    }
+
+    #[inline]
+    fn serialize_i32(self, v: i32) -> error::CompatResult<Self::Ok> {
+        self.by_number(i64::from(v))
+    }
+
+    #[inline]
+    fn serialize_i64(self, v: i64) -> error::CompatResult<Self::Ok> {
+        self.by_number(v)
+    }
+
+    #[inline]
+    fn serialize_u8(self, v: u8) -> error::CompatResult<Self::Ok> {
+        self.by_number(i64::from(v))
+    }
+
+    #[inline]
+    fn serialize_u16(self, v: u16) -> error::CompatResult<Self::Ok> {
+        self.by_number(i64::from(v))
+    }
+
+    #[inline]
+    fn serialize_u32(self, v: u32) -> error::CompatResult<Self::Ok> {
+        self.by_number(i64::from(v))
+    }
+
+    #[inline]
+    fn serialize_u64(self, v: u64) -> error::CompatResult<Self::Ok> {
+        self.by_number(i64::try_from(v).unwrap_or(i64::MAX))
+    }
+
+    #[inline]
+    fn serialize_char(self, v: char) -> error::CompatResult<Self::Ok> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    #[inline]
+    fn serialize_str(self, v: &str) -> error::CompatResult<Self::Ok> {
+        self.by_name(v)
+    }
+
+    #[inline]
+    fn serialize_some<T>(self, value: &T) -> error::CompatResult<Self::Ok>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        value.serialize(self)
+    }
+
+    #[inline]
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> error::CompatResult<Self::Ok> {
+        self.serialize_str(variant)
+    }
+
+    #[inline]
+    fn serialize_newtype_struct<T>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> error::CompatResult<Self::Ok>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        value.serialize(self)
+    }
+}
+
+impl<'a> MessageKey<'a> {
+    /// Serializes the value of this key of the specified message.
+    #[inline]
+    fn serialize_value<T>(
+        self,
+        descriptors: &'a descriptor::Descriptors,
+        descriptor: &'a descriptor::MessageDescriptor,
+        output: &mut protobuf::CodedOutputStream,
+        value: &T,
+    ) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        match self {
+            MessageKey::Field(field) => {
+                value.serialize(MessageFieldSerializer::new(descriptors, field, output))
+            }
+            MessageKey::Oneof(oneof) => {
+                value.serialize(OneofSerializer::new(descriptors, descriptor, oneof, output))
+            }
+        }
+    }
+}
+
+impl<'a, 'b, 'c> MessageFieldSerializer<'a, 'b, 'c> {
+    #[inline]
+    fn new(
+        descriptors: &'a descriptor::Descriptors,
+        descriptor: &'a descriptor::FieldDescriptor,
+        output: &'c mut protobuf::CodedOutputStream<'b>,
+    ) -> MessageFieldSerializer<'a, 'b, 'c> {
+        MessageFieldSerializer {
+            descriptors,
+            descriptor,
+            output,
+        }
+    }
+
+    #[inline]
+    fn value(self) -> ValueSerializer<'a, 'b, 'c> {
+        ValueSerializer::new(self.descriptors, self.descriptor, self.output)
+    }
+
+    fn unexpected(&self) -> error::CompatError {
+        bad_value(self.descriptor)
+    }
+}
+
+/// Forwards a `serde::Serializer` method to the `ValueSerializer` for singular fields, and
+/// rejects it for repeated fields.
+macro_rules! forward_singular {
+    ($($method:ident($($arg:ident: $t:ty),*) -> $ret:ty;)*) => {
+        $(
+            #[inline]
+            fn $method(self, $($arg: $t),*) -> error::CompatResult<$ret> {
+                if self.descriptor.is_repeated() {
+                    Err(self.unexpected())
+                } else {
+                    serde::Serializer::$method(self.value(), $($arg),*)
+                }
+            }
+        )*
+    };
+}
+
+impl<'a: 'c, 'b, 'c> serde::Serializer for MessageFieldSerializer<'a, 'b, 'c> {
+    type Ok = ();
+    type Error = error::CompatError;
+    type SerializeSeq = RepeatedValueSerializer<'a, 'b, 'c>;
+    type SerializeTuple = RepeatedValueSerializer<'a, 'b, 'c>;
+    type SerializeTupleStruct = serde::ser::Impossible<(), error::CompatError>;
+    type SerializeTupleVariant = serde::ser::Impossible<(), error::CompatError>;
+    type SerializeMap = MessageSerializer<'c, 'b>;
+    type SerializeStruct = MessageSerializer<'c, 'b>;
+    type SerializeStructVariant = serde::ser::Impossible<(), error::CompatError>;
+
+    reject! {
+        tuple_struct tuple_variant struct_variant
+    }
+
+    forward_singular! {
+        serialize_bool(v: bool) -> ();
+        serialize_i8(v: i8) -> ();
+        serialize_i16(v: i16) -> ();
+        serialize_i32(v: i32) -> ();
+        serialize_i64(v: i64) -> ();
+        serialize_u8(v: u8) -> ();
+        serialize_u16(v: u16) -> ();
+        serialize_u32(v: u32) -> ();
+        serialize_u64(v: u64) -> ();
+        serialize_f32(v: f32) -> ();
+        serialize_f64(v: f64) -> ();
+        serialize_char(v: char) -> ();
+        serialize_str(v: &str) -> ();
+        serialize_bytes(v: &[u8]) -> ();
+        serialize_unit_variant(name: &'static str, index: u32, variant: &'static str) -> ();
+        serialize_struct(name: &'static str, len: usize) -> Self::SerializeStruct;
+    }
+
+    #[inline]
+    fn serialize_none(self) -> error::CompatResult<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_some<T>(self, value: &T) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        value.serialize(self)
+    }
+
+    #[inline]
+    fn serialize_unit(self) -> error::CompatResult<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_unit_struct(self, _: &'static str) -> error::CompatResult<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_newtype_struct<T>(self, _: &'static str, value: &T) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        value.serialize(self)
+    }
+
+    #[inline]
+    fn serialize_newtype_variant<T>(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        if self.descriptor.is_repeated() {
+            Err(self.unexpected())
+        } else {
+            serde::Serializer::serialize_newtype_variant(self.value(), name, index, variant, value)
+        }
+    }
+
+    #[inline]
+    fn serialize_seq(self, _: Option<usize>) -> error::CompatResult<Self::SerializeSeq> {
+        if self.descriptor.is_repeated() {
+            Ok(RepeatedValueSerializer::new(
+                self.descriptors,
+                self.descriptor,
+                self.output,
+            ))
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    #[inline]
+    fn serialize_tuple(self, len: usize) -> error::CompatResult<Self::SerializeTuple> {
+        self.serialize_seq(Some(len))
+    }
+
+    #[inline]
+    fn serialize_map(self, len: Option<usize>) -> error::CompatResult<Self::SerializeMap> {
+        if !self.descriptor.is_repeated() {
+            return serde::Serializer::serialize_map(self.value(), len);
+        }
+        match self.descriptor.field_type(self.descriptors) {
+            // Each entry of a map field is written as an entry message of its own
+            descriptor::FieldType::Message(m) if self.descriptor.is_map() => {
+                let number = self.descriptor.number() as u32;
+                Ok(MessageSerializer::new(
+                    self.descriptors,
+                    m,
+                    Output::Entries(self.output, number, Vec::new()),
+                ))
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+}
+
+impl<'a, 'b, 'c> OneofSerializer<'a, 'b, 'c> {
+    #[inline]
+    fn new(
+        descriptors: &'a descriptor::Descriptors,
+        descriptor: &'a descriptor::MessageDescriptor,
+        oneof: &'a descriptor::OneofDescriptor,
+        output: &'c mut protobuf::CodedOutputStream<'b>,
+    ) -> OneofSerializer<'a, 'b, 'c> {
+        OneofSerializer {
+            descriptors,
+            descriptor,
+            oneof,
+            output,
+            field: None,
+        }
+    }
+
+    /// Finds the member field that a variant or map key refers to.
+    #[inline]
+    fn member<T>(&self, key: &T) -> error::CompatResult<&'a descriptor::FieldDescriptor>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        match key.serialize(MessageKeySerializer::new(self.descriptors, self.descriptor))? {
+            MessageKey::Field(field) => match self.descriptor.oneof_of_field(field) {
+                Some(oneof) if ptr::eq(oneof, self.oneof) => Ok(field),
+                _ => Err(self.unexpected()),
+            },
+            MessageKey::Oneof(_) => Err(self.unexpected()),
+        }
+    }
+
+    fn unexpected(&self) -> error::CompatError {
+        error::Error::BadFieldValue {
+            field: self.oneof.name().to_owned(),
+        }
+        .into()
+    }
+}
+
+impl<'a: 'c, 'b, 'c> serde::Serializer for OneofSerializer<'a, 'b, 'c> {
+    type Ok = ();
+    type Error = error::CompatError;
+    type SerializeSeq = serde::ser::Impossible<(), error::CompatError>;
+    type SerializeTuple = serde::ser::Impossible<(), error::CompatError>;
+    type SerializeTupleStruct = serde::ser::Impossible<(), error::CompatError>;
+    type SerializeTupleVariant = serde::ser::Impossible<(), error::CompatError>;
+    type SerializeMap = Self;
+    type SerializeStruct = serde::ser::Impossible<(), error::CompatError>;
+    type SerializeStructVariant = serde::ser::Impossible<(), error::CompatError>;
+
+    reject! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str bytes unit_struct unit_variant seq
+        tuple tuple_struct tuple_variant struct struct_variant
+    }
+
+    #[inline]
+    fn serialize_none(self) -> error::CompatResult<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_some<T>(self, value: &T) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        value.serialize(self)
+    }
+
+    #[inline]
+    fn serialize_unit(self) -> error::CompatResult<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_newtype_struct<T>(self, _: &'static str, value: &T) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        value.serialize(self)
+    }
+
+    #[inline]
+    fn serialize_newtype_variant<T>(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        let field = self.member(variant)?;
+        value.serialize(MessageFieldSerializer::new(
+            self.descriptors,
+            field,
+            self.output,
+        ))
+    }
+
+    #[inline]
+    fn serialize_map(self, _: Option<usize>) -> error::CompatResult<Self::SerializeMap> {
+        Ok(self)
+    }
+}
+
+impl<'a: 'c, 'b, 'c> serde::ser::SerializeMap for OneofSerializer<'a, 'b, 'c> {
+    type Ok = ();
+    type Error = error::CompatError;
+
+    #[inline]
+    fn serialize_key<T>(&mut self, key: &T) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        self.field = Some(self.member(key)?);
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_value<T>(&mut self, value: &T) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        let field = self
+            .field
+            .take()
+            .expect("serialize_value was called before serialize_key");
+        value.serialize(MessageFieldSerializer::new(
+            self.descriptors,
+            field,
+            self.output,
+        ))
+    }
+
+    #[inline]
+    fn end(self) -> error::CompatResult<()> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b, 'c> RepeatedValueSerializer<'a, 'b, 'c> {
+    #[inline]
+    fn new(
+        descriptors: &'a descriptor::Descriptors,
+        descriptor: &'a descriptor::FieldDescriptor,
+        output: &'c mut protobuf::CodedOutputStream<'b>,
+    ) -> RepeatedValueSerializer<'a, 'b, 'c> {
+        RepeatedValueSerializer {
+            descriptors,
+            descriptor,
+            output,
+            packed: if descriptor.is_packed() {
+                Some(Vec::new())
+            } else {
+                None
+            },
+        }
+    }
+}
+
+impl<'a: 'c, 'b, 'c> serde::ser::SerializeSeq for RepeatedValueSerializer<'a, 'b, 'c> {
+    type Ok = ();
+    type Error = error::CompatError;
+
+    #[inline]
+    fn serialize_element<T>(&mut self, value: &T) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        match self.packed {
+            Some(ref mut buffer) => {
+                let mut output = protobuf::CodedOutputStream::vec(buffer);
+                value.serialize(ValueSerializer::packed(
+                    self.descriptors,
+                    self.descriptor,
+                    &mut output,
+                ))?;
+                Ok(output.flush()?)
+            }
+            None => value.serialize(ValueSerializer::new(
+                self.descriptors,
+                self.descriptor,
+                self.output,
+            )),
+        }
+    }
+
+    #[inline]
+    fn end(self) -> error::CompatResult<()> {
+        match self.packed {
+            // A packed field without any elements is left out entirely
+            Some(ref buffer) if !buffer.is_empty() => {
+                let number = self.descriptor.number() as u32;
+                Ok(self.output.write_bytes(number, buffer)?)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<'a: 'c, 'b, 'c> serde::ser::SerializeTuple for RepeatedValueSerializer<'a, 'b, 'c> {
+    type Ok = ();
+    type Error = error::CompatError;
+
+    #[inline]
+    fn serialize_element<T>(&mut self, value: &T) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        serde::ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    #[inline]
+    fn end(self) -> error::CompatResult<()> {
+        serde::ser::SerializeSeq::end(self)
+    }
+}
+
+impl<'a, 'b, 'c> ValueSerializer<'a, 'b, 'c> {
+    #[inline]
+    fn new(
+        descriptors: &'a descriptor::Descriptors,
+        descriptor: &'a descriptor::FieldDescriptor,
+        output: &'c mut protobuf::CodedOutputStream<'b>,
+    ) -> ValueSerializer<'a, 'b, 'c> {
+        ValueSerializer {
+            descriptors,
+            descriptor,
+            output,
+            packed: false,
+        }
+    }
+
+    #[inline]
+    fn packed(
+        descriptors: &'a descriptor::Descriptors,
+        descriptor: &'a descriptor::FieldDescriptor,
+        output: &'c mut protobuf::CodedOutputStream<'b>,
+    ) -> ValueSerializer<'a, 'b, 'c> {
+        ValueSerializer {
+            packed: true,
+            ..ValueSerializer::new(descriptors, descriptor, output)
+        }
+    }
+
+    fn unexpected(&self) -> error::CompatError {
+        bad_value(self.descriptor)
+    }
+
+    /// Writes a scalar value, which must agree with the field type, with a tag unless it is an
+    /// element of a packed field.
+    #[inline]
+    fn write_value(self, v: &value::Value) -> error::Result<()> {
+        if self.packed {
+            v.write_packed_to(self.descriptors, self.descriptor, self.output)
+        } else {
+            v.write_to(self.descriptors, self.descriptor, self.output)
+        }
+    }
+
+    #[inline]
+    fn write_signed(self, v: i64) -> error::Result<()> {
+        use crate::descriptor::FieldType::*;
+
+        let descriptor = self.descriptor;
+        let bad = || error::Error::BadFieldValue {
+            field: descriptor.name().to_owned(),
+        };
+
+        let value = match descriptor.field_type(self.descriptors) {
+            Int32 | SInt32 | SFixed32 => value::Value::I32(i32::try_from(v).map_err(|_| bad())?),
+            Int64 | SInt64 | SFixed64 => value::Value::I64(v),
+            UInt32 | Fixed32 => value::Value::U32(u32::try_from(v).map_err(|_| bad())?),
+            UInt64 | Fixed64 => value::Value::U64(u64::try_from(v).map_err(|_| bad())?),
+            Float => value::Value::F32(v as f32),
+            Double => value::Value::F64(v as f64),
+            Enum(_) => value::Value::Enum(i32::try_from(v).map_err(|_| bad())?),
+            _ => return Err(bad()),
+        };
+        self.write_value(&value)
+    }
+
+    #[inline]
+    fn write_unsigned(self, v: u64) -> error::Result<()> {
+        use crate::descriptor::FieldType::*;
+
+        match self.descriptor.field_type(self.descriptors) {
+            UInt64 | Fixed64 => self.write_value(&value::Value::U64(v)),
+            _ => match i64::try_from(v) {
+                Ok(v) => self.write_signed(v),
+                Err(_) => Err(error::Error::BadFieldValue {
+                    field: self.descriptor.name().to_owned(),
+                }),
+            },
+        }
+    }
+
+    #[inline]
+    fn write_float(self, v: f64) -> error::Result<()> {
+        use crate::descriptor::FieldType::*;
+
+        match self.descriptor.field_type(self.descriptors) {
+            Float => self.write_value(&value::Value::F32(v as f32)),
+            Double => self.write_value(&value::Value::F64(v)),
+            _ => Err(error::Error::BadFieldValue {
+                field: self.descriptor.name().to_owned(),
+            }),
+        }
+    }
+
+    #[inline]
+    fn write_str(self, v: &str) -> error::Result<()> {
+        use crate::descriptor::FieldType::*;
+
+        let number = self.descriptor.number() as u32;
+        match self.descriptor.field_type(self.descriptors) {
+            String if !self.packed => self.output.write_string(number, v)?,
+            Bytes if !self.packed => self.output.write_bytes(number, v.as_bytes())?,
+            Enum(e) => match e.value_by_name(v) {
+                Some(value) => return self.write_value(&value::Value::Enum(value.number())),
+                None => {
+                    return Err(error::Error::BadFieldValue {
+                        field: self.descriptor.name().to_owned(),
+                    })
+                }
+            },
+            _ => {
+                return Err(error::Error::BadFieldValue {
+                    field: self.descriptor.name().to_owned(),
+                })
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a: 'c, 'b, 'c> serde::Serializer for ValueSerializer<'a, 'b, 'c> {
+    type Ok = ();
+    type Error = error::CompatError;
+    type SerializeSeq = serde::ser::Impossible<(), error::CompatError>;
+    type SerializeTuple = serde::ser::Impossible<(), error::CompatError>;
+    type SerializeTupleStruct = serde::ser::Impossible<(), error::CompatError>;
+    type SerializeTupleVariant = serde::ser::Impossible<(), error::CompatError>;
+    type SerializeMap = MessageSerializer<'c, 'b>;
+    type SerializeStruct = MessageSerializer<'c, 'b>;
+    type SerializeStructVariant = serde::ser::Impossible<(), error::CompatError>;
+
+    reject! {
+        none unit unit_struct newtype_variant seq tuple tuple_struct tuple_variant
+        struct_variant
+    }
+
+    #[inline]
+    fn serialize_bool(self, v: bool) -> error::CompatResult<()> {
+        if let descriptor::FieldType::Bool = self.descriptor.field_type(self.descriptors) {
+            Ok(self.write_value(&value::Value::Bool(v))?)
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    #[inline]
+    fn serialize_i8(self, v: i8) -> error::CompatResult<()> {
+        Ok(self.write_signed(i64::from(v))?)
+    }
+
+    #[inline]
+    fn serialize_i16(self, v: i16) -> error::CompatResult<()> {
+        Ok(self.write_signed(i64::from(v))?)
+    }
+
+    #[inline]
+    fn serialize_i32(self, v: i32) -> error::CompatResult<()> {
+        Ok(self.write_signed(i64::from(v))?)
+    }
+
+    #[inline]
+    fn serialize_i64(self, v: i64) -> error::CompatResult<()> {
+        Ok(self.write_signed(v)?)
+    }
+
+    #[inline]
+    fn serialize_u8(self, v: u8) -> error::CompatResult<()> {
+        Ok(self.write_unsigned(u64::from(v))?)
+    }
+
+    #[inline]
+    fn serialize_u16(self, v: u16) -> error::CompatResult<()> {
+        Ok(self.write_unsigned(u64::from(v))?)
+    }
+
+    #[inline]
+    fn serialize_u32(self, v: u32) -> error::CompatResult<()> {
+        Ok(self.write_unsigned(u64::from(v))?)
+    }
+
+    #[inline]
+    fn serialize_u64(self, v: u64) -> error::CompatResult<()> {
+        Ok(self.write_unsigned(v)?)
+    }
+
+    #[inline]
+    fn serialize_f32(self, v: f32) -> error::CompatResult<()> {
+        Ok(self.write_float(f64::from(v))?)
+    }
+
+    #[inline]
+    fn serialize_f64(self, v: f64) -> error::CompatResult<()> {
+        Ok(self.write_float(v)?)
+    }
+
+    #[inline]
+    fn serialize_char(self, v: char) -> error::CompatResult<()> {
+        Ok(self.write_str(v.encode_utf8(&mut [0; 4]))?)
+    }
+
+    #[inline]
+    fn serialize_str(self, v: &str) -> error::CompatResult<()> {
+        Ok(self.write_str(v)?)
+    }
+
+    #[inline]
+    fn serialize_bytes(self, v: &[u8]) -> error::CompatResult<()> {
+        if let (descriptor::FieldType::Bytes, false) =
+            (self.descriptor.field_type(self.descriptors), self.packed)
+        {
+            let number = self.descriptor.number() as u32;
+            Ok(self.output.write_bytes(number, v)?)
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    #[inline]
+    fn serialize_some<T>(self, value: &T) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        value.serialize(self)
+    }
+
+    #[inline]
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> error::CompatResult<()> {
+        Ok(self.write_str(variant)?)
+    }
+
+    #[inline]
+    fn serialize_newtype_struct<T>(self, _: &'static str, value: &T) -> error::CompatResult<()>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        value.serialize(self)
+    }
+
+    #[inline]
+    fn serialize_map(self, _: Option<usize>) -> error::CompatResult<Self::SerializeMap> {
+        let number = self.descriptor.number() as u32;
+        match self.descriptor.field_type(self.descriptors) {
+            // Messages can't be elements of a packed field
+            _ if self.packed => Err(self.unexpected()),
+            descriptor::FieldType::Message(m) => Ok(MessageSerializer::new(
+                self.descriptors,
+                m,
+                Output::Nested(self.output, number, Vec::new()),
+            )),
+            descriptor::FieldType::Group(m) => {
+                // The fields of a group are delimited by tags, so they can be written right away
+                self.output
+                    .write_tag(number, wire_format::WireTypeStartGroup)?;
+                Ok(MessageSerializer::new(
+                    self.descriptors,
+                    m,
+                    Output::Group(self.output, number),
+                ))
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    #[inline]
+    fn serialize_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> error::CompatResult<Self::SerializeStruct> {
+        self.serialize_map(None)
+    }
+}
+
+/// The key and value fields of a map entry message.
+#[inline]
+fn map_entry_fields(
+    descriptor: &descriptor::MessageDescriptor,
+) -> error::Result<(&descriptor::FieldDescriptor, &descriptor::FieldDescriptor)> {
+    match (descriptor.field_by_number(1), descriptor.field_by_number(2)) {
+        (Some(key), Some(value)) => Ok((key, value)),
+        _ => Err(error::Error::ExpectedMessage {
+            name: descriptor.name().to_owned(),
+        }),
+    }
+}
+
+#[inline]
+fn bad_value(descriptor: &descriptor::FieldDescriptor) -> error::CompatError {
+    error::Error::BadFieldValue {
+        field: descriptor.name().to_owned(),
+    }
+    .into()
+}
@@ -0,0 +1,1033 @@
+//! Reading and writing of the protocol buffer [text format][1].
+//!
+//! The text format is the human readable representation of messages that is commonly used for
+//! configuration files and test fixtures.  Like all other operations, reading and writing it
+//! requires a previously loaded set of schema descriptors; see the
+//! [`descriptor`](../descriptor/index.html) module for more information.
+//!
+//! Text can be parsed into a [`value::Message`](../value/struct.Message.html) with `from_str` and
+//! printed with `to_string`, or deserialized directly into something that implements
+//! `Deserialize` with a `Deserializer`, which presents messages exactly like the binary
+//! [`de::Deserializer`](../de/struct.Deserializer.html) does:
+//!
+//! ```
+//! extern crate serde;
+//! extern crate protobuf;
+//! extern crate serde_protobuf;
+//! extern crate serde_value;
+//!
+//! use std::fs;
+//! use serde::de::Deserialize;
+//! use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::text::Deserializer;
+//! use serde_value::Value;
+//!
+//! # use std::io;
+//! # #[derive(Debug)] struct Error;
+//! # impl From<protobuf::ProtobufError> for Error {
+//! #   fn from(a: protobuf::ProtobufError) -> Error {
+//! #     Error
+//! #   }
+//! # }
+//! # impl From<io::Error> for Error {
+//! #   fn from(a: io::Error) -> Error {
+//! #     Error
+//! #   }
+//! # }
+//! # impl From<serde_protobuf::error::Error> for Error {
+//! #   fn from(a: serde_protobuf::error::Error) -> Error {
+//! #     Error
+//! #   }
+//! # }
+//! # impl From<serde_protobuf::error::CompatError> for Error {
+//! #   fn from(a: serde_protobuf::error::CompatError) -> Error {
+//! #     Error
+//! #   }
+//! # }
+//! # fn foo() -> Result<(), Error> {
+//! // Load a descriptor registry (see descriptor module)
+//! let mut file = fs::File::open("testdata/descriptors.pb")?;
+//! let proto = protobuf::parse_from_reader(&mut file)?;
+//! let descriptors = Descriptors::from_proto(&proto);
+//!
+//! // Some text to read
+//! let text = r#"
+//!     optional_int32: 42
+//!     optional_nested_message { bb: 7 }
+//!     repeated_string: ["a", "b"]
+//! "#;
+//!
+//! // Create a deserializer
+//! let name = ".protobuf_unittest.TestAllTypes";
+//! let mut deserializer = Deserializer::for_named_message(&descriptors, name, text)?;
+//!
+//! // Deserialize some struct
+//! let value = Value::deserialize(&mut deserializer)?;
+//! # println!("{:?}", value);
+//! # Ok(())
+//! # }
+//! # fn main() {
+//! #   foo().unwrap();
+//! # }
+//! ```
+//!
+//! ## Syntax
+//!
+//! Fields are written as `name: value`, where the colon is optional before messages.  Messages
+//! are enclosed in either `{ }` or `< >`, and repeated fields are written once per value or as a
+//! list like `[1, 2, 3]`.  Fields may be separated by `,` or `;`, and `#` starts a comment that
+//! runs until the end of the line.
+//!
+//!   * Integers may be written in decimal, hexadecimal (`0x2a`) or octal (`052`) notation.
+//!   * Floats additionally accept an `f` suffix, `inf`, `infinity` and `nan`.
+//!   * Strings and bytes are quoted with `"` or `'`, support C style escapes, and adjacent
+//!     strings are concatenated.
+//!   * Enum values are written by name or by number.
+//!   * Groups are keyed by the name of their message type, e.g. `OptionalGroup { a: 1 }`.
+//!   * Map fields are written as repeated entries, e.g. `counts { key: "a" value: 1 }`.
+//!
+//! Extensions are keyed by their fully qualified name in brackets, e.g.
+//! `[foo.package.extension]: 1`.
+//!
+//! A `google.protobuf.Any` can be written in expanded form, by keying its payload with its type
+//! URL in brackets: `[type.googleapis.com/foo.Bar] { baz: 1 }`.  Type URLs are looked up with
+//! `Descriptors::message_by_type_url`, and the printer expands every `Any` whose type can be
+//! resolved.
+//!
+//! Errors in the input are reported as `Error::Syntax`, with the line and column where the
+//! problem was found.
+//!
+//! [1]: https://developers.google.com/protocol-buffers/docs/text-format-spec
+use std::collections;
+use std::f64;
+use std::fmt;
+use std::str;
+
+use protobuf;
+use serde;
+
+use crate::de;
+use crate::descriptor;
+use crate::error;
+use crate::value;
+use crate::well_known::WellKnownType;
+
+/// A deserializer that can deserialize a single message type from the text format.
+pub struct Deserializer<'de> {
+    descriptors: &'de descriptor::Descriptors,
+    descriptor: &'de descriptor::MessageDescriptor,
+    input: &'de str,
+    options: de::Options,
+}
+
+impl<'de> Deserializer<'de> {
+    /// Constructs a new text format deserializer for the specified message type.
+    ///
+    /// The input is only parsed once something is deserialized.
+    pub fn new(
+        descriptors: &'de descriptor::Descriptors,
+        descriptor: &'de descriptor::MessageDescriptor,
+        input: &'de str,
+    ) -> Deserializer<'de> {
+        Deserializer {
+            descriptors,
+            descriptor,
+            input,
+            options: de::Options::default(),
+        }
+    }
+
+    /// Constructs a new text format deserializer for the specified named message type.
+    ///
+    /// The message type name must be fully qualified (for example
+    /// `".google.protobuf.FileDescriptorSet"`).
+    pub fn for_named_message(
+        descriptors: &'de descriptor::Descriptors,
+        message_name: &str,
+        input: &'de str,
+    ) -> error::Result<Deserializer<'de>> {
+        if let Some(message) = descriptors.message_by_name(message_name) {
+            Ok(Deserializer::new(descriptors, message, input))
+        } else {
+            Err(error::Error::UnknownMessage {
+                name: message_name.to_owned(),
+            })
+        }
+    }
+
+    /// The options currently used by this deserializer.
+    pub fn options(&self) -> &de::Options {
+        &self.options
+    }
+
+    /// Changes the options used by this deserializer.
+    ///
+    /// All options except for streaming apply; the whole input is always parsed up front.
+    pub fn set_options(&mut self, options: de::Options) {
+        self.options = options;
+    }
+}
+
+impl<'de> fmt::Debug for Deserializer<'de> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Deserializer").finish()
+    }
+}
+
+impl<'de> serde::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = error::CompatError;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+
+    #[inline]
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        let defaults = !self.options.present_fields_only();
+        let message = parse(self.descriptors, self.descriptor, self.input, defaults)?;
+        de::visit_message(
+            self.descriptors,
+            self.descriptor,
+            self.options,
+            message,
+            visitor,
+        )
+    }
+}
+
+/// Parses a message of the specified type from the text format.
+///
+/// The message only holds the fields that were present in the input, like one created with
+/// `Message::empty`.
+pub fn from_str(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    s: &str,
+) -> error::Result<value::Message> {
+    parse(descriptors, descriptor, s, false)
+}
+
+/// Prints a message of the specified type in the text format, with one field per line.
+///
+/// Fields with implicit presence are left out when they hold the zero value of their type, and
+/// unknown fields are left out altogether.
+pub fn to_string(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    message: &value::Message,
+) -> error::Result<String> {
+    let mut output = String::new();
+    print_message(descriptors, descriptor, message, 0, &mut output)?;
+    Ok(output)
+}
+
+fn parse(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    s: &str,
+    defaults: bool,
+) -> error::Result<value::Message> {
+    let mut parser = Parser {
+        input: s,
+        pos: 0,
+        defaults,
+    };
+    let mut message = parser.new_message(descriptor);
+    parser.parse_fields(descriptors, descriptor, &mut message, None)?;
+    Ok(message)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    // Whether nested messages are created with `Message::new` instead of `Message::empty`
+    defaults: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn new_message(&self, descriptor: &descriptor::MessageDescriptor) -> value::Message {
+        if self.defaults {
+            value::Message::new(descriptor)
+        } else {
+            value::Message::empty()
+        }
+    }
+
+    /// Parses fields into the message until the closing delimiter, or the end of the input if
+    /// there is none.
+    fn parse_fields(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        descriptor: &descriptor::MessageDescriptor,
+        message: &mut value::Message,
+        close: Option<char>,
+    ) -> error::Result<()> {
+        let mut seen = collections::BTreeSet::new();
+        let mut oneofs = collections::BTreeSet::new();
+        loop {
+            self.skip_whitespace();
+            match close {
+                Some(c) if self.consume(c) => return Ok(()),
+                Some(c) if self.peek().is_none() => {
+                    return Err(self.error(format!("expected '{}'", c)));
+                }
+                None if self.peek().is_none() => return Ok(()),
+                _ => (),
+            }
+
+            let start = self.pos;
+            let field = if self.consume('[') {
+                let name = self.parse_bracketed_name()?;
+                if name.contains('/') && WellKnownType::of(descriptor) == Some(WellKnownType::Any) {
+                    if !seen.insert(1) || !seen.insert(2) {
+                        return Err(self.error_at(start, "Any specified multiple times"));
+                    }
+                    self.parse_any(descriptors, message, &name, start)?;
+                    None
+                } else {
+                    match descriptors.extension_of(descriptor, &name) {
+                        Some(field) => Some((field, format!("[{}]", name))),
+                        None => {
+                            let message = format!("unknown extension: {}", name);
+                            return Err(self.error_at(start, message));
+                        }
+                    }
+                }
+            } else {
+                let name = match self.parse_identifier() {
+                    Some(name) => name,
+                    None => return Err(self.error("expected field name")),
+                };
+                match find_field(descriptors, descriptor, name) {
+                    Some(field) => Some((field, name.to_owned())),
+                    None => return Err(self.error_at(start, format!("unknown field: {}", name))),
+                }
+            };
+
+            if let Some((field, name)) = field {
+                if !field.is_repeated() && !seen.insert(field.number()) {
+                    let message = format!("field specified multiple times: {}", name);
+                    return Err(self.error_at(start, message));
+                }
+                if let Some(oneof_index) = field.oneof_index() {
+                    if !oneofs.insert(oneof_index) {
+                        let message =
+                            format!("another member of the oneof is already set: {}", name);
+                        return Err(self.error_at(start, message));
+                    }
+                }
+                self.parse_field(descriptors, field, message)?;
+            }
+
+            self.skip_whitespace();
+            if !self.consume(';') {
+                self.consume(',');
+            }
+        }
+    }
+
+    fn parse_field(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        message: &mut value::Message,
+    ) -> error::Result<()> {
+        self.skip_whitespace();
+        if !self.consume(':') && !is_message(descriptors, field) {
+            return Err(self.error("expected ':'"));
+        }
+
+        self.skip_whitespace();
+        if field.is_repeated() && self.consume('[') {
+            self.skip_whitespace();
+            if self.consume(']') {
+                return Ok(());
+            }
+            loop {
+                self.parse_element(descriptors, field, message)?;
+                self.skip_whitespace();
+                if self.consume(']') {
+                    return Ok(());
+                }
+                if !self.consume(',') {
+                    return Err(self.error("expected ',' or ']'"));
+                }
+            }
+        } else {
+            self.parse_element(descriptors, field, message)
+        }
+    }
+
+    fn parse_element(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        message: &mut value::Message,
+    ) -> error::Result<()> {
+        let start = self.pos;
+        let value = self.parse_value(descriptors, field)?;
+        let slot = message
+            .fields
+            .entry(field.number())
+            .or_insert_with(|| value::Field::new(field));
+        match slot {
+            value::Field::Singular(v) => *v = Some(value),
+            value::Field::Repeated(vs) => vs.push(value),
+            value::Field::Map(entries) => {
+                let (key, value) = self
+                    .map_entry(descriptors, field, value)
+                    .ok_or_else(|| self.error_at(start, "bad map entry"))?;
+                entries.insert(key, value);
+            }
+        }
+        Ok(())
+    }
+
+    fn map_entry(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        entry: value::Value,
+    ) -> Option<(value::MapKey, value::Value)> {
+        let entry_descriptor = match field.field_type(descriptors) {
+            descriptor::FieldType::Message(m) => m,
+            _ => return None,
+        };
+        let mut entry = match entry {
+            value::Value::Message(m) => m,
+            _ => return None,
+        };
+        let mut take = |number| {
+            let field = entry_descriptor.field_by_number(number)?;
+            match entry.fields.remove(&number) {
+                Some(value::Field::Singular(Some(v))) => Some(v),
+                _ => match field.field_type(descriptors) {
+                    descriptor::FieldType::Message(m) => {
+                        Some(value::Value::Message(self.new_message(m)))
+                    }
+                    _ => field.default_value().cloned(),
+                },
+            }
+        };
+        let key = value::MapKey::from_value(take(1)?)?;
+        let value = take(2)?;
+        Some((key, value))
+    }
+
+    fn parse_value(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+    ) -> error::Result<value::Value> {
+        use crate::descriptor::FieldType;
+
+        self.skip_whitespace();
+        let start = self.pos;
+        let value = match field.field_type(descriptors) {
+            FieldType::Bool => match self.parse_identifier() {
+                Some("true") | Some("True") | Some("t") => value::Value::Bool(true),
+                Some("false") | Some("False") | Some("f") => value::Value::Bool(false),
+                Some(_) => return Err(self.error_at(start, "expected a boolean")),
+                None => match self.parse_integer()? {
+                    0 => value::Value::Bool(false),
+                    1 => value::Value::Bool(true),
+                    _ => return Err(self.error_at(start, "expected a boolean")),
+                },
+            },
+            FieldType::Int32 | FieldType::SInt32 | FieldType::SFixed32 => {
+                value::Value::I32(self.parse_ranged_integer(start)?)
+            }
+            FieldType::Int64 | FieldType::SInt64 | FieldType::SFixed64 => {
+                value::Value::I64(self.parse_ranged_integer(start)?)
+            }
+            FieldType::UInt32 | FieldType::Fixed32 => {
+                value::Value::U32(self.parse_ranged_integer(start)?)
+            }
+            FieldType::UInt64 | FieldType::Fixed64 => {
+                value::Value::U64(self.parse_ranged_integer(start)?)
+            }
+            FieldType::Float => value::Value::F32(self.parse_float()? as f32),
+            FieldType::Double => value::Value::F64(self.parse_float()?),
+            FieldType::String => match String::from_utf8(self.parse_string()?) {
+                Ok(s) => value::Value::String(s),
+                Err(_) => return Err(self.error_at(start, "string is not valid UTF-8")),
+            },
+            FieldType::Bytes => value::Value::Bytes(self.parse_string()?),
+            FieldType::Enum(e) => {
+                let number = match self.parse_identifier() {
+                    Some(name) => match e.value_by_name(name) {
+                        Some(v) => v.number(),
+                        None => {
+                            let message = format!("unknown value for enum {}: {}", e.name(), name);
+                            return Err(self.error_at(start, message));
+                        }
+                    },
+                    None => {
+                        let number = self.parse_ranged_integer(start)?;
+                        if e.is_closed() && e.value_by_number(number).is_none() {
+                            let message =
+                                format!("unknown value for enum {}: {}", e.name(), number);
+                            return Err(self.error_at(start, message));
+                        }
+                        number
+                    }
+                };
+                value::Value::Enum(number)
+            }
+            FieldType::Message(m) | FieldType::Group(m) => {
+                let close = self.parse_open_brace()?;
+                let mut message = self.new_message(m);
+                self.parse_fields(descriptors, m, &mut message, Some(close))?;
+                value::Value::Message(message)
+            }
+            FieldType::UnresolvedMessage(name) | FieldType::UnresolvedGroup(name) => {
+                return Err(error::Error::UnknownMessage {
+                    name: name.to_owned(),
+                });
+            }
+            FieldType::UnresolvedEnum(name) => {
+                return Err(error::Error::UnknownEnum {
+                    name: name.to_owned(),
+                });
+            }
+        };
+        Ok(value)
+    }
+
+    /// Parses the payload of an `Any` in expanded form, after the type URL in brackets.
+    fn parse_any(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &mut value::Message,
+        type_url: &str,
+        start: usize,
+    ) -> error::Result<()> {
+        let (ds, d) = match descriptors.message_by_type_url(type_url) {
+            Some(resolved) => resolved,
+            None => return Err(self.error_at(start, format!("unknown type URL: {}", type_url))),
+        };
+        self.skip_whitespace();
+        self.consume(':');
+        self.skip_whitespace();
+        let close = self.parse_open_brace()?;
+        let mut payload = value::Message::empty();
+        self.parse_fields(ds, d, &mut payload, Some(close))?;
+
+        let mut bytes = Vec::new();
+        {
+            let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+            payload.write_to(ds, d, &mut output)?;
+            output.flush()?;
+        }
+        message.fields.insert(
+            1,
+            value::Field::Singular(Some(value::Value::String(type_url.to_owned()))),
+        );
+        message
+            .fields
+            .insert(2, value::Field::Singular(Some(value::Value::Bytes(bytes))));
+        Ok(())
+    }
+
+    /// Parses an extension name or type URL up to and including the closing bracket.
+    fn parse_bracketed_name(&mut self) -> error::Result<String> {
+        let mut name = String::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(']') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(c) if c.is_ascii_alphanumeric() || "_./-".contains(c) => {
+                    self.pos += c.len_utf8();
+                    name.push(c);
+                }
+                _ => return Err(self.error("expected ']'")),
+            }
+        }
+        if name.is_empty() {
+            Err(self.error("expected a name"))
+        } else {
+            Ok(name)
+        }
+    }
+
+    fn parse_open_brace(&mut self) -> error::Result<char> {
+        if self.consume('{') {
+            Ok('}')
+        } else if self.consume('<') {
+            Ok('>')
+        } else {
+            Err(self.error("expected '{' or '<'"))
+        }
+    }
+
+    fn parse_ranged_integer<T>(&mut self, start: usize) -> error::Result<T>
+    where
+        T: std::convert::TryFrom<i128>,
+    {
+        let v = self.parse_integer()?;
+        T::try_from(v).map_err(|_| self.error_at(start, "integer out of range"))
+    }
+
+    fn parse_integer(&mut self) -> error::Result<i128> {
+        let start = self.pos;
+        let negative = self.parse_minus();
+        let token = self.parse_number_token();
+        let (digits, radix) = if token.starts_with("0x") || token.starts_with("0X") {
+            (&token[2..], 16)
+        } else if token.len() > 1 && token.starts_with('0') {
+            (&token[1..], 8)
+        } else {
+            (token, 10)
+        };
+        match u64::from_str_radix(digits, radix) {
+            Ok(v) if negative => Ok(-i128::from(v)),
+            Ok(v) => Ok(i128::from(v)),
+            Err(_) => Err(self.error_at(start, "expected an integer")),
+        }
+    }
+
+    fn parse_float(&mut self) -> error::Result<f64> {
+        let start = self.pos;
+        let negative = self.parse_minus();
+        let v = if let Some(name) = self.parse_identifier() {
+            match name.to_ascii_lowercase().as_str() {
+                "inf" | "infinity" => f64::INFINITY,
+                "nan" => f64::NAN,
+                _ => return Err(self.error_at(start, "expected a number")),
+            }
+        } else {
+            let token = self.parse_number_token();
+            let parsed = if token.starts_with("0x") || token.starts_with("0X") {
+                u64::from_str_radix(&token[2..], 16).ok().map(|v| v as f64)
+            } else {
+                token.trim_end_matches(['f', 'F']).parse().ok()
+            };
+            match parsed {
+                Some(v) => v,
+                None => return Err(self.error_at(start, "expected a number")),
+            }
+        };
+        Ok(if negative { -v } else { v })
+    }
+
+    fn parse_minus(&mut self) -> bool {
+        if self.consume('-') {
+            self.skip_whitespace();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Consumes the characters of a number, which are validated when it's converted.
+    fn parse_number_token(&mut self) -> &'a str {
+        let start = self.pos;
+        let mut prev = None;
+        while let Some(c) = self.peek() {
+            let exponent_sign = (c == '+' || c == '-')
+                && matches!(prev, Some('e') | Some('E'))
+                && !self.input[start..self.pos].starts_with("0x");
+            if c.is_ascii_alphanumeric() || c == '.' || exponent_sign {
+                self.pos += 1;
+                prev = Some(c);
+            } else {
+                break;
+            }
+        }
+        &self.input[start..self.pos]
+    }
+
+    fn parse_identifier(&mut self) -> Option<&'a str> {
+        let start = self.pos;
+        match self.peek() {
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => (),
+            _ => return None,
+        }
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        Some(&self.input[start..self.pos])
+    }
+
+    /// Parses one or more adjacent quoted strings, resolving escape sequences.
+    fn parse_string(&mut self) -> error::Result<Vec<u8>> {
+        let mut result = Vec::new();
+        let mut any = false;
+        loop {
+            self.skip_whitespace();
+            let quote = match self.peek() {
+                Some(c) if c == '"' || c == '\'' => c,
+                _ if any => return Ok(result),
+                _ => return Err(self.error("expected a string")),
+            };
+            self.pos += 1;
+            any = true;
+            loop {
+                let c = match self.peek() {
+                    Some('\n') | None => return Err(self.error("unterminated string")),
+                    Some(c) => c,
+                };
+                self.pos += c.len_utf8();
+                if c == quote {
+                    break;
+                } else if c == '\\' {
+                    self.parse_escape(&mut result)?;
+                } else {
+                    let mut buf = [0; 4];
+                    result.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+            }
+        }
+    }
+
+    fn parse_escape(&mut self, result: &mut Vec<u8>) -> error::Result<()> {
+        let start = self.pos - 1;
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Err(self.error("unterminated string")),
+        };
+        self.pos += c.len_utf8();
+        let byte = match c {
+            'n' => b'\n',
+            'r' => b'\r',
+            't' => b'\t',
+            'a' => 0x07,
+            'b' => 0x08,
+            'f' => 0x0c,
+            'v' => 0x0b,
+            '\\' | '\'' | '"' | '?' => c as u8,
+            '0'..='7' => {
+                let mut v = c.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match self.peek().and_then(|c| c.to_digit(8)) {
+                        Some(d) => {
+                            v = v * 8 + d;
+                            self.pos += 1;
+                        }
+                        None => break,
+                    }
+                }
+                if v > 0xff {
+                    return Err(self.error_at(start, "octal escape out of range"));
+                }
+                v as u8
+            }
+            'x' => {
+                let mut v = 0;
+                let mut digits = 0;
+                while digits < 2 {
+                    match self.peek().and_then(|c| c.to_digit(16)) {
+                        Some(d) => {
+                            v = v * 16 + d;
+                            self.pos += 1;
+                            digits += 1;
+                        }
+                        None => break,
+                    }
+                }
+                if digits == 0 {
+                    return Err(self.error_at(start, "bad hex escape"));
+                }
+                v as u8
+            }
+            'u' | 'U' => {
+                let len = if c == 'u' { 4 } else { 8 };
+                let end = self.pos + len;
+                let c = self
+                    .input
+                    .get(self.pos..end)
+                    .and_then(|s| u32::from_str_radix(s, 16).ok())
+                    .and_then(std::char::from_u32)
+                    .ok_or_else(|| self.error_at(start, "bad unicode escape"))?;
+                self.pos = end;
+                let mut buf = [0; 4];
+                result.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                return Ok(());
+            }
+            _ => return Err(self.error_at(start, "unknown escape sequence")),
+        };
+        result.push(byte);
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while let Some(c) = self.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    self.pos += c.len_utf8();
+                }
+            } else if c.is_whitespace() {
+                self.pos += c.len_utf8();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn consume(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error<S>(&self, message: S) -> error::Error
+    where
+        S: Into<String>,
+    {
+        self.error_at(self.pos, message)
+    }
+
+    fn error_at<S>(&self, pos: usize, message: S) -> error::Error
+    where
+        S: Into<String>,
+    {
+        let before = &self.input[..pos];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        error::Error::Syntax {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            message: message.into(),
+        }
+    }
+}
+
+/// Looks up a field by name, where groups are named after their message type.
+fn find_field<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::MessageDescriptor,
+    name: &str,
+) -> Option<&'a descriptor::FieldDescriptor> {
+    descriptor
+        .fields()
+        .iter()
+        .find(|field| match field.field_type(descriptors) {
+            descriptor::FieldType::Group(m) => short_name(m) == name,
+            _ => field.name() == name,
+        })
+}
+
+fn is_message(descriptors: &descriptor::Descriptors, field: &descriptor::FieldDescriptor) -> bool {
+    matches!(
+        field.field_type(descriptors),
+        descriptor::FieldType::Message(_) | descriptor::FieldType::Group(_)
+    )
+}
+
+fn short_name(message: &descriptor::MessageDescriptor) -> &str {
+    let name = message.name();
+    &name[name.rfind('.').map_or(0, |i| i + 1)..]
+}
+
+fn print_message(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    message: &value::Message,
+    indent: usize,
+    output: &mut String,
+) -> error::Result<()> {
+    if WellKnownType::of(descriptor) == Some(WellKnownType::Any)
+        && print_any(descriptors, message, indent, output)?
+    {
+        return Ok(());
+    }
+
+    for (&number, field) in &message.fields {
+        let field_descriptor = match descriptors.field_or_extension(descriptor, number) {
+            Some(d) => d,
+            None => continue,
+        };
+        match field {
+            value::Field::Singular(None) => (),
+            value::Field::Singular(Some(v)) => {
+                if field_descriptor.has_presence() || !v.is_zero() {
+                    print_field(descriptors, field_descriptor, v, indent, output)?;
+                }
+            }
+            value::Field::Repeated(vs) => {
+                for v in vs {
+                    print_field(descriptors, field_descriptor, v, indent, output)?;
+                }
+            }
+            value::Field::Map(entries) => {
+                for (k, v) in entries {
+                    let mut fields = collections::BTreeMap::new();
+                    fields.insert(1, value::Field::Singular(Some(k.clone().into_value())));
+                    fields.insert(2, value::Field::Singular(Some(v.clone())));
+                    let entry = value::Value::Message(value::Message {
+                        fields,
+                        unknown: protobuf::UnknownFields::new(),
+                    });
+                    print_field(descriptors, field_descriptor, &entry, indent, output)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints an `Any` in expanded form, or returns `false` if its type can't be resolved.
+fn print_any(
+    descriptors: &descriptor::Descriptors,
+    message: &value::Message,
+    indent: usize,
+    output: &mut String,
+) -> error::Result<bool> {
+    let type_url = match message.fields.get(&1) {
+        Some(value::Field::Singular(Some(value::Value::String(v)))) => v,
+        _ => return Ok(false),
+    };
+    let bytes = match message.fields.get(&2) {
+        Some(value::Field::Singular(Some(value::Value::Bytes(v)))) => &v[..],
+        _ => &[],
+    };
+    let (ds, d) = match descriptors.message_by_type_url(type_url) {
+        Some(resolved) => resolved,
+        None => return Ok(false),
+    };
+    let mut payload = value::Message::empty();
+    payload.merge_present_from(ds, d, &mut protobuf::CodedInputStream::from_bytes(bytes))?;
+
+    push_indent(indent, output);
+    output.push('[');
+    output.push_str(type_url);
+    output.push_str("] {\n");
+    print_message(ds, d, &payload, indent + 1, output)?;
+    push_indent(indent, output);
+    output.push_str("}\n");
+    Ok(true)
+}
+
+fn print_field(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    value: &value::Value,
+    indent: usize,
+    output: &mut String,
+) -> error::Result<()> {
+    use std::fmt::Write;
+
+    let field_type = field.field_type(descriptors);
+    push_indent(indent, output);
+    match field_type {
+        _ if field.is_extension() => write!(output, "[{}]", &field.full_name()[1..]).unwrap(),
+        descriptor::FieldType::Group(m) => output.push_str(short_name(m)),
+        _ => output.push_str(field.name()),
+    }
+
+    match (field_type, value) {
+        (descriptor::FieldType::Message(m), value::Value::Message(v))
+        | (descriptor::FieldType::Group(m), value::Value::Message(v)) => {
+            output.push_str(" {\n");
+            print_message(descriptors, m, v, indent + 1, output)?;
+            push_indent(indent, output);
+            output.push('}');
+        }
+        (descriptor::FieldType::Enum(e), &value::Value::Enum(v)) => {
+            output.push_str(": ");
+            match e.value_by_number(v) {
+                Some(ev) => output.push_str(ev.name()),
+                None => write!(output, "{}", v).unwrap(),
+            }
+        }
+        (_, value) => {
+            output.push_str(": ");
+            match *value {
+                value::Value::Bool(v) => write!(output, "{}", v).unwrap(),
+                value::Value::I32(v) => write!(output, "{}", v).unwrap(),
+                value::Value::I64(v) => write!(output, "{}", v).unwrap(),
+                value::Value::U32(v) => write!(output, "{}", v).unwrap(),
+                value::Value::U64(v) => write!(output, "{}", v).unwrap(),
+                value::Value::Enum(v) => write!(output, "{}", v).unwrap(),
+                value::Value::F32(v) if v.is_finite() => write!(output, "{:?}", v).unwrap(),
+                value::Value::F64(v) if v.is_finite() => write!(output, "{:?}", v).unwrap(),
+                value::Value::F32(v) => print_non_finite(f64::from(v), output),
+                value::Value::F64(v) => print_non_finite(v, output),
+                value::Value::String(ref v) => print_string(v, output),
+                value::Value::Bytes(ref v) => print_bytes(v, output),
+                value::Value::Message(_) => {
+                    return Err(error::Error::BadFieldValue {
+                        field: field.name().to_owned(),
+                    });
+                }
+            }
+        }
+    }
+    output.push('\n');
+    Ok(())
+}
+
+fn print_non_finite(v: f64, output: &mut String) {
+    if v.is_nan() {
+        output.push_str("nan");
+    } else if v > 0.0 {
+        output.push_str("inf");
+    } else {
+        output.push_str("-inf");
+    }
+}
+
+/// Prints a quoted string, escaping non-printable ASCII characters.
+fn print_string(s: &str, output: &mut String) {
+    output.push('"');
+    for c in s.chars() {
+        if c.is_ascii() {
+            push_escaped_byte(c as u8, output);
+        } else {
+            output.push(c);
+        }
+    }
+    output.push('"');
+}
+
+/// Prints quoted bytes, escaping everything but printable ASCII characters since the bytes need
+/// not be UTF-8.
+fn print_bytes(bytes: &[u8], output: &mut String) {
+    output.push('"');
+    for &b in bytes {
+        push_escaped_byte(b, output);
+    }
+    output.push('"');
+}
+
+fn push_escaped_byte(b: u8, output: &mut String) {
+    use std::fmt::Write;
+
+    match b {
+        b'\n' => output.push_str("\\n"),
+        b'\r' => output.push_str("\\r"),
+        b'\t' => output.push_str("\\t"),
+        b'"' => output.push_str("\\\""),
+        b'\'' => output.push_str("\\'"),
+        b'\\' => output.push_str("\\\\"),
+        0x20..=0x7e => output.push(char::from(b)),
+        _ => write!(output, "\\{:03o}", b).unwrap(),
+    }
+}
+
+fn push_indent(indent: usize, output: &mut String) {
+    for _ in 0..indent {
+        output.push_str("  ");
+    }
+}
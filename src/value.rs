@@ -37,7 +37,7 @@ pub enum Value {
 /// A message value.
 #[derive(Clone, Debug)]
 pub struct Message {
-    /// Known fields on the message.
+    /// Known fields on the message, including extensions that are registered for it.
     pub fields: collections::BTreeMap<i32, Field>,
     /// Unknown fields on the message.
     pub unknown: protobuf::UnknownFields,
@@ -50,6 +50,25 @@ pub enum Field {
     Singular(Option<Value>),
     /// A field with several (repeated) values.
     Repeated(Vec<Value>),
+    /// A map field, with the value of the last entry for each key.
+    Map(collections::BTreeMap<MapKey, Value>),
+}
+
+/// The key of a map field entry.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum MapKey {
+    /// A boolean key.
+    Bool(bool),
+    /// A 32-bit signed integer key.
+    I32(i32),
+    /// A 64-bit signed integer key.
+    I64(i64),
+    /// A 32-bit unsigned integer key.
+    U32(u32),
+    /// A 64-bit unsigned integer key.
+    U64(u64),
+    /// A string key.
+    String(String),
 }
 
 impl Message {
@@ -65,7 +84,7 @@ impl Message {
             m.fields.insert(
                 field.number(),
                 if field.is_repeated() {
-                    Field::Repeated(Vec::new())
+                    Field::new(field)
                 } else {
                     Field::Singular(field.default_value().cloned())
                 },
@@ -75,6 +94,18 @@ impl Message {
         m
     }
 
+    /// Creates a message without any fields, not even ones with default values.
+    ///
+    /// Merging into such a message with `merge_present_from` leaves exactly the fields that were
+    /// present in the input.
+    #[inline]
+    pub fn empty() -> Message {
+        Message {
+            fields: collections::BTreeMap::new(),
+            unknown: protobuf::UnknownFields::new(),
+        }
+    }
+
     /// Merge data from the given input stream into this message.
     #[inline]
     pub fn merge_from(
@@ -82,26 +113,373 @@ impl Message {
         descriptors: &descriptor::Descriptors,
         message: &descriptor::MessageDescriptor,
         input: &mut protobuf::CodedInputStream,
+    ) -> error::Result<()> {
+        self.merge_fields_from(descriptors, message, input, true)
+    }
+
+    /// Merge data from the given input stream into this message, creating nested messages with
+    /// `Message::empty` instead of `Message::new`.
+    #[inline]
+    pub fn merge_present_from(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+    ) -> error::Result<()> {
+        self.merge_fields_from(descriptors, message, input, false)
+    }
+
+    /// Merge data from the given input stream into this message, until the end of the group with
+    /// the specified field number is reached.
+    #[inline]
+    pub fn merge_group_from(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        group_number: u32,
+    ) -> error::Result<()> {
+        self.merge_group_fields_from(descriptors, message, input, group_number, true)
+    }
+
+    /// Write this message to the given output stream.
+    ///
+    /// Every field holding a value is written, except for fields with implicit presence that hold
+    /// the zero value of their type.  This includes default values that were filled in by
+    /// `Message::new`, so a message that should be re-encoded without adding any data has to be
+    /// decoded into `Message::empty` with `merge_present_from`.  Unknown fields are written back
+    /// out after all of the known fields.
+    #[inline]
+    pub fn write_to(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        output: &mut protobuf::CodedOutputStream,
+    ) -> error::Result<()> {
+        for (&number, field) in &self.fields {
+            if let Some(descriptor) = descriptors.field_or_extension(message, number) {
+                if !field.is_implicit_zero(descriptor) {
+                    field.write_to(descriptors, descriptor, output)?;
+                }
+            }
+        }
+        output.write_unknown_fields(&self.unknown)?;
+        Ok(())
+    }
+
+    /// Compute the size in bytes of this message when written with `write_to`.
+    #[inline]
+    pub fn compute_size(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+    ) -> u32 {
+        let mut size = 0;
+        for (&number, field) in &self.fields {
+            if let Some(descriptor) = descriptors.field_or_extension(message, number) {
+                if !field.is_implicit_zero(descriptor) {
+                    size += field.compute_size(descriptors, descriptor);
+                }
+            }
+        }
+        size + protobuf::rt::unknown_fields_size(&self.unknown)
+    }
+
+    #[inline]
+    fn with_defaults(message: &descriptor::MessageDescriptor, defaults: bool) -> Message {
+        if defaults {
+            Message::new(message)
+        } else {
+            Message::empty()
+        }
+    }
+
+    #[inline]
+    fn merge_fields_from(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        defaults: bool,
     ) -> error::Result<()> {
         while !input.eof()? {
             let (number, wire_type) = input.read_tag_unpack()?;
+            self.merge_field_from(descriptors, message, input, number, wire_type, defaults)?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn merge_group_fields_from(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        group_number: u32,
+        defaults: bool,
+    ) -> error::Result<()> {
+        loop {
+            let (number, wire_type) = input.read_tag_unpack()?;
+            if wire_type == wire_format::WireTypeEndGroup {
+                return if number == group_number {
+                    Ok(())
+                } else {
+                    Err(error::Error::BadWireType { wire_type })
+                };
+            }
+            self.merge_field_from(descriptors, message, input, number, wire_type, defaults)?;
+        }
+    }
+
+    #[inline]
+    fn merge_field_from(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        number: u32,
+        wire_type: wire_format::WireType,
+        defaults: bool,
+    ) -> error::Result<()> {
+        if let Some(field) = descriptors.field_or_extension(message, number as i32) {
+            let value = self
+                .fields
+                .entry(field.number())
+                .or_insert_with(|| Field::new(field));
+            value.merge_value_from(
+                descriptors,
+                field,
+                input,
+                wire_type,
+                Some(&mut self.unknown),
+                defaults,
+            )
+        } else {
+            use protobuf::rt::read_unknown_or_skip_group as u;
+            Ok(u(number, wire_type, input, &mut self.unknown)?)
+        }
+    }
+}
+
+impl Value {
+    /// Whether this is the zero value of its type, which isn't encoded for fields with implicit
+    /// presence.
+    ///
+    /// Messages are never considered to be zero, and neither is a negative zero float.
+    #[inline]
+    pub fn is_zero(&self) -> bool {
+        match *self {
+            Value::Bool(v) => !v,
+            Value::I32(v) => v == 0,
+            Value::I64(v) => v == 0,
+            Value::U32(v) => v == 0,
+            Value::U64(v) => v == 0,
+            Value::F32(v) => v.to_bits() == 0,
+            Value::F64(v) => v.to_bits() == 0,
+            Value::Bytes(ref v) => v.is_empty(),
+            Value::String(ref v) => v.is_empty(),
+            Value::Enum(v) => v == 0,
+            Value::Message(_) => false,
+        }
+    }
+
+    /// Write this value, including its tag, to the given output stream.
+    ///
+    /// The wire encoding is chosen based on the type of the specified field, which must agree with
+    /// the kind of value.
+    #[inline]
+    pub fn write_to(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        output: &mut protobuf::CodedOutputStream,
+    ) -> error::Result<()> {
+        use crate::descriptor::FieldType as T;
+
+        let n = field.number() as u32;
+        match (field.field_type(descriptors), self) {
+            (T::Bool, Value::Bool(v)) => output.write_bool(n, *v)?,
+            (T::Int32, Value::I32(v)) => output.write_int32(n, *v)?,
+            (T::Int64, Value::I64(v)) => output.write_int64(n, *v)?,
+            (T::SInt32, Value::I32(v)) => output.write_sint32(n, *v)?,
+            (T::SInt64, Value::I64(v)) => output.write_sint64(n, *v)?,
+            (T::UInt32, Value::U32(v)) => output.write_uint32(n, *v)?,
+            (T::UInt64, Value::U64(v)) => output.write_uint64(n, *v)?,
+            (T::Fixed32, Value::U32(v)) => output.write_fixed32(n, *v)?,
+            (T::Fixed64, Value::U64(v)) => output.write_fixed64(n, *v)?,
+            (T::SFixed32, Value::I32(v)) => output.write_sfixed32(n, *v)?,
+            (T::SFixed64, Value::I64(v)) => output.write_sfixed64(n, *v)?,
+            (T::Float, Value::F32(v)) => output.write_float(n, *v)?,
+            (T::Double, Value::F64(v)) => output.write_double(n, *v)?,
+            (T::Bytes, Value::Bytes(v)) => output.write_bytes(n, v)?,
+            (T::String, Value::String(v)) => output.write_string(n, v)?,
+            (T::Enum(_), Value::Enum(v)) => output.write_enum(n, *v)?,
+            (T::Message(m), Value::Message(v)) => {
+                output.write_tag(n, wire_format::WireTypeLengthDelimited)?;
+                output.write_raw_varint32(v.compute_size(descriptors, m))?;
+                v.write_to(descriptors, m, output)?;
+            }
+            (T::Group(m), Value::Message(v)) => {
+                output.write_tag(n, wire_format::WireTypeStartGroup)?;
+                v.write_to(descriptors, m, output)?;
+                output.write_tag(n, wire_format::WireTypeEndGroup)?;
+            }
+            (T::UnresolvedEnum(e), _) => {
+                return Err(error::Error::UnknownEnum { name: e.to_owned() })
+            }
+            (T::UnresolvedMessage(m), _) | (T::UnresolvedGroup(m), _) => {
+                return Err(error::Error::UnknownMessage { name: m.to_owned() })
+            }
+            _ => {
+                return Err(error::Error::BadFieldValue {
+                    field: field.name().to_owned(),
+                })
+            }
+        }
+        Ok(())
+    }
+
+    /// Compute the size in bytes of this value, including its tag, when written with `write_to`.
+    ///
+    /// Values that don't agree with the type of the field don't contribute to the size; trying to
+    /// write them fails.
+    #[inline]
+    pub fn compute_size(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+    ) -> u32 {
+        use crate::descriptor::FieldType as T;
+        use protobuf::rt;
+        use protobuf::stream::wire_format::WireType::*;
+
+        let n = field.number() as u32;
+        match (field.field_type(descriptors), self) {
+            (T::Bool, Value::Bool(v)) => rt::value_size(n, *v, WireTypeVarint),
+            (T::Int32, Value::I32(v)) => rt::value_size(n, *v, WireTypeVarint),
+            (T::Int64, Value::I64(v)) => rt::value_size(n, *v, WireTypeVarint),
+            (T::SInt32, Value::I32(v)) => rt::value_varint_zigzag_size(n, *v),
+            (T::SInt64, Value::I64(v)) => rt::value_varint_zigzag_size(n, *v),
+            (T::UInt32, Value::U32(v)) => rt::value_size(n, *v, WireTypeVarint),
+            (T::UInt64, Value::U64(v)) => rt::value_size(n, *v, WireTypeVarint),
+            (T::Fixed32, Value::U32(_))
+            | (T::SFixed32, Value::I32(_))
+            | (T::Float, Value::F32(_)) => rt::tag_size(n) + 4,
+            (T::Fixed64, Value::U64(_))
+            | (T::SFixed64, Value::I64(_))
+            | (T::Double, Value::F64(_)) => rt::tag_size(n) + 8,
+            (T::Bytes, Value::Bytes(v)) => rt::bytes_size(n, v),
+            (T::String, Value::String(v)) => rt::string_size(n, v),
+            (T::Enum(_), Value::Enum(v)) => rt::value_size(n, *v, WireTypeVarint),
+            (T::Message(m), Value::Message(v)) => {
+                let len = v.compute_size(descriptors, m);
+                rt::tag_size(n) + rt::compute_raw_varint32_size(len) + len
+            }
+            (T::Group(m), Value::Message(v)) => {
+                2 * rt::tag_size(n) + v.compute_size(descriptors, m)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Write this value without a tag to the given output stream, as an element of a packed
+    /// repeated field.
+    ///
+    /// Only scalar values other than strings and bytes can be packed.
+    #[inline]
+    pub fn write_packed_to(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        output: &mut protobuf::CodedOutputStream,
+    ) -> error::Result<()> {
+        use crate::descriptor::FieldType as T;
 
-            if let Some(field) = message.field_by_number(number as i32) {
-                let value = self.ensure_field(field);
-                value.merge_from(descriptors, field, input, wire_type)?;
-            } else {
-                use protobuf::rt::read_unknown_or_skip_group as u;
-                u(number, wire_type, input, &mut self.unknown)?;
+        match (field.field_type(descriptors), self) {
+            (T::Bool, Value::Bool(v)) => output.write_bool_no_tag(*v)?,
+            (T::Int32, Value::I32(v)) => output.write_int32_no_tag(*v)?,
+            (T::Int64, Value::I64(v)) => output.write_int64_no_tag(*v)?,
+            (T::SInt32, Value::I32(v)) => output.write_sint32_no_tag(*v)?,
+            (T::SInt64, Value::I64(v)) => output.write_sint64_no_tag(*v)?,
+            (T::UInt32, Value::U32(v)) => output.write_uint32_no_tag(*v)?,
+            (T::UInt64, Value::U64(v)) => output.write_uint64_no_tag(*v)?,
+            (T::Fixed32, Value::U32(v)) => output.write_fixed32_no_tag(*v)?,
+            (T::Fixed64, Value::U64(v)) => output.write_fixed64_no_tag(*v)?,
+            (T::SFixed32, Value::I32(v)) => output.write_sfixed32_no_tag(*v)?,
+            (T::SFixed64, Value::I64(v)) => output.write_sfixed64_no_tag(*v)?,
+            (T::Float, Value::F32(v)) => output.write_float_no_tag(*v)?,
+            (T::Double, Value::F64(v)) => output.write_double_no_tag(*v)?,
+            (T::Enum(_), Value::Enum(v)) => output.write_enum_no_tag(*v)?,
+            (T::UnresolvedEnum(e), _) => {
+                return Err(error::Error::UnknownEnum { name: e.to_owned() })
+            }
+            _ => {
+                return Err(error::Error::BadFieldValue {
+                    field: field.name().to_owned(),
+                })
             }
         }
         Ok(())
     }
 
+    /// Compute the size in bytes of this value when written with `write_packed_to`.
+    ///
+    /// Values that can't be packed don't contribute to the size; trying to write them fails.
+    #[inline]
+    pub fn compute_packed_size(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+    ) -> u32 {
+        use crate::descriptor::FieldType as T;
+        use protobuf::rt::{ProtobufVarint, ProtobufVarintZigzag};
+
+        match (field.field_type(descriptors), self) {
+            (T::Bool, Value::Bool(v)) => v.len_varint(),
+            (T::Int32, Value::I32(v)) => v.len_varint(),
+            (T::Int64, Value::I64(v)) => v.len_varint(),
+            (T::SInt32, Value::I32(v)) => v.len_varint_zigzag(),
+            (T::SInt64, Value::I64(v)) => v.len_varint_zigzag(),
+            (T::UInt32, Value::U32(v)) => v.len_varint(),
+            (T::UInt64, Value::U64(v)) => v.len_varint(),
+            (T::Fixed32, Value::U32(_))
+            | (T::SFixed32, Value::I32(_))
+            | (T::Float, Value::F32(_)) => 4,
+            (T::Fixed64, Value::U64(_))
+            | (T::SFixed64, Value::I64(_))
+            | (T::Double, Value::F64(_)) => 8,
+            (T::Enum(_), Value::Enum(v)) => v.len_varint(),
+            _ => 0,
+        }
+    }
+}
+
+impl MapKey {
+    /// Converts a value into a map key, if it has one of the types allowed for map keys.
+    #[inline]
+    pub fn from_value(value: Value) -> Option<MapKey> {
+        match value {
+            Value::Bool(v) => Some(MapKey::Bool(v)),
+            Value::I32(v) => Some(MapKey::I32(v)),
+            Value::I64(v) => Some(MapKey::I64(v)),
+            Value::U32(v) => Some(MapKey::U32(v)),
+            Value::U64(v) => Some(MapKey::U64(v)),
+            Value::String(v) => Some(MapKey::String(v)),
+            _ => None,
+        }
+    }
+
+    /// Converts this map key back into a value.
     #[inline]
-    fn ensure_field(&mut self, field: &descriptor::FieldDescriptor) -> &mut Field {
-        self.fields
-            .entry(field.number())
-            .or_insert_with(|| Field::new(field))
+    pub fn into_value(self) -> Value {
+        match self {
+            MapKey::Bool(v) => Value::Bool(v),
+            MapKey::I32(v) => Value::I32(v),
+            MapKey::I64(v) => Value::I64(v),
+            MapKey::U32(v) => Value::U32(v),
+            MapKey::U64(v) => Value::U64(v),
+            MapKey::String(v) => Value::String(v),
+        }
     }
 }
 
@@ -109,7 +487,9 @@ impl Field {
     /// Creates a field given a Protobuf descriptor.
     #[inline]
     pub fn new(field: &descriptor::FieldDescriptor) -> Field {
-        if field.is_repeated() {
+        if field.is_map() {
+            Field::Map(collections::BTreeMap::new())
+        } else if field.is_repeated() {
             Field::Repeated(Vec::new())
         } else {
             Field::Singular(None)
@@ -117,6 +497,9 @@ impl Field {
     }
 
     /// Merge data from the given input stream into this field.
+    ///
+    /// Undeclared values of closed enums are kept in the field, since there is nowhere else to put
+    /// them; `Message::merge_from` moves them to the unknown fields of the message instead.
     #[inline]
     pub fn merge_from(
         &mut self,
@@ -125,6 +508,39 @@ impl Field {
         input: &mut protobuf::CodedInputStream,
         wire_type: protobuf::stream::wire_format::WireType,
     ) -> error::Result<()> {
+        self.merge_value_from(descriptors, field, input, wire_type, None, true)
+    }
+
+    /// Merge data from the given input stream into this field, creating nested messages with
+    /// `Message::empty` instead of `Message::new`.
+    #[inline]
+    pub fn merge_present_from(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        wire_type: wire_format::WireType,
+    ) -> error::Result<()> {
+        self.merge_value_from(descriptors, field, input, wire_type, None, false)
+    }
+
+    /// Merge data from the given input stream into this field, moving undeclared values of closed
+    /// enums to the specified unknown fields if any, and creating nested messages with
+    /// `Message::new` if `defaults` is set.
+    #[inline]
+    pub(crate) fn merge_value_from(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        wire_type: wire_format::WireType,
+        unknown: Option<&mut protobuf::UnknownFields>,
+        defaults: bool,
+    ) -> error::Result<()> {
+        if let Field::Map(ref mut entries) = *self {
+            return merge_map_entry(entries, descriptors, field, input, wire_type, defaults);
+        }
+
         // Make the type dispatch below more compact
         use crate::descriptor::FieldType::*;
         use protobuf::stream::wire_format::WireType::*;
@@ -176,11 +592,94 @@ impl Field {
             Double => ps!(WireTypeFixed64, 8, Value::F64, I::read_double),
             Bytes => ss!(WireTypeLengthDelimited, Value::Bytes, I::read_bytes),
             String => ss!(WireTypeLengthDelimited, Value::String, I::read_string),
-            Enum(_) => self.merge_enum(input, wire_type),
-            Message(ref m) => self.merge_message(input, descriptors, m, wire_type),
-            Group => unimplemented!(),
+            Enum(e) => self.merge_enum(input, wire_type, e, field.number(), unknown),
+            Message(ref m) => self.merge_message(input, descriptors, m, wire_type, defaults),
+            Group(m) => {
+                let number = field.number();
+                self.merge_group(input, descriptors, m, number, wire_type, defaults)
+            }
             UnresolvedEnum(e) => Err(error::Error::UnknownEnum { name: e.to_owned() }),
-            UnresolvedMessage(m) => Err(error::Error::UnknownMessage { name: m.to_owned() }),
+            UnresolvedMessage(m) | UnresolvedGroup(m) => {
+                Err(error::Error::UnknownMessage { name: m.to_owned() })
+            }
+        }
+    }
+
+    /// Write this field, including tags, to the given output stream.
+    ///
+    /// The values of a repeated field that uses the packed encoding are written as a single
+    /// length-delimited run.
+    #[inline]
+    pub fn write_to(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        output: &mut protobuf::CodedOutputStream,
+    ) -> error::Result<()> {
+        match *self {
+            Field::Singular(None) => Ok(()),
+            Field::Singular(Some(ref v)) => v.write_to(descriptors, field, output),
+            Field::Repeated(ref vs) if field.is_packed() => {
+                if !vs.is_empty() {
+                    let n = field.number() as u32;
+                    output.write_tag(n, wire_format::WireTypeLengthDelimited)?;
+                    output.write_raw_varint32(packed_size(descriptors, field, vs))?;
+                    for v in vs {
+                        v.write_packed_to(descriptors, field, output)?;
+                    }
+                }
+                Ok(())
+            }
+            Field::Repeated(ref vs) => {
+                for v in vs {
+                    v.write_to(descriptors, field, output)?;
+                }
+                Ok(())
+            }
+            Field::Map(ref entries) => {
+                for (k, v) in entries {
+                    map_entry(k, v).write_to(descriptors, field, output)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Compute the size in bytes of this field, including tags, when written with `write_to`.
+    #[inline]
+    pub fn compute_size(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+    ) -> u32 {
+        match *self {
+            Field::Singular(None) => 0,
+            Field::Singular(Some(ref v)) => v.compute_size(descriptors, field),
+            Field::Repeated(ref vs) if field.is_packed() => {
+                if vs.is_empty() {
+                    0
+                } else {
+                    let len = packed_size(descriptors, field, vs);
+                    protobuf::rt::tag_size(field.number() as u32)
+                        + protobuf::rt::compute_raw_varint32_size(len)
+                        + len
+                }
+            }
+            Field::Repeated(ref vs) => vs.iter().map(|v| v.compute_size(descriptors, field)).sum(),
+            Field::Map(ref entries) => entries
+                .iter()
+                .map(|(k, v)| map_entry(k, v).compute_size(descriptors, field))
+                .sum(),
+        }
+    }
+
+    /// Whether this field has implicit presence and holds the zero value of its type, and so
+    /// isn't encoded.
+    #[inline]
+    fn is_implicit_zero(&self, field: &descriptor::FieldDescriptor) -> bool {
+        match *self {
+            Field::Singular(Some(ref v)) => !field.has_presence() && v.is_zero(),
+            _ => false,
         }
     }
 
@@ -246,10 +745,31 @@ impl Field {
         &mut self,
         input: &mut protobuf::CodedInputStream,
         actual_wire_type: wire_format::WireType,
+        descriptor: &descriptor::EnumDescriptor,
+        number: i32,
+        unknown: Option<&mut protobuf::UnknownFields>,
     ) -> error::Result<()> {
-        if wire_format::WireType::WireTypeVarint == actual_wire_type {
-            let v = input.read_raw_varint32()? as i32;
-            self.put(Value::Enum(v));
+        // Undeclared values of closed enums are treated like unknown fields
+        let mut unknown = unknown.filter(|_| descriptor.is_closed());
+        let mut put = |field: &mut Field, v: i32| match unknown {
+            Some(ref mut u) if descriptor.value_by_number(v).is_none() => {
+                u.add_varint(number as u32, v as i64 as u64)
+            }
+            _ => field.put(Value::Enum(v)),
+        };
+
+        if wire_format::WireType::WireTypeLengthDelimited == actual_wire_type {
+            let len = input.read_raw_varint64()?;
+
+            let old_limit = input.push_limit(len)?;
+            while !input.eof()? {
+                put(self, input.read_int32()?);
+            }
+            input.pop_limit(old_limit);
+
+            Ok(())
+        } else if wire_format::WireType::WireTypeVarint == actual_wire_type {
+            put(self, input.read_int32()?);
             Ok(())
         } else {
             Err(error::Error::BadWireType {
@@ -265,6 +785,7 @@ impl Field {
         descriptors: &descriptor::Descriptors,
         message: &descriptor::MessageDescriptor,
         actual_wire_type: wire_format::WireType,
+        defaults: bool,
     ) -> error::Result<()> {
         if wire_format::WireType::WireTypeLengthDelimited == actual_wire_type {
             let len = input.read_raw_varint64()?;
@@ -273,14 +794,14 @@ impl Field {
                     if let Some(Value::Message(m)) = o.take() {
                         m
                     } else {
-                        Message::new(message)
+                        Message::with_defaults(message, defaults)
                     }
                 }
-                _ => Message::new(message),
+                _ => Message::with_defaults(message, defaults),
             };
 
             let old_limit = input.push_limit(len)?;
-            msg.merge_from(descriptors, message, input)?;
+            msg.merge_fields_from(descriptors, message, input, defaults)?;
             input.pop_limit(old_limit);
 
             self.put(Value::Message(msg));
@@ -292,11 +813,127 @@ impl Field {
         }
     }
 
+    #[inline]
+    fn merge_group(
+        &mut self,
+        input: &mut protobuf::CodedInputStream,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        number: i32,
+        actual_wire_type: wire_format::WireType,
+        defaults: bool,
+    ) -> error::Result<()> {
+        if wire_format::WireType::WireTypeStartGroup == actual_wire_type {
+            let mut msg = match *self {
+                Field::Singular(ref mut o) => {
+                    if let Some(Value::Message(m)) = o.take() {
+                        m
+                    } else {
+                        Message::with_defaults(message, defaults)
+                    }
+                }
+                _ => Message::with_defaults(message, defaults),
+            };
+
+            msg.merge_group_fields_from(descriptors, message, input, number as u32, defaults)?;
+
+            self.put(Value::Message(msg));
+            Ok(())
+        } else {
+            Err(error::Error::BadWireType {
+                wire_type: actual_wire_type,
+            })
+        }
+    }
+
     #[inline]
     fn put(&mut self, value: Value) {
         match *self {
             Field::Singular(ref mut s) => *s = Some(value),
             Field::Repeated(ref mut r) => r.push(value),
+            Field::Map(_) => panic!("Map entries can only be merged with their keys"),
+        }
+    }
+}
+
+/// Merges a single map entry message from the given input stream into the entries of a map field.
+///
+/// A later entry with the same key replaces an earlier one.  A missing key or value is the zero
+/// value of its type, or an empty message.
+#[inline]
+fn merge_map_entry(
+    entries: &mut collections::BTreeMap<MapKey, Value>,
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    input: &mut protobuf::CodedInputStream,
+    wire_type: wire_format::WireType,
+    defaults: bool,
+) -> error::Result<()> {
+    let entry = match field.field_type(descriptors) {
+        descriptor::FieldType::Message(m) => m,
+        descriptor::FieldType::UnresolvedMessage(m) => {
+            return Err(error::Error::UnknownMessage { name: m.to_owned() })
+        }
+        _ => {
+            return Err(error::Error::BadFieldValue {
+                field: field.name().to_owned(),
+            })
         }
+    };
+    if wire_type != wire_format::WireTypeLengthDelimited {
+        return Err(error::Error::BadWireType { wire_type });
     }
+
+    let len = input.read_raw_varint64()?;
+    let mut message = Message::new(entry);
+    let old_limit = input.push_limit(len)?;
+    message.merge_fields_from(descriptors, entry, input, defaults)?;
+    input.pop_limit(old_limit);
+
+    let bad_entry = || error::Error::BadFieldValue {
+        field: field.name().to_owned(),
+    };
+    let key = match (message.fields.remove(&1), entry.field_by_number(1)) {
+        (Some(Field::Singular(Some(k))), _) => k,
+        (_, Some(key_field)) => key_field.zero_value().ok_or_else(bad_entry)?,
+        (_, None) => return Err(bad_entry()),
+    };
+    let key = MapKey::from_value(key).ok_or_else(bad_entry)?;
+    let value = match (message.fields.remove(&2), entry.field_by_number(2)) {
+        (Some(Field::Singular(Some(v))), _) => v,
+        (_, Some(value_field)) => match value_field.field_type(descriptors) {
+            descriptor::FieldType::Message(m) => {
+                Value::Message(Message::with_defaults(m, defaults))
+            }
+            _ => value_field.zero_value().ok_or_else(bad_entry)?,
+        },
+        (_, None) => return Err(bad_entry()),
+    };
+    entries.insert(key, value);
+    Ok(())
+}
+
+/// The size in bytes of the payload of a packed repeated field, without its tag and length.
+#[inline]
+fn packed_size(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    values: &[Value],
+) -> u32 {
+    values
+        .iter()
+        .map(|v| v.compute_packed_size(descriptors, field))
+        .sum()
+}
+
+/// Builds the entry message of a map field for writing it out.
+#[inline]
+fn map_entry(key: &MapKey, value: &Value) -> Value {
+    let mut fields = collections::BTreeMap::new();
+    fields.insert(1, Field::Singular(Some(key.clone().into_value())));
+    fields.insert(2, Field::Singular(Some(value.clone())));
+    Value::Message(Message {
+        fields,
+        unknown: protobuf::UnknownFields::new(),
+    })
 }
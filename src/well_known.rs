@@ -0,0 +1,256 @@
+//! Helpers shared by the representations of the `google.protobuf` well-known types.
+use crate::descriptor;
+
+/// The `google.protobuf` types with a special representation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WellKnownType {
+    Any,
+    Timestamp,
+    Duration,
+    FieldMask,
+    Empty,
+    Wrapper,
+    Struct,
+    Value,
+    ListValue,
+}
+
+impl WellKnownType {
+    #[inline]
+    pub fn of(message: &descriptor::MessageDescriptor) -> Option<WellKnownType> {
+        match message.name() {
+            ".google.protobuf.Any" => Some(WellKnownType::Any),
+            ".google.protobuf.Timestamp" => Some(WellKnownType::Timestamp),
+            ".google.protobuf.Duration" => Some(WellKnownType::Duration),
+            ".google.protobuf.FieldMask" => Some(WellKnownType::FieldMask),
+            ".google.protobuf.Empty" => Some(WellKnownType::Empty),
+            ".google.protobuf.DoubleValue"
+            | ".google.protobuf.FloatValue"
+            | ".google.protobuf.Int64Value"
+            | ".google.protobuf.UInt64Value"
+            | ".google.protobuf.Int32Value"
+            | ".google.protobuf.UInt32Value"
+            | ".google.protobuf.BoolValue"
+            | ".google.protobuf.StringValue"
+            | ".google.protobuf.BytesValue" => Some(WellKnownType::Wrapper),
+            ".google.protobuf.Struct" => Some(WellKnownType::Struct),
+            ".google.protobuf.Value" => Some(WellKnownType::Value),
+            ".google.protobuf.ListValue" => Some(WellKnownType::ListValue),
+            _ => None,
+        }
+    }
+}
+
+/// Formats a timestamp as an RFC 3339 string in UTC, or returns `None` if it is outside of the
+/// range from `0001-01-01T00:00:00Z` to `9999-12-31T23:59:59.999999999Z`.
+pub fn format_timestamp(secs: i64, nanos: i32) -> Option<String> {
+    if !(-62_135_596_800..=253_402_300_799).contains(&secs) || !(0..=999_999_999).contains(&nanos) {
+        return None;
+    }
+
+    // Converts days since the epoch to a date in the proleptic Gregorian calendar, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = secs.div_euclid(86_400) + 719_468;
+    let time = secs.rem_euclid(86_400);
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    Some(format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        format_nanos(nanos)
+    ))
+}
+
+/// Formats a duration as a number of seconds with an `s` suffix, or returns `None` if it is out of
+/// range or the signs of `secs` and `nanos` disagree.
+pub fn format_duration(secs: i64, nanos: i32) -> Option<String> {
+    if !(-315_576_000_000..=315_576_000_000).contains(&secs)
+        || !(-999_999_999..=999_999_999).contains(&nanos)
+        || (secs < 0 && nanos > 0)
+        || (secs > 0 && nanos < 0)
+    {
+        return None;
+    }
+    let sign = if secs < 0 || nanos < 0 { "-" } else { "" };
+    Some(format!(
+        "{}{}{}s",
+        sign,
+        secs.abs(),
+        format_nanos(nanos.abs())
+    ))
+}
+
+/// Formats the fractional part of a number of seconds with 0, 3, 6 or 9 digits, as required to
+/// represent it exactly.
+fn format_nanos(nanos: i32) -> String {
+    if nanos == 0 {
+        String::new()
+    } else if nanos % 1_000_000 == 0 {
+        format!(".{:03}", nanos / 1_000_000)
+    } else if nanos % 1_000 == 0 {
+        format!(".{:06}", nanos / 1_000)
+    } else {
+        format!(".{:09}", nanos)
+    }
+}
+
+/// Parses an RFC 3339 timestamp into seconds and nanoseconds since the epoch, or returns `None` if
+/// it is malformed or outside of the range accepted by `format_timestamp`.
+///
+/// The date and time must be separated by an uppercase `T`, and the offset must be either `Z` or
+/// of the form `+HH:MM`.  Between 1 and 9 fractional digits are accepted.
+#[cfg(feature = "json")]
+pub fn parse_timestamp(s: &str) -> Option<(i64, i32)> {
+    let b = s.as_bytes();
+    if b.len() < 20
+        || b[4] != b'-'
+        || b[7] != b'-'
+        || b[10] != b'T'
+        || b[13] != b':'
+        || b[16] != b':'
+    {
+        return None;
+    }
+    let year = parse_digits(&b[0..4])?;
+    let month = parse_digits(&b[5..7])?;
+    let day = parse_digits(&b[8..10])?;
+    let hour = parse_digits(&b[11..13])?;
+    let minute = parse_digits(&b[14..16])?;
+    let second = parse_digits(&b[17..19])?;
+    if year == 0
+        || !(1..=12).contains(&month)
+        || day == 0
+        || day > days_in_month(year, month)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+
+    let (nanos, rest) = parse_fraction(&b[19..])?;
+    let offset = match rest {
+        b"Z" => 0,
+        [sign @ b'+', h1, h2, b':', m1, m2] | [sign @ b'-', h1, h2, b':', m1, m2] => {
+            let hours = parse_digits(&[*h1, *h2])?;
+            let minutes = parse_digits(&[*m1, *m2])?;
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            let offset = hours * 3600 + minutes * 60;
+            if *sign == b'-' {
+                -offset
+            } else {
+                offset
+            }
+        }
+        _ => return None,
+    };
+
+    // Converts a date in the proleptic Gregorian calendar to days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * shifted_month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second - offset;
+    if (-62_135_596_800..=253_402_300_799).contains(&secs) {
+        Some((secs, nanos))
+    } else {
+        None
+    }
+}
+
+/// Parses a duration of the form produced by `format_duration`, accepting between 0 and 9
+/// fractional digits.
+#[cfg(feature = "json")]
+pub fn parse_duration(s: &str) -> Option<(i64, i32)> {
+    let b = s.as_bytes();
+    let (negative, b) = match b.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        _ => (false, b),
+    };
+    let b = match b.split_last() {
+        Some((b's', rest)) => rest,
+        _ => return None,
+    };
+    let int_len = b.iter().take_while(|c| c.is_ascii_digit()).count();
+    if int_len == 0 || int_len > 12 {
+        return None;
+    }
+    let secs = parse_digits(&b[..int_len])?;
+    let nanos = match &b[int_len..] {
+        [] => 0,
+        fraction => match parse_fraction(fraction)? {
+            (nanos, []) => nanos,
+            _ => return None,
+        },
+    };
+    if secs > 315_576_000_000 {
+        return None;
+    }
+    if negative {
+        Some((-secs, -nanos))
+    } else {
+        Some((secs, nanos))
+    }
+}
+
+/// Parses an optional fraction of a second starting with `.`, returning the number of
+/// nanoseconds and the remaining input.
+#[cfg(feature = "json")]
+fn parse_fraction(b: &[u8]) -> Option<(i32, &[u8])> {
+    match b.split_first() {
+        Some((b'.', rest)) => {
+            let len = rest.iter().take_while(|c| c.is_ascii_digit()).count();
+            if len == 0 || len > 9 {
+                return None;
+            }
+            let nanos = parse_digits(&rest[..len])? * 10i64.pow(9 - len as u32);
+            Some((nanos as i32, &rest[len..]))
+        }
+        _ => Some((0, b)),
+    }
+}
+
+#[cfg(feature = "json")]
+fn parse_digits(b: &[u8]) -> Option<i64> {
+    b.iter().try_fold(0i64, |acc, &c| {
+        if c.is_ascii_digit() {
+            Some(acc * 10 + i64::from(c - b'0'))
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(feature = "json")]
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
@@ -1,5 +1,7 @@
 extern crate protobuf;
 extern crate serde;
+#[cfg(feature = "json")]
+extern crate serde_json;
 extern crate serde_value;
 
 extern crate serde_protobuf;
@@ -7,8 +9,17 @@ extern crate serde_protobuf;
 use std::collections;
 use std::fs;
 
+use protobuf::Message;
+
 use serde_protobuf::de;
 use serde_protobuf::descriptor;
+use serde_protobuf::error;
+#[cfg(feature = "json")]
+use serde_protobuf::json;
+use serde_protobuf::parser;
+use serde_protobuf::ser;
+use serde_protobuf::text;
+use serde_protobuf::value;
 
 mod protobuf_unittest;
 
@@ -149,7 +160,10 @@ macro_rules! assert_subset {
 }
 
 macro_rules! roundtrip {
-    ($t:ty, $v:ident, $s:stmt) => {{
+    ($t:ty, $v:ident, $s:stmt) => {
+        roundtrip!($t, de::Options::new(), $v, $s)
+    };
+    ($t:ty, $options:expr, $v:ident, $s:stmt) => {{
         use serde::de::Deserialize;
 
         let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
@@ -165,10 +179,36 @@ macro_rules! roundtrip {
 
         let mut deserializer =
             de::Deserializer::for_named_message(&descriptors, &message_name, input).unwrap();
+        deserializer.set_options($options);
         serde_value::Value::deserialize(&mut deserializer).unwrap()
     }};
 }
 
+macro_rules! serialize {
+    ($t:ty, $v:expr) => {{
+        use serde::ser::Serialize;
+
+        let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+        let proto = protobuf::parse_from_reader(&mut file).unwrap();
+        let descriptors = descriptor::Descriptors::from_proto(&proto);
+
+        let message_name = format!(
+            ".{}",
+            protobuf::Message::descriptor(&<$t>::new()).full_name()
+        );
+
+        let mut bytes = Vec::new();
+        let result = {
+            let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+            let mut serializer =
+                ser::Serializer::for_named_message(&descriptors, &message_name, &mut output)
+                    .unwrap();
+            $v.serialize(&mut serializer)
+        };
+        result.map(|()| protobuf::parse_from_bytes::<$t>(&bytes).unwrap())
+    }};
+}
+
 #[test]
 fn roundtrip_optional_message() {
     let v = roundtrip!(protobuf_unittest::unittest::TestAllTypes, v, {
@@ -465,3 +505,3622 @@ check_roundtrip_repeated!(
     [vec![1, 2, 3], vec![2, 3, 4]],
     byte_buf
 );
+
+#[test]
+fn serialize_scalars() {
+    let v = serialize!(
+        protobuf_unittest::unittest::TestAllTypes,
+        value!(map {
+            (str: "optional_int32") => (i32: -42),
+            (str: "optional_uint64") => (u8: 42),
+            (str: "optional_sint32") => (i64: -7),
+            (str: "optional_fixed64") => (u64: 9),
+            (str: "optional_double") => (f32: 0.5),
+            (str: "optional_bool") => (some bool: true),
+            (str: "optional_string") => (str: "hello"),
+            (str: "optional_bytes") => (bytes: b"world"),
+            (str: "optional_int64") => (none)
+        })
+    )
+    .unwrap();
+
+    assert_eq!(-42, v.get_optional_int32());
+    assert_eq!(42, v.get_optional_uint64());
+    assert_eq!(-7, v.get_optional_sint32());
+    assert_eq!(9, v.get_optional_fixed64());
+    assert_eq!(0.5, v.get_optional_double());
+    assert!(v.get_optional_bool());
+    assert_eq!("hello", v.get_optional_string());
+    assert_eq!(b"world", v.get_optional_bytes());
+    assert!(!v.has_optional_int64());
+}
+
+#[test]
+fn serialize_enum_and_message() {
+    let v = serialize!(
+        protobuf_unittest::unittest::TestAllTypes,
+        value!(map {
+            (str: "optional_nested_enum") => (str: "BAZ"),
+            (str: "optional_foreign_enum") => (i32: 5),
+            (str: "optional_nested_message") => (map {
+                (str: "bb") => (i32: 1)
+            }),
+            (str: "repeated_nested_message") => (seq [
+                (map { (str: "bb") => (i32: 2) }),
+                (map { (str: "bb") => (i32: 3) })
+            ])
+        })
+    )
+    .unwrap();
+
+    assert_eq!(
+        protobuf_unittest::unittest::TestAllTypes_NestedEnum::BAZ,
+        v.get_optional_nested_enum()
+    );
+    assert_eq!(
+        protobuf_unittest::unittest::ForeignEnum::FOREIGN_BAR,
+        v.get_optional_foreign_enum()
+    );
+    assert_eq!(1, v.get_optional_nested_message().get_bb());
+    assert_eq!(2, v.get_repeated_nested_message().len());
+    assert_eq!(3, v.get_repeated_nested_message()[1].get_bb());
+}
+
+#[test]
+fn serialize_repeated() {
+    let v = serialize!(
+        protobuf_unittest::unittest::TestAllTypes,
+        value!(map {
+            (str: "repeated_int32") => (seq [(i32: 42), (i32: 21), (i32: 0)]),
+            (str: "repeated_string") => (seq [(str: "a"), (str: "b")])
+        })
+    )
+    .unwrap();
+
+    assert_eq!(&[42, 21, 0], v.get_repeated_int32());
+    assert_eq!(&["a".to_owned(), "b".to_owned()], v.get_repeated_string());
+}
+
+#[test]
+fn serialize_packed() {
+    use serde::ser::Serialize;
+
+    let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+    let proto = protobuf::parse_from_reader(&mut file).unwrap();
+    let descriptors = descriptor::Descriptors::from_proto(&proto);
+    let serialize = |v: serde_value::Value| {
+        let mut bytes = Vec::new();
+        {
+            let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+            let mut serializer = ser::Serializer::for_named_message(
+                &descriptors,
+                ".protobuf_unittest.TestPackedTypes",
+                &mut output,
+            )
+            .unwrap();
+            v.serialize(&mut serializer).unwrap();
+        }
+        bytes
+    };
+
+    // A single length-delimited run of 11 bytes, and nothing at all for an empty field
+    let bytes = serialize(value!(map {
+        (str: "packed_int32") => (seq [(i32: 1), (i32: -2)]),
+        (str: "packed_uint32") => (seq [])
+    }));
+    assert_eq!(
+        vec![0xd2, 0x05, 0x0b, 0x01, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+        bytes
+    );
+
+    let bytes = serialize(value!(map {
+        (str: "packed_sint64") => (seq [(i64: -3)]),
+        (str: "packed_fixed32") => (seq [(u32: 7)]),
+        (str: "packed_double") => (seq [(f64: 0.5)]),
+        (str: "packed_bool") => (seq [(bool: true)]),
+        (str: "packed_enum") => (seq [(str: "FOREIGN_BAR")])
+    }));
+    let v =
+        protobuf::parse_from_bytes::<protobuf_unittest::unittest::TestPackedTypes>(&bytes).unwrap();
+    assert_eq!(&[-3], v.get_packed_sint64());
+    assert_eq!(&[7], v.get_packed_fixed32());
+    assert_eq!(&[0.5], v.get_packed_double());
+    assert_eq!(&[true], v.get_packed_bool());
+    assert_eq!(
+        &[protobuf_unittest::unittest::ForeignEnum::FOREIGN_BAR],
+        v.get_packed_enum()
+    );
+    // Each field is a single run, with a two-byte tag and a one-byte length
+    assert_eq!((3 + 1) + (3 + 4) + (3 + 8) + (3 + 1) + (3 + 1), bytes.len());
+}
+
+#[test]
+fn serialize_unknown_field() {
+    let e = serialize!(
+        protobuf_unittest::unittest::TestAllTypes,
+        value!(map { (str: "no_such_field") => (i32: 1) })
+    )
+    .unwrap_err();
+
+    match e.into_error() {
+        error::Error::UnknownField { name } => assert_eq!("no_such_field", name),
+        e => panic!("unexpected error: {:?}", e),
+    }
+}
+
+#[test]
+fn serialize_bad_field_value() {
+    let e = serialize!(
+        protobuf_unittest::unittest::TestAllTypes,
+        value!(map { (str: "optional_int32") => (i64: 1 << 40) })
+    )
+    .unwrap_err();
+
+    match e.into_error() {
+        error::Error::BadFieldValue { field } => assert_eq!("optional_int32", field),
+        e => panic!("unexpected error: {:?}", e),
+    }
+}
+
+macro_rules! reencode {
+    ($t:ty, $message_name:expr, $v:ident, $s:stmt) => {{
+        let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+        let proto = protobuf::parse_from_reader(&mut file).unwrap();
+        let descriptors = descriptor::Descriptors::from_proto(&proto);
+        let message_descriptor = descriptors.message_by_name($message_name).unwrap();
+
+        let mut $v = <$t>::new();
+        $s
+        let bytes = protobuf::Message::write_to_bytes(&mut $v).unwrap();
+
+        let mut message = value::Message::empty();
+        {
+            let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+            message
+                .merge_present_from(&descriptors, message_descriptor, &mut input)
+                .unwrap();
+        }
+
+        let mut output_bytes = Vec::new();
+        {
+            let mut output = protobuf::CodedOutputStream::vec(&mut output_bytes);
+            message
+                .write_to(&descriptors, message_descriptor, &mut output)
+                .unwrap();
+            output.flush().unwrap();
+        }
+        assert_eq!(
+            message.compute_size(&descriptors, message_descriptor) as usize,
+            output_bytes.len()
+        );
+
+        let parsed = protobuf::parse_from_bytes::<$t>(&output_bytes).unwrap();
+        ($v, parsed, bytes, output_bytes)
+    }};
+}
+
+#[test]
+fn reencode_all_types() {
+    let (_, v, bytes, output_bytes) = reencode!(
+        protobuf_unittest::unittest::TestAllTypes,
+        ".protobuf_unittest.TestAllTypes",
+        v,
+        {
+            v.set_optional_int32(-42);
+            v.set_optional_sint64(-7);
+            v.set_optional_fixed32(9);
+            v.set_optional_float(0.5);
+            v.set_optional_string("hello".to_owned());
+            v.set_optional_nested_enum(protobuf_unittest::unittest::TestAllTypes_NestedEnum::BAZ);
+            v.mut_optional_nested_message().set_bb(1);
+            v.mut_repeated_uint64().push(3);
+            v.mut_repeated_uint64().push(4);
+            v.mut_repeated_bytes().push(vec![1, 2, 3]);
+        }
+    );
+
+    assert_eq!(-42, v.get_optional_int32());
+    assert_eq!(-7, v.get_optional_sint64());
+    assert_eq!(9, v.get_optional_fixed32());
+    assert_eq!(0.5, v.get_optional_float());
+    assert_eq!("hello", v.get_optional_string());
+    assert_eq!(
+        protobuf_unittest::unittest::TestAllTypes_NestedEnum::BAZ,
+        v.get_optional_nested_enum()
+    );
+    assert_eq!(1, v.get_optional_nested_message().get_bb());
+    assert_eq!(&[3, 4], v.get_repeated_uint64());
+    assert_eq!(&[vec![1, 2, 3]], v.get_repeated_bytes());
+    assert_eq!(41, v.get_default_int32());
+    assert!(!v.has_default_int32());
+    assert_eq!(bytes, output_bytes);
+}
+
+#[test]
+fn reencode_defaults() {
+    let (_, v, bytes, output_bytes) = reencode!(
+        protobuf_unittest::unittest::TestExtremeDefaultValues,
+        ".protobuf_unittest.TestExtremeDefaultValues",
+        v,
+        {}
+    );
+
+    assert!(!v.has_large_uint32());
+    assert!(bytes.is_empty());
+    assert!(output_bytes.is_empty());
+}
+
+#[test]
+fn reencode_proto3() {
+    let mut parser = parser::Parser::new();
+    parser.add_source(
+        "test.proto",
+        r#"
+            syntax = "proto3";
+            package test;
+
+            message M {
+                int32 a = 1;
+                string s = 2;
+                bool b = 3;
+                M child = 4;
+                optional int32 o = 5;
+            }
+        "#,
+    );
+    let descriptors = parser.parse_descriptors(&["test.proto"]).unwrap();
+    let message_descriptor = descriptors.message_by_name(".test.M").unwrap();
+
+    // a = 5, child = {}, o = 0; the zero values of a, s and b aren't encoded
+    let bytes = [0x08, 0x05, 0x22, 0x00, 0x28, 0x00];
+    let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut message = value::Message::new(message_descriptor);
+    message
+        .merge_from(&descriptors, message_descriptor, &mut input)
+        .unwrap();
+
+    let mut output_bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::vec(&mut output_bytes);
+        message
+            .write_to(&descriptors, message_descriptor, &mut output)
+            .unwrap();
+        output.flush().unwrap();
+    }
+    assert_eq!(&bytes[..], &output_bytes[..]);
+    assert_eq!(
+        bytes.len(),
+        message.compute_size(&descriptors, message_descriptor) as usize
+    );
+}
+
+#[test]
+fn reencode_packed() {
+    let (_, v, bytes, output_bytes) = reencode!(
+        protobuf_unittest::unittest::TestPackedTypes,
+        ".protobuf_unittest.TestPackedTypes",
+        v,
+        {
+            v.mut_packed_int32().push(1);
+            v.mut_packed_int32().push(-2);
+            v.mut_packed_sint64().push(-3);
+            v.mut_packed_fixed32().push(7);
+            v.mut_packed_double().push(0.5);
+            v.mut_packed_bool().push(true);
+            v.mut_packed_enum()
+                .push(protobuf_unittest::unittest::ForeignEnum::FOREIGN_BAR);
+        }
+    );
+
+    assert_eq!(&[1, -2], v.get_packed_int32());
+    assert!(v.get_packed_uint32().is_empty());
+    // packed_int32 is written as a single length-delimited run of 11 bytes
+    assert_eq!(
+        &[0xd2, 0x05, 0x0b, 0x01, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+        &output_bytes[..14]
+    );
+    assert_eq!(bytes, output_bytes);
+}
+
+#[test]
+fn reencode_unknown_fields() {
+    let (original, v, _, _) = reencode!(
+        protobuf_unittest::unittest::TestAllTypes,
+        ".protobuf_unittest.ForeignMessage",
+        v,
+        {
+            v.set_optional_int32(42);
+            v.set_optional_fixed64(8);
+            v.set_optional_float(0.25);
+            v.set_optional_string("hello".to_owned());
+            v.mut_optional_nested_message().set_bb(1);
+            v.mut_repeated_int32().push(5);
+        }
+    );
+
+    assert_eq!(original, v);
+}
+
+macro_rules! check_streaming {
+    ($id:ident, $t:ty, $v:ident, $s:stmt) => {
+        check_streaming!($id, de::Options::new(), $t, $v, $s);
+    };
+    ($id:ident, $options:expr, $t:ty, $v:ident, $s:stmt) => {
+        #[test]
+        fn $id() {
+            let eager = roundtrip!($t, $options, $v, $s);
+            let streamed = roundtrip!($t, $options.with_streaming(true), $v, $s);
+            assert_eq!(eager, streamed);
+        }
+    };
+}
+
+check_streaming!(
+    streaming_empty,
+    protobuf_unittest::unittest::TestAllTypes,
+    v,
+    {}
+);
+
+check_streaming!(
+    streaming_all_types,
+    protobuf_unittest::unittest::TestAllTypes,
+    v,
+    {
+        v.set_optional_int32(42);
+        v.set_optional_string("hello".to_owned());
+        v.set_optional_bytes(vec![1, 2, 3]);
+        v.set_optional_nested_enum(protobuf_unittest::unittest::TestAllTypes_NestedEnum::BAZ);
+        v.mut_optional_nested_message().set_bb(1);
+        v.mut_repeated_int32().push(1);
+        v.mut_repeated_int32().push(2);
+        v.mut_repeated_string().push("a".to_owned());
+        v.mut_repeated_nested_message().push_default().set_bb(2);
+        v.mut_repeated_nested_message().push_default().set_bb(3);
+        v.set_default_int32(7);
+    }
+);
+
+check_streaming!(
+    streaming_packed,
+    protobuf_unittest::unittest::TestPackedTypes,
+    v,
+    {
+        v.mut_packed_int32().push(1);
+        v.mut_packed_int32().push(-2);
+        v.mut_packed_double().push(0.5);
+        v.mut_packed_bool().push(true);
+        v.mut_packed_enum()
+            .push(protobuf_unittest::unittest::ForeignEnum::FOREIGN_BAR);
+    }
+);
+
+check_streaming!(
+    streaming_closed_enum,
+    protobuf_unittest::unittest::TestAllTypes,
+    v,
+    {
+        v.set_optional_int32(1);
+        v.mut_repeated_nested_enum()
+            .push(protobuf_unittest::unittest::TestAllTypes_NestedEnum::BAR);
+        // Values that the closed enum doesn't declare in optional_nested_enum and
+        // repeated_nested_enum
+        v.mut_unknown_fields().add_varint(21, 99);
+        v.mut_unknown_fields().add_varint(51, 99);
+    }
+);
+
+check_streaming!(
+    streaming_closed_enum_unknown_fields,
+    de::Options::new().with_unknown_fields(true),
+    protobuf_unittest::unittest::TestAllTypes,
+    v,
+    {
+        v.set_optional_int32(1);
+        v.mut_repeated_nested_enum()
+            .push(protobuf_unittest::unittest::TestAllTypes_NestedEnum::BAR);
+        v.mut_unknown_fields().add_varint(21, 99);
+        v.mut_unknown_fields().add_varint(51, 99);
+    }
+);
+
+check_streaming!(
+    streaming_recursive,
+    protobuf_unittest::unittest::TestRecursiveMessage,
+    v,
+    {
+        v.mut_a().mut_a().set_i(3);
+        v.mut_a().mut_a().mut_a().mut_a().set_i(4);
+    }
+);
+
+check_streaming!(
+    streaming_required,
+    protobuf_unittest::unittest::TestRequired,
+    v,
+    {
+        v.set_a(1);
+        v.set_b(2);
+        v.set_c(3);
+    }
+);
+
+#[test]
+fn streaming_skips_unknown_fields() {
+    use serde::de::Deserialize;
+
+    let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+    let proto = protobuf::parse_from_reader(&mut file).unwrap();
+    let descriptors = descriptor::Descriptors::from_proto(&proto);
+
+    let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+    v.set_optional_int32(42);
+    v.set_optional_string("hello".to_owned());
+    v.mut_repeated_nested_message().push_default().set_bb(2);
+    let bytes = protobuf::Message::write_to_bytes(&v).unwrap();
+
+    let input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut deserializer = de::Deserializer::for_named_message(
+        &descriptors,
+        ".protobuf_unittest.ForeignMessage",
+        input,
+    )
+    .unwrap();
+    deserializer.set_options(de::Options::new().with_streaming(true));
+    let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+
+    assert_eq!(
+        value!(map {
+            (str: "c") => (some i32: 42)
+        }),
+        v
+    );
+}
+
+/// A value that only accepts strings and bytes that are borrowed from the input.
+#[derive(Debug, PartialEq)]
+enum Borrowed<'a> {
+    Str(&'a str),
+    Bytes(&'a [u8]),
+    Seq(Vec<Borrowed<'a>>),
+    Map(collections::BTreeMap<&'a str, Borrowed<'a>>),
+    Other,
+}
+
+impl<'de> serde::de::Deserialize<'de> for Borrowed<'de> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Borrowed<'de>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a borrowed value")
+            }
+
+            fn visit_borrowed_str<E>(self, v: &'de str) -> Result<Self::Value, E> {
+                Ok(Borrowed::Str(v))
+            }
+
+            fn visit_borrowed_bytes<E>(self, v: &'de [u8]) -> Result<Self::Value, E> {
+                Ok(Borrowed::Bytes(v))
+            }
+
+            fn visit_bool<E>(self, _: bool) -> Result<Self::Value, E> {
+                Ok(Borrowed::Other)
+            }
+
+            fn visit_i64<E>(self, _: i64) -> Result<Self::Value, E> {
+                Ok(Borrowed::Other)
+            }
+
+            fn visit_u64<E>(self, _: u64) -> Result<Self::Value, E> {
+                Ok(Borrowed::Other)
+            }
+
+            fn visit_f64<E>(self, _: f64) -> Result<Self::Value, E> {
+                Ok(Borrowed::Other)
+            }
+
+            fn visit_none<E>(self) -> Result<Self::Value, E> {
+                Ok(Borrowed::Other)
+            }
+
+            fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+            where
+                D: serde::de::Deserializer<'de>,
+            {
+                serde::de::Deserialize::deserialize(deserializer)
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::SeqAccess<'de>,
+            {
+                let mut values = Vec::new();
+                while let Some(v) = seq.next_element()? {
+                    values.push(v);
+                }
+                Ok(Borrowed::Seq(values))
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let mut entries = collections::BTreeMap::new();
+                while let Some((k, v)) = map.next_entry()? {
+                    entries.insert(k, v);
+                }
+                Ok(Borrowed::Map(entries))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[test]
+fn streaming_borrows_from_bytes() {
+    use serde::de::Deserialize;
+
+    let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+    let proto = protobuf::parse_from_reader(&mut file).unwrap();
+    let descriptors = descriptor::Descriptors::from_proto(&proto);
+
+    let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+    v.set_optional_string("hello".to_owned());
+    v.set_optional_bytes(vec![1, 2, 3]);
+    v.mut_repeated_string().push("a".to_owned());
+    v.mut_repeated_string().push("b".to_owned());
+    let bytes = protobuf::Message::write_to_bytes(&v).unwrap();
+
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let mut deserializer = de::Deserializer::from_bytes(&descriptors, message, &bytes);
+    deserializer.set_options(de::Options::new().with_streaming(true));
+    let v = collections::BTreeMap::<&str, Borrowed>::deserialize(&mut deserializer).unwrap();
+
+    assert_eq!(Borrowed::Str("hello"), v["optional_string"]);
+    assert_eq!(Borrowed::Bytes(&[1, 2, 3]), v["optional_bytes"]);
+    assert_eq!(
+        Borrowed::Seq(vec![Borrowed::Str("a"), Borrowed::Str("b")]),
+        v["repeated_string"]
+    );
+    // Defaults of missing fields are borrowed from the descriptors
+    assert_eq!(Borrowed::Str("hello"), v["default_string"]);
+    assert_eq!(Borrowed::Bytes(b"world"), v["default_bytes"]);
+}
+
+#[test]
+fn from_bytes_borrows_by_default() {
+    use serde::de::Deserialize;
+
+    let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+    let proto = protobuf::parse_from_reader(&mut file).unwrap();
+    let descriptors = descriptor::Descriptors::from_proto(&proto);
+
+    let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+    v.set_optional_string("hello".to_owned());
+    let bytes = protobuf::Message::write_to_bytes(&v).unwrap();
+
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let mut deserializer = de::Deserializer::from_bytes(&descriptors, message, &bytes);
+    let v = collections::BTreeMap::<&str, Borrowed>::deserialize(&mut deserializer).unwrap();
+
+    assert_eq!(Borrowed::Str("hello"), v["optional_string"]);
+}
+
+#[test]
+fn from_bytes_borrows_without_streaming() {
+    use serde::de::Deserialize;
+
+    let mut parser = parser::Parser::new();
+    parser.add_source(
+        "test.proto",
+        r#"
+            syntax = "proto3";
+            package test;
+
+            message Inner {
+                string s = 1;
+            }
+
+            message M {
+                string name = 1;
+                repeated string tags = 2;
+                Inner inner = 3;
+                bytes data = 4;
+            }
+        "#,
+    );
+    let descriptors = parser.parse_descriptors(&["test.proto"]).unwrap();
+    let message = descriptors.message_by_name(".test.M").unwrap();
+
+    // name = "a", tags = ["x", "y"], inner = { s = "in" }, data = [1, 2], then name = "b" again
+    let bytes = [
+        0x0a, 0x01, b'a', 0x12, 0x01, b'x', 0x12, 0x01, b'y', 0x1a, 0x04, 0x0a, 0x02, b'i', b'n',
+        0x22, 0x02, 0x01, 0x02, 0x0a, 0x01, b'b',
+    ];
+
+    let mut deserializer = de::Deserializer::from_bytes(&descriptors, message, &bytes);
+    deserializer.set_options(de::Options::new());
+    let v = collections::BTreeMap::<&str, Borrowed>::deserialize(&mut deserializer).unwrap();
+    // The last value of a singular field wins, like outside of `from_bytes`
+    assert_eq!(Borrowed::Str("b"), v["name"]);
+    assert_eq!(
+        Borrowed::Seq(vec![Borrowed::Str("x"), Borrowed::Str("y")]),
+        v["tags"]
+    );
+    let mut inner = collections::BTreeMap::new();
+    inner.insert("s", Borrowed::Str("in"));
+    assert_eq!(Borrowed::Map(inner), v["inner"]);
+    assert_eq!(Borrowed::Bytes(&[1, 2]), v["data"]);
+
+    let mut deserializer = de::Deserializer::from_bytes(&descriptors, message, &bytes);
+    let borrowed = serde_value::Value::deserialize(&mut deserializer).unwrap();
+    let input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut deserializer = de::Deserializer::new(&descriptors, message, input);
+    assert_eq!(
+        serde_value::Value::deserialize(&mut deserializer).unwrap(),
+        borrowed
+    );
+}
+
+/// Deserializes a message as a struct with the specified fields, using `deserialize_struct`.
+struct Select(&'static [&'static str]);
+
+impl<'de> serde::de::DeserializeSeed<'de> for Select {
+    type Value = collections::BTreeMap<String, serde_value::Value>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = collections::BTreeMap<String, serde_value::Value>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a struct")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let mut result = collections::BTreeMap::new();
+                while let Some((k, v)) = map.next_entry()? {
+                    result.insert(k, v);
+                }
+                Ok(result)
+            }
+        }
+
+        deserializer.deserialize_struct("Select", self.0, Visitor)
+    }
+}
+
+macro_rules! check_select {
+    ($id:ident, $options:expr) => {
+        #[test]
+        fn $id() {
+            use serde::de::DeserializeSeed;
+
+            let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+            let proto = protobuf::parse_from_reader(&mut file).unwrap();
+            let descriptors = descriptor::Descriptors::from_proto(&proto);
+
+            let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+            v.set_optional_int32(42);
+            v.set_optional_string("hello".to_owned());
+            v.mut_repeated_string().push("a".to_owned());
+            let mut bytes = protobuf::Message::write_to_bytes(&v).unwrap();
+            // An optional_string field with invalid UTF-8, which must not be decoded
+            bytes.extend_from_slice(&[0x72, 0x01, 0xff]);
+            // An optional_nested_enum field with a value that the closed enum doesn't declare
+            bytes.extend_from_slice(UNKNOWN_ENUM);
+
+            let input = protobuf::CodedInputStream::from_bytes(&bytes);
+            let mut deserializer = de::Deserializer::for_named_message(
+                &descriptors,
+                ".protobuf_unittest.TestAllTypes",
+                input,
+            )
+            .unwrap();
+            deserializer.set_options($options);
+            let fields = &[
+                "optional_int32",
+                "repeated_string",
+                "default_int32",
+                "optional_nested_enum",
+            ];
+            let v = Select(fields).deserialize(&mut deserializer).unwrap();
+
+            let mut expected = collections::BTreeMap::new();
+            expected.insert("optional_int32".to_owned(), value!(some i32: 42));
+            expected.insert("repeated_string".to_owned(), value!(seq [(str: "a")]));
+            expected.insert("default_int32".to_owned(), value!(some i32: 41));
+            expected.insert("optional_nested_enum".to_owned(), value!(none));
+            assert_eq!(expected, v);
+        }
+    };
+}
+
+check_select!(select_fields, de::Options::new());
+check_select!(
+    select_fields_streaming,
+    de::Options::new().with_streaming(true)
+);
+
+/// The first two values of each repeated field of a message, and the values of all of its other
+/// fields, which must be `int32`s.
+#[derive(Debug, PartialEq)]
+struct Truncated(collections::BTreeMap<String, Vec<i32>>);
+
+impl<'de> serde::de::Deserialize<'de> for Truncated {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Truncated;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a message")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let mut result = collections::BTreeMap::new();
+                while let Some(k) = map.next_key::<String>()? {
+                    let v = if k == "after" {
+                        vec![map.next_value::<i32>()?]
+                    } else {
+                        map.next_value::<[i32; 2]>()?.to_vec()
+                    };
+                    result.insert(k, v);
+                }
+                Ok(Truncated(result))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[test]
+fn streaming_stops_sequences_early() {
+    use serde::de::Deserialize;
+
+    let mut parser = parser::Parser::new();
+    parser.add_source(
+        "test.proto",
+        r#"
+            syntax = "proto3";
+            package test;
+
+            message M {
+                repeated int32 packed = 1;
+                repeated int32 unpacked = 2 [packed = false];
+                int32 after = 3;
+            }
+        "#,
+    );
+    let descriptors = parser.parse_descriptors(&["test.proto"]).unwrap();
+    let message = descriptors.message_by_name(".test.M").unwrap();
+
+    // packed = [1, 2, 3], unpacked = [4, 5, 6], after = 7
+    let bytes = [
+        0x0a, 0x03, 0x01, 0x02, 0x03, 0x10, 0x04, 0x10, 0x05, 0x10, 0x06, 0x18, 0x07,
+    ];
+    let mut expected = collections::BTreeMap::new();
+    expected.insert("packed".to_owned(), vec![1, 2]);
+    expected.insert("unpacked".to_owned(), vec![4, 5]);
+    expected.insert("after".to_owned(), vec![7]);
+
+    for &streaming in &[false, true] {
+        let input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let mut deserializer = de::Deserializer::new(&descriptors, message, input);
+        deserializer.set_options(de::Options::new().with_streaming(streaming));
+        let v = Truncated::deserialize(&mut deserializer).unwrap();
+        assert_eq!(Truncated(expected.clone()), v);
+    }
+}
+
+#[test]
+fn streaming_skips_ignored_values() {
+    use serde::de::Deserialize;
+
+    let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+    let proto = protobuf::parse_from_reader(&mut file).unwrap();
+    let descriptors = descriptor::Descriptors::from_proto(&proto);
+
+    let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+    v.mut_optional_nested_message().set_bb(1);
+    v.mut_repeated_int32().push(1);
+    v.mut_repeated_int32().push(2);
+    let mut bytes = protobuf::Message::write_to_bytes(&v).unwrap();
+    // A repeated_string value with invalid UTF-8 and a truncated nested message, which must not be
+    // decoded
+    bytes.extend_from_slice(&[0xe2, 0x02, 0x01, 0xff]);
+    bytes.extend_from_slice(&[0x92, 0x01, 0x03, 0x08, 0xff, 0xff]);
+
+    let input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut deserializer =
+        de::Deserializer::for_named_message(&descriptors, ".protobuf_unittest.TestAllTypes", input)
+            .unwrap();
+    deserializer.set_options(de::Options::new().with_streaming(true));
+    let v = collections::BTreeMap::<String, serde::de::IgnoredAny>::deserialize(&mut deserializer)
+        .unwrap();
+
+    assert!(v.contains_key("optional_nested_message"));
+    assert!(v.contains_key("repeated_string"));
+}
+
+// optionalgroup { a: 5 } and two repeatedgroup { a: 1 }, { a: 2 } in the message described by
+// `group_descriptors`
+const GROUPS: &[u8] = &[
+    0x83, 0x01, 0x88, 0x01, 0x05, 0x84, 0x01, // optionalgroup
+    0xf3, 0x02, 0xf8, 0x02, 0x01, 0xf4, 0x02, // repeatedgroup
+    0xf3, 0x02, 0xf8, 0x02, 0x02, 0xf4, 0x02, // repeatedgroup
+];
+
+/// Descriptors for a message with the groups of `TestAllTypes`, which are missing from the test
+/// data.
+fn group_descriptors() -> descriptor::Descriptors {
+    use serde_protobuf::descriptor::{
+        FieldDescriptor, FieldLabel, InternalFieldType, MessageDescriptor,
+    };
+
+    let mut descriptors = descriptor::Descriptors::new();
+    let mut message = MessageDescriptor::new(".test.Groups");
+    for &(name, number, label) in &[
+        ("OptionalGroup", 16, FieldLabel::Optional),
+        ("RepeatedGroup", 46, FieldLabel::Repeated),
+    ] {
+        let group_name = format!(".test.Groups.{}", name);
+        let mut group = MessageDescriptor::new(group_name.clone());
+        group.add_field(FieldDescriptor::new(
+            "a",
+            number + 1,
+            FieldLabel::Optional,
+            InternalFieldType::Int32,
+            None,
+        ));
+        descriptors.add_message(group);
+        message.add_field(FieldDescriptor::new(
+            name.to_lowercase(),
+            number,
+            label,
+            InternalFieldType::UnresolvedGroup(group_name),
+            None,
+        ));
+    }
+    descriptors.add_message(message);
+    descriptors.resolve_refs();
+    descriptors
+}
+
+macro_rules! check_groups {
+    ($id:ident, $options:expr) => {
+        #[test]
+        fn $id() {
+            use serde::de::Deserialize;
+
+            let descriptors = group_descriptors();
+            let input = protobuf::CodedInputStream::from_bytes(GROUPS);
+            let mut deserializer =
+                de::Deserializer::for_named_message(&descriptors, ".test.Groups", input).unwrap();
+            deserializer.set_options($options);
+            let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+
+            assert_eq!(
+                value!(map {
+                    (str: "optionalgroup") => (some map {
+                        (str: "a") => (some i32: 5)
+                    }),
+                    (str: "repeatedgroup") => (seq [
+                        (map { (str: "a") => (some i32: 1) }),
+                        (map { (str: "a") => (some i32: 2) })
+                    ])
+                }),
+                v
+            );
+        }
+    };
+}
+
+check_groups!(deserialize_groups, de::Options::new());
+check_groups!(
+    deserialize_groups_streaming,
+    de::Options::new().with_streaming(true)
+);
+
+#[test]
+fn reencode_groups() {
+    let descriptors = group_descriptors();
+    let message_descriptor = descriptors.message_by_name(".test.Groups").unwrap();
+
+    let mut input = protobuf::CodedInputStream::from_bytes(GROUPS);
+    let mut message = value::Message::new(message_descriptor);
+    message
+        .merge_from(&descriptors, message_descriptor, &mut input)
+        .unwrap();
+
+    let mut output_bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::vec(&mut output_bytes);
+        message
+            .write_to(&descriptors, message_descriptor, &mut output)
+            .unwrap();
+        output.flush().unwrap();
+    }
+    assert_eq!(
+        message.compute_size(&descriptors, message_descriptor) as usize,
+        output_bytes.len()
+    );
+    assert_eq!(GROUPS, &output_bytes[..]);
+}
+
+#[test]
+fn serialize_groups() {
+    use serde::ser::Serialize;
+
+    let descriptors = group_descriptors();
+    let v = value!(map {
+        (str: "optionalgroup") => (map { (str: "a") => (i32: 5) }),
+        (str: "repeatedgroup") => (seq [
+            (map { (str: "a") => (i32: 1) }),
+            (map { (str: "a") => (i32: 2) })
+        ])
+    });
+
+    let mut bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+        let mut serializer =
+            ser::Serializer::for_named_message(&descriptors, ".test.Groups", &mut output).unwrap();
+        v.serialize(&mut serializer).unwrap();
+    }
+
+    assert_eq!(GROUPS, &bytes[..]);
+}
+
+// optional_nested_enum = 99 in TestAllTypes, which is not a declared value
+const UNKNOWN_ENUM: &[u8] = &[0xa8, 0x01, 0x63];
+
+#[test]
+fn closed_enum_unknown_value_is_unknown_field() {
+    let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+    let proto = protobuf::parse_from_reader(&mut file).unwrap();
+    let descriptors = descriptor::Descriptors::from_proto(&proto);
+    let message_descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let mut input = protobuf::CodedInputStream::from_bytes(UNKNOWN_ENUM);
+    let mut message = value::Message::new(message_descriptor);
+    message
+        .merge_from(&descriptors, message_descriptor, &mut input)
+        .unwrap();
+
+    match message.fields[&21] {
+        value::Field::Singular(None) => (),
+        ref f => panic!("Expected no value, got {:?}", f),
+    }
+    assert_eq!(&[99], message.unknown.get(21).unwrap().varint.as_slice());
+}
+
+macro_rules! check_unknown_enum {
+    ($id:ident, $message_name:expr, $bytes:expr, $options:expr, $expected:expr) => {
+        #[test]
+        fn $id() {
+            use serde::de::Deserialize;
+
+            let descriptors = enum_descriptors();
+            let input = protobuf::CodedInputStream::from_bytes($bytes);
+            let mut deserializer =
+                de::Deserializer::for_named_message(&descriptors, $message_name, input).unwrap();
+            deserializer.set_options($options);
+            let v = serde_value::Value::deserialize(&mut deserializer)
+                .map_err(|e| e.to_string())
+                .and_then(|v| match v {
+                    serde_value::Value::Map(mut m) => Ok(m.remove(&value!(str: "e")).unwrap()),
+                    v => panic!("Expected a map, got {:?}", v),
+                });
+            assert_eq!($expected, v);
+        }
+    };
+}
+
+/// Descriptors for the messages `.test.Closed` and `.test.Open`, each with an enum field `e = 1`
+/// whose type only declares the value `A = 0`.
+fn enum_descriptors() -> descriptor::Descriptors {
+    use serde_protobuf::descriptor::{
+        EnumDescriptor, EnumValueDescriptor, FieldDescriptor, FieldLabel, InternalFieldType,
+        MessageDescriptor,
+    };
+
+    let mut descriptors = descriptor::Descriptors::new();
+    for &(name, closed) in &[("Closed", true), ("Open", false)] {
+        let enum_name = format!(".test.{}.E", name);
+        let mut enum_descriptor = EnumDescriptor::new(enum_name.clone());
+        enum_descriptor.add_value(EnumValueDescriptor::new("A", 0));
+        enum_descriptor.set_closed(closed);
+        descriptors.add_enum(enum_descriptor);
+
+        let mut message = MessageDescriptor::new(format!(".test.{}", name));
+        message.add_field(FieldDescriptor::new(
+            "e",
+            1,
+            FieldLabel::Optional,
+            InternalFieldType::UnresolvedEnum(enum_name),
+            None,
+        ));
+        descriptors.add_message(message);
+    }
+    descriptors.resolve_refs();
+    descriptors
+}
+
+check_unknown_enum!(
+    unknown_enum_known_value,
+    ".test.Open",
+    &[0x08, 0x00],
+    de::Options::new(),
+    Ok(value!(some str: "A"))
+);
+
+check_unknown_enum!(
+    unknown_enum_error,
+    ".test.Open",
+    &[0x08, 0x05],
+    de::Options::new(),
+    Err("unknown enum value: 5".to_owned())
+);
+
+check_unknown_enum!(
+    unknown_enum_number,
+    ".test.Open",
+    &[0x08, 0x05],
+    de::Options::new().with_unknown_enum_values(de::UnknownEnumValues::Number),
+    Ok(value!(some i32: 5))
+);
+
+check_unknown_enum!(
+    unknown_enum_name,
+    ".test.Open",
+    &[0x08, 0x05],
+    de::Options::new().with_unknown_enum_values(de::UnknownEnumValues::Name),
+    Ok(value!(some str: "UNKNOWN_ENUM_VALUE_5"))
+);
+
+check_unknown_enum!(
+    unknown_enum_closed,
+    ".test.Closed",
+    &[0x08, 0x05],
+    de::Options::new(),
+    Ok(value!(none))
+);
+
+check_unknown_enum!(
+    unknown_enum_closed_streaming,
+    ".test.Closed",
+    &[0x08, 0x05],
+    de::Options::new()
+        .with_streaming(true)
+        .with_unknown_enum_values(de::UnknownEnumValues::Number),
+    Ok(value!(none))
+);
+
+// c: 42 followed by fields that `.protobuf_unittest.ForeignMessage` doesn't declare: varints 1 and
+// 2 in field 10, fixed32 7 in field 11, fixed64 8 in field 12, "abc" in field 13 and an empty
+// group in field 14
+const UNKNOWN_FIELDS: &[u8] = &[
+    0x08, 0x2a, // c
+    0x50, 0x01, 0x50, 0x02, // varint
+    0x5d, 0x07, 0x00, 0x00, 0x00, // fixed32
+    0x61, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // fixed64
+    0x6a, 0x03, 0x61, 0x62, 0x63, // length delimited
+    0x73, 0x74, // group
+];
+
+macro_rules! check_unknown_fields {
+    ($id:ident, $options:expr) => {
+        #[test]
+        fn $id() {
+            use serde::de::Deserialize;
+
+            let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+            let proto = protobuf::parse_from_reader(&mut file).unwrap();
+            let descriptors = descriptor::Descriptors::from_proto(&proto);
+
+            let input = protobuf::CodedInputStream::from_bytes(UNKNOWN_FIELDS);
+            let mut deserializer = de::Deserializer::for_named_message(
+                &descriptors,
+                ".protobuf_unittest.ForeignMessage",
+                input,
+            )
+            .unwrap();
+            deserializer.set_options($options.with_unknown_fields(true));
+            let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+
+            assert_eq!(
+                value!(map {
+                    (str: "c") => (some i32: 42),
+                    (str: de::UNKNOWN_FIELDS_KEY) => (map {
+                        (u32: 10) => (map {
+                            (str: "varint") => (seq [(u64: 1), (u64: 2)])
+                        }),
+                        (u32: 11) => (map {
+                            (str: "fixed32") => (seq [(u32: 7)])
+                        }),
+                        (u32: 12) => (map {
+                            (str: "fixed64") => (seq [(u64: 8)])
+                        }),
+                        (u32: 13) => (map {
+                            (str: "length_delimited") => (seq [(bytes: b"abc")])
+                        })
+                    })
+                }),
+                v
+            );
+        }
+    };
+}
+
+check_unknown_fields!(unknown_fields, de::Options::new());
+check_unknown_fields!(
+    unknown_fields_streaming,
+    de::Options::new().with_streaming(true)
+);
+
+#[test]
+fn unknown_fields_dropped_by_default() {
+    use serde::de::Deserialize;
+
+    let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+    let proto = protobuf::parse_from_reader(&mut file).unwrap();
+    let descriptors = descriptor::Descriptors::from_proto(&proto);
+
+    let input = protobuf::CodedInputStream::from_bytes(UNKNOWN_FIELDS);
+    let mut deserializer = de::Deserializer::for_named_message(
+        &descriptors,
+        ".protobuf_unittest.ForeignMessage",
+        input,
+    )
+    .unwrap();
+    let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+
+    assert_eq!(value!(map { (str: "c") => (some i32: 42) }), v);
+}
+
+macro_rules! check_field_keys {
+    ($id:ident, $options:expr, $fields:expr, $expected:expr) => {
+        #[test]
+        fn $id() {
+            use serde::de::DeserializeSeed;
+
+            let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+            let proto = protobuf::parse_from_reader(&mut file).unwrap();
+            let descriptors = descriptor::Descriptors::from_proto(&proto);
+
+            let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+            v.set_optional_int32(42);
+            v.mut_optional_nested_message().set_bb(1);
+            let bytes = protobuf::Message::write_to_bytes(&v).unwrap();
+
+            let input = protobuf::CodedInputStream::from_bytes(&bytes);
+            let mut deserializer = de::Deserializer::for_named_message(
+                &descriptors,
+                ".protobuf_unittest.TestAllTypes",
+                input,
+            )
+            .unwrap();
+            deserializer.set_options($options);
+            let v = Select($fields).deserialize(&mut deserializer).unwrap();
+
+            let expected: &[(&str, serde_value::Value)] = &$expected;
+            let expected = expected
+                .iter()
+                .map(|&(k, ref v)| (k.to_owned(), v.clone()))
+                .collect::<collections::BTreeMap<_, _>>();
+            assert_eq!(expected, v);
+        }
+    };
+}
+
+check_field_keys!(
+    field_keys_name,
+    de::Options::new(),
+    &["optional_int32", "optionalNestedMessage"],
+    [("optional_int32", value!(some i32: 42))]
+);
+
+check_field_keys!(
+    field_keys_json_name,
+    de::Options::new().with_field_keys(de::FieldKeys::JsonName),
+    &["optionalInt32", "optionalNestedMessage", "optional_string"],
+    [
+        ("optionalInt32", value!(some i32: 42)),
+        (
+            "optionalNestedMessage",
+            value!(some map { (str: "bb") => (some i32: 1) })
+        )
+    ]
+);
+
+check_field_keys!(
+    field_keys_json_name_streaming,
+    de::Options::new()
+        .with_streaming(true)
+        .with_field_keys(de::FieldKeys::JsonName),
+    &["optionalInt32", "optionalNestedMessage", "optional_string"],
+    [
+        ("optionalInt32", value!(some i32: 42)),
+        (
+            "optionalNestedMessage",
+            value!(some map { (str: "bb") => (some i32: 1) })
+        )
+    ]
+);
+
+#[test]
+fn field_keys_number() {
+    use serde::de::Deserialize;
+
+    let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+    let proto = protobuf::parse_from_reader(&mut file).unwrap();
+    let descriptors = descriptor::Descriptors::from_proto(&proto);
+
+    let input = protobuf::CodedInputStream::from_bytes(&[0x08, 0x2a]);
+    let mut deserializer = de::Deserializer::for_named_message(
+        &descriptors,
+        ".protobuf_unittest.ForeignMessage",
+        input,
+    )
+    .unwrap();
+    deserializer.set_options(de::Options::new().with_field_keys(de::FieldKeys::Number));
+    let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+
+    assert_eq!(value!(map { (u32: 1) => (some i32: 42) }), v);
+}
+
+macro_rules! check_omit_fields {
+    ($id:ident, $options:expr, $expected:expr) => {
+        #[test]
+        fn $id() {
+            use serde::de::Deserialize;
+
+            let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+            let proto = protobuf::parse_from_reader(&mut file).unwrap();
+            let descriptors = descriptor::Descriptors::from_proto(&proto);
+
+            let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+            v.set_optional_int32(42);
+            v.set_optional_int64(0);
+            v.set_optional_string("".to_owned());
+            v.set_optional_bytes(b"abc".to_vec());
+            v.set_default_int32(41);
+            v.set_default_string("world".to_owned());
+            v.mut_optional_nested_message();
+            let bytes = protobuf::Message::write_to_bytes(&v).unwrap();
+
+            let mut deserializer = de::Deserializer::from_bytes(
+                &descriptors,
+                descriptors
+                    .message_by_name(".protobuf_unittest.TestAllTypes")
+                    .unwrap(),
+                &bytes,
+            );
+            deserializer.set_options($options);
+            let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+
+            assert_eq!($expected, v);
+        }
+    };
+}
+
+check_omit_fields!(
+    present_fields_only,
+    de::Options::new().with_present_fields_only(true),
+    value!(map {
+        (str: "optional_int32") => (some i32: 42),
+        (str: "optional_int64") => (some i64: 0),
+        (str: "optional_string") => (some str: ""),
+        (str: "optional_bytes") => (some bytes: b"abc"),
+        (str: "optional_nested_message") => (some map {}),
+        (str: "default_int32") => (some i32: 41),
+        (str: "default_string") => (some str: "world")
+    })
+);
+
+check_omit_fields!(
+    present_fields_only_streaming,
+    de::Options::new()
+        .with_streaming(true)
+        .with_present_fields_only(true),
+    value!(map {
+        (str: "optional_int32") => (some i32: 42),
+        (str: "optional_int64") => (some i64: 0),
+        (str: "optional_string") => (some str: ""),
+        (str: "optional_bytes") => (some bytes: b"abc"),
+        (str: "optional_nested_message") => (some map {}),
+        (str: "default_int32") => (some i32: 41),
+        (str: "default_string") => (some str: "world")
+    })
+);
+
+check_omit_fields!(
+    omit_defaults,
+    de::Options::new().with_omit_defaults(true),
+    value!(map {
+        (str: "optional_int32") => (some i32: 42),
+        (str: "optional_bytes") => (some bytes: b"abc"),
+        (str: "optional_nested_message") => (some map {}),
+        (str: "default_string") => (some str: "world")
+    })
+);
+
+check_omit_fields!(
+    omit_defaults_streaming,
+    de::Options::new()
+        .with_streaming(true)
+        .with_omit_defaults(true),
+    value!(map {
+        (str: "optional_int32") => (some i32: 42),
+        (str: "optional_bytes") => (some bytes: b"abc"),
+        (str: "optional_nested_message") => (some map {}),
+        (str: "default_string") => (some str: "world")
+    })
+);
+
+/// Descriptors for the `proto3` message `.test.Proto3` with an implicit-presence field `a = 1`, an
+/// explicit-presence `optional` field `b = 2` and an implicit-presence enum field `e = 3`.
+fn proto3_descriptors() -> descriptor::Descriptors {
+    use protobuf::descriptor::{
+        DescriptorProto, EnumDescriptorProto, EnumValueDescriptorProto, FieldDescriptorProto,
+        FieldDescriptorProto_Label, FieldDescriptorProto_Type, FileDescriptorProto,
+        OneofDescriptorProto,
+    };
+    use protobuf::Message;
+
+    let mut a = FieldDescriptorProto::new();
+    a.set_name("a".to_owned());
+    a.set_number(1);
+    a.set_label(FieldDescriptorProto_Label::LABEL_OPTIONAL);
+    a.set_field_type(FieldDescriptorProto_Type::TYPE_INT32);
+    let mut b = a.clone();
+    b.set_name("b".to_owned());
+    b.set_number(2);
+    b.set_oneof_index(0);
+    b.mut_unknown_fields().add_varint(17, 1);
+    let mut e = a.clone();
+    e.set_name("e".to_owned());
+    e.set_number(3);
+    e.set_field_type(FieldDescriptorProto_Type::TYPE_ENUM);
+    e.set_type_name(".test.E".to_owned());
+
+    let mut oneof = OneofDescriptorProto::new();
+    oneof.set_name("_b".to_owned());
+    let mut message = DescriptorProto::new();
+    message.set_name("Proto3".to_owned());
+    message.mut_field().push(a);
+    message.mut_field().push(b);
+    message.mut_field().push(e);
+    message.mut_oneof_decl().push(oneof);
+
+    let mut value = EnumValueDescriptorProto::new();
+    value.set_name("ZERO".to_owned());
+    value.set_number(0);
+    let mut enum_proto = EnumDescriptorProto::new();
+    enum_proto.set_name("E".to_owned());
+    enum_proto.mut_value().push(value);
+
+    let mut file = FileDescriptorProto::new();
+    file.set_package("test".to_owned());
+    file.set_syntax("proto3".to_owned());
+    file.mut_message_type().push(message);
+    file.mut_enum_type().push(enum_proto);
+
+    let mut descriptors = descriptor::Descriptors::new();
+    descriptors.add_file_proto(&file);
+    descriptors.resolve_refs();
+    descriptors
+}
+
+macro_rules! check_proto3 {
+    ($id:ident, $bytes:expr, $options:expr, $expected:expr) => {
+        #[test]
+        fn $id() {
+            use serde::de::Deserialize;
+
+            let descriptors = proto3_descriptors();
+            let input = protobuf::CodedInputStream::from_bytes($bytes);
+            let mut deserializer =
+                de::Deserializer::for_named_message(&descriptors, ".test.Proto3", input).unwrap();
+            deserializer.set_options($options);
+            let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+            assert_eq!($expected, v);
+        }
+    };
+}
+
+check_proto3!(
+    proto3_zero_values,
+    &[],
+    de::Options::new(),
+    value!(map {
+        (str: "a") => (i32: 0),
+        (str: "b") => (none),
+        (str: "e") => (str: "ZERO")
+    })
+);
+
+check_proto3!(
+    proto3_zero_values_streaming,
+    &[],
+    de::Options::new().with_streaming(true),
+    value!(map {
+        (str: "a") => (i32: 0),
+        (str: "b") => (none),
+        (str: "e") => (str: "ZERO")
+    })
+);
+
+check_proto3!(
+    proto3_present_values,
+    &[0x08, 0x05, 0x10, 0x00],
+    de::Options::new(),
+    value!(map {
+        (str: "a") => (i32: 5),
+        (str: "b") => (some i32: 0),
+        (str: "e") => (str: "ZERO")
+    })
+);
+
+check_proto3!(
+    proto3_present_values_streaming,
+    &[0x08, 0x05, 0x10, 0x00],
+    de::Options::new().with_streaming(true),
+    value!(map {
+        (str: "a") => (i32: 5),
+        (str: "b") => (some i32: 0),
+        (str: "e") => (str: "ZERO")
+    })
+);
+
+macro_rules! check_oneof_value {
+    ($id:ident, $options:expr) => {
+        #[test]
+        fn $id() {
+            use serde::de::DeserializeSeed;
+
+            let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+            let proto = protobuf::parse_from_reader(&mut file).unwrap();
+            let descriptors = descriptor::Descriptors::from_proto(&proto);
+            let message = descriptors
+                .message_by_name(".protobuf_unittest.TestAllTypes")
+                .unwrap();
+            let fields = &["oneof_field", "oneof_string", "optional_int32"];
+
+            let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+            v.set_oneof_string("abc".to_owned());
+            let bytes = protobuf::Message::write_to_bytes(&v).unwrap();
+            let mut deserializer = de::Deserializer::from_bytes(&descriptors, message, &bytes);
+            deserializer.set_options($options.with_oneofs(true));
+            let v = Select(fields).deserialize(&mut deserializer).unwrap();
+
+            let mut expected = collections::BTreeMap::new();
+            expected.insert(
+                "oneof_field".to_owned(),
+                value!(some map { (str: "oneof_string") => (str: "abc") }),
+            );
+            expected.insert("optional_int32".to_owned(), value!(none));
+            assert_eq!(expected, v);
+
+            let mut deserializer = de::Deserializer::from_bytes(&descriptors, message, &[]);
+            deserializer.set_options($options.with_oneofs(true));
+            let v = Select(fields).deserialize(&mut deserializer).unwrap();
+
+            let mut expected = collections::BTreeMap::new();
+            expected.insert("oneof_field".to_owned(), value!(none));
+            expected.insert("optional_int32".to_owned(), value!(none));
+            assert_eq!(expected, v);
+        }
+    };
+}
+
+check_oneof_value!(oneof_value, de::Options::new());
+check_oneof_value!(
+    oneof_value_streaming,
+    de::Options::new().with_streaming(true)
+);
+
+#[derive(Debug, PartialEq)]
+enum OneofField {
+    Uint32(u32),
+    NestedMessage(collections::BTreeMap<String, serde_value::Value>),
+    String(String),
+}
+
+impl<'de> serde::de::Deserialize<'de> for OneofField {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = OneofField;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a oneof_field enum")
+            }
+
+            fn visit_enum<A>(self, data: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::EnumAccess<'de>,
+            {
+                use serde::de::VariantAccess;
+
+                let (variant, value) = data.variant::<String>()?;
+                match variant.as_str() {
+                    "oneof_uint32" => value.newtype_variant().map(OneofField::Uint32),
+                    "oneof_nested_message" => {
+                        value.newtype_variant().map(OneofField::NestedMessage)
+                    }
+                    "oneof_string" => value.newtype_variant().map(OneofField::String),
+                    v => Err(serde::de::Error::unknown_variant(v, VARIANTS)),
+                }
+            }
+        }
+
+        const VARIANTS: &[&str] = &["oneof_uint32", "oneof_nested_message", "oneof_string"];
+        deserializer.deserialize_enum("OneofField", VARIANTS, Visitor)
+    }
+}
+
+impl serde::ser::Serialize for OneofField {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::ser::Serializer,
+    {
+        match *self {
+            OneofField::Uint32(ref v) => {
+                serializer.serialize_newtype_variant("OneofField", 0, "oneof_uint32", v)
+            }
+            OneofField::NestedMessage(ref v) => {
+                serializer.serialize_newtype_variant("OneofField", 1, "oneof_nested_message", v)
+            }
+            OneofField::String(ref v) => {
+                serializer.serialize_newtype_variant("OneofField", 2, "oneof_string", v)
+            }
+        }
+    }
+}
+
+/// A seed that only visits the `oneof_field` oneof of a message.
+struct SelectOneof;
+
+impl<'de> serde::de::DeserializeSeed<'de> for SelectOneof {
+    type Value = Option<OneofField>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Option<OneofField>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a struct")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let mut result = None;
+                while let Some(k) = map.next_key::<String>()? {
+                    assert_eq!("oneof_field", k);
+                    result = map.next_value()?;
+                }
+                Ok(result)
+            }
+        }
+
+        deserializer.deserialize_struct("TestAllTypes", &["oneof_field"], Visitor)
+    }
+}
+
+macro_rules! check_oneof_enum {
+    ($id:ident, $options:expr) => {
+        #[test]
+        fn $id() {
+            use serde::de::DeserializeSeed;
+
+            let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+            let proto = protobuf::parse_from_reader(&mut file).unwrap();
+            let descriptors = descriptor::Descriptors::from_proto(&proto);
+            let message = descriptors
+                .message_by_name(".protobuf_unittest.TestAllTypes")
+                .unwrap();
+
+            let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+            v.set_optional_int32(1);
+            v.set_oneof_uint32(42);
+            let bytes = protobuf::Message::write_to_bytes(&v).unwrap();
+            let mut deserializer = de::Deserializer::from_bytes(&descriptors, message, &bytes);
+            deserializer.set_options($options.with_oneofs(true));
+            let v = SelectOneof.deserialize(&mut deserializer).unwrap();
+            assert_eq!(Some(OneofField::Uint32(42)), v);
+
+            let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+            v.mut_oneof_nested_message().set_bb(7);
+            v.set_optional_int32(1);
+            let bytes = protobuf::Message::write_to_bytes(&v).unwrap();
+            let mut deserializer = de::Deserializer::from_bytes(&descriptors, message, &bytes);
+            deserializer.set_options($options.with_oneofs(true));
+            let v = SelectOneof.deserialize(&mut deserializer).unwrap();
+            let mut expected = collections::BTreeMap::new();
+            expected.insert("bb".to_owned(), value!(some i32: 7));
+            assert_eq!(Some(OneofField::NestedMessage(expected)), v);
+        }
+    };
+}
+
+check_oneof_enum!(oneof_enum, de::Options::new());
+check_oneof_enum!(
+    oneof_enum_streaming,
+    de::Options::new().with_streaming(true)
+);
+
+/// A `TestAllTypes` message with only its `oneof_field` oneof set.
+struct WithOneof(Option<OneofField>);
+
+impl serde::ser::Serialize for WithOneof {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::ser::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        let mut s = serializer.serialize_struct("TestAllTypes", 1)?;
+        s.serialize_field("oneof_field", &self.0)?;
+        s.end()
+    }
+}
+
+#[test]
+fn serialize_oneof_enum() {
+    let v = serialize!(
+        protobuf_unittest::unittest::TestAllTypes,
+        WithOneof(Some(OneofField::Uint32(42)))
+    )
+    .unwrap();
+    assert_eq!(42, v.get_oneof_uint32());
+
+    let mut nested = collections::BTreeMap::new();
+    nested.insert("bb".to_owned(), value!(i32: 7));
+    let v = serialize!(
+        protobuf_unittest::unittest::TestAllTypes,
+        WithOneof(Some(OneofField::NestedMessage(nested)))
+    )
+    .unwrap();
+    assert_eq!(7, v.get_oneof_nested_message().get_bb());
+
+    let v = serialize!(protobuf_unittest::unittest::TestAllTypes, WithOneof(None)).unwrap();
+    assert!(!v.has_oneof_uint32() && !v.has_oneof_nested_message() && !v.has_oneof_string());
+
+    // Only the members of the oneof can be selected
+    let v = value!(map {
+        (str: "oneof_field") => (map { (str: "optional_int32") => (i32: 1) })
+    });
+    assert!(serialize!(protobuf_unittest::unittest::TestAllTypes, v).is_err());
+}
+
+#[test]
+fn serialize_oneof_value() {
+    let v = roundtrip!(
+        protobuf_unittest::unittest::TestAllTypes,
+        de::Options::new().with_oneofs(true),
+        v,
+        v.set_oneof_string("abc".to_owned())
+    );
+    assert_eq!(
+        Some(&value!(some map { (str: "oneof_string") => (str: "abc") })),
+        match v {
+            serde_value::Value::Map(ref fields) => fields.get(&value!(str: "oneof_field")),
+            ref v => panic!("Expected a map, got {:?}", v),
+        }
+    );
+
+    let v = serialize!(protobuf_unittest::unittest::TestAllTypes, v).unwrap();
+    assert_eq!("abc", v.get_oneof_string());
+}
+
+fn map_descriptors() -> descriptor::Descriptors {
+    use protobuf::descriptor::{
+        DescriptorProto, FieldDescriptorProto, FieldDescriptorProto_Label,
+        FieldDescriptorProto_Type, FileDescriptorProto,
+    };
+
+    fn field(
+        name: &str,
+        number: i32,
+        field_type: FieldDescriptorProto_Type,
+    ) -> FieldDescriptorProto {
+        let mut field = FieldDescriptorProto::new();
+        field.set_name(name.to_owned());
+        field.set_number(number);
+        field.set_label(FieldDescriptorProto_Label::LABEL_OPTIONAL);
+        field.set_field_type(field_type);
+        field
+    }
+
+    fn entry(
+        name: &str,
+        key: FieldDescriptorProto,
+        value: FieldDescriptorProto,
+    ) -> DescriptorProto {
+        let mut entry = DescriptorProto::new();
+        entry.set_name(name.to_owned());
+        entry.mut_field().push(key);
+        entry.mut_field().push(value);
+        entry.mut_options().set_map_entry(true);
+        entry
+    }
+
+    let mut counts = field("counts", 1, FieldDescriptorProto_Type::TYPE_MESSAGE);
+    counts.set_label(FieldDescriptorProto_Label::LABEL_REPEATED);
+    counts.set_type_name(".test.Maps.CountsEntry".to_owned());
+    let mut inner = counts.clone();
+    inner.set_name("inner".to_owned());
+    inner.set_number(3);
+    inner.set_type_name(".test.Maps.InnerEntry".to_owned());
+    let mut inner_value = field("value", 2, FieldDescriptorProto_Type::TYPE_MESSAGE);
+    inner_value.set_type_name(".test.Inner".to_owned());
+
+    let mut message = DescriptorProto::new();
+    message.set_name("Maps".to_owned());
+    message.mut_field().push(counts);
+    message
+        .mut_field()
+        .push(field("after", 2, FieldDescriptorProto_Type::TYPE_INT32));
+    message.mut_field().push(inner);
+    message.mut_nested_type().push(entry(
+        "CountsEntry",
+        field("key", 1, FieldDescriptorProto_Type::TYPE_STRING),
+        field("value", 2, FieldDescriptorProto_Type::TYPE_INT32),
+    ));
+    message.mut_nested_type().push(entry(
+        "InnerEntry",
+        field("key", 1, FieldDescriptorProto_Type::TYPE_INT32),
+        inner_value,
+    ));
+
+    let mut inner_message = DescriptorProto::new();
+    inner_message.set_name("Inner".to_owned());
+    inner_message
+        .mut_field()
+        .push(field("x", 1, FieldDescriptorProto_Type::TYPE_INT32));
+
+    let mut file = FileDescriptorProto::new();
+    file.set_package("test".to_owned());
+    file.set_syntax("proto3".to_owned());
+    file.mut_message_type().push(message);
+    file.mut_message_type().push(inner_message);
+
+    let mut descriptors = descriptor::Descriptors::new();
+    descriptors.add_file_proto(&file);
+    descriptors.resolve_refs();
+    descriptors
+}
+
+// Entries for "a" (twice), "b" and "c" without a value, then `after`, then two entries of `inner`
+// of which the second one has no value
+const MAPS: &[u8] = &[
+    0x0a, 0x05, 0x0a, 0x01, b'a', 0x10, 0x01, 0x0a, 0x05, 0x0a, 0x01, b'b', 0x10, 0x02, 0x0a, 0x05,
+    0x0a, 0x01, b'a', 0x10, 0x03, 0x0a, 0x03, 0x0a, 0x01, b'c', 0x10, 0x07, 0x1a, 0x06, 0x08, 0x05,
+    0x12, 0x02, 0x08, 0x09, 0x1a, 0x02, 0x08, 0x06,
+];
+
+macro_rules! check_map {
+    ($id:ident, $options:expr) => {
+        #[test]
+        fn $id() {
+            use serde::de::Deserialize;
+
+            let descriptors = map_descriptors();
+            let input = protobuf::CodedInputStream::from_bytes(MAPS);
+            let mut deserializer =
+                de::Deserializer::for_named_message(&descriptors, ".test.Maps", input).unwrap();
+            deserializer.set_options($options);
+            let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+            let expected = value!(map {
+                (str: "counts") => (map {
+                    (string: "a".to_owned()) => (i32: 3),
+                    (string: "b".to_owned()) => (i32: 2),
+                    (string: "c".to_owned()) => (i32: 0)
+                }),
+                (str: "after") => (i32: 7),
+                (str: "inner") => (map {
+                    (i32: 5) => (map {(str: "x") => (i32: 9)}),
+                    (i32: 6) => (map {(str: "x") => (i32: 0)})
+                })
+            });
+            assert_eq!(expected, v);
+
+            let counts = match v {
+                serde_value::Value::Map(mut fields) => fields.remove(&value!(str: "counts")),
+                v => panic!("Expected a map, got {:?}", v),
+            };
+            let counts = counts.unwrap();
+            let counts: collections::HashMap<String, i32> = counts.deserialize_into().unwrap();
+            assert_eq!(Some(&3), counts.get("a"));
+            assert_eq!(3, counts.len());
+        }
+    };
+}
+
+check_map!(map_fields, de::Options::new());
+check_map!(
+    map_fields_streaming,
+    de::Options::new().with_streaming(true)
+);
+
+#[test]
+fn map_fields_missing_key_proto2() {
+    use serde::de::Deserialize;
+    use serde_protobuf::descriptor::{
+        FieldDescriptor, FieldLabel, InternalFieldType, MessageDescriptor,
+    };
+
+    // A proto2 map entry whose fields track presence, so that a missing key or value isn't
+    // filled in with a default value
+    let mut descriptors = descriptor::Descriptors::new();
+    let mut entry = MessageDescriptor::new(".test.Maps.CountsEntry");
+    entry.set_map_entry(true);
+    entry.add_field(FieldDescriptor::new(
+        "key",
+        1,
+        FieldLabel::Optional,
+        InternalFieldType::String,
+        None,
+    ));
+    entry.add_field(FieldDescriptor::new(
+        "value",
+        2,
+        FieldLabel::Optional,
+        InternalFieldType::Int32,
+        None,
+    ));
+    descriptors.add_message(entry);
+    let mut counts = FieldDescriptor::new(
+        "counts",
+        1,
+        FieldLabel::Repeated,
+        InternalFieldType::UnresolvedMessage(".test.Maps.CountsEntry".to_owned()),
+        None,
+    );
+    counts.set_map(true);
+    let mut message = MessageDescriptor::new(".test.Maps");
+    message.add_field(counts);
+    descriptors.add_message(message);
+    descriptors.resolve_refs();
+
+    // An entry with the key omitted, and an entry with the value omitted
+    let bytes = [0x0a, 0x02, 0x10, 0x04, 0x0a, 0x03, 0x0a, 0x01, b'a'];
+    let expected = value!(map {
+        (str: "counts") => (map {
+            (string: String::new()) => (i32: 4),
+            (string: "a".to_owned()) => (i32: 0)
+        })
+    });
+    for &streaming in &[false, true] {
+        let input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let mut deserializer =
+            de::Deserializer::for_named_message(&descriptors, ".test.Maps", input).unwrap();
+        deserializer.set_options(de::Options::new().with_streaming(streaming));
+        let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+        assert_eq!(expected, v);
+    }
+}
+
+#[test]
+fn map_fields_value() {
+    let descriptors = map_descriptors();
+    let message_descriptor = descriptors.message_by_name(".test.Maps").unwrap();
+
+    let mut input = protobuf::CodedInputStream::from_bytes(MAPS);
+    let mut message = value::Message::new(message_descriptor);
+    message
+        .merge_from(&descriptors, message_descriptor, &mut input)
+        .unwrap();
+
+    let keys = match message.fields[&1] {
+        value::Field::Map(ref entries) => entries.keys().cloned().collect::<Vec<_>>(),
+        ref f => panic!("Expected a map, got {:?}", f),
+    };
+    let expected = ["a", "b", "c"]
+        .iter()
+        .map(|&k| value::MapKey::String(k.to_owned()))
+        .collect::<Vec<_>>();
+    assert_eq!(expected, keys);
+
+    // Written back out, there is only a single entry left for each key
+    let mut bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::new(&mut bytes);
+        message
+            .write_to(&descriptors, message_descriptor, &mut output)
+            .unwrap();
+        output.flush().unwrap();
+    }
+    assert_eq!(
+        message.compute_size(&descriptors, message_descriptor) as usize,
+        bytes.len()
+    );
+    let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut copy = value::Message::new(message_descriptor);
+    copy.merge_from(&descriptors, message_descriptor, &mut input)
+        .unwrap();
+    match copy.fields[&1] {
+        value::Field::Map(ref entries) => {
+            assert_eq!(3, entries.len());
+            match entries[&value::MapKey::String("a".to_owned())] {
+                value::Value::I32(v) => assert_eq!(3, v),
+                ref v => panic!("Expected an i32, got {:?}", v),
+            }
+        }
+        ref f => panic!("Expected a map, got {:?}", f),
+    }
+}
+
+#[test]
+fn serialize_map_fields() {
+    use serde::de::Deserialize;
+    use serde::ser::Serialize;
+
+    let descriptors = map_descriptors();
+    let message = descriptors.message_by_name(".test.Maps").unwrap();
+
+    let mut deserializer = de::Deserializer::from_bytes(&descriptors, message, MAPS);
+    let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+
+    let mut bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+        let mut serializer =
+            ser::Serializer::for_named_message(&descriptors, ".test.Maps", &mut output).unwrap();
+        v.serialize(&mut serializer).unwrap();
+    }
+
+    // Each entry is written as an entry message with both its key and its value
+    let entry = [0x0a, 0x05, 0x0a, 0x01, b'a', 0x10, 0x03];
+    assert!(bytes.windows(entry.len()).any(|w| w == entry));
+
+    let mut deserializer = de::Deserializer::from_bytes(&descriptors, message, &bytes);
+    let copy = serde_value::Value::deserialize(&mut deserializer).unwrap();
+    assert_eq!(v, copy);
+
+    // Map values are rejected for fields that aren't map fields
+    let v = value!(map { (str: "after") => (map { (str: "x") => (i32: 1) }) });
+    let mut bytes = Vec::new();
+    let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+    let mut serializer =
+        ser::Serializer::for_named_message(&descriptors, ".test.Maps", &mut output).unwrap();
+    assert!(v.serialize(&mut serializer).is_err());
+}
+
+fn well_known_descriptors() -> descriptor::Descriptors {
+    use protobuf::descriptor::FieldDescriptorProto_Label::*;
+    use protobuf::descriptor::FieldDescriptorProto_Type::*;
+    use protobuf::descriptor::{
+        DescriptorProto, EnumDescriptorProto, EnumValueDescriptorProto, FieldDescriptorProto,
+        FieldDescriptorProto_Label, FieldDescriptorProto_Type, FileDescriptorProto,
+        OneofDescriptorProto,
+    };
+
+    // A subset of the well-known types, as declared in `google/protobuf/*.proto`
+    type Field = (
+        &'static str,
+        FieldDescriptorProto_Label,
+        FieldDescriptorProto_Type,
+        &'static str,
+    );
+    let types: &[(&str, &[Field])] = &[
+        (
+            "Any",
+            &[
+                ("type_url", LABEL_OPTIONAL, TYPE_STRING, ""),
+                ("value", LABEL_OPTIONAL, TYPE_BYTES, ""),
+            ],
+        ),
+        (
+            "Timestamp",
+            &[
+                ("seconds", LABEL_OPTIONAL, TYPE_INT64, ""),
+                ("nanos", LABEL_OPTIONAL, TYPE_INT32, ""),
+            ],
+        ),
+        (
+            "Duration",
+            &[
+                ("seconds", LABEL_OPTIONAL, TYPE_INT64, ""),
+                ("nanos", LABEL_OPTIONAL, TYPE_INT32, ""),
+            ],
+        ),
+        ("FieldMask", &[("paths", LABEL_REPEATED, TYPE_STRING, "")]),
+        ("Empty", &[]),
+        ("Int32Value", &[("value", LABEL_OPTIONAL, TYPE_INT32, "")]),
+        ("StringValue", &[("value", LABEL_OPTIONAL, TYPE_STRING, "")]),
+        (
+            "Struct",
+            &[(
+                "fields",
+                LABEL_REPEATED,
+                TYPE_MESSAGE,
+                ".google.protobuf.Struct.FieldsEntry",
+            )],
+        ),
+        (
+            "Value",
+            &[
+                (
+                    "null_value",
+                    LABEL_OPTIONAL,
+                    TYPE_ENUM,
+                    ".google.protobuf.NullValue",
+                ),
+                ("number_value", LABEL_OPTIONAL, TYPE_DOUBLE, ""),
+                ("string_value", LABEL_OPTIONAL, TYPE_STRING, ""),
+                ("bool_value", LABEL_OPTIONAL, TYPE_BOOL, ""),
+                (
+                    "struct_value",
+                    LABEL_OPTIONAL,
+                    TYPE_MESSAGE,
+                    ".google.protobuf.Struct",
+                ),
+                (
+                    "list_value",
+                    LABEL_OPTIONAL,
+                    TYPE_MESSAGE,
+                    ".google.protobuf.ListValue",
+                ),
+            ],
+        ),
+        (
+            "ListValue",
+            &[(
+                "values",
+                LABEL_REPEATED,
+                TYPE_MESSAGE,
+                ".google.protobuf.Value",
+            )],
+        ),
+        (
+            "FieldsEntry",
+            &[
+                ("key", LABEL_OPTIONAL, TYPE_STRING, ""),
+                (
+                    "value",
+                    LABEL_OPTIONAL,
+                    TYPE_MESSAGE,
+                    ".google.protobuf.Value",
+                ),
+            ],
+        ),
+    ];
+    let messages = types
+        .iter()
+        .map(|&(name, fields)| {
+            let mut message = DescriptorProto::new();
+            message.set_name(name.to_owned());
+            for (i, &(name, label, field_type, type_name)) in fields.iter().enumerate() {
+                let mut field = FieldDescriptorProto::new();
+                field.set_name(name.to_owned());
+                field.set_number(i as i32 + 1);
+                field.set_label(label);
+                field.set_field_type(field_type);
+                if !type_name.is_empty() {
+                    field.set_type_name(type_name.to_owned());
+                }
+                message.mut_field().push(field);
+            }
+            (name, message)
+        })
+        .collect::<collections::HashMap<_, _>>();
+
+    let mut google = FileDescriptorProto::new();
+    google.set_package("google.protobuf".to_owned());
+    google.set_syntax("proto3".to_owned());
+    for &(name, _) in types {
+        let mut message = messages[name].clone();
+        match name {
+            "Struct" => {
+                let mut entry = messages["FieldsEntry"].clone();
+                entry.mut_options().set_map_entry(true);
+                message.mut_nested_type().push(entry);
+            }
+            "Value" => {
+                let mut kind = OneofDescriptorProto::new();
+                kind.set_name("kind".to_owned());
+                message.mut_oneof_decl().push(kind);
+                for field in message.mut_field().iter_mut() {
+                    field.set_oneof_index(0);
+                }
+            }
+            "FieldsEntry" => continue,
+            _ => (),
+        }
+        google.mut_message_type().push(message);
+    }
+    let mut null = EnumValueDescriptorProto::new();
+    null.set_name("NULL_VALUE".to_owned());
+    let mut null_value = EnumDescriptorProto::new();
+    null_value.set_name("NullValue".to_owned());
+    null_value.mut_value().push(null);
+    google.mut_enum_type().push(null_value);
+
+    let fields = [
+        ("timestamp", "Timestamp"),
+        ("duration", "Duration"),
+        ("mask", "FieldMask"),
+        ("empty", "Empty"),
+        ("count", "Int32Value"),
+        ("name", "StringValue"),
+        ("struct", "Struct"),
+        ("any", "Any"),
+    ];
+    let mut message = DescriptorProto::new();
+    message.set_name("WellKnown".to_owned());
+    for (i, &(name, type_name)) in fields.iter().enumerate() {
+        let mut field = FieldDescriptorProto::new();
+        field.set_name(name.to_owned());
+        field.set_number(i as i32 + 1);
+        field.set_label(LABEL_OPTIONAL);
+        field.set_field_type(TYPE_MESSAGE);
+        field.set_type_name(format!(".google.protobuf.{}", type_name));
+        message.mut_field().push(field);
+    }
+    let mut file = FileDescriptorProto::new();
+    file.set_package("test".to_owned());
+    file.set_syntax("proto3".to_owned());
+    file.mut_message_type().push(message);
+
+    let mut descriptors = descriptor::Descriptors::new();
+    descriptors.add_file_proto(&google);
+    descriptors.add_file_proto(&file);
+    descriptors.resolve_refs();
+    descriptors
+}
+
+fn well_known_bytes() -> Vec<u8> {
+    use protobuf::well_known_types::{
+        Duration, Empty, FieldMask, Int32Value, ListValue, NullValue, Struct, Timestamp, Value,
+    };
+
+    let mut timestamp = Timestamp::new();
+    timestamp.set_seconds(63_108_020);
+    timestamp.set_nanos(21_000_000);
+    let mut duration = Duration::new();
+    duration.set_seconds(-1);
+    duration.set_nanos(-500_000_000);
+    let mut mask = FieldMask::new();
+    mask.mut_paths().push("a.b".to_owned());
+    mask.mut_paths().push("c".to_owned());
+    let mut count = Int32Value::new();
+    count.set_value(7);
+
+    let value = |f: &dyn Fn(&mut Value)| {
+        let mut value = Value::new();
+        f(&mut value);
+        value
+    };
+    let mut list = ListValue::new();
+    list.mut_values().push(value(&|v| v.set_number_value(1.5)));
+    list.mut_values()
+        .push(value(&|v| v.set_string_value("x".to_owned())));
+    let mut object = Struct::new();
+    let fields = object.mut_fields();
+    fields.insert("b".to_owned(), value(&|v| v.set_bool_value(true)));
+    fields.insert(
+        "n".to_owned(),
+        value(&|v| v.set_null_value(NullValue::NULL_VALUE)),
+    );
+    fields.insert("l".to_owned(), value(&|v| v.set_list_value(list.clone())));
+    let mut inner = Struct::new();
+    inner.mut_fields().insert("k".to_owned(), Value::new());
+    fields.insert(
+        "s".to_owned(),
+        value(&|v| v.set_struct_value(inner.clone())),
+    );
+
+    let mut bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::new(&mut bytes);
+        output.write_message(1, &timestamp).unwrap();
+        output.write_message(2, &duration).unwrap();
+        output.write_message(3, &mask).unwrap();
+        output.write_message(4, &Empty::new()).unwrap();
+        output.write_message(5, &count).unwrap();
+        output.write_message(7, &object).unwrap();
+        output.flush().unwrap();
+    }
+    bytes
+}
+
+macro_rules! check_well_known {
+    ($id:ident, $options:expr) => {
+        #[test]
+        fn $id() {
+            use serde::de::Deserialize;
+
+            let descriptors = well_known_descriptors();
+            let bytes = well_known_bytes();
+            let input = protobuf::CodedInputStream::from_bytes(&bytes);
+            let mut deserializer =
+                de::Deserializer::for_named_message(&descriptors, ".test.WellKnown", input)
+                    .unwrap();
+            deserializer.set_options($options);
+            let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+            let expected = value!(map {
+                (str: "timestamp") => (some str: "1972-01-01T10:00:20.021Z"),
+                (str: "duration") => (some str: "-1.500s"),
+                (str: "mask") => (some seq [(str: "a.b"), (str: "c")]),
+                (str: "empty") => (some unit),
+                (str: "count") => (some i32: 7),
+                (str: "name") => (none),
+                (str: "struct") => (some map {
+                    (str: "b") => (bool: true),
+                    (str: "n") => (unit),
+                    (str: "l") => (seq [(f64: 1.5), (str: "x")]),
+                    (str: "s") => (map {(str: "k") => (unit)})
+                }),
+                (str: "any") => (none)
+            });
+            assert_eq!(expected, v);
+        }
+    };
+}
+
+check_well_known!(well_known_types, de::Options::new());
+check_well_known!(
+    well_known_types_streaming,
+    de::Options::new().with_streaming(true)
+);
+
+#[test]
+fn well_known_types_secs_nanos() {
+    use serde::de::Deserialize;
+
+    let descriptors = well_known_descriptors();
+    let bytes = well_known_bytes();
+    let input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut deserializer =
+        de::Deserializer::for_named_message(&descriptors, ".test.WellKnown", input).unwrap();
+    deserializer.set_options(de::Options::new().with_time_format(de::TimeFormat::SecsNanos));
+    let v = collections::BTreeMap::<String, serde_value::Value>::deserialize(&mut deserializer)
+        .unwrap();
+    assert_eq!(
+        value!(some map {(str: "secs") => (i64: 63_108_020), (str: "nanos") => (i32: 21_000_000)}),
+        v["timestamp"]
+    );
+    let duration = value!(map {(str: "secs") => (i64: 1), (str: "nanos") => (i32: 5)});
+    let duration: std::time::Duration = duration.deserialize_into().unwrap();
+    assert_eq!(std::time::Duration::new(1, 5), duration);
+}
+
+#[test]
+fn well_known_types_disabled() {
+    use serde::de::Deserialize;
+
+    let descriptors = well_known_descriptors();
+    let bytes = well_known_bytes();
+    let input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut deserializer =
+        de::Deserializer::for_named_message(&descriptors, ".test.WellKnown", input).unwrap();
+    deserializer.set_options(de::Options::new().with_well_known_types(false));
+    let v = collections::BTreeMap::<String, serde_value::Value>::deserialize(&mut deserializer)
+        .unwrap();
+    let timestamp = value!(some map {
+        (str: "seconds") => (i64: 63_108_020),
+        (str: "nanos") => (i32: 21_000_000)
+    });
+    assert_eq!(timestamp, v["timestamp"]);
+    assert_eq!(value!(some map {(str: "value") => (i32: 7)}), v["count"]);
+}
+
+macro_rules! check_timestamp {
+    ($id:ident, $message_name:expr, $secs:expr, $nanos:expr, $expected:expr) => {
+        #[test]
+        fn $id() {
+            use serde::de::Deserialize;
+
+            let descriptors = well_known_descriptors();
+            let mut timestamp = protobuf::well_known_types::Timestamp::new();
+            timestamp.set_seconds($secs);
+            timestamp.set_nanos($nanos);
+            let bytes = protobuf::Message::write_to_bytes(&timestamp).unwrap();
+            let input = protobuf::CodedInputStream::from_bytes(&bytes);
+            let mut deserializer =
+                de::Deserializer::for_named_message(&descriptors, $message_name, input).unwrap();
+            let v = serde_value::Value::deserialize(&mut deserializer);
+            match $expected {
+                Some(expected) => assert_eq!(value!(str: expected), v.unwrap()),
+                None => match v.unwrap_err().into_error() {
+                    error::Error::BadWellKnownValue { .. } => (),
+                    e => panic!("Expected a bad value error, got {:?}", e),
+                },
+            }
+        }
+    };
+}
+
+check_timestamp!(
+    timestamp_epoch,
+    ".google.protobuf.Timestamp",
+    0,
+    0,
+    Some("1970-01-01T00:00:00Z")
+);
+check_timestamp!(
+    timestamp_before_epoch,
+    ".google.protobuf.Timestamp",
+    -1,
+    0,
+    Some("1969-12-31T23:59:59Z")
+);
+check_timestamp!(
+    timestamp_leap_day,
+    ".google.protobuf.Timestamp",
+    951_825_600,
+    1_000,
+    Some("2000-02-29T12:00:00.000001Z")
+);
+check_timestamp!(
+    timestamp_min,
+    ".google.protobuf.Timestamp",
+    -62_135_596_800,
+    0,
+    Some("0001-01-01T00:00:00Z")
+);
+check_timestamp!(
+    timestamp_max,
+    ".google.protobuf.Timestamp",
+    253_402_300_799,
+    999_999_999,
+    Some("9999-12-31T23:59:59.999999999Z")
+);
+check_timestamp!(
+    timestamp_out_of_range,
+    ".google.protobuf.Timestamp",
+    253_402_300_800,
+    0,
+    None::<&str>
+);
+// Timestamps and durations have the same fields
+check_timestamp!(
+    duration_seconds,
+    ".google.protobuf.Duration",
+    3,
+    0,
+    Some("3s")
+);
+check_timestamp!(
+    duration_mixed_signs,
+    ".google.protobuf.Duration",
+    -3,
+    1,
+    None::<&str>
+);
+
+fn any_bytes(type_url: &str, payload: &[u8]) -> Vec<u8> {
+    let mut any = protobuf::well_known_types::Any::new();
+    any.set_type_url(type_url.to_owned());
+    any.set_value(payload.to_vec());
+    let mut bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::new(&mut bytes);
+        output.write_message(8, &any).unwrap();
+        output.flush().unwrap();
+    }
+    bytes
+}
+
+macro_rules! check_any {
+    ($id:ident, $descriptors:expr, $type_url:expr, $payload:expr, $options:expr, $expected:expr) => {
+        #[test]
+        fn $id() {
+            use serde::de::Deserialize;
+
+            let descriptors = $descriptors;
+            let bytes = any_bytes($type_url, $payload);
+            let input = protobuf::CodedInputStream::from_bytes(&bytes);
+            let mut deserializer =
+                de::Deserializer::for_named_message(&descriptors, ".test.WellKnown", input)
+                    .unwrap();
+            deserializer.set_options($options.with_present_fields_only(true));
+            let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+            let mut expected = collections::BTreeMap::new();
+            let any = serde_value::Value::Option(Some(Box::new($expected)));
+            expected.insert(value!(str: "any"), any);
+            assert_eq!(serde_value::Value::Map(expected), v);
+        }
+    };
+}
+
+// A `WellKnown` message with the `count` field set to 7
+const ANY_PAYLOAD: &[u8] = &[0x2a, 0x02, 0x08, 0x07];
+
+check_any!(
+    any_message,
+    well_known_descriptors(),
+    "type.googleapis.com/test.WellKnown",
+    ANY_PAYLOAD,
+    de::Options::new(),
+    value!(map {
+        (str: "@type") => (str: "type.googleapis.com/test.WellKnown"),
+        (str: "count") => (some i32: 7)
+    })
+);
+
+check_any!(
+    any_message_streaming,
+    well_known_descriptors(),
+    "type.googleapis.com/test.WellKnown",
+    ANY_PAYLOAD,
+    de::Options::new().with_streaming(true),
+    value!(map {
+        (str: "@type") => (str: "type.googleapis.com/test.WellKnown"),
+        (str: "count") => (some i32: 7)
+    })
+);
+
+check_any!(
+    any_well_known,
+    well_known_descriptors(),
+    "type.googleapis.com/google.protobuf.Duration",
+    &[0x08, 0x03],
+    de::Options::new(),
+    value!(map {
+        (str: "@type") => (str: "type.googleapis.com/google.protobuf.Duration"),
+        (str: "value") => (str: "3s")
+    })
+);
+
+check_any!(
+    any_unknown_type,
+    well_known_descriptors(),
+    "type.googleapis.com/test.Unknown",
+    ANY_PAYLOAD,
+    de::Options::new(),
+    value!(map {
+        (str: "type_url") => (str: "type.googleapis.com/test.Unknown"),
+        (str: "value") => (bytes: ANY_PAYLOAD)
+    })
+);
+
+check_any!(
+    any_disabled,
+    well_known_descriptors(),
+    "type.googleapis.com/test.WellKnown",
+    ANY_PAYLOAD,
+    de::Options::new().with_well_known_types(false),
+    value!(map {
+        (str: "type_url") => (str: "type.googleapis.com/test.WellKnown"),
+        (str: "value") => (bytes: ANY_PAYLOAD)
+    })
+);
+
+#[derive(Debug)]
+struct Proto3Resolver(descriptor::Descriptors);
+
+impl descriptor::TypeResolver for Proto3Resolver {
+    fn resolve(
+        &self,
+        type_url: &str,
+    ) -> Option<(&descriptor::Descriptors, &descriptor::MessageDescriptor)> {
+        match type_url {
+            "example.com/proto3" => self.0.message_by_name(".test.Proto3").map(|m| (&self.0, m)),
+            _ => None,
+        }
+    }
+}
+
+check_any!(
+    any_type_resolver,
+    {
+        let mut descriptors = well_known_descriptors();
+        descriptors.set_type_resolver(Proto3Resolver(proto3_descriptors()));
+        descriptors
+    },
+    "example.com/proto3",
+    &[0x08, 0x05],
+    de::Options::new(),
+    value!(map {
+        (str: "@type") => (str: "example.com/proto3"),
+        (str: "a") => (i32: 5)
+    })
+);
+
+fn unittest_descriptors() -> descriptor::Descriptors {
+    let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+    let proto = protobuf::parse_from_reader(&mut file).unwrap();
+    descriptor::Descriptors::from_proto(&proto)
+}
+
+macro_rules! check_json {
+    ($id:ident, $descriptors:expr, $name:expr, $bytes:expr, $options:expr, $json:expr) => {
+        #[test]
+        #[cfg(feature = "json")]
+        fn $id() {
+            let descriptors = $descriptors;
+            let message = descriptors.message_by_name($name).unwrap();
+            let bytes: &[u8] = &$bytes;
+            let expected: serde_json::Value = serde_json::from_str($json).unwrap();
+
+            let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+            let v = json::to_value(&descriptors, message, &mut input, $options).unwrap();
+            assert_eq!(expected, v);
+
+            let output = json::from_str(&descriptors, message, $json, $options).unwrap();
+            assert_eq!(bytes, &output[..]);
+        }
+    };
+}
+
+macro_rules! check_json_error {
+    ($id:ident, $descriptors:expr, $name:expr, $json:expr, $error:pat) => {
+        #[test]
+        #[cfg(feature = "json")]
+        fn $id() {
+            let descriptors = $descriptors;
+            let message = descriptors.message_by_name($name).unwrap();
+            match json::from_str(&descriptors, message, $json, json::Options::default()) {
+                Err($error) => (),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    };
+}
+
+check_json!(
+    json_all_types,
+    unittest_descriptors(),
+    ".protobuf_unittest.TestAllTypes",
+    {
+        use protobuf_unittest::unittest::{TestAllTypes, TestAllTypes_NestedEnum};
+
+        let mut v = TestAllTypes::new();
+        v.set_optional_int32(-3);
+        v.set_optional_int64(-5);
+        v.set_optional_uint64(u64::MAX);
+        v.set_optional_float(0.1);
+        v.set_optional_double(f64::NAN);
+        v.set_optional_bool(false);
+        v.set_optional_string("hi".to_owned());
+        v.set_optional_bytes(vec![0, 1, 2, 255]);
+        v.mut_optional_nested_message().set_bb(7);
+        v.set_optional_nested_enum(TestAllTypes_NestedEnum::BAZ);
+        v.mut_repeated_int32().extend(&[1, 2]);
+        v.mut_repeated_double().push(f64::NEG_INFINITY);
+        protobuf::Message::write_to_bytes(&v).unwrap()
+    },
+    json::Options::default(),
+    r#"{
+        "optionalInt32": -3,
+        "optionalInt64": "-5",
+        "optionalUint64": "18446744073709551615",
+        "optionalFloat": 0.1,
+        "optionalDouble": "NaN",
+        "optionalBool": false,
+        "optionalString": "hi",
+        "optionalBytes": "AAEC/w==",
+        "optionalNestedMessage": {"bb": 7},
+        "optionalNestedEnum": "BAZ",
+        "repeatedInt32": [1, 2],
+        "repeatedDouble": ["-Infinity"]
+    }"#
+);
+
+check_json!(
+    json_proto_field_names,
+    unittest_descriptors(),
+    ".protobuf_unittest.TestAllTypes",
+    [0x08, 0x2a],
+    json::Options::default().with_proto_field_names(true),
+    r#"{"optional_int32": 42}"#
+);
+
+check_json!(
+    json_proto3_zero_values,
+    proto3_descriptors(),
+    ".test.Proto3",
+    [0x10, 0x00],
+    json::Options::default(),
+    r#"{"b": 0}"#
+);
+
+check_json!(
+    json_maps,
+    map_descriptors(),
+    ".test.Maps",
+    [
+        0x0a, 0x05, 0x0a, 0x01, b'a', 0x10, 0x03, 0x0a, 0x05, 0x0a, 0x01, b'b', 0x10, 0x02, 0x10,
+        0x07, 0x1a, 0x06, 0x08, 0x05, 0x12, 0x02, 0x08, 0x09,
+    ],
+    json::Options::default(),
+    r#"{"counts": {"a": 3, "b": 2}, "after": 7, "inner": {"5": {"x": 9}}}"#
+);
+
+check_json!(
+    json_extensions,
+    unittest_descriptors(),
+    ".protobuf_unittest.TestAllExtensions",
+    EXTENSION_BYTES,
+    json::Options::default(),
+    r#"{
+        "[protobuf_unittest.optional_int32_extension]": 42,
+        "[protobuf_unittest.optional_string_extension]": "hi",
+        "[protobuf_unittest.optional_nested_message_extension]": {"bb": 7},
+        "[protobuf_unittest.repeated_int32_extension]": [1, 2],
+        "[protobuf_unittest.TestNestedExtension.test]": "x"
+    }"#
+);
+
+#[test]
+#[cfg(feature = "json")]
+fn json_well_known_types() {
+    let descriptors = well_known_descriptors();
+    let message = descriptors.message_by_name(".test.WellKnown").unwrap();
+    let options = json::Options::default();
+    // A `Value` without a kind is printed as `null`, so this doesn't round trip to the same bytes
+    let expected: serde_json::Value = serde_json::from_str(
+        r#"{
+            "timestamp": "1972-01-01T10:00:20.021Z",
+            "duration": "-1.500s",
+            "mask": "a.b,c",
+            "empty": {},
+            "count": 7,
+            "struct": {"b": true, "l": [1.5, "x"], "n": null, "s": {"k": null}}
+        }"#,
+    )
+    .unwrap();
+
+    let bytes = well_known_bytes();
+    let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let v = json::to_value(&descriptors, message, &mut input, options).unwrap();
+    assert_eq!(expected, v);
+
+    let mut bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+        json::from_value(&descriptors, message, &expected, &mut output, options).unwrap();
+        output.flush().unwrap();
+    }
+    let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let v = json::to_value(&descriptors, message, &mut input, options).unwrap();
+    assert_eq!(expected, v);
+}
+
+check_json!(
+    json_any_message,
+    well_known_descriptors(),
+    ".test.WellKnown",
+    any_bytes("type.googleapis.com/test.WellKnown", ANY_PAYLOAD),
+    json::Options::default(),
+    r#"{"any": {"@type": "type.googleapis.com/test.WellKnown", "count": 7}}"#
+);
+
+check_json!(
+    json_any_well_known,
+    well_known_descriptors(),
+    ".test.WellKnown",
+    any_bytes(
+        "type.googleapis.com/google.protobuf.Duration",
+        &[0x08, 0x03]
+    ),
+    json::Options::default(),
+    r#"{"any": {"@type": "type.googleapis.com/google.protobuf.Duration", "value": "3s"}}"#
+);
+
+#[test]
+#[cfg(feature = "json")]
+fn json_emit_defaults() {
+    let descriptors = proto3_descriptors();
+    let message = descriptors.message_by_name(".test.Proto3").unwrap();
+    let options = json::Options::default().with_emit_defaults(true);
+    let text = json::to_string(&descriptors, message, &[], options).unwrap();
+    assert_eq!(r#"{"a":0,"e":"ZERO"}"#, text);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn json_lenient_input() {
+    let descriptors = well_known_descriptors();
+    let message = descriptors.message_by_name(".test.WellKnown").unwrap();
+    let bytes = json::from_str(
+        &descriptors,
+        message,
+        r#"{"timestamp": "1972-01-01T11:00:20.021000+01:00", "count": "7e0", "unknown": 1}"#,
+        json::Options::default().with_ignore_unknown_fields(true),
+    )
+    .unwrap();
+    let expected = json::from_str(
+        &descriptors,
+        message,
+        r#"{"timestamp": "1972-01-01T10:00:20.021Z", "count": 7}"#,
+        json::Options::default(),
+    )
+    .unwrap();
+    assert_eq!(expected, bytes);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn json_any_unknown_type() {
+    let descriptors = well_known_descriptors();
+    let message = descriptors.message_by_name(".test.WellKnown").unwrap();
+    let bytes = any_bytes("type.googleapis.com/test.Unknown", ANY_PAYLOAD);
+    match json::to_string(&descriptors, message, &bytes, json::Options::default()) {
+        Err(error::Error::UnknownMessage { ref name })
+            if name == "type.googleapis.com/test.Unknown" => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+check_json_error!(
+    json_int32_out_of_range,
+    unittest_descriptors(),
+    ".protobuf_unittest.TestAllTypes",
+    r#"{"optionalInt32": 2147483648}"#,
+    error::Error::BadFieldValue { .. }
+);
+
+check_json_error!(
+    json_int32_fraction,
+    unittest_descriptors(),
+    ".protobuf_unittest.TestAllTypes",
+    r#"{"optionalInt32": 1.5}"#,
+    error::Error::BadFieldValue { .. }
+);
+
+check_json_error!(
+    json_float_out_of_range,
+    unittest_descriptors(),
+    ".protobuf_unittest.TestAllTypes",
+    r#"{"optionalFloat": 3.5e38}"#,
+    error::Error::BadFieldValue { .. }
+);
+
+check_json_error!(
+    json_double_bad_special_value,
+    unittest_descriptors(),
+    ".protobuf_unittest.TestAllTypes",
+    r#"{"optionalDouble": "inf"}"#,
+    error::Error::BadFieldValue { .. }
+);
+
+check_json_error!(
+    json_bool_string,
+    unittest_descriptors(),
+    ".protobuf_unittest.TestAllTypes",
+    r#"{"optionalBool": "true"}"#,
+    error::Error::BadFieldValue { .. }
+);
+
+check_json_error!(
+    json_bad_base64,
+    unittest_descriptors(),
+    ".protobuf_unittest.TestAllTypes",
+    r#"{"optionalBytes": "A"}"#,
+    error::Error::BadFieldValue { .. }
+);
+
+check_json_error!(
+    json_unknown_field,
+    unittest_descriptors(),
+    ".protobuf_unittest.TestAllTypes",
+    r#"{"unknownField": 1}"#,
+    error::Error::UnknownField { .. }
+);
+
+check_json_error!(
+    json_duplicate_field,
+    unittest_descriptors(),
+    ".protobuf_unittest.TestAllTypes",
+    r#"{"optionalInt32": 1, "optional_int32": 2}"#,
+    error::Error::BadFieldValue { .. }
+);
+
+check_json_error!(
+    json_multiple_oneof_fields,
+    unittest_descriptors(),
+    ".protobuf_unittest.TestAllTypes",
+    r#"{"oneofUint32": 1, "oneofString": "x"}"#,
+    error::Error::BadFieldValue { .. }
+);
+
+check_json_error!(
+    json_lowercase_timestamp,
+    well_known_descriptors(),
+    ".test.WellKnown",
+    r#"{"timestamp": "1972-01-01t10:00:20z"}"#,
+    error::Error::BadWellKnownValue { .. }
+);
+
+check_json_error!(
+    json_field_mask_underscore,
+    well_known_descriptors(),
+    ".test.WellKnown",
+    r#"{"mask": "foo_bar"}"#,
+    error::Error::BadWellKnownValue { .. }
+);
+
+check_json_error!(
+    json_syntax_error,
+    unittest_descriptors(),
+    ".protobuf_unittest.TestAllTypes",
+    r#"{"optionalInt32": }"#,
+    error::Error::Json(_)
+);
+
+const TEXT_ALL_TYPES: &str = r#"
+    # Scalars in various notations
+    optional_int32: -0x2a
+    optional_int64: 9223372036854775807,
+    optional_uint32: 017;
+    optional_float: 1.5f
+    optional_double: -inf
+    optional_bool: t
+    optional_string: "caf\303\251 " 'and \"more\"'
+    optional_bytes: "\x00\001\n"
+    optional_nested_enum: BAZ
+    optional_foreign_enum: 5
+    optional_nested_message: < bb: 7 >
+    repeated_int32: [1, 2]
+    repeated_int32: 3
+    repeated_nested_message { bb: 1 }
+    repeated_nested_message: { bb: 2 }
+"#;
+
+#[test]
+fn text_all_types() {
+    use protobuf_unittest::unittest::{ForeignEnum, TestAllTypes, TestAllTypes_NestedEnum};
+    use serde::de::Deserialize;
+
+    let descriptors = unittest_descriptors();
+
+    let mut v = TestAllTypes::new();
+    v.set_optional_int32(-42);
+    v.set_optional_int64(i64::MAX);
+    v.set_optional_uint32(15);
+    v.set_optional_float(1.5);
+    v.set_optional_double(f64::NEG_INFINITY);
+    v.set_optional_bool(true);
+    v.set_optional_string("caf\u{e9} and \"more\"".to_owned());
+    v.set_optional_bytes(vec![0, 1, b'\n']);
+    v.set_optional_nested_enum(TestAllTypes_NestedEnum::BAZ);
+    v.set_optional_foreign_enum(ForeignEnum::FOREIGN_BAR);
+    v.mut_optional_nested_message().set_bb(7);
+    v.mut_repeated_int32().extend(&[1, 2, 3]);
+    v.mut_repeated_nested_message().push_default().set_bb(1);
+    v.mut_repeated_nested_message().push_default().set_bb(2);
+    let bytes = protobuf::Message::write_to_bytes(&v).unwrap();
+
+    let name = ".protobuf_unittest.TestAllTypes";
+    let input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut deserializer = de::Deserializer::for_named_message(&descriptors, name, input).unwrap();
+    let expected = serde_value::Value::deserialize(&mut deserializer).unwrap();
+
+    let mut deserializer =
+        text::Deserializer::for_named_message(&descriptors, name, TEXT_ALL_TYPES).unwrap();
+    let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+    assert_eq!(expected, v);
+}
+
+#[test]
+fn text_print() {
+    let descriptors = unittest_descriptors();
+    let message_descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let message = text::from_str(&descriptors, message_descriptor, TEXT_ALL_TYPES).unwrap();
+    let printed = text::to_string(&descriptors, message_descriptor, &message).unwrap();
+    assert_eq!(
+        "optional_int32: -42
+optional_int64: 9223372036854775807
+optional_uint32: 15
+optional_float: 1.5
+optional_double: -inf
+optional_bool: true
+optional_string: \"caf\u{e9} and \\\"more\\\"\"
+optional_bytes: \"\\000\\001\\n\"
+optional_nested_message {
+  bb: 7
+}
+optional_nested_enum: BAZ
+optional_foreign_enum: FOREIGN_BAR
+repeated_int32: 1
+repeated_int32: 2
+repeated_int32: 3
+repeated_nested_message {
+  bb: 1
+}
+repeated_nested_message {
+  bb: 2
+}
+",
+        printed
+    );
+
+    let reparsed = text::from_str(&descriptors, message_descriptor, &printed).unwrap();
+    let reprinted = text::to_string(&descriptors, message_descriptor, &reparsed).unwrap();
+    assert_eq!(printed, reprinted);
+}
+
+#[test]
+fn text_groups() {
+    let descriptors = group_descriptors();
+    let message_descriptor = descriptors.message_by_name(".test.Groups").unwrap();
+    let text = "OptionalGroup { a: 5 } RepeatedGroup { a: 1 } RepeatedGroup { a: 2 }";
+    let message = text::from_str(&descriptors, message_descriptor, text).unwrap();
+
+    let mut output_bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::vec(&mut output_bytes);
+        message
+            .write_to(&descriptors, message_descriptor, &mut output)
+            .unwrap();
+        output.flush().unwrap();
+    }
+    assert_eq!(GROUPS, &output_bytes[..]);
+    assert_eq!(
+        "OptionalGroup {\n  a: 5\n}\nRepeatedGroup {\n  a: 1\n}\nRepeatedGroup {\n  a: 2\n}\n",
+        text::to_string(&descriptors, message_descriptor, &message).unwrap()
+    );
+}
+
+#[test]
+fn text_maps() {
+    use serde::de::Deserialize;
+
+    let descriptors = map_descriptors();
+    let text = r#"
+        counts { key: "a" value: 1 }
+        counts { key: "b" value: 2 }
+        counts { key: "a" value: 3 }
+        counts { key: "c" }
+        after: 7
+        inner { key: 5 value { x: 9 } }
+        inner { key: 6 }
+    "#;
+    let mut deserializer =
+        text::Deserializer::for_named_message(&descriptors, ".test.Maps", text).unwrap();
+    let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+
+    let input = protobuf::CodedInputStream::from_bytes(MAPS);
+    let mut deserializer =
+        de::Deserializer::for_named_message(&descriptors, ".test.Maps", input).unwrap();
+    let expected = serde_value::Value::deserialize(&mut deserializer).unwrap();
+    assert_eq!(expected, v);
+}
+
+#[test]
+fn text_any() {
+    let descriptors = well_known_descriptors();
+    let message_descriptor = descriptors.message_by_name(".test.WellKnown").unwrap();
+    let text = "any {\n  [type.googleapis.com/test.WellKnown] {\n    count {\n      value: 7\n    }\n  }\n}\n";
+    let message = text::from_str(&descriptors, message_descriptor, text).unwrap();
+
+    let mut output_bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::vec(&mut output_bytes);
+        message
+            .write_to(&descriptors, message_descriptor, &mut output)
+            .unwrap();
+        output.flush().unwrap();
+    }
+    assert_eq!(
+        any_bytes("type.googleapis.com/test.WellKnown", ANY_PAYLOAD),
+        output_bytes
+    );
+    assert_eq!(
+        text,
+        text::to_string(&descriptors, message_descriptor, &message).unwrap()
+    );
+}
+
+macro_rules! check_text_error {
+    ($id:ident, $text:expr, $line:expr, $column:expr) => {
+        #[test]
+        fn $id() {
+            let descriptors = unittest_descriptors();
+            let message_descriptor = descriptors
+                .message_by_name(".protobuf_unittest.TestAllTypes")
+                .unwrap();
+            match text::from_str(&descriptors, message_descriptor, $text) {
+                Err(error::Error::Syntax { line, column, .. }) => {
+                    assert_eq!(($line, $column), (line, column))
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    };
+}
+
+check_text_error!(text_unknown_field, "optional_int32: 1\n  unknown: 2", 2, 3);
+check_text_error!(text_missing_colon, "optional_int32 1", 1, 16);
+check_text_error!(text_out_of_range, "optional_int32: 2147483648", 1, 17);
+check_text_error!(text_unknown_enum_value, "optional_nested_enum: QUX", 1, 23);
+check_text_error!(
+    text_repeated_singular,
+    "optional_int32: 1 optional_int32: 2",
+    1,
+    19
+);
+check_text_error!(
+    text_unterminated_message,
+    "optional_nested_message {",
+    1,
+    26
+);
+check_text_error!(text_unknown_extension, "[foo.bar]: 1", 1, 1);
+check_text_error!(
+    text_extension_of_other_message,
+    "[protobuf_unittest.optional_int32_extension]: 1",
+    1,
+    1
+);
+
+const EXTENSION_BYTES: &[u8] = &[
+    0x08, 0x2a, 0x72, 0x02, b'h', b'i', 0x92, 0x01, 0x02, 0x08, 0x07, 0xf8, 0x01, 0x01, 0xf8, 0x01,
+    0x02, 0xd2, 0x3e, 0x01, b'x',
+];
+
+const TEXT_EXTENSIONS: &str = r#"[protobuf_unittest.optional_int32_extension]: 42
+[protobuf_unittest.optional_string_extension]: "hi"
+[protobuf_unittest.optional_nested_message_extension] {
+  bb: 7
+}
+[protobuf_unittest.repeated_int32_extension]: 1
+[protobuf_unittest.repeated_int32_extension]: 2
+[protobuf_unittest.TestNestedExtension.test]: "x"
+"#;
+
+#[test]
+fn extensions_registered() {
+    let descriptors = unittest_descriptors();
+    let extendee = ".protobuf_unittest.TestAllExtensions";
+
+    let extension = descriptors.extension_by_number(extendee, 1002).unwrap();
+    assert_eq!(
+        ".protobuf_unittest.TestNestedExtension.test",
+        extension.full_name()
+    );
+    assert_eq!(Some(extendee), extension.extendee());
+    match extension.default_value() {
+        Some(value::Value::String(v)) => assert_eq!("test", v),
+        v => panic!("unexpected default value: {:?}", v),
+    }
+
+    let extension = descriptors
+        .extension_by_name(".protobuf_unittest.optional_nested_message_extension")
+        .unwrap();
+    match extension.field_type(&descriptors) {
+        descriptor::FieldType::Message(m) => {
+            assert_eq!(".protobuf_unittest.TestAllTypes.NestedMessage", m.name())
+        }
+        t => panic!("unexpected field type: {:?}", t),
+    }
+
+    let message = descriptors.message_by_name(extendee).unwrap();
+    assert!(message.is_extension_number(1002));
+    assert!(descriptors
+        .extension_of(message, "protobuf_unittest.optional_int32_extension")
+        .is_some());
+    let other = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    assert!(descriptors
+        .extension_of(other, "protobuf_unittest.optional_int32_extension")
+        .is_none());
+}
+
+#[test]
+fn extensions_deserialize() {
+    use serde::de::Deserialize;
+
+    let descriptors = unittest_descriptors();
+    let name = ".protobuf_unittest.TestAllExtensions";
+    let input = protobuf::CodedInputStream::from_bytes(EXTENSION_BYTES);
+    let mut deserializer = de::Deserializer::for_named_message(&descriptors, name, input).unwrap();
+    let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+
+    assert_eq!(
+        value!(map {
+            (str: "[protobuf_unittest.optional_int32_extension]") => (some i32: 42),
+            (str: "[protobuf_unittest.optional_string_extension]") => (some str: "hi"),
+            (str: "[protobuf_unittest.optional_nested_message_extension]") =>
+                (some map { (str: "bb") => (some i32: 7) }),
+            (str: "[protobuf_unittest.repeated_int32_extension]") => (seq [(i32: 1), (i32: 2)]),
+            (str: "[protobuf_unittest.TestNestedExtension.test]") => (some str: "x")
+        }),
+        v
+    );
+}
+
+#[test]
+fn extensions_reencode() {
+    let descriptors = unittest_descriptors();
+    let message_descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllExtensions")
+        .unwrap();
+    let mut message = value::Message::new(message_descriptor);
+    message
+        .merge_from(
+            &descriptors,
+            message_descriptor,
+            &mut protobuf::CodedInputStream::from_bytes(EXTENSION_BYTES),
+        )
+        .unwrap();
+    assert!(message.unknown.fields.is_none());
+
+    let mut output_bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::vec(&mut output_bytes);
+        message
+            .write_to(&descriptors, message_descriptor, &mut output)
+            .unwrap();
+        output.flush().unwrap();
+    }
+    assert_eq!(EXTENSION_BYTES, &output_bytes[..]);
+    assert_eq!(
+        EXTENSION_BYTES.len() as u32,
+        message.compute_size(&descriptors, message_descriptor)
+    );
+}
+
+#[test]
+fn extensions_serialize() {
+    let m = serialize!(
+        protobuf_unittest::unittest::TestAllExtensions,
+        value!(map {
+            (str: "[protobuf_unittest.optional_int32_extension]") => (i32: 42),
+            (str: "[protobuf_unittest.TestNestedExtension.test]") => (str: "x")
+        })
+    )
+    .unwrap();
+    let int32 = m.get_unknown_fields().get(1).unwrap();
+    assert_eq!(vec![42], int32.varint);
+    let test = m.get_unknown_fields().get(1002).unwrap();
+    assert_eq!(vec![b"x".to_vec()], test.length_delimited);
+}
+
+#[test]
+fn text_extensions() {
+    let descriptors = unittest_descriptors();
+    let message_descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllExtensions")
+        .unwrap();
+    let message = text::from_str(&descriptors, message_descriptor, TEXT_EXTENSIONS).unwrap();
+
+    let mut output_bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::vec(&mut output_bytes);
+        message
+            .write_to(&descriptors, message_descriptor, &mut output)
+            .unwrap();
+        output.flush().unwrap();
+    }
+    assert_eq!(EXTENSION_BYTES, &output_bytes[..]);
+    assert_eq!(
+        TEXT_EXTENSIONS,
+        text::to_string(&descriptors, message_descriptor, &message).unwrap()
+    );
+}
+
+#[test]
+fn parser_unittest_matches_protoc() {
+    let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+    let expected: protobuf::descriptor::FileDescriptorSet =
+        protobuf::parse_from_reader(&mut file).unwrap();
+
+    let mut parser = parser::Parser::new();
+    parser.add_include_path("testdata");
+    let actual = parser
+        .parse_files(&["google/protobuf/unittest.proto"])
+        .unwrap();
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn parser_proto3() {
+    let mut parser = parser::Parser::new();
+    parser.add_source(
+        "common.proto",
+        r#"
+            syntax = "proto3";
+            package test.common;
+
+            enum Level {
+                LOW = 0;
+                HIGH = 1;
+            }
+        "#,
+    );
+    parser.add_source(
+        "schema.proto",
+        r#"
+            syntax = "proto3";
+            package test.schema;
+
+            import "common.proto";
+
+            message Outer {
+                message Inner {
+                    int32 x = 1;
+                }
+
+                map<string, Inner> inners = 1;
+                common.Level level = 2;
+                optional string nickname = 3;
+                oneof choice {
+                    int64 id = 4;
+                    Inner inner = 5;
+                }
+                repeated int32 values = 6;
+            }
+        "#,
+    );
+    let descriptors = parser.parse_descriptors(&["schema.proto"]).unwrap();
+    let outer = descriptors.message_by_name(".test.schema.Outer").unwrap();
+
+    match outer
+        .field_by_name("inners")
+        .unwrap()
+        .field_type(&descriptors)
+    {
+        descriptor::FieldType::Message(entry) => {
+            assert_eq!(".test.schema.Outer.InnersEntry", entry.name());
+            assert!(entry.is_map_entry());
+        }
+        other => panic!("unexpected field type: {:?}", other),
+    }
+    match outer
+        .field_by_name("level")
+        .unwrap()
+        .field_type(&descriptors)
+    {
+        descriptor::FieldType::Enum(e) => assert_eq!(".test.common.Level", e.name()),
+        other => panic!("unexpected field type: {:?}", other),
+    }
+
+    let nickname = outer.field_by_name("nickname").unwrap();
+    assert!(nickname.is_proto3_optional());
+    let oneofs = outer.oneofs().iter().map(|o| o.name()).collect::<Vec<_>>();
+    assert_eq!(vec!["choice", "_nickname"], oneofs);
+    assert_eq!("_nickname", outer.oneof_of_field(nickname).unwrap().name());
+
+    let text = "inners {\n  key: \"a\"\n  value {\n    x: 1\n  }\n}\nlevel: HIGH\nnickname: \"n\"\ninner {\n  x: 2\n}\nvalues: 1\nvalues: 2\n";
+    let message = text::from_str(&descriptors, outer, text).unwrap();
+    assert_eq!(
+        text,
+        text::to_string(&descriptors, outer, &message).unwrap()
+    );
+}
+
+#[test]
+fn parser_options() {
+    let mut parser = parser::Parser::new();
+    parser.add_source(
+        "options.proto",
+        r#"
+            package test;
+
+            import "google/protobuf/descriptor.proto";
+
+            option java_package = "com.example.test";
+            option optimize_for = LITE_RUNTIME;
+
+            extend google.protobuf.MessageOptions {
+                optional string label = 50000;
+            }
+
+            extend google.protobuf.FieldOptions {
+                optional Sensitivity sensitivity = 50001;
+                optional sint32 weight = 50002;
+            }
+
+            enum Sensitivity {
+                PUBLIC = 0;
+                SECRET = 1;
+            }
+
+            message Account {
+                option (label) = "account";
+
+                optional string password = 1 [(sensitivity) = SECRET, (test.weight) = -3,
+                                              deprecated = true];
+                repeated int32 ids = 2 [packed = true];
+                optional double limit = 3 [default = 1e6];
+                optional Sensitivity level = 4 [default = SECRET];
+                optional bytes magic = 5 [default = "\001ab"];
+            }
+        "#,
+    );
+    let file_set = parser.parse_files(&["options.proto"]).unwrap();
+    assert_eq!(2, file_set.get_file().len());
+    assert_eq!(
+        "google/protobuf/descriptor.proto",
+        file_set.get_file()[0].get_name()
+    );
+
+    let file = &file_set.get_file()[1];
+    assert_eq!("com.example.test", file.get_options().get_java_package());
+    assert_eq!(
+        protobuf::descriptor::FileOptions_OptimizeMode::LITE_RUNTIME,
+        file.get_options().get_optimize_for()
+    );
+
+    let account = &file.get_message_type()[0];
+    let label = account
+        .get_options()
+        .get_unknown_fields()
+        .get(50000)
+        .unwrap();
+    assert_eq!(vec![b"account".to_vec()], label.length_delimited);
+
+    let fields = account.get_field();
+    let password = fields[0].get_options();
+    assert!(password.get_deprecated());
+    let unknown_fields = password.get_unknown_fields();
+    assert_eq!(vec![1], unknown_fields.get(50001).unwrap().varint);
+    assert_eq!(vec![5], unknown_fields.get(50002).unwrap().varint);
+    assert!(fields[1].get_options().get_packed());
+    assert_eq!("1000000", fields[2].get_default_value());
+    assert_eq!("SECRET", fields[3].get_default_value());
+    assert_eq!(".test.Sensitivity", fields[3].get_type_name());
+    assert_eq!("\\001ab", fields[4].get_default_value());
+}
+
+#[test]
+fn descriptor_options() {
+    let mut parser = parser::Parser::new();
+    parser.add_source(
+        "options.proto",
+        r#"
+            syntax = "proto3";
+            package test;
+
+            import "google/protobuf/descriptor.proto";
+
+            extend google.protobuf.MessageOptions {
+                string table = 50000;
+            }
+
+            extend google.protobuf.FieldOptions {
+                bool redact = 50001;
+                int32 max_length = 50002;
+            }
+
+            extend google.protobuf.EnumOptions {
+                string prefix = 50003;
+            }
+
+            extend google.protobuf.EnumValueOptions {
+                string label = 50004;
+            }
+
+            message Account {
+                option (table) = "accounts";
+                option deprecated = true;
+
+                string password = 1 [(redact) = true, (max_length) = 64, deprecated = true];
+                repeated int32 ids = 2;
+                repeated int32 legacy_ids = 3 [packed = false];
+            }
+
+            enum Status {
+                option allow_alias = true;
+                option (prefix) = "STATUS_";
+
+                ACTIVE = 0 [(label) = "Active"];
+                ENABLED = 0 [deprecated = true];
+            }
+        "#,
+    );
+    let descriptors = parser.parse_descriptors(&["options.proto"]).unwrap();
+
+    let account = descriptors.message_by_name(".test.Account").unwrap();
+    assert!(account.is_deprecated());
+    let options = account.options(&descriptors).unwrap();
+    match options.fields[&50000] {
+        value::Field::Singular(Some(value::Value::String(ref s))) => assert_eq!("accounts", s),
+        ref f => panic!("Expected a string, got {:?}", f),
+    }
+    match options.fields[&3] {
+        value::Field::Singular(Some(value::Value::Bool(true))) => (),
+        ref f => panic!("Expected true, got {:?}", f),
+    }
+
+    let password = account.field_by_name("password").unwrap();
+    assert!(password.is_deprecated());
+    let options = password.options(&descriptors).unwrap();
+    match options.fields[&50001] {
+        value::Field::Singular(Some(value::Value::Bool(true))) => (),
+        ref f => panic!("Expected true, got {:?}", f),
+    }
+    match options.fields[&50002] {
+        value::Field::Singular(Some(value::Value::I32(64))) => (),
+        ref f => panic!("Expected 64, got {:?}", f),
+    }
+    assert!(options.unknown.iter().next().is_none());
+    assert!(account.field_by_name("ids").unwrap().is_packed());
+    assert!(!account.field_by_name("legacy_ids").unwrap().is_packed());
+    assert!(account
+        .field_by_name("ids")
+        .unwrap()
+        .options(&descriptors)
+        .unwrap()
+        .fields
+        .is_empty());
+
+    let status = descriptors.enum_by_name(".test.Status").unwrap();
+    assert!(status.allows_alias());
+    assert!(!status.is_deprecated());
+    match status.options(&descriptors).unwrap().fields[&50003] {
+        value::Field::Singular(Some(value::Value::String(ref s))) => assert_eq!("STATUS_", s),
+        ref f => panic!("Expected a string, got {:?}", f),
+    }
+    let active = status.value_by_name("ACTIVE").unwrap();
+    assert!(!active.is_deprecated());
+    match active.options(&descriptors).unwrap().fields[&50004] {
+        value::Field::Singular(Some(value::Value::String(ref s))) => assert_eq!("Active", s),
+        ref f => panic!("Expected a string, got {:?}", f),
+    }
+    assert!(status.value_by_name("ENABLED").unwrap().is_deprecated());
+}
+
+#[test]
+fn parser_services() {
+    use serde::de::Deserialize;
+
+    let mut parser = parser::Parser::new();
+    parser.add_source(
+        "chat.proto",
+        r#"
+            syntax = "proto3";
+            package test.chat;
+
+            message Line {
+                string text = 1;
+            }
+
+            message Ack {
+                int32 count = 1;
+            }
+
+            service Chat {
+                rpc Send(stream Line) returns (Ack);
+                rpc Listen(Ack) returns (stream Line);
+            }
+        "#,
+    );
+    let descriptors = parser.parse_descriptors(&["chat.proto"]).unwrap();
+
+    let send = descriptors.method_by_path("/test.chat.Chat/Send").unwrap();
+    assert!(send.is_client_streaming());
+    assert!(!send.is_server_streaming());
+    let listen = descriptors
+        .method_by_path("/test.chat.Chat/Listen")
+        .unwrap();
+    assert!(!listen.is_client_streaming());
+    assert!(listen.is_server_streaming());
+
+    // Decode a captured request body just from the method path
+    let bytes = [0x0a, 0x02, b'h', b'i'];
+    let input = send.input_type(&descriptors).unwrap();
+    let mut deserializer = de::Deserializer::new(
+        &descriptors,
+        input,
+        protobuf::CodedInputStream::from_bytes(&bytes),
+    );
+    let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+    assert_eq!(value!(map { (str: "text") => (str: "hi") }), v);
+    assert_eq!(
+        ".test.chat.Ack",
+        send.output_type(&descriptors).unwrap().name()
+    );
+}
+
+#[test]
+fn parser_import_cycle() {
+    let mut parser = parser::Parser::new();
+    parser.add_source("a.proto", "import \"b.proto\";");
+    parser.add_source("b.proto", "\nimport \"a.proto\";");
+    match parser.parse_files(&["a.proto"]) {
+        Err(error::Error::BadProtoFile {
+            file,
+            line,
+            column,
+            message,
+        }) => {
+            assert_eq!(("b.proto", 2, 8), (file.as_str(), line, column));
+            assert_eq!("import cycle: a.proto -> b.proto -> a.proto", message);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+macro_rules! check_parser_error {
+    ($id:ident, $source:expr, $line:expr, $column:expr) => {
+        #[test]
+        fn $id() {
+            let mut parser = parser::Parser::new();
+            parser.add_source("test.proto", $source);
+            match parser.parse_files(&["test.proto"]) {
+                Err(error::Error::BadProtoFile {
+                    file, line, column, ..
+                }) => assert_eq!(
+                    ("test.proto", $line, $column),
+                    (file.as_str(), line, column)
+                ),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    };
+}
+
+check_parser_error!(
+    parser_missing_semicolon,
+    "syntax = \"proto3\";\nmessage A {\n  int32 a = 1\n}",
+    4,
+    1
+);
+check_parser_error!(
+    parser_unknown_type,
+    "syntax = \"proto3\";\nmessage A {\n  Missing a = 1;\n}",
+    3,
+    3
+);
+check_parser_error!(parser_missing_label, "message A {\n  int32 a = 1;\n}", 2, 3);
+check_parser_error!(
+    parser_field_number_out_of_range,
+    "syntax = \"proto3\";\nmessage A { int32 a = 0; }",
+    2,
+    23
+);
+check_parser_error!(parser_unknown_option, "option foo = 1;", 1, 8);
+check_parser_error!(parser_bad_option_value, "option java_package = 1;", 1, 8);
+check_parser_error!(parser_unterminated_string, "package \"foo;", 1, 9);
+check_parser_error!(parser_missing_import, "import \"missing.proto\";", 1, 8);
+check_parser_error!(
+    parser_unknown_enum_default,
+    "message A { optional E e = 1 [default = C]; }\nenum E { B = 1; }",
+    1,
+    31
+);
+check_parser_error!(
+    parser_proto3_nonzero_enum,
+    "syntax = \"proto3\";\nenum E { A = 1; }",
+    2,
+    10
+);